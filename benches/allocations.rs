@@ -0,0 +1,97 @@
+//! Allocations made while receiving a sustained video stream on localhost, for
+//! several frame sizes
+//!
+//! Counts every allocation of the process between the first and the last frame,
+//! so the recv loop along with the monitor delivering the frames
+//!
+//! `cargo bench --bench allocations`
+
+mod common;
+
+use common::VideoStream;
+use dvrip_rs::{Authentication, Connection, DVRIPCam, Monitoring};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+
+const FRAMES: usize = 20_000;
+const FRAME_SIZES: &[usize] = &[1024, 16 * 1024, 64 * 1024];
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+/// The system allocator, counting the allocations and reallocations
+struct Counting;
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(new_size, Ordering::Relaxed);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+#[tokio::main]
+async fn main() {
+    println!("{} frames per run", FRAMES);
+    for &frame_size in FRAME_SIZES {
+        let addr = common::spawn_streaming(VideoStream {
+            frames: FRAMES,
+            frame_size,
+        })
+        .await;
+        let mut cam = DVRIPCam::new(addr.ip().to_string())
+            .with_port(addr.port())
+            .with_keep_alive(false);
+        cam.connect(Duration::from_secs(2)).await.unwrap();
+        assert!(cam.login("admin", "").await.unwrap());
+
+        let mut frames = cam.start_monitor_bytes("Main", 0).await.unwrap();
+        // Counted from the first frame on, once the buffers have grown
+        let mut skipped = 0;
+        while let Err(RecvError::Lagged(lagged)) = frames.recv().await {
+            skipped += lagged as usize;
+        }
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+        let allocated_bytes = ALLOCATED_BYTES.load(Ordering::Relaxed);
+
+        let mut received = 1;
+        let mut lagged = 0;
+        let remaining = FRAMES - skipped;
+        while received + lagged < remaining {
+            match frames.recv().await {
+                Ok(_) => received += 1,
+                Err(RecvError::Lagged(skipped)) => lagged += skipped as usize,
+                Err(RecvError::Closed) => break,
+            }
+        }
+
+        let counted = (received + lagged - 1).max(1);
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+        let allocated_bytes = ALLOCATED_BYTES.load(Ordering::Relaxed) - allocated_bytes;
+        println!(
+            "{:>2} KiB frames: {:.1} allocations, {} bytes allocated per frame ({} lagged)",
+            frame_size / 1024,
+            allocations as f64 / counted as f64,
+            allocated_bytes / counted,
+            lagged
+        );
+
+        drop(frames);
+        let _ = cam.close().await;
+    }
+}
@@ -0,0 +1,164 @@
+//! A device on localhost answering every command with `Ret` 100, for the benchmarks
+//!
+//! `spawn_streaming` also sends video frames once a monitor is started
+#![allow(dead_code)]
+
+use dvrip_rs::constants::UPGRADE_SUCCESS;
+use dvrip_rs::protocol::{PacketHeader, packet_header, packet_tail, parse_json};
+use serde_json::{Value, json};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+const SESSION: u32 = 0x11;
+const LOGIN: u16 = 1000;
+const SEND_FILE: u16 = 0x5F2;
+const MONITOR_START: u16 = 1410;
+const MONITOR_DATA: u16 = 1412;
+
+/// Video sent after the monitor start, `frames` P-frames of `frame_size` bytes each
+/// in their own packet
+#[derive(Debug, Clone, Copy)]
+pub struct VideoStream {
+    pub frames: usize,
+    pub frame_size: usize,
+}
+
+/// What the writer task sends once due
+enum Outgoing {
+    Packet(Vec<u8>),
+    /// The same frame packet over and over, built once so the device doesn't
+    /// allocate per frame
+    Stream(VideoStream),
+}
+
+/// Start a device serving one connection, every packet it sends arrives `latency`
+/// after the request it answers, like over a slow link
+pub async fn spawn(latency: Duration) -> SocketAddr {
+    serve(latency, None).await
+}
+
+/// Start a device serving one connection without latency, sending `stream` on
+/// channel 0 once a monitor is started
+pub async fn spawn_streaming(stream: VideoStream) -> SocketAddr {
+    serve(Duration::ZERO, Some(stream)).await
+}
+
+async fn serve(latency: Duration, video: Option<VideoStream>) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        stream.set_nodelay(true).unwrap();
+        let (mut reader, mut writer) = stream.into_split();
+
+        // Delayed on their own task so replies overlap like they do on the wire
+        let (replies, mut queue) = mpsc::unbounded_channel::<(Instant, Outgoing)>();
+        tokio::spawn(async move {
+            while let Some((due, outgoing)) = queue.recv().await {
+                tokio::time::sleep_until(due).await;
+                let sent = match outgoing {
+                    Outgoing::Packet(packet) => writer.write_all(&packet).await,
+                    Outgoing::Stream(stream) => {
+                        let packet = frame_packet(stream.frame_size);
+                        let mut sent = Ok(());
+                        for _ in 0..stream.frames {
+                            sent = writer.write_all(&packet).await;
+                            if sent.is_err() {
+                                break;
+                            }
+                        }
+                        sent
+                    }
+                };
+                if sent.is_err() {
+                    return;
+                }
+            }
+        });
+
+        let mut header = [0u8; PacketHeader::SIZE];
+        while reader.read_exact(&mut header).await.is_ok() {
+            let Ok(header) = PacketHeader::decode(&header) else {
+                return;
+            };
+            let mut data = vec![0u8; header.data_len as usize];
+            if reader.read_exact(&mut data).await.is_err() {
+                return;
+            }
+
+            let due = Instant::now() + latency;
+            // Devices answer these one packet count further, see `CommandRequest::reply_key`
+            let packet_count = if matches!(header.msg_id, 0x0585 | 0x590 | 0x059a) {
+                header.packet_count + 1
+            } else {
+                header.packet_count
+            };
+            for (msg_id, reply) in answer(&header, &data) {
+                let packet = encode(packet_count, msg_id, &reply);
+                if replies.send((due, Outgoing::Packet(packet))).is_err() {
+                    return;
+                }
+            }
+
+            // Leaves the client time to subscribe to the monitor it just started
+            if let Some(video) = video
+                && header.msg_id == MONITOR_START
+            {
+                let due = Instant::now() + Duration::from_millis(100);
+                if replies.send((due, Outgoing::Stream(video))).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    addr
+}
+
+/// Replies to a request, the last chunk of an upload is followed by the end of
+/// the upgrade
+fn answer(header: &PacketHeader, data: &[u8]) -> Vec<(u16, Value)> {
+    let reply_id = header.msg_id + 1;
+    match header.msg_id {
+        LOGIN => vec![(
+            reply_id,
+            json!({
+                "Ret": 100,
+                "SessionID": format!("0x{:08X}", SESSION),
+                "AliveInterval": 20,
+            }),
+        )],
+        SEND_FILE if data.len() <= packet_tail(0).len() => vec![
+            (reply_id, json!({"Ret": 100})),
+            (SEND_FILE, json!({"Ret": UPGRADE_SUCCESS})),
+        ],
+        _ => {
+            let name = parse_json(data)
+                .ok()
+                .and_then(|request| request.get("Name").cloned())
+                .unwrap_or(Value::Null);
+            vec![(reply_id, json!({"Name": name, "Ret": 100}))]
+        }
+    }
+}
+
+fn encode(packet_count: u32, msg_id: u16, reply: &Value) -> Vec<u8> {
+    let mut payload = serde_json::to_vec(reply).unwrap();
+    payload.extend_from_slice(packet_tail(0));
+    let mut packet = packet_header(SESSION, packet_count, msg_id, payload.len(), 0).encode();
+    packet.extend_from_slice(&payload);
+    packet
+}
+
+/// A monitor packet holding one P-frame of `size` bytes
+fn frame_packet(size: usize) -> Vec<u8> {
+    let mut payload = vec![0, 0, 1, 0xFD];
+    payload.extend_from_slice(&(size as u32).to_le_bytes());
+    payload.resize(payload.len() + size, 0x5A);
+    let mut packet = packet_header(SESSION, 0, MONITOR_DATA, payload.len(), 0).encode();
+    packet.extend_from_slice(&payload);
+    packet
+}
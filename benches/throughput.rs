@@ -0,0 +1,59 @@
+//! Firmware upload throughput on localhost, for several chunk sizes
+//!
+//! `cargo bench --bench throughput`
+
+mod common;
+
+use dvrip_rs::{Authentication, Connection, DVRIPCam, Upgrade, UpgradeOptions};
+use std::time::{Duration, Instant};
+
+const FIRMWARE_SIZE: usize = 8 * 1024 * 1024;
+const PACKET_SIZES: &[usize] = &[1024, 8 * 1024, 32 * 1024, 64 * 1024];
+const WINDOW: usize = 4;
+const ROUNDS: u32 = 3;
+
+#[tokio::main]
+async fn main() {
+    let firmware = std::env::temp_dir().join(format!("dvrip-bench-{}.bin", std::process::id()));
+    std::fs::write(&firmware, vec![0xA5; FIRMWARE_SIZE]).unwrap();
+    let firmware_path = firmware.to_str().unwrap();
+
+    println!(
+        "{} MiB, window {}, best of {}",
+        FIRMWARE_SIZE / (1024 * 1024),
+        WINDOW,
+        ROUNDS
+    );
+    for &packet_size in PACKET_SIZES {
+        let mut best = Duration::MAX;
+        for _ in 0..ROUNDS {
+            let addr = common::spawn(Duration::ZERO).await;
+            let mut cam = DVRIPCam::new(addr.ip().to_string())
+                .with_port(addr.port())
+                .with_keep_alive(false);
+            cam.connect(Duration::from_secs(2)).await.unwrap();
+            assert!(cam.login("admin", "").await.unwrap());
+
+            let options = UpgradeOptions {
+                window: WINDOW,
+                force: true,
+            };
+            let start = Instant::now();
+            cam.upgrade_with_options(firmware_path, packet_size, options, None)
+                .await
+                .unwrap();
+            best = best.min(start.elapsed());
+            let _ = cam.close().await;
+        }
+
+        let mib_per_sec = FIRMWARE_SIZE as f64 / (1024.0 * 1024.0) / best.as_secs_f64();
+        println!(
+            "{:>3} KiB chunks: {:>8.1?} ({:.0} MiB/s)",
+            packet_size / 1024,
+            best,
+            mib_per_sec
+        );
+    }
+
+    let _ = std::fs::remove_file(&firmware);
+}
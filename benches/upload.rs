@@ -0,0 +1,57 @@
+//! Firmware upload time over a link with latency, for each `UpgradeOptions::window`
+//!
+//! `cargo bench --bench upload`
+
+mod common;
+
+use dvrip_rs::{Authentication, Connection, DVRIPCam, Upgrade, UpgradeOptions};
+use std::time::{Duration, Instant};
+
+const FIRMWARE_SIZE: usize = 512 * 1024;
+const PACKET_SIZE: usize = 8 * 1024;
+const LATENCY: Duration = Duration::from_millis(5);
+const WINDOWS: &[usize] = &[1, 2, 4, 8, 16];
+
+#[tokio::main]
+async fn main() {
+    let firmware = std::env::temp_dir().join(format!("dvrip-bench-{}.bin", std::process::id()));
+    std::fs::write(&firmware, vec![0xA5; FIRMWARE_SIZE]).unwrap();
+    let firmware_path = firmware.to_str().unwrap();
+
+    println!(
+        "{} KiB in {} KiB chunks, {:?} latency",
+        FIRMWARE_SIZE / 1024,
+        PACKET_SIZE / 1024,
+        LATENCY
+    );
+    let mut serial = None;
+    for &window in WINDOWS {
+        let addr = common::spawn(LATENCY).await;
+        let mut cam = DVRIPCam::new(addr.ip().to_string())
+            .with_port(addr.port())
+            .with_keep_alive(false);
+        cam.connect(Duration::from_secs(2)).await.unwrap();
+        assert!(cam.login("admin", "").await.unwrap());
+
+        let options = UpgradeOptions {
+            window,
+            force: true,
+        };
+        let start = Instant::now();
+        cam.upgrade_with_options(firmware_path, PACKET_SIZE, options, None)
+            .await
+            .unwrap();
+        let elapsed = start.elapsed();
+        let serial = *serial.get_or_insert(elapsed);
+
+        println!(
+            "window {:>2}: {:>8.1?} ({:.1}x)",
+            window,
+            elapsed,
+            serial.as_secs_f64() / elapsed.as_secs_f64()
+        );
+        let _ = cam.close().await;
+    }
+
+    let _ = std::fs::remove_file(&firmware);
+}
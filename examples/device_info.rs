@@ -31,6 +31,17 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
 
     // 4. Retrieve System Information
+    println!("\n--- Device Identity ---");
+    match cam.get_device_identity().await {
+        Ok(identity) => println!("{:#?}", identity),
+        Err(e) => eprintln!("Error getting device identity: {}", e),
+    }
+
+    match cam.get_device_name().await {
+        Ok(name) => println!("Device name: {}", name),
+        Err(e) => eprintln!("Error getting device name: {}", e),
+    }
+
     println!("\n--- General Info ---");
     match cam.get_general_info().await {
         Ok(general) => println!("{:#?}", general),
@@ -0,0 +1,50 @@
+use dvrip_rs::{Authentication, Connection, DVRIPCam, ProxyConfig, SystemInfo};
+use std::time::Duration;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 5 {
+        println!(
+            "Usage: {} <IP> <Username> <Password> <ProxyHost:Port> [ProxyUser] [ProxyPass]",
+            args[0]
+        );
+        println!(
+            "Example: cargo run --example proxy_connect -- 192.168.1.10 admin pass123 jump.example.com:1080"
+        );
+        println!("Tip: `ssh -D 1080 user@jump-host` opens a SOCKS5 proxy on localhost:1080");
+        return Ok(());
+    }
+
+    let ip = &args[1];
+    let user = &args[2];
+    let pass = &args[3];
+
+    let (proxy_host, proxy_port) = args[4]
+        .rsplit_once(':')
+        .ok_or("Proxy must be given as host:port")?;
+    let mut proxy = ProxyConfig::new(proxy_host, proxy_port.parse()?);
+    if let (Some(proxy_user), Some(proxy_pass)) = (args.get(5), args.get(6)) {
+        proxy = proxy.with_credentials(proxy_user, proxy_pass);
+    }
+
+    // 1. Initialize the camera client, every packet goes through the proxy
+    let mut cam = DVRIPCam::new(ip).with_proxy(proxy);
+
+    // 2. Connect, the timeout includes the proxy handshake
+    println!("Connecting to {} through {}...", ip, args[4]);
+    cam.connect(Duration::from_secs(10)).await?;
+
+    // 3. Login
+    if !cam.login(user, pass).await? {
+        println!("Login failed!");
+        return Ok(());
+    }
+
+    // 4. Use the device as usual
+    let info = cam.get_system_info().await?;
+    println!("{:#}", info);
+
+    cam.close().await?;
+    Ok(())
+}
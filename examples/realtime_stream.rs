@@ -25,9 +25,6 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     println!("Starting real-time stream...");
 
-    // Define the frame callback
-    let callback = Box::new(|frame: Vec<u8>, metadata: dvrip_rs::FrameMetadata| {});
-
     // Start monitoring on channel 0, main stream ("Main")
     let mut data = cam.start_monitor("Main", 0).await?;
 
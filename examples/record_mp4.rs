@@ -0,0 +1,44 @@
+use dvrip_rs::{Authentication, Connection, DVRIPCam, Monitoring, Mp4Recorder};
+use std::time::Duration;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 4 {
+        println!("Usage: {} <IP> <Username> <Password> [output.mp4]", args[0]);
+        return Ok(());
+    }
+
+    let ip = &args[1];
+    let user = &args[2];
+    let pass = &args[3];
+    let output = args.get(4).map(|s| s.as_str()).unwrap_or("recording.mp4");
+
+    let mut cam = DVRIPCam::new(ip);
+    cam.connect(Duration::from_secs(5)).await?;
+
+    if !cam.login(user, pass).await? {
+        println!("Login failed");
+        return Ok(());
+    }
+
+    let mut frames = cam.start_monitor_bytes("Main", 0).await?;
+    let mut recorder = Mp4Recorder::create(output).await?;
+
+    println!("Recording for 10 seconds...");
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+    while let Ok(Ok((metadata, frame))) = tokio::time::timeout_at(deadline, frames.recv()).await {
+        recorder.push(&metadata, &frame).await?;
+    }
+
+    cam.stop_monitor().await?;
+    cam.close().await?;
+
+    if recorder.finish().await? {
+        println!("Saved {}", output);
+    } else {
+        println!("Unknown codec, saved the raw stream to {}", output);
+    }
+
+    Ok(())
+}
@@ -1,5 +1,8 @@
 use chrono::{Duration as ChronoDuration, Local};
-use dvrip_rs::{Authentication, Connection, DVRIPCam, FileManagement};
+use dvrip_rs::encoding::hex_to_u64;
+use dvrip_rs::{
+    Authentication, Connection, DVRIPCam, EventFilter, FileManagement, FileType, RecordEvent,
+};
 use std::time::Duration;
 
 #[tokio::main]
@@ -24,7 +27,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let end_time = Local::now();
     let start_time = end_time - ChronoDuration::hours(24);
 
-    match cam.list_local_files(start_time, end_time, "video", 0).await {
+    match cam
+        .list_local_files(start_time, end_time, FileType::Video, 0, EventFilter::All)
+        .await
+    {
         Ok(files) => {
             println!("Found {} files.", files.len());
 
@@ -37,18 +43,22 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     .get("FileLength")
                     .and_then(|s| s.as_str())
                     .unwrap_or("0");
-                let size = u64::from_str_radix(size_str.trim_start_matches("0x"), 16).unwrap_or(0);
+                let size = hex_to_u64(size_str).unwrap_or(0);
                 let begin = file
                     .get("BeginTime")
                     .and_then(|t| t.as_str())
                     .unwrap_or("?");
+                let event = RecordEvent::of_recording(file)
+                    .map(|e| e.to_string())
+                    .unwrap_or_else(|| "?".to_string());
 
                 println!(
-                    "{}. {} ({:?} MB) - Start: {}",
+                    "{}. {} ({:?} MB) - Start: {} - {}",
                     i + 1,
                     name,
                     size as f64 / 1024.0,
-                    begin
+                    begin,
+                    event
                 );
 
                 if i == 0 {
@@ -0,0 +1,47 @@
+use chrono::{Duration as ChronoDuration, Local};
+use dvrip_rs::{Authentication, Connection, DVRIPCam, EventFilter, FileManagement, FileType};
+use std::time::Duration;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 4 {
+        println!("Usage: {} <IP> <Username> <Password>", args[0]);
+        return Ok(());
+    }
+
+    let ip = &args[1];
+    let user = &args[2];
+    let pass = &args[3];
+
+    let mut cam = DVRIPCam::new(ip);
+
+    cam.connect(Duration::from_secs(5)).await?;
+    cam.login(user, pass).await?;
+
+    println!("Searching for stored snapshots from the last 24 hours...");
+
+    let end_time = Local::now();
+    let start_time = end_time - ChronoDuration::hours(24);
+
+    let pictures = cam
+        .list_local_files(start_time, end_time, FileType::Picture, 0, EventFilter::All)
+        .await?;
+    println!("Found {} pictures.", pictures.len());
+
+    if let Some(picture) = pictures.last() {
+        let name = picture
+            .get("FileName")
+            .and_then(|f| f.as_str())
+            .unwrap_or_default();
+        let target = "stored_snapshot.jpg";
+
+        println!("Downloading {} to '{}'...", name, target);
+        cam.download_file(start_time, end_time, name, target)
+            .await?;
+        println!("Saved to {}", target);
+    }
+
+    cam.close().await?;
+    Ok(())
+}
@@ -0,0 +1,215 @@
+// A small WebSocket bridge so non-Rust frontends (e.g. a browser) can drive a camera.
+//
+// Every text message sent by a client is one JSON command and gets one JSON text
+// message back. Pictures and video frames are sent as binary messages.
+//
+// Commands:
+//   {"cmd":"info"}                      -> {"ok":true,"data":<SystemInfo>}
+//   {"cmd":"time"}                      -> {"ok":true,"data":"2024-01-01 12:00:00"}
+//   {"cmd":"ptz","dir":"up","step":5}   -> {"ok":true}
+//       dir is one of up, down, left, right, zoom_in, zoom_out
+//   {"cmd":"snapshot","channel":0}      -> {"ok":true,"size":1234}
+//       followed by a binary message with the JPEG
+//   {"cmd":"monitor","channel":0,"stream":"Extra1"}
+//                                       -> {"ok":true}
+//       followed by one binary message per video frame, the raw h264/h265 data.
+//       A text message {"frame":{<FrameMetadata>}} comes before every I-frame so
+//       the client knows the codec and the picture size
+//   {"cmd":"stop"}                      -> {"ok":true}, stops the frames
+//
+// Errors are reported as {"ok":false,"error":"..."}
+//
+// Try it from a browser console:
+//   const ws = new WebSocket("ws://127.0.0.1:9000");
+//   ws.onmessage = (m) => console.log(m.data);
+//   ws.onopen = () => ws.send(JSON.stringify({cmd: "info"}));
+
+use dvrip_rs::{
+    Authentication, Connection, DVRIPCam, MonitorHandle, Monitoring, PTZ, PTZCommand, SystemInfo,
+};
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{Value, json};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::error::RecvError;
+use tokio_tungstenite::tungstenite::{Message, Result as WsResult};
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 4 {
+        println!(
+            "Usage: {} <IP> <Username> <Password> [listen address]",
+            args[0]
+        );
+        println!(
+            "Example: cargo run --example ws_bridge -- 192.168.1.10 admin pass123 127.0.0.1:9000"
+        );
+        return Ok(());
+    }
+
+    let ip = &args[1];
+    let user = &args[2];
+    let pass = &args[3];
+    let listen = args.get(4).map(|s| s.as_str()).unwrap_or("127.0.0.1:9000");
+
+    let mut cam = DVRIPCam::new(ip);
+    cam.connect(Duration::from_secs(5)).await?;
+    if !cam.login(user, pass).await? {
+        println!("Login failed");
+        return Ok(());
+    }
+
+    // Every method used by the bridge takes &self so the camera can be shared by all clients
+    let cam = Arc::new(cam);
+
+    let listener = TcpListener::bind(listen).await?;
+    println!("Bridge listening on ws://{}", listen);
+
+    loop {
+        let (socket, addr) = listener.accept().await?;
+        println!("Client connected: {}", addr);
+
+        let cam = Arc::clone(&cam);
+        tokio::spawn(async move {
+            if let Err(e) = handle_client(cam, socket).await {
+                eprintln!("Client {} error: {}", addr, e);
+            }
+            println!("Client disconnected: {}", addr);
+        });
+    }
+}
+
+async fn handle_client(cam: Arc<DVRIPCam>, socket: TcpStream) -> WsResult<()> {
+    let mut ws = tokio_tungstenite::accept_async(socket).await?;
+    // Frames of the "monitor" command, dropping the handle stops the stream
+    let mut frames: Option<MonitorHandle> = None;
+
+    loop {
+        let frame = async {
+            match frames.as_mut() {
+                Some(frames) => frames.recv().await,
+                None => std::future::pending().await,
+            }
+        };
+
+        tokio::select! {
+            message = ws.next() => {
+                let text = match message {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | None => return Ok(()),
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => return Err(e),
+                };
+                let request = match serde_json::from_str::<Value>(&text) {
+                    Ok(request) => request,
+                    Err(e) => {
+                        let error = json!({"ok": false, "error": format!("Invalid JSON: {}", e)});
+                        ws.send(Message::text(error.to_string())).await?;
+                        continue;
+                    }
+                };
+
+                let (response, binary) = match request.get("cmd").and_then(|c| c.as_str()) {
+                    Some("monitor") => {
+                        let channel = request.get("channel").and_then(|c| c.as_u64()).unwrap_or(0) as u8;
+                        let stream = request.get("stream").and_then(|s| s.as_str()).unwrap_or("Extra1");
+                        frames = None;
+                        match cam.start_monitor_bytes(stream, channel).await {
+                            Ok(handle) => {
+                                frames = Some(handle);
+                                (json!({"ok": true}), None)
+                            }
+                            Err(e) => (json!({"ok": false, "error": e.to_string()}), None),
+                        }
+                    }
+                    Some("stop") => {
+                        frames = None;
+                        (json!({"ok": true}), None)
+                    }
+                    _ => handle_command(&cam, &request).await,
+                };
+
+                ws.send(Message::text(response.to_string())).await?;
+                if let Some(binary) = binary {
+                    ws.send(Message::binary(binary)).await?;
+                }
+            }
+            frame = frame => match frame {
+                Ok((metadata, data)) => {
+                    if metadata.frame_type.as_deref() == Some("I") {
+                        let info = json!({"frame": {
+                            "media_type": metadata.media_type,
+                            "width": metadata.width,
+                            "height": metadata.height,
+                            "fps": metadata.fps,
+                        }});
+                        ws.send(Message::text(info.to_string())).await?;
+                    }
+                    ws.send(Message::binary(data.to_vec())).await?;
+                }
+                // A slow client skips frames instead of holding the others up
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => {
+                    frames = None;
+                    let error = json!({"ok": false, "error": "Stream closed"});
+                    ws.send(Message::text(error.to_string())).await?;
+                }
+            },
+        }
+    }
+}
+
+/// Answer a command, with the binary message to send after the answer if any
+async fn handle_command(cam: &DVRIPCam, request: &Value) -> (Value, Option<Vec<u8>>) {
+    let cmd = request.get("cmd").and_then(|c| c.as_str()).unwrap_or("");
+    let mut binary = None;
+
+    let result = match cmd {
+        "info" => cam
+            .get_system_info()
+            .await
+            .map(|data| json!({"data": data})),
+        "time" => cam
+            .get_time()
+            .await
+            .map(|time| json!({"data": time.format("%Y-%m-%d %H:%M:%S").to_string()})),
+        "ptz" => {
+            let step = request.get("step").and_then(|s| s.as_u64()).unwrap_or(5) as u8;
+            let command = match request.get("dir").and_then(|d| d.as_str()) {
+                Some("up") => PTZCommand::DirectionUp,
+                Some("down") => PTZCommand::DirectionDown,
+                Some("left") => PTZCommand::DirectionLeft,
+                Some("right") => PTZCommand::DirectionRight,
+                Some("zoom_in") => PTZCommand::ZoomTile,
+                Some("zoom_out") => PTZCommand::ZoomWide,
+                other => {
+                    let error = format!("Unknown direction: {:?}", other);
+                    return (json!({"ok": false, "error": error}), None);
+                }
+            };
+            cam.ptz_step(command, step).await.map(|_| json!({}))
+        }
+        "snapshot" => {
+            let channel = request.get("channel").and_then(|c| c.as_u64()).unwrap_or(0) as u8;
+            cam.snapshot(channel).await.map(|jpeg| {
+                let size = jpeg.len();
+                binary = Some(jpeg);
+                json!({"size": size})
+            })
+        }
+        other => {
+            let error = format!("Unknown command: {}", other);
+            return (json!({"ok": false, "error": error}), None);
+        }
+    };
+
+    match result {
+        Ok(mut value) => {
+            value["ok"] = json!(true);
+            (value, binary)
+        }
+        Err(e) => (json!({"ok": false, "error": e.to_string()}), None),
+    }
+}
@@ -0,0 +1,282 @@
+use chrono::{Local, NaiveDateTime, TimeZone};
+use clap::{Parser, Subcommand, ValueEnum};
+use dvrip_rs::constants::DATE_FORMAT;
+use dvrip_rs::protocol::{PacketHeader, parse_json};
+use dvrip_rs::{
+    Authentication, Connection, DVRIPCam, EventFilter, FileManagement, FileType, Monitoring, PTZ,
+    PTZCommand, SystemInfo,
+};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+// Devices listen for search requests on this port and answer to it
+const DISCOVERY_PORT: u16 = 34569;
+const DISCOVERY_MSG_ID: u16 = 1530;
+
+type CliResult<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// Command line client for DVRIP (XiongMai/Sofia) cameras and recorders
+///
+/// Usage errors exit with 2, failures talking to the device with 1
+#[derive(Parser)]
+#[command(name = "dvrip", version, arg_required_else_help = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print the device identity and system info
+    Info {
+        #[command(flatten)]
+        device: Device,
+    },
+    /// Save a snapshot of a channel
+    Snapshot {
+        #[command(flatten)]
+        device: Device,
+        #[arg(short, long, default_value = "snapshot.jpg")]
+        output: PathBuf,
+        #[arg(long, default_value_t = 0)]
+        channel: u8,
+    },
+    /// Move the camera one step
+    Ptz {
+        #[command(flatten)]
+        device: Device,
+        direction: Direction,
+        #[arg(long, default_value_t = 5)]
+        step: u8,
+    },
+    /// Download the recordings of a time range
+    Download {
+        #[command(flatten)]
+        device: Device,
+        /// Start time, "YYYY-MM-DD HH:MM:SS"
+        #[arg(long, value_parser = parse_time)]
+        from: chrono::DateTime<Local>,
+        /// End time, "YYYY-MM-DD HH:MM:SS"
+        #[arg(long, value_parser = parse_time)]
+        to: chrono::DateTime<Local>,
+        #[arg(long, default_value_t = 0)]
+        channel: u8,
+        /// Directory the files are saved in
+        #[arg(short, long, default_value = ".")]
+        output: PathBuf,
+    },
+    /// Find devices on the local network
+    Discover {
+        /// Seconds to wait for answers
+        #[arg(long, default_value_t = 3)]
+        wait: u64,
+    },
+}
+
+/// How to reach and log in to the device
+#[derive(clap::Args)]
+struct Device {
+    /// Address of the device
+    ip: String,
+    /// Username
+    #[arg(short, long, env = "DVRIP_USER", default_value = "admin")]
+    user: String,
+    /// Password
+    #[arg(
+        short,
+        long,
+        env = "DVRIP_PASSWORD",
+        default_value = "",
+        hide_env_values = true
+    )]
+    password: String,
+    /// Device TCP port
+    #[arg(long)]
+    port: Option<u16>,
+    /// Command timeout in seconds
+    #[arg(long, default_value_t = 10)]
+    timeout: u64,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Direction {
+    Up,
+    Down,
+    Left,
+    Right,
+    ZoomIn,
+    ZoomOut,
+}
+
+impl From<Direction> for PTZCommand {
+    fn from(direction: Direction) -> Self {
+        match direction {
+            Direction::Up => PTZCommand::DirectionUp,
+            Direction::Down => PTZCommand::DirectionDown,
+            Direction::Left => PTZCommand::DirectionLeft,
+            Direction::Right => PTZCommand::DirectionRight,
+            Direction::ZoomIn => PTZCommand::ZoomTile,
+            Direction::ZoomOut => PTZCommand::ZoomWide,
+        }
+    }
+}
+
+fn parse_time(value: &str) -> Result<chrono::DateTime<Local>, String> {
+    let naive = NaiveDateTime::parse_from_str(value, DATE_FORMAT).map_err(|e| e.to_string())?;
+    Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .ok_or_else(|| "Invalid local time".to_string())
+}
+
+async fn open(device: &Device) -> CliResult<DVRIPCam> {
+    let mut cam = DVRIPCam::new(&device.ip);
+    if let Some(port) = device.port {
+        cam = cam.with_port(port);
+    }
+
+    cam.connect(Duration::from_secs(device.timeout)).await?;
+    if !cam.login(&device.user, &device.password).await? {
+        return Err("Login failed".into());
+    }
+    Ok(cam)
+}
+
+async fn info(device: &Device) -> CliResult<()> {
+    let mut cam = open(device).await?;
+    println!("Name: {}", cam.get_device_name().await?);
+    println!("{:#?}", cam.get_device_identity().await?);
+    println!("{:#}", cam.get_system_info().await?);
+    cam.close().await?;
+    Ok(())
+}
+
+async fn snapshot(device: &Device, output: &std::path::Path, channel: u8) -> CliResult<()> {
+    let mut cam = open(device).await?;
+
+    let image = cam.snapshot(channel).await?;
+    tokio::fs::write(output, &image).await?;
+    println!("Saved {} bytes to {}", image.len(), output.display());
+
+    cam.close().await?;
+    Ok(())
+}
+
+async fn ptz(device: &Device, direction: Direction, step: u8) -> CliResult<()> {
+    let mut cam = open(device).await?;
+    cam.ptz_step(direction.into(), step).await?;
+    cam.close().await?;
+    Ok(())
+}
+
+async fn download(
+    device: &Device,
+    from: chrono::DateTime<Local>,
+    to: chrono::DateTime<Local>,
+    channel: u8,
+    output: &std::path::Path,
+) -> CliResult<()> {
+    let mut cam = open(device).await?;
+    let files = cam
+        .list_local_files(from, to, FileType::Video, channel, EventFilter::All)
+        .await?;
+    println!("Found {} files", files.len());
+
+    for file in files {
+        let Some(name) = file.get("FileName").and_then(|f| f.as_str()) else {
+            continue;
+        };
+        let base = name.rsplit('/').next().unwrap_or(name);
+        let target = output.join(base);
+
+        println!("Downloading {} to {}", name, target.display());
+        cam.download_file(from, to, name, &target.to_string_lossy())
+            .await?;
+    }
+
+    cam.close().await?;
+    Ok(())
+}
+
+async fn discover(wait: u64) -> CliResult<()> {
+    let socket = UdpSocket::bind(("0.0.0.0", DISCOVERY_PORT)).await?;
+    socket.set_broadcast(true)?;
+
+    let header = PacketHeader {
+        head: 0xFF,
+        version: 0,
+        session: 0,
+        packet_count: 0,
+        msg_id: DISCOVERY_MSG_ID,
+        data_len: 0,
+        raw_header: [0; PacketHeader::SIZE],
+    };
+    socket
+        .send_to(&header.encode(), ("255.255.255.255", DISCOVERY_PORT))
+        .await?;
+
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(wait);
+    let mut buf = vec![0u8; 4096];
+    loop {
+        let Ok(received) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await
+        else {
+            break;
+        };
+        let (len, from) = received?;
+
+        // Our own request comes back too, it has no body
+        if len <= PacketHeader::SIZE {
+            continue;
+        }
+        let Ok(reply) = parse_json(&buf[PacketHeader::SIZE..len]) else {
+            continue;
+        };
+        let Some(net) = reply.get("NetWork.NetCommon") else {
+            continue;
+        };
+
+        let text = |key: &str| net.get(key).and_then(|v| v.as_str()).unwrap_or("?");
+        println!(
+            "{}\tport {}\t{}\t{}\t{}",
+            from.ip(),
+            net.get("TCPPort").and_then(|p| p.as_u64()).unwrap_or(0),
+            text("HostName"),
+            text("MAC"),
+            text("SN"),
+        );
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    // Exits with 2 on a usage error or an unknown command
+    let cli = Cli::parse();
+    let result = match &cli.command {
+        Command::Info { device } => info(device).await,
+        Command::Snapshot {
+            device,
+            output,
+            channel,
+        } => snapshot(device, output, *channel).await,
+        Command::Ptz {
+            device,
+            direction,
+            step,
+        } => ptz(device, *direction, *step).await,
+        Command::Download {
+            device,
+            from,
+            to,
+            channel,
+            output,
+        } => download(device, *from, *to, *channel, output).await,
+        Command::Discover { wait } => discover(*wait).await,
+    };
+
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,89 @@
+//! Synchronous wrapper for consumers that don't run inside tokio.
+//!
+//! Every call is driven on a runtime owned by `BlockingDVRIPCam`. The runtime keeps one
+//! worker thread alive so the connection tasks (receive loop, keep-alive) keep running
+//! between calls, alarm and frame callbacks are executed on that thread as well.
+//!
+//! Don't use it from inside an async context, blocking on the runtime there will panic.
+
+use crate::commands::{Authentication, Connection, Monitoring, PTZ, PTZCommand, SystemInfo};
+use crate::dvrip::DVRIPCam;
+use crate::error::{DVRIPError, Result};
+use chrono::{DateTime, Local};
+use serde_json::Value;
+use std::future::Future;
+use tokio::runtime::Runtime;
+use tokio::time::Duration;
+
+pub struct BlockingDVRIPCam {
+    runtime: Runtime,
+    cam: DVRIPCam,
+}
+
+impl BlockingDVRIPCam {
+    pub fn new(ip: impl Into<String>) -> Result<Self> {
+        Self::from_cam(DVRIPCam::new(ip))
+    }
+
+    /// Wrap an already configured camera (e.g. with a custom port or timeout)
+    pub fn from_cam(cam: DVRIPCam) -> Result<Self> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .map_err(DVRIPError::IoError)?;
+
+        Ok(Self { runtime, cam })
+    }
+
+    /// Access the async camera, to be used with `block_on` for the methods not wrapped here
+    pub fn cam(&self) -> &DVRIPCam {
+        &self.cam
+    }
+
+    /// Run any future on the internal runtime
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.runtime.block_on(future)
+    }
+
+    pub fn connect(&mut self, timeout: Duration) -> Result<()> {
+        self.runtime.block_on(self.cam.connect(timeout))
+    }
+
+    pub fn close(&mut self) -> Result<()> {
+        self.runtime.block_on(self.cam.close())
+    }
+
+    pub fn login(&mut self, username: &str, password: &str) -> Result<bool> {
+        self.runtime.block_on(self.cam.login(username, password))
+    }
+
+    pub fn snapshot(&self, channel: u8) -> Result<Vec<u8>> {
+        self.runtime.block_on(self.cam.snapshot(channel))
+    }
+
+    pub fn get_system_info(&self) -> Result<Value> {
+        self.runtime.block_on(self.cam.get_system_info())
+    }
+
+    pub fn get_time(&self) -> Result<DateTime<Local>> {
+        self.runtime.block_on(self.cam.get_time())
+    }
+
+    pub fn set_time(&self, time: Option<DateTime<Local>>) -> Result<bool> {
+        self.runtime.block_on(self.cam.set_time(time))
+    }
+
+    pub fn measure_time_drift(&self) -> Result<chrono::Duration> {
+        self.runtime.block_on(self.cam.measure_time_drift())
+    }
+
+    pub fn sync_time_if_drift_exceeds(&self, threshold: chrono::Duration) -> Result<bool> {
+        self.runtime
+            .block_on(self.cam.sync_time_if_drift_exceeds(threshold))
+    }
+
+    pub fn ptz_step(&self, cmd: PTZCommand, step: u8) -> Result<bool> {
+        self.runtime.block_on(self.cam.ptz_step(cmd, step))
+    }
+}
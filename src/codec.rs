@@ -0,0 +1,123 @@
+//! G.711 conversion between the 8 bit samples the device sends and accepts
+//! (`g711a` frames of the monitor, `send_audio`) and 16 bit linear PCM.
+//!
+//! Same algorithm as the ITU-T G.191 reference implementation.
+
+const ALAW_XOR: u8 = 0x55;
+const ULAW_BIAS: i32 = 0x84;
+const ULAW_CLIP: i32 = 32635;
+
+/// Decode A-law samples to 16 bit PCM
+pub fn g711a_to_pcm16(data: &[u8]) -> Vec<i16> {
+    data.iter().map(|&b| alaw_to_linear(b)).collect()
+}
+
+/// Decode µ-law samples to 16 bit PCM
+pub fn g711u_to_pcm16(data: &[u8]) -> Vec<i16> {
+    data.iter().map(|&b| ulaw_to_linear(b)).collect()
+}
+
+/// Encode 16 bit PCM to A-law, e.g. for `send_audio` with `AudioCodec::PCMA`
+pub fn pcm16_to_g711a(samples: &[i16]) -> Vec<u8> {
+    samples.iter().map(|&s| linear_to_alaw(s)).collect()
+}
+
+/// Encode 16 bit PCM to µ-law, e.g. for `send_audio` with `AudioCodec::PCMU`
+pub fn pcm16_to_g711u(samples: &[i16]) -> Vec<u8> {
+    samples.iter().map(|&s| linear_to_ulaw(s)).collect()
+}
+
+fn alaw_to_linear(value: u8) -> i16 {
+    let value = value ^ ALAW_XOR;
+    let mantissa = ((value & 0x0F) as i16) << 4;
+    let segment = (value & 0x70) >> 4;
+
+    let magnitude = match segment {
+        0 => mantissa + 8,
+        1 => mantissa + 0x108,
+        _ => (mantissa + 0x108) << (segment - 1),
+    };
+    if value & 0x80 != 0 {
+        magnitude
+    } else {
+        -magnitude
+    }
+}
+
+fn linear_to_alaw(sample: i16) -> u8 {
+    // A-law works on 13 bits
+    let (sign, magnitude) = if sample >= 0 {
+        (0x80, (sample >> 3) as i32)
+    } else {
+        (0x00, (!sample >> 3) as i32)
+    };
+
+    let encoded = if magnitude < 32 {
+        (magnitude >> 1) as u8
+    } else {
+        // Position of the highest bit above the 5 bit mantissa, capped to segment 7
+        let segment = (31 - (magnitude as u32).leading_zeros() - 4).min(7);
+        if magnitude >= 0x1000 {
+            0x7F
+        } else {
+            ((segment << 4) as u8) | ((magnitude >> segment) & 0x0F) as u8
+        }
+    };
+    (encoded | sign) ^ ALAW_XOR
+}
+
+fn ulaw_to_linear(value: u8) -> i16 {
+    let value = !value;
+    let magnitude = ((((value & 0x0F) as i32) << 3) + ULAW_BIAS) << ((value & 0x70) >> 4);
+    (if value & 0x80 != 0 {
+        ULAW_BIAS - magnitude
+    } else {
+        magnitude - ULAW_BIAS
+    }) as i16
+}
+
+fn linear_to_ulaw(sample: i16) -> u8 {
+    let sign = if sample < 0 { 0x80 } else { 0x00 };
+    let magnitude = (sample as i32).abs().min(ULAW_CLIP) + ULAW_BIAS;
+
+    let segment = (31 - (magnitude as u32).leading_zeros() - 7) as u8;
+    let mantissa = ((magnitude >> (segment + 3)) & 0x0F) as u8;
+    !(sign | (segment << 4) | mantissa)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Values of the G.191 / Sun g711.c tables
+    #[test]
+    fn alaw_reference_values() {
+        assert_eq!(
+            g711a_to_pcm16(&[0xD5, 0x55, 0x80, 0xAA, 0x2A]),
+            [8, -8, 5504, 32256, -32256]
+        );
+        assert_eq!(
+            pcm16_to_g711a(&[0, -1, 32767, -32768]),
+            [0xD5, 0x55, 0xAA, 0x2A]
+        );
+    }
+
+    #[test]
+    fn ulaw_reference_values() {
+        assert_eq!(
+            g711u_to_pcm16(&[0xFF, 0x7F, 0x80, 0x00]),
+            [0, 0, 32124, -32124]
+        );
+        assert_eq!(pcm16_to_g711u(&[0, 32767, -32768]), [0xFF, 0x80, 0x00]);
+    }
+
+    #[test]
+    fn every_code_survives_a_round_trip() {
+        let codes: Vec<u8> = (0..=255).collect();
+        assert_eq!(pcm16_to_g711a(&g711a_to_pcm16(&codes)), codes);
+
+        // 0x7F is the negative zero, it comes back as 0xFF
+        let codes: Vec<u8> = (0..=255).filter(|&c| c != 0x7F).collect();
+        assert_eq!(pcm16_to_g711u(&g711u_to_pcm16(&codes)), codes);
+    }
+}
@@ -1,30 +1,241 @@
-use crate::error::Result;
+use crate::error::{DVRIPError, Result};
 use async_trait::async_trait;
-use serde_json::Value;
+use serde_json::{Value, json};
+use std::str::FromStr;
+use strum_macros::{AsRefStr, EnumString};
 
-use crate::constants::{OK_CODES, QCODES};
+use crate::constants::{MAX_CHANNEL_TITLE_LEN, OK_CODES};
 use crate::dvrip::DVRIPCam;
+use std::sync::Arc;
 use std::sync::atomic::Ordering;
+use tokio::sync::Mutex;
 
 pub type AlarmCallback = Box<dyn Fn(Value, u32) + Send + Sync>;
 
+pub type AlarmEventCallback = Box<dyn Fn(AlarmEvent, u32) + Send + Sync>;
+
+/// Payload of an alarm packet
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum AlarmEvent {
+    /// The alarm info object, the same value `AlarmCallback` gets
+    Json(Value),
+    /// A payload that isn't JSON, passed on as received
+    ///
+    /// Cameras with analytics send some events (e.g. with bounding boxes) this way.
+    /// Their layout isn't documented and differs between firmware
+    Binary(Vec<u8>),
+}
+
+/// Alarm events, as sent in the `Event` field of the alarm info
+#[derive(Debug, Clone, Copy, PartialEq, Eq, AsRefStr, EnumString)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum AlarmType {
+    VideoMotion,
+    VideoLoss,
+    /// Camera covered or tampered with
+    VideoBlind,
+    LocalAlarm,
+    NetAlarm,
+    #[strum(serialize = "appEventHumanDetectAlarm")]
+    HumanDetect,
+    StorageNotExist,
+    StorageFailure,
+    StorageLowSpace,
+}
+
+/// Alarms that reach the callback, an empty list lets everything through
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AlarmFilter {
+    pub events: Vec<AlarmType>,
+    pub channels: Vec<u8>,
+}
+
+impl AlarmFilter {
+    pub(crate) fn matches(&self, alarm: &Value) -> bool {
+        let event_matches = self.events.is_empty()
+            || alarm
+                .get("Event")
+                .and_then(|e| e.as_str())
+                .is_some_and(|e| self.events.iter().any(|t| t.as_ref() == e));
+
+        let channel_matches = self.channels.is_empty()
+            || alarm
+                .get("Channel")
+                .and_then(|c| c.as_u64())
+                .is_some_and(|c| self.channels.iter().any(|ch| *ch as u64 == c));
+
+        event_matches && channel_matches
+    }
+}
+
+/// Wiring of an alarm input, `SensorType` in `Alarm.LocalAlarm`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, AsRefStr, EnumString)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SensorType {
+    /// Triggers when the contact closes
+    #[default]
+    #[strum(serialize = "NO")]
+    NormallyOpen,
+    /// Triggers when the contact opens
+    #[strum(serialize = "NC")]
+    NormallyClosed,
+}
+
+/// Settings of a wired alarm input (door contact, PIR, ...)
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AlarmInputConfig {
+    pub enabled: bool,
+    pub sensor: SensorType,
+    /// `None` keeps the name stored on the device, most firmware has none
+    pub name: Option<String>,
+}
+
+impl AlarmInputConfig {
+    fn from_value(value: &Value) -> Self {
+        Self {
+            enabled: value
+                .get("Enable")
+                .and_then(|e| e.as_bool())
+                .unwrap_or(false),
+            sensor: value
+                .get("SensorType")
+                .and_then(|s| s.as_str())
+                .and_then(|s| SensorType::from_str(s).ok())
+                .unwrap_or_default(),
+            name: value
+                .get("Name")
+                .and_then(|n| n.as_str())
+                .filter(|n| !n.is_empty())
+                .map(|n| n.to_string()),
+        }
+    }
+
+    /// Write the typed fields over `value`, keeping the event handler untouched
+    fn apply(&self, value: &mut Value) -> Result<()> {
+        if let Some(name) = &self.name
+            && name.len() > MAX_CHANNEL_TITLE_LEN
+        {
+            return Err(DVRIPError::InvalidParameter(format!(
+                "Alarm input name '{}' is longer than {} bytes",
+                name, MAX_CHANNEL_TITLE_LEN
+            )));
+        }
+        value["Enable"] = json!(self.enabled);
+        value["SensorType"] = json!(self.sensor.as_ref());
+        if let Some(name) = &self.name {
+            value["Name"] = json!(name);
+        }
+        Ok(())
+    }
+}
+
+/// An alarm input with its current state
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AlarmInput {
+    pub index: u32,
+    /// `None` when the firmware doesn't report the live state of its inputs
+    pub triggered: Option<bool>,
+    pub name: Option<String>,
+    pub config: AlarmInputConfig,
+}
+
+/// State of input `index` from `Status.AlarmIn`, either a bit mask or a list
+fn input_triggered(status: &Value, index: usize) -> Option<bool> {
+    if let Some(mask) = status.as_u64() {
+        return (index < 64).then(|| mask & (1 << index) != 0);
+    }
+    let state = status.as_array()?.get(index)?;
+    let state = state.get("Status").unwrap_or(state);
+    match state {
+        Value::Bool(b) => Some(*b),
+        Value::Number(n) => n.as_u64().map(|n| n != 0),
+        Value::String(s) => Some(matches!(s.as_str(), "OPEN" | "Start" | "true" | "1")),
+        _ => None,
+    }
+}
+
+/// Put a callback in place without waiting, only falling back to a spawned task when
+/// the slot is in use (an alarm being delivered)
+fn store_callback<T: Send + 'static>(slot: &Arc<Mutex<Option<T>>>, callback: Option<T>) {
+    let callback = match slot.try_lock() {
+        Ok(mut current) => {
+            *current = callback;
+            return;
+        }
+        Err(_) => callback,
+    };
+    match tokio::runtime::Handle::try_current() {
+        Ok(handle) => {
+            let slot = Arc::clone(slot);
+            handle.spawn(async move {
+                *slot.lock().await = callback;
+            });
+        }
+        // Outside of a runtime nothing stops us from blocking
+        Err(_) => *slot.blocking_lock() = callback,
+    }
+}
+
 #[async_trait]
 pub trait Alarm: Send + Sync {
     /// Set the alarm callback function
+    ///
+    /// Installed right away unless an alarm is being delivered at that moment, then
+    /// it's set from a spawned task once the delivery is done.
+    /// Use `set_alarm_callback_async` to be sure it's in place when the call returns
     fn set_alarm_callback(&self, callback: Option<AlarmCallback>);
 
+    /// Set the alarm callback function, it's installed when this returns
+    async fn set_alarm_callback_async(&self, callback: Option<AlarmCallback>);
+
     /// Clear the alarm callback
     fn clear_alarm_callback(&self);
 
+    /// Set a callback that also gets the alarms that aren't JSON
+    ///
+    /// Binary alarms have no event or channel to filter on, they always reach it
+    fn set_alarm_event_callback(&self, callback: Option<AlarmEventCallback>);
+
+    /// Same as `set_alarm_event_callback`, the callback is installed when this returns
+    async fn set_alarm_event_callback_async(&self, callback: Option<AlarmEventCallback>);
+
     /// Start alarm monitoring
     async fn start_alarm_monitoring(&self) -> Result<()>;
 
+    /// Start alarm monitoring, only calling the callback for the given events and channels
+    ///
+    /// An empty `events` or `channels` list matches everything
+    async fn start_alarm_monitoring_filtered(
+        &self,
+        events: &[AlarmType],
+        channels: &[u8],
+    ) -> Result<()>;
+
     /// Stop alarm monitoring
     async fn stop_alarm_monitoring(&self) -> Result<()>;
 
     /// Set remote alarm
     async fn set_remote_alarm(&self, state: bool) -> Result<bool>;
 
+    /// Set the state of a single alarm output (relay)
+    ///
+    /// `output_index` goes from 0 to `AlarmOutChannel - 1` as reported by `get_system_info`
+    async fn set_alarm_output(&self, output_index: u32, state: bool) -> Result<bool>;
+
+    /// Get the state of every alarm output, indexed the same way as `set_alarm_output`
+    async fn get_alarm_outputs(&self) -> Result<Vec<bool>>;
+
+    /// Get the wired alarm inputs with their settings and, when the device reports it,
+    /// whether they are triggered right now
+    async fn get_alarm_inputs(&self) -> Result<Vec<AlarmInput>>;
+
+    /// Set the settings of an alarm input, `index` as in `get_alarm_inputs`
+    async fn set_alarm_input_config(&self, index: u32, config: AlarmInputConfig) -> Result<bool>;
+
     /// Check if monitoring alarms
     fn is_alarm_monitoring(&self) -> bool;
 }
@@ -32,44 +243,47 @@ pub trait Alarm: Send + Sync {
 #[async_trait]
 impl Alarm for DVRIPCam {
     fn set_alarm_callback(&self, callback: Option<AlarmCallback>) {
-        let alarm_cb = self.alarm_callback.clone();
-        if let Ok(handle) = tokio::runtime::Handle::try_current() {
-            handle.spawn(async move {
-                *alarm_cb.lock().await = callback;
-            });
-        } else {
-            // If not in an async context, create a temporary runtime
-            tokio::spawn(async move {
-                *alarm_cb.lock().await = callback;
-            });
-        }
+        store_callback(&self.alarm_callback, callback);
+    }
+
+    async fn set_alarm_callback_async(&self, callback: Option<AlarmCallback>) {
+        *self.alarm_callback.lock().await = callback;
     }
 
     fn clear_alarm_callback(&self) {
-        let alarm_cb = self.alarm_callback.clone();
-        if let Ok(handle) = tokio::runtime::Handle::try_current() {
-            handle.spawn(async move {
-                *alarm_cb.lock().await = None;
-            });
-        } else {
-            tokio::spawn(async move {
-                *alarm_cb.lock().await = None;
-            });
-        }
+        store_callback(&self.alarm_callback, None);
+    }
+
+    fn set_alarm_event_callback(&self, callback: Option<AlarmEventCallback>) {
+        store_callback(&self.alarm_event_callback, callback);
+    }
+
+    async fn set_alarm_event_callback_async(&self, callback: Option<AlarmEventCallback>) {
+        *self.alarm_event_callback.lock().await = callback;
     }
 
     async fn start_alarm_monitoring(&self) -> Result<()> {
+        self.start_alarm_monitoring_filtered(&[], &[]).await
+    }
+
+    async fn start_alarm_monitoring_filtered(
+        &self,
+        events: &[AlarmType],
+        channels: &[u8],
+    ) -> Result<()> {
+        *self.alarm_filter.lock().await = AlarmFilter {
+            events: events.to_vec(),
+            channels: channels.to_vec(),
+        };
+
         let reply = self
-            .get_command(
-                "",
-                Some(QCODES.get("AlarmSet").copied().unwrap_or(1500) as u32),
-            )
+            .get_command("", Some(self.code("AlarmSet").unwrap_or(1500) as u32))
             .await?;
 
         if let Some(ret) = reply.get("Ret").and_then(|r| r.as_u64())
             && !OK_CODES.contains(&(ret as u32))
         {
-            return Err(crate::error::DVRIPError::ProtocolError(
+            return Err(DVRIPError::ProtocolError(
                 "Failed to start alarm monitoring".to_string(),
             ));
         }
@@ -86,8 +300,12 @@ impl Alarm for DVRIPCam {
     }
 
     async fn set_remote_alarm(&self, state: bool) -> Result<bool> {
-        let data = serde_json::json!({
-            "Event": 0,
+        self.set_alarm_output(0, state).await
+    }
+
+    async fn set_alarm_output(&self, output_index: u32, state: bool) -> Result<bool> {
+        let data = json!({
+            "Event": output_index,
             "State": state,
         });
 
@@ -98,7 +316,123 @@ impl Alarm for DVRIPCam {
         Ok(false)
     }
 
+    async fn get_alarm_outputs(&self) -> Result<Vec<bool>> {
+        let data = self.get_command("Alarm.AlarmOut", Some(1042)).await?;
+
+        let Some(outputs) = data.as_array() else {
+            return Ok(vec![]);
+        };
+
+        Ok(outputs
+            .iter()
+            .map(|o| o.get("AlarmOutStatus").and_then(|s| s.as_str()) == Some("OPEN"))
+            .collect())
+    }
+
+    async fn get_alarm_inputs(&self) -> Result<Vec<AlarmInput>> {
+        let inputs = self.get_command("Alarm.LocalAlarm", Some(1042)).await?;
+        let Some(inputs) = inputs.as_array() else {
+            return Ok(vec![]);
+        };
+        // Not every firmware has it, the inputs are still listed without a state
+        let status = self
+            .get_command("Status.AlarmIn", Some(1042))
+            .await
+            .unwrap_or(Value::Null);
+
+        Ok(inputs
+            .iter()
+            .enumerate()
+            .map(|(index, input)| {
+                let config = AlarmInputConfig::from_value(input);
+                AlarmInput {
+                    index: index as u32,
+                    triggered: input_triggered(&status, index),
+                    name: config.name.clone(),
+                    config,
+                }
+            })
+            .collect())
+    }
+
+    async fn set_alarm_input_config(&self, index: u32, config: AlarmInputConfig) -> Result<bool> {
+        let mut inputs = self.get_command("Alarm.LocalAlarm", Some(1042)).await?;
+
+        let Some(input) = inputs.get_mut(index as usize) else {
+            return Err(DVRIPError::InvalidParameter(format!(
+                "Alarm input {} doesn't exist",
+                index
+            )));
+        };
+        config.apply(input)?;
+
+        let reply = self
+            .set_command("Alarm.LocalAlarm", inputs, Some(1040))
+            .await?;
+        if let Some(ret) = reply.get("Ret").and_then(|r| r.as_u64()) {
+            return Ok(OK_CODES.contains(&(ret as u32)));
+        }
+        Ok(false)
+    }
+
     fn is_alarm_monitoring(&self) -> bool {
         self.alarm_monitoring.load(Ordering::Acquire)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_device;
+    use std::sync::Mutex as StdMutex;
+    use tokio::time::Duration;
+
+    fn alarm(channel: u32) -> Vec<u8> {
+        let alarm = json!({
+            "Name": "AlarmInfo",
+            "AlarmInfo": {"Channel": channel, "Event": "VideoMotion", "Status": "Start"},
+        });
+        serde_json::to_vec(&alarm).unwrap()
+    }
+
+    #[tokio::test]
+    async fn alarms_and_replies_interleave_on_one_reader() {
+        let (cam, mut device) = test_device::login(DVRIPCam::new("127.0.0.1")).await;
+        let alarms = Arc::new(StdMutex::new(Vec::new()));
+        let seen = Arc::clone(&alarms);
+        cam.set_alarm_callback_async(Some(Box::new(move |alarm, _| {
+            seen.lock()
+                .unwrap()
+                .push(alarm["Channel"].as_u64().unwrap());
+        })))
+        .await;
+
+        let (started, _) = tokio::join!(
+            cam.start_alarm_monitoring(),
+            device.answer(json!({"Ret": 100}))
+        );
+        started.unwrap();
+
+        let device_side = async {
+            let (request, _) = device.recv_json().await;
+            // Numbered like the pending command, still not taken as its reply
+            device.send(request.packet_count, 1504, &alarm(0)).await;
+            device.send(request.packet_count, 1504, &alarm(1)).await;
+            let reply = json!({"Name": "General", "Ret": 100, "General": {"Location": {}}});
+            device.reply(&request, reply).await;
+            device.send(request.packet_count + 1, 1504, &alarm(2)).await;
+        };
+        let (general, _) = tokio::join!(cam.get_command("General", None), device_side);
+        assert_eq!(general.unwrap(), json!({"Location": {}}));
+
+        // The last alarm comes after the reply, give the recv loop a moment
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while alarms.lock().unwrap().len() < 3 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(*alarms.lock().unwrap(), vec![0, 1, 2]);
+    }
+}
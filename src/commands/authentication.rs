@@ -1,15 +1,62 @@
 use crate::commands::Connection;
-use crate::constants::{OK_CODES, QCODES};
+use crate::constants::{
+    CODES, DEFAULT_ALIVE_INTERVAL, LOGIN_ERROR_CODES, MIN_ALIVE_INTERVAL, OK_CODES,
+    RETRYABLE_LOGIN_CODES,
+};
 use crate::dvrip::DVRIPCam;
+use crate::encoding::hex_to_u64;
 use crate::error::Result;
 use crate::protocol::sofia_hash;
+use crate::responses::{self, LoginResponse};
 use async_trait::async_trait;
 use serde_json::json;
 use std::sync::atomic::Ordering;
+use tokio::time::sleep;
+
+/// Seconds between keep-alives for an `AliveInterval` as sent by the device
+fn normalize_alive_interval(interval: u64) -> u64 {
+    // No device asks for a keep-alive every 17 minutes, that's milliseconds
+    let seconds = if interval > 1000 {
+        interval / 1000
+    } else {
+        interval
+    };
+    seconds.max(MIN_ALIVE_INTERVAL)
+}
+
+/// What the device tells about itself in the login reply
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LoginInfo {
+    /// Video channels, `ChannelNum` plus `ExtraChannel`
+    pub channel_count: Option<u32>,
+    /// e.g. "IPC", "DVR" or "NVR"
+    pub device_type: Option<String>,
+    /// The device expects AES encrypted payloads (`DataUseAES`)
+    pub data_use_aes: bool,
+}
+
+impl From<&LoginResponse> for LoginInfo {
+    fn from(login: &LoginResponse) -> Self {
+        let channels = login
+            .channel_num
+            .map(|n| n + login.extra_channel.unwrap_or(0));
+        Self {
+            channel_count: channels.and_then(|n| u32::try_from(n).ok()),
+            device_type: login.device_type.clone(),
+            data_use_aes: login.data_use_aes.unwrap_or(false),
+        }
+    }
+}
 
 #[async_trait]
 pub trait Authentication: Send + Sync {
     /// Login to the device
+    ///
+    /// Returns `DVRIPError::LoginFailed` when the device rejects the credentials
+    /// and `Ok(false)` for any other non-OK code.
+    /// Devices asking for AES encrypted payloads are disconnected with
+    /// `DVRIPError::Unsupported` unless a key was given with `DVRIPCam::with_aes_key`
     async fn login(&mut self, username: &str, password: &str) -> Result<bool>;
 
     /// Logout from the device
@@ -21,6 +68,26 @@ pub trait Authentication: Send + Sync {
     /// Get the session ID
     fn session_id(&self) -> u32;
 
+    /// Channel count, device type and encryption announced in the last login reply,
+    /// `None` before the first successful login
+    fn login_info(&self) -> Option<&LoginInfo>;
+
+    /// Session of the current login as a string that can be stored and given to
+    /// `resume_with_session` after a restart
+    fn session_token(&self) -> String;
+
+    /// Reuse the session of `token` instead of logging in again, falling back to
+    /// `login` when the device no longer knows it
+    ///
+    /// Firmware forgets a session once it misses keep-alives for about its
+    /// `AliveInterval`, so this only helps for restarts within a few seconds
+    async fn resume_with_session(
+        &mut self,
+        token: &str,
+        username: &str,
+        password: &str,
+    ) -> Result<bool>;
+
     /// Change user password
     async fn change_password(
         &self,
@@ -34,7 +101,7 @@ pub trait Authentication: Send + Sync {
 impl Authentication for DVRIPCam {
     async fn login(&mut self, username: &str, password: &str) -> Result<bool> {
         if !Connection::is_connected(self) {
-            Connection::connect(self, self.timeout).await?;
+            Connection::connect(self, self.command_timeout).await?;
         }
 
         let data = json!({
@@ -45,30 +112,76 @@ impl Authentication for DVRIPCam {
         });
         self.username = Some(username.to_string());
 
-        let reply = self.send_command(1000, data, true).await?.ok_or_else(|| {
-            crate::error::DVRIPError::AuthenticationError("Empty response".to_string())
-        })?;
-
-        if let Some(ret) = reply.get("Ret").and_then(|r| r.as_u64())
-            && OK_CODES.contains(&(ret as u32))
-        {
-            if let Some(session_str) = reply.get("SessionID").and_then(|s| s.as_str()) {
-                let session_id = u32::from_str_radix(&session_str[2..], 16).map_err(|_| {
-                    crate::error::DVRIPError::ProtocolError("Invalid SessionID".to_string())
+        let mut attempt = 0;
+        loop {
+            let reply = self
+                .send_command(1000, data.clone(), true)
+                .await?
+                .ok_or_else(|| {
+                    crate::error::DVRIPError::AuthenticationError("Empty response".to_string())
                 })?;
-                self.session.store(session_id, Ordering::Release);
-            }
 
-            if let Some(interval) = reply.get("AliveInterval").and_then(|i| i.as_u64()) {
+            let login: LoginResponse = responses::parse(&reply)?;
+            let ret = login.ret;
+
+            if let Some(ret) = ret
+                && OK_CODES.contains(&ret)
+            {
+                if login.session_id.is_some() {
+                    let session_id = login.session_id().ok_or_else(|| {
+                        crate::error::DVRIPError::ProtocolError("Invalid SessionID".to_string())
+                    })?;
+                    self.session.store(session_id, Ordering::Release);
+                }
+
+                let interval = login
+                    .alive_interval
+                    .filter(|i| *i > 0)
+                    .map_or(DEFAULT_ALIVE_INTERVAL, normalize_alive_interval);
                 self.alive_time.store(interval, Ordering::Release);
+                self.login_info = Some(LoginInfo::from(&login));
+
+                // Every plaintext command after this would be rejected, better to say why
+                if self.login_info.as_ref().is_some_and(|l| l.data_use_aes) && !self.has_aes_key() {
+                    let _ = Connection::close(self).await;
+                    return Err(crate::error::DVRIPError::Unsupported(
+                        "The device expects AES encrypted payloads (DataUseAES), set its key with DVRIPCam::with_aes_key (aes feature)"
+                            .to_string(),
+                    ));
+                }
+
+                self.authenticated.store(true, Ordering::Release);
+                if self.keep_alive {
+                    self.start_keep_alive().await;
+                }
+                return Ok(true);
             }
 
-            self.authenticated.store(true, Ordering::Release);
-            self.start_keep_alive().await;
-            return Ok(true);
-        }
+            if let Some(ret) = ret
+                && LOGIN_ERROR_CODES.contains(&ret)
+            {
+                return Err(crate::error::DVRIPError::LoginFailed {
+                    code: ret,
+                    message: CODES
+                        .get(&ret)
+                        .copied()
+                        .unwrap_or("Unknown error")
+                        .to_string(),
+                });
+            }
 
-        Ok(false)
+            // The previous session may still be alive on the device, give it some time
+            if let Some(ret) = ret
+                && RETRYABLE_LOGIN_CODES.contains(&ret)
+                && attempt < self.login_retries
+            {
+                attempt += 1;
+                sleep(self.login_retry_delay).await;
+                continue;
+            }
+
+            return Ok(false);
+        }
     }
 
     async fn logout(&mut self) -> Result<()> {
@@ -83,6 +196,62 @@ impl Authentication for DVRIPCam {
         self.session.load(Ordering::Acquire)
     }
 
+    fn login_info(&self) -> Option<&LoginInfo> {
+        self.login_info.as_ref()
+    }
+
+    fn session_token(&self) -> String {
+        format!("0x{:08X}", self.session_id())
+    }
+
+    async fn resume_with_session(
+        &mut self,
+        token: &str,
+        username: &str,
+        password: &str,
+    ) -> Result<bool> {
+        let session = token
+            .strip_prefix("0x")
+            .and_then(hex_to_u64)
+            .and_then(|session| u32::try_from(session).ok())
+            .ok_or_else(|| {
+                crate::error::DVRIPError::InvalidParameter(format!(
+                    "Invalid session token {}",
+                    token
+                ))
+            })?;
+
+        if !Connection::is_connected(self) {
+            Connection::connect(self, self.command_timeout).await?;
+        }
+        self.session.store(session, Ordering::Release);
+
+        // A keep-alive is only answered with OK on a session the device knows
+        let data = json!({
+            "Name": "KeepAlive",
+            "SessionID": token,
+        });
+        let resumed = self
+            .send_command(self.code("KeepAlive").unwrap_or(1006), data, true)
+            .await
+            .ok()
+            .flatten()
+            .and_then(|reply| reply.get("Ret").and_then(|r| r.as_u64()))
+            .is_some_and(|ret| OK_CODES.contains(&(ret as u32)));
+
+        if !resumed {
+            self.session.store(0, Ordering::Release);
+            return self.login(username, password).await;
+        }
+
+        self.username = Some(username.to_string());
+        self.authenticated.store(true, Ordering::Release);
+        if self.keep_alive {
+            self.start_keep_alive().await;
+        }
+        Ok(true)
+    }
+
     async fn change_password(
         &self,
         old_password: &str,
@@ -98,11 +267,7 @@ impl Authentication for DVRIPCam {
         });
 
         let reply = self
-            .send_command(
-                QCODES.get("ModifyPassword").copied().unwrap_or(1488),
-                data,
-                true,
-            )
+            .send_command(self.code("ModifyPassword").unwrap_or(1488), data, true)
             .await?
             .ok_or_else(|| crate::error::DVRIPError::ProtocolError("Empty response".to_string()))?;
 
@@ -113,3 +278,127 @@ impl Authentication for DVRIPCam {
         Ok(false)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_device;
+    use serde_json::json;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn only_transient_login_codes_are_retried() {
+        let cam = DVRIPCam::new("127.0.0.1").with_login_retry(2, Duration::from_millis(10));
+        let (mut cam, mut device) = test_device::connect(cam.with_keep_alive(false)).await;
+
+        let (logged_in, _) = tokio::join!(cam.login("admin", ""), async {
+            device.answer(json!({"Ret": 104})).await;
+            device
+                .answer(json!({"Ret": 100, "SessionID": "0x00000011", "AliveInterval": 20}))
+                .await;
+        });
+        assert!(logged_in.unwrap());
+
+        let (logged_in, _) =
+            tokio::join!(cam.login("admin", ""), device.answer(json!({"Ret": 101})));
+        assert!(!logged_in.unwrap());
+        assert!(device.try_recv(Duration::from_millis(100)).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn both_tails_follow_the_login_version() {
+        let cam = DVRIPCam::new("127.0.0.1").with_keep_alive(false);
+        let (mut cam, mut device) = test_device::connect(cam).await;
+
+        let device_side = async {
+            let (request, data) = device.recv().await;
+            assert_eq!(request.version, 0);
+            assert!(data.ends_with(b"}\x0a\x00"));
+
+            // The device speaks version 1, its payloads end with a single null byte
+            let reply = json!({"Ret": 100, "SessionID": "0x00000011", "AliveInterval": 20});
+            let mut payload = serde_json::to_vec(&reply).unwrap();
+            payload.push(0);
+            let header = crate::protocol::packet_header(
+                test_device::SESSION,
+                request.packet_count,
+                request.msg_id + 1,
+                payload.len(),
+                1,
+            );
+            device.send_raw(&[header.encode(), payload].concat()).await;
+        };
+        let (logged_in, _) = tokio::join!(cam.login("admin", ""), device_side);
+        assert!(logged_in.unwrap());
+        assert_eq!(cam.protocol_version(), 1);
+
+        let device_side = async {
+            let (request, data) = device.recv().await;
+            assert_eq!(request.version, 1);
+            assert!(data.ends_with(b"}\x00"));
+            let reply = json!({"Name": "General", "Ret": 100, "General": {}});
+            device.reply(&request, reply).await;
+        };
+        let (general, _) = tokio::join!(cam.get_command("General", None), device_side);
+        assert!(general.is_ok());
+    }
+
+    #[tokio::test]
+    async fn no_keep_alives_when_disabled() {
+        let (cam, mut device) = test_device::login(DVRIPCam::new("127.0.0.1")).await;
+        assert!(cam.keep_alive_handle.lock().await.is_none());
+
+        // Several intervals of the login reply go by without a packet
+        tokio::time::pause();
+        let quiet = Duration::from_secs(3 * cam.alive_time.load(Ordering::Acquire));
+        assert!(device.try_recv(quiet).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn aes_device_without_key_is_refused() {
+        let (mut cam, mut device) = test_device::connect(DVRIPCam::new("127.0.0.1")).await;
+
+        let reply = json!({"Ret": 100, "SessionID": "0x00000011", "DataUseAES": true});
+        let (logged_in, _) = tokio::join!(cam.login("admin", ""), device.answer(reply));
+        assert!(matches!(
+            logged_in,
+            Err(crate::error::DVRIPError::Unsupported(_))
+        ));
+        assert!(!cam.is_authenticated());
+    }
+
+    #[cfg(feature = "aes")]
+    #[tokio::test]
+    async fn aes_payloads_after_login() {
+        use crate::crypto::{AesMode, PayloadCipher};
+
+        let key = [0x24; 16];
+        let cipher = PayloadCipher::new(key, AesMode::Ecb);
+        let cam = DVRIPCam::new("127.0.0.1")
+            .with_keep_alive(false)
+            .with_aes_key(key, AesMode::Ecb);
+        let (cam, mut device) = test_device::login_with(cam, json!({"DataUseAES": "1"})).await;
+
+        let device_side = async {
+            let (request, data) = device.recv().await;
+            assert!(data.len().is_multiple_of(16));
+            assert!(crate::protocol::parse_json(&data).is_err());
+            let plain = cipher.decrypt(&data).unwrap();
+            // The tail is encrypted too, followed by the zero padding
+            let end = plain.iter().rposition(|b| *b != 0).unwrap();
+            assert!(plain[..=end].ends_with(b"}\x0a"));
+            let command = crate::protocol::parse_json(&plain).unwrap();
+            assert_eq!(command["Name"], "General");
+
+            let reply = json!({"Name": "General", "Ret": 100, "General": {"LocalNo": 3}});
+            let mut payload = serde_json::to_vec(&reply).unwrap();
+            payload.extend_from_slice(crate::protocol::packet_tail(0));
+            let payload = cipher.encrypt(&payload);
+            device
+                .send(request.packet_count, request.msg_id + 1, &payload)
+                .await;
+        };
+        let (general, _) = tokio::join!(cam.get_command("General", None), device_side);
+        assert_eq!(general.unwrap(), json!({"LocalNo": 3}));
+    }
+}
@@ -1,13 +1,20 @@
 use std::sync::atomic::Ordering;
 
-use crate::constants::QCODES;
+use crate::constants::OK_CODES;
 use crate::dvrip::DVRIPCam;
 use crate::error::Result;
 use async_trait::async_trait;
+use futures_core::Stream;
 use serde_json::json;
+use tokio::time::{Duration, interval};
 
 // This is based of the go2rtc implementation
 
+/// Size of a single talk packet payload.
+/// G.711 at 8kHz is one byte per sample, so this is 40ms of audio
+const TALK_PACKET_SIZE: usize = 320;
+const TALK_SAMPLE_RATE: u64 = 8000;
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum AudioCodec {
     PCMA,
@@ -25,13 +32,22 @@ pub trait Backchannel: Send + Sync {
 
     /// Stop the backchannel
     async fn stop_talk(&self) -> Result<()>;
+
+    /// Start talking and play the audio of `samples` at real-time rate, e.g. a
+    /// `tokio_stream::wrappers::ReceiverStream` fed by a decoder
+    ///
+    /// The talk session is stopped once the stream ends or sending failed, the error
+    /// returned is the first one
+    async fn play_audio_stream<S>(&self, samples: S, codec: AudioCodec) -> Result<()>
+    where
+        S: Stream<Item = Vec<u8>> + Send;
 }
 
 #[async_trait]
 impl Backchannel for DVRIPCam {
     async fn start_talk(&self, codec: AudioCodec) -> Result<()> {
         let cmd = "OPTalk";
-        let code = QCODES.get(cmd).copied().unwrap_or(1434);
+        let code = self.code(cmd).unwrap_or(1434);
 
         // Claim the channel
         let data = json!({
@@ -45,7 +61,15 @@ impl Backchannel for DVRIPCam {
         });
 
         // We expect a response to confirm claim
-        self.set_command(cmd, data, Some(code as u32)).await?;
+        let reply = self.set_command(cmd, data, Some(code as u32)).await?;
+        if let Some(ret) = reply.get("Ret").and_then(|r| r.as_u64())
+            && !OK_CODES.contains(&(ret as u32))
+        {
+            return Err(crate::DVRIPError::ProtocolError(format!(
+                "Failed to claim the talk channel ({})",
+                ret
+            )));
+        }
 
         let session = self.session.load(Ordering::Acquire);
 
@@ -63,9 +87,14 @@ impl Backchannel for DVRIPCam {
             }
         });
         // self.set_command(cmd, start, Some(0x0596)).await?;
-        let start_code = QCODES.get("OPTalkStart").copied().unwrap_or(1430);
-        self.send_command(start_code, start, false).await?;
+        let start_code = self.code("OPTalkStart").unwrap_or(1430);
+        if let Err(e) = self.send_command(start_code, start, false).await {
+            // Release the claimed channel, otherwise the device refuses the next talk
+            let _ = self.stop_talk().await;
+            return Err(e);
+        }
 
+        // Only once the whole handshake went through
         *self.codec.lock().await = Some(codec);
 
         Ok(())
@@ -80,8 +109,8 @@ impl Backchannel for DVRIPCam {
         buffer.extend_from_slice(&data);
 
         let cmd = "OPTalkData";
-        let code = QCODES.get(cmd).copied().unwrap_or(1432);
-        let packet_size = 320;
+        let code = self.code(cmd).unwrap_or(1432);
+        let packet_size = TALK_PACKET_SIZE;
 
         let codec_id = match codec {
             AudioCodec::PCMA => 14,
@@ -111,7 +140,7 @@ impl Backchannel for DVRIPCam {
 
     async fn stop_talk(&self) -> Result<()> {
         let cmd = "OPTalk";
-        let code = QCODES.get(cmd).copied().unwrap_or(1434);
+        let code = self.code(cmd).unwrap_or(1434);
 
         let data = json!({
             "Name": cmd,
@@ -128,4 +157,125 @@ impl Backchannel for DVRIPCam {
 
         Ok(())
     }
+
+    async fn play_audio_stream<S>(&self, samples: S, codec: AudioCodec) -> Result<()>
+    where
+        S: Stream<Item = Vec<u8>> + Send,
+    {
+        self.start_talk(codec).await?;
+        let mut samples = std::pin::pin!(samples);
+
+        // One packet every 40ms, same rate as 160 samples every 20ms
+        let mut ticker = interval(Duration::from_millis(
+            TALK_PACKET_SIZE as u64 * 1000 / TALK_SAMPLE_RATE,
+        ));
+        let mut pending: Vec<u8> = Vec::new();
+        let mut finished = false;
+
+        let result = async {
+            loop {
+                while !finished && pending.len() < TALK_PACKET_SIZE {
+                    match std::future::poll_fn(|cx| samples.as_mut().poll_next(cx)).await {
+                        Some(data) => pending.extend_from_slice(&data),
+                        None => finished = true,
+                    }
+                }
+
+                if pending.is_empty() {
+                    return Ok(());
+                }
+
+                if pending.len() < TALK_PACKET_SIZE {
+                    // Pad the last frame with silence so it is not held in the buffer
+                    let silence = match codec {
+                        AudioCodec::PCMA => 0xD5,
+                        AudioCodec::PCMU => 0xFF,
+                    };
+                    pending.resize(TALK_PACKET_SIZE, silence);
+                }
+
+                ticker.tick().await;
+                let frame: Vec<u8> = pending.drain(0..TALK_PACKET_SIZE).collect();
+                self.send_audio(frame).await?;
+            }
+        }
+        .await;
+
+        // Stopped either way, a failed stop only matters once the audio went through
+        let stopped = self.stop_talk().await;
+        result.and(stopped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DVRIPError;
+    use crate::test_device;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn stream_is_played_in_padded_packets() {
+        let (cam, mut device) = test_device::login(DVRIPCam::new("127.0.0.1")).await;
+        let samples = futures_util::stream::iter([vec![0x11; 200], vec![0x22; 300]]);
+
+        let device_side = async {
+            let (claim, _) = device.answer(json!({"Name": "OPTalk", "Ret": 100})).await;
+            assert_eq!(claim.msg_id, 1434);
+            let (start, _) = device.recv().await;
+            assert_eq!(start.msg_id, 1430);
+
+            let mut audio = vec![];
+            for _ in 0..2 {
+                let (header, data) = device.recv().await;
+                assert_eq!(header.msg_id, 1432);
+                assert_eq!(data.len(), 8 + TALK_PACKET_SIZE);
+                audio.extend_from_slice(&data[8..]);
+            }
+            device.answer(json!({"Name": "OPTalk", "Ret": 100})).await;
+            audio
+        };
+        let (played, audio) = tokio::join!(
+            cam.play_audio_stream(samples, AudioCodec::PCMA),
+            device_side
+        );
+        played.unwrap();
+
+        assert!(audio[..200].iter().all(|b| *b == 0x11));
+        assert!(audio[200..500].iter().all(|b| *b == 0x22));
+        // The end is padded with A-law silence
+        assert!(audio[500..].iter().all(|b| *b == 0xD5));
+    }
+
+    #[tokio::test]
+    async fn failed_start_leaves_no_codec() {
+        let (cam, mut device) = test_device::login(DVRIPCam::new("127.0.0.1")).await;
+
+        // The Start isn't answered, only a local send error can fail it
+        let device_side = async {
+            let (claim, _) = device.answer(json!({"Name": "OPTalk", "Ret": 100})).await;
+            assert_eq!(claim.msg_id, 1434);
+            cam.closing.store(true, Ordering::Release);
+        };
+        let (started, _) = tokio::join!(cam.start_talk(AudioCodec::PCMA), device_side);
+        assert!(matches!(started, Err(DVRIPError::ConnectionError(_))));
+        assert!(cam.codec.lock().await.is_none());
+        assert!(matches!(
+            cam.send_audio(vec![0; TALK_PACKET_SIZE]).await,
+            Err(DVRIPError::NotInitialized())
+        ));
+
+        // Nothing is left half open, the next talk goes through from the Claim
+        cam.closing.store(false, Ordering::Release);
+        let device_side = async {
+            let (claim, body) = device.answer(json!({"Name": "OPTalk", "Ret": 100})).await;
+            assert_eq!(claim.msg_id, 1434);
+            assert_eq!(body["OPTalk"]["Action"], "Claim");
+            let (start, _) = device.recv().await;
+            assert_eq!(start.msg_id, 1430);
+        };
+        let (started, _) = tokio::join!(cam.start_talk(AudioCodec::PCMU), device_side);
+        started.unwrap();
+        assert_eq!(*cam.codec.lock().await, Some(AudioCodec::PCMU));
+    }
 }
@@ -0,0 +1,307 @@
+use crate::commands::SystemInfo;
+use crate::commands::ptz::Rect;
+use crate::commands::system_info::value_to_u64;
+use crate::constants::OK_CODES;
+use crate::dvrip::DVRIPCam;
+use crate::encoding::hex_to_u64;
+use crate::error::{DVRIPError, Result};
+use async_trait::async_trait;
+use serde_json::{Value, json};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DayNightMode {
+    Auto,
+    Day,
+    Night,
+}
+
+impl DayNightMode {
+    fn to_device(self) -> &'static str {
+        match self {
+            DayNightMode::Auto => "0x00000000",
+            DayNightMode::Day => "0x00000001",
+            DayNightMode::Night => "0x00000002",
+        }
+    }
+
+    fn from_device(value: &Value) -> Option<Self> {
+        // Usually a hex string, but some firmware sends a plain number
+        let raw = match value {
+            Value::String(s) => hex_to_u64(s)?,
+            Value::Number(n) => n.as_u64()?,
+            _ => return None,
+        };
+
+        match raw {
+            0 => Some(DayNightMode::Auto),
+            1 => Some(DayNightMode::Day),
+            2 => Some(DayNightMode::Night),
+            _ => None,
+        }
+    }
+}
+
+/// Picture settings, every value goes from 0 to 100
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ImageParams {
+    pub brightness: u8,
+    pub contrast: u8,
+    pub saturation: u8,
+    pub hue: u8,
+    pub sharpness: u8,
+}
+
+impl ImageParams {
+    // Device key for every field, sharpness is called acutance by the firmware
+    const KEYS: [&'static str; 5] = ["Brightness", "Contrast", "Saturation", "Hue", "Acutance"];
+
+    fn values(&self) -> [u8; 5] {
+        [
+            self.brightness,
+            self.contrast,
+            self.saturation,
+            self.hue,
+            self.sharpness,
+        ]
+    }
+
+    fn from_device(value: &Value) -> Option<Self> {
+        let field = |key: &str| {
+            value
+                .get(key)
+                .and_then(|v| v.as_u64())
+                .map(|v| v.min(100) as u8)
+        };
+
+        Some(Self {
+            brightness: field("Brightness")?,
+            contrast: field("Contrast")?,
+            saturation: field("Saturation")?,
+            hue: field("Hue")?,
+            sharpness: field("Acutance").unwrap_or(0),
+        })
+    }
+}
+
+/// Privacy masks of a channel, the masked areas are blacked out on the video
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PrivacyMask {
+    /// Areas currently masked, empty when masking is off
+    pub areas: Vec<Rect>,
+    /// Number of masks the channel supports
+    pub max_areas: usize,
+}
+
+#[async_trait]
+pub trait CameraSettings: Send + Sync {
+    /// Get the day/night (IR-cut) mode of a channel
+    async fn get_daynight_mode(&self, channel: u8) -> Result<DayNightMode>;
+
+    /// Set the day/night (IR-cut) mode of a channel
+    async fn set_daynight_mode(&self, channel: u8, mode: DayNightMode) -> Result<bool>;
+
+    /// Turn the IR LEDs of a channel on or off
+    async fn set_ir_led(&self, channel: u8, on: bool) -> Result<bool>;
+
+    /// Get the picture settings of a channel
+    async fn get_image_params(&self, channel: u8) -> Result<ImageParams>;
+
+    /// Set the picture settings of a channel, values above 100 are clamped
+    async fn set_image_params(&self, channel: u8, params: ImageParams) -> Result<bool>;
+
+    /// Get the privacy masks of a channel
+    async fn get_privacy_mask(&self, channel: u8) -> Result<PrivacyMask>;
+
+    /// Mask the given areas of a channel, an empty list turns masking off
+    ///
+    /// Areas must lie within the picture and not overlap, and there can't be more
+    /// of them than `PrivacyMask::max_areas`
+    async fn set_privacy_mask(&self, channel: u8, areas: Vec<Rect>) -> Result<bool>;
+}
+
+#[async_trait]
+impl CameraSettings for DVRIPCam {
+    async fn get_daynight_mode(&self, channel: u8) -> Result<DayNightMode> {
+        let camera = self.get_camera_info(false).await?;
+
+        camera
+            .get("Param")
+            .and_then(|p| p.get(channel as usize))
+            .and_then(|p| p.get("DayNightColor"))
+            .and_then(DayNightMode::from_device)
+            .ok_or_else(|| DVRIPError::ProtocolError("Day/night mode not reported".to_string()))
+    }
+
+    async fn set_daynight_mode(&self, channel: u8, mode: DayNightMode) -> Result<bool> {
+        self.update_camera_config("Param", channel, "DayNightColor", json!(mode.to_device()))
+            .await
+    }
+
+    async fn set_ir_led(&self, channel: u8, on: bool) -> Result<bool> {
+        self.update_camera_config("ParamEx", channel, "SoftLedSwitch", json!(on))
+            .await
+    }
+
+    async fn get_image_params(&self, channel: u8) -> Result<ImageParams> {
+        let colors = self.get_command("AVEnc.VideoColor", Some(1042)).await?;
+
+        // Each channel has a list of time sections, the first one is the one in use
+        colors
+            .get(channel as usize)
+            .and_then(|c| c.get(0))
+            .and_then(|c| c.get("VideoColorParam"))
+            .and_then(ImageParams::from_device)
+            .ok_or_else(|| DVRIPError::ProtocolError("Image parameters not reported".to_string()))
+    }
+
+    async fn set_image_params(&self, channel: u8, params: ImageParams) -> Result<bool> {
+        let mut colors = self.get_command("AVEnc.VideoColor", Some(1042)).await?;
+
+        let Some(sections) = colors
+            .get_mut(channel as usize)
+            .and_then(|c| c.as_array_mut())
+        else {
+            return Err(DVRIPError::InvalidParameter(format!(
+                "Channel {} has no video color config",
+                channel
+            )));
+        };
+
+        for section in sections {
+            if let Some(color) = section
+                .get_mut("VideoColorParam")
+                .and_then(|c| c.as_object_mut())
+            {
+                for (key, value) in ImageParams::KEYS.iter().zip(params.values()) {
+                    color.insert(key.to_string(), json!(value.min(100)));
+                }
+            }
+        }
+
+        let reply = self
+            .set_command("AVEnc.VideoColor", colors, Some(1040))
+            .await?;
+        if let Some(ret) = reply.get("Ret").and_then(|r| r.as_u64()) {
+            return Ok(OK_CODES.contains(&(ret as u32)));
+        }
+        Ok(false)
+    }
+
+    async fn get_privacy_mask(&self, channel: u8) -> Result<PrivacyMask> {
+        let widgets = self.get_command("AVEnc.VideoWidget", Some(1042)).await?;
+        let widget = widgets.get(channel as usize).ok_or_else(|| {
+            DVRIPError::ProtocolError(format!("No video widget config for channel {}", channel))
+        })?;
+
+        let covers = widget
+            .get("Covers")
+            .and_then(|c| c.as_array())
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        let active = widget.get("CoversNum").and_then(value_to_u64).unwrap_or(0) as usize;
+
+        let areas = covers
+            .iter()
+            .take(active)
+            .filter(|c| c.get("EncodeBlend").and_then(|b| b.as_bool()) == Some(true))
+            .filter_map(|c| {
+                let pos = c.get("RelativePos")?.as_array()?;
+                let sides: Vec<u64> = pos.iter().filter_map(value_to_u64).collect();
+                Some(Rect::from_grid(sides.try_into().ok()?))
+            })
+            .collect();
+
+        Ok(PrivacyMask {
+            areas,
+            max_areas: covers.len(),
+        })
+    }
+
+    async fn set_privacy_mask(&self, channel: u8, areas: Vec<Rect>) -> Result<bool> {
+        let grid = areas
+            .iter()
+            .map(|a| a.to_grid())
+            .collect::<Result<Vec<_>>>()?;
+        for (i, area) in areas.iter().enumerate() {
+            if let Some(other) = areas[i + 1..].iter().find(|o| area.overlaps(o)) {
+                return Err(DVRIPError::InvalidParameter(format!(
+                    "Privacy masks {:?} and {:?} overlap",
+                    area, other
+                )));
+            }
+        }
+
+        let mut widgets = self.get_command("AVEnc.VideoWidget", Some(1042)).await?;
+        let Some(widget) = widgets.get_mut(channel as usize) else {
+            return Err(DVRIPError::InvalidParameter(format!(
+                "Channel {} has no video widget config",
+                channel
+            )));
+        };
+        let Some(covers) = widget.get_mut("Covers").and_then(|c| c.as_array_mut()) else {
+            return Err(DVRIPError::Unsupported("Privacy masks".to_string()));
+        };
+        if grid.len() > covers.len() {
+            return Err(DVRIPError::InvalidParameter(format!(
+                "Got {} privacy masks but channel {} supports {}",
+                grid.len(),
+                channel,
+                covers.len()
+            )));
+        }
+
+        // Every slot is written so the masks that were removed are turned off
+        for (i, cover) in covers.iter_mut().enumerate() {
+            let enabled = i < grid.len();
+            cover["EncodeBlend"] = json!(enabled);
+            cover["PreviewBlend"] = json!(enabled);
+            if let Some(sides) = grid.get(i) {
+                cover["RelativePos"] = json!(sides);
+            }
+        }
+        widget["CoversNum"] = json!(grid.len());
+
+        let reply = self
+            .set_command("AVEnc.VideoWidget", widgets, Some(1040))
+            .await?;
+        if let Some(ret) = reply.get("Ret").and_then(|r| r.as_u64()) {
+            return Ok(OK_CODES.contains(&(ret as u32)));
+        }
+        Ok(false)
+    }
+}
+
+impl DVRIPCam {
+    /// Change a single field of the `Camera` config and write the whole config back
+    /// so the other image settings are left untouched
+    pub(crate) async fn update_camera_config(
+        &self,
+        section: &str,
+        channel: u8,
+        field: &str,
+        value: Value,
+    ) -> Result<bool> {
+        let mut camera = self.get_camera_info(false).await?;
+
+        let Some(entry) = camera
+            .get_mut(section)
+            .and_then(|s| s.get_mut(channel as usize))
+            .and_then(|e| e.as_object_mut())
+        else {
+            return Err(DVRIPError::InvalidParameter(format!(
+                "Channel {} has no Camera.{} config",
+                channel, section
+            )));
+        };
+        entry.insert(field.to_string(), value);
+
+        let reply = self.set_command("Camera", camera, Some(1040)).await?;
+        if let Some(ret) = reply.get("Ret").and_then(|r| r.as_u64()) {
+            return Ok(OK_CODES.contains(&(ret as u32)));
+        }
+        Ok(false)
+    }
+}
@@ -0,0 +1,184 @@
+use crate::constants::OK_CODES;
+use crate::dvrip::{DVRIPCam, StreamHandler, check_ret};
+use crate::error::{DVRIPError, Result};
+use crate::protocol::parse_json;
+use async_trait::async_trait;
+use bytes::Bytes;
+use serde_json::json;
+
+const CONFIG_PACKET_SIZE: usize = 0x8000;
+
+#[async_trait]
+pub trait ConfigTransfer: Send + Sync {
+    /// Export the whole device configuration as an opaque blob
+    ///
+    /// A refused export fails with `DVRIPError::PermissionDenied` or a `ProtocolError`
+    /// with the code of the device
+    async fn export_config(&self) -> Result<Vec<u8>>;
+
+    /// Restore a configuration blob obtained from `export_config`
+    async fn import_config(&self, blob: &[u8]) -> Result<()>;
+}
+
+#[async_trait]
+impl ConfigTransfer for DVRIPCam {
+    async fn export_config(&self) -> Result<Vec<u8>> {
+        self.check_authenticated()?;
+        let export_code = self.code("OPConfigExport").unwrap_or(1542);
+        // The blob comes back on the response id
+        let response_code = export_code + 1;
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel(self.stream_capacity);
+        self.stream_handlers
+            .insert(response_code, StreamHandler::Queue(tx));
+
+        let session = self.session_id();
+        let data = json!({
+            "Name": "",
+            "SessionID": format!("0x{:08X}", session),
+        });
+
+        let result = async {
+            self.send_command(export_code, data, false).await?;
+
+            let mut blob = Vec::new();
+            loop {
+                let packet = tokio::time::timeout(self.command_timeout, rx.recv())
+                    .await
+                    .map_err(|_| {
+                        DVRIPError::ConnectionError("Timeout receiving config".to_string())
+                    })?;
+
+                let Some((header, data)) = packet else {
+                    return Err(DVRIPError::ConnectionError(
+                        "Stream closed unexpectedly".to_string(),
+                    ));
+                };
+
+                if header.data_len == 0 {
+                    break;
+                }
+                // A refusal comes as a JSON reply instead of the archive
+                if blob.is_empty()
+                    && let Ok(reply) = parse_json(&data)
+                    && let Some(ret) = reply.get("Ret").and_then(|r| r.as_u64())
+                {
+                    check_ret(&reply)?;
+                    if !OK_CODES.contains(&(ret as u32)) {
+                        return Err(DVRIPError::ProtocolError(format!(
+                            "Config export rejected with code {}",
+                            ret
+                        )));
+                    }
+                    continue;
+                }
+                blob.extend_from_slice(&data);
+            }
+            Ok(blob)
+        }
+        .await;
+
+        self.stream_handlers.remove(&response_code);
+        result
+    }
+
+    async fn import_config(&self, blob: &[u8]) -> Result<()> {
+        self.check_authenticated()?;
+        let import_code = self.code("OPConfigImport").unwrap_or(1540);
+
+        let reply = self
+            .send_chunked(
+                import_code,
+                Bytes::copy_from_slice(blob),
+                CONFIG_PACKET_SIZE,
+            )
+            .await?;
+
+        check_ret(&reply)?;
+        match reply.get("Ret").and_then(|r| r.as_u64()) {
+            Some(ret) if OK_CODES.contains(&(ret as u32)) => Ok(()),
+            Some(ret) => Err(DVRIPError::ProtocolError(format!(
+                "Config import rejected with code {}",
+                ret
+            ))),
+            None => Err(DVRIPError::ProtocolError(
+                "Config import reply has no Ret".to_string(),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_device;
+
+    #[tokio::test]
+    async fn export_is_read_until_the_empty_packet() {
+        let (cam, mut device) = test_device::login(DVRIPCam::new("127.0.0.1")).await;
+
+        let device_side = async {
+            let (request, export) = device.recv_json().await;
+            assert_eq!(request.msg_id, 1542);
+            assert_eq!(
+                export["SessionID"],
+                format!("0x{:08X}", test_device::SESSION)
+            );
+            device
+                .send(request.packet_count, 1543, &[0x1F, 0x8B, 1, 2])
+                .await;
+            device.send(request.packet_count, 1543, &[3, 4, 5]).await;
+            device.send(request.packet_count, 1543, b"").await;
+        };
+        let (blob, _) = tokio::join!(cam.export_config(), device_side);
+
+        assert_eq!(blob.unwrap(), [0x1F, 0x8B, 1, 2, 3, 4, 5]);
+        assert!(cam.stream_handlers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn denied_export_is_an_error() {
+        let (cam, mut device) = test_device::login(DVRIPCam::new("127.0.0.1")).await;
+
+        let device_side = async {
+            let (request, _) = device.recv().await;
+            let reply = serde_json::to_vec(&json!({"Name": "", "Ret": 107})).unwrap();
+            device.send(request.packet_count, 1543, &reply).await;
+        };
+        let (blob, _) = tokio::join!(cam.export_config(), device_side);
+
+        assert!(matches!(
+            blob,
+            Err(DVRIPError::PermissionDenied { code: 107, .. })
+        ));
+        assert!(cam.stream_handlers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejected_import_is_an_error() {
+        let (cam, mut device) = test_device::login(DVRIPCam::new("127.0.0.1")).await;
+
+        let device_side = async {
+            let (request, chunk) = device.recv().await;
+            assert_eq!(request.msg_id, 1540);
+            assert!(chunk.starts_with(b"config"));
+            device.reply(&request, json!({"Ret": 107})).await;
+        };
+        let (imported, _) = tokio::join!(cam.import_config(b"config"), device_side);
+        assert!(matches!(
+            imported,
+            Err(DVRIPError::PermissionDenied { code: 107, .. })
+        ));
+
+        // Refused once the whole blob was sent
+        let device_side = async {
+            let (request, _) = device.recv().await;
+            device.reply(&request, json!({"Ret": 100})).await;
+            let (end, _) = device.recv().await;
+            assert_eq!(end.data_len as usize, crate::protocol::packet_tail(0).len());
+            device.reply(&end, json!({"Ret": 106})).await;
+        };
+        let (imported, _) = tokio::join!(cam.import_config(b"config"), device_side);
+        assert!(matches!(imported, Err(DVRIPError::ProtocolError(_))));
+    }
+}
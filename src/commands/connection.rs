@@ -1,9 +1,11 @@
-use crate::constants::QCODES;
-use crate::dvrip::DVRIPCam;
+use crate::constants::LOGIN_REPLY_MSG_ID;
+use crate::dvrip::{CommandRequest, DVRIPCam, ReplyKey, StreamHandler};
 use crate::error::Result;
-use crate::protocol::PacketHeader;
+use crate::protocol::{PacketHeader, pack_packet, write_all_vectored};
 use async_trait::async_trait;
-use dashmap::DashMap;
+use bytes::BytesMut;
+use serde_json::json;
+use std::io::IoSlice;
 use std::sync::Arc;
 use std::sync::atomic::Ordering;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
@@ -11,17 +13,69 @@ use tokio::net::TcpStream;
 use tokio::sync;
 use tokio::time::Duration;
 
+/// Lifecycle events of the connection, emitted by the background tasks
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ConnectionEvent {
+    /// A keep-alive couldn't be sent or `MAX_MISSED_KEEP_ALIVES` in a row went
+    /// unanswered, the connection is marked as disconnected
+    KeepAliveFailed,
+    /// The connection was lost or closed
+    Disconnected(String),
+    /// `connect` succeeded on a camera that was connected before
+    Reconnected,
+    /// An alarm packet couldn't be parsed
+    AlarmReadError(String),
+}
+
+/// State of the keep-alives of the current session, from `keep_alive_info`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct KeepAliveInfo {
+    /// Interval between keep-alives, from the login reply
+    pub interval: Duration,
+    /// Time since the device last answered a keep-alive, `None` before the first answer
+    pub since_last_success: Option<Duration>,
+    /// Keep-alives left unanswered since the last answered one
+    pub failures: u32,
+}
+
+// Big enough for most video packets, grows on demand
+const RECV_BUFFER_CAPACITY: usize = 64 * 1024;
+
 #[async_trait]
 pub trait Connection: Send + Sync {
     /// Connect to the device
     async fn connect(&mut self, timeout: tokio::time::Duration) -> Result<()>;
 
-    /// Disconnect from the device
+    /// Disconnect from the device, waiting up to the command timeout for pending replies
     async fn close(&mut self) -> Result<()>;
 
+    /// Disconnect from the device
+    ///
+    /// New commands are refused right away, commands still waiting for a reply
+    /// get up to `close_timeout` to finish before the session is logged out and closed
+    async fn close_with_timeout(&mut self, close_timeout: Duration) -> Result<()>;
+
     /// Check if connected
     fn is_connected(&self) -> bool;
 
+    /// Send a keep-alive and measure the round-trip time
+    ///
+    /// Unlike `is_connected` this detects a half-open connection,
+    /// a `ConnectionError` is returned if there is no reply within the command timeout
+    async fn ping(&self) -> Result<Duration>;
+
+    /// Get the keep-alive interval and how the last keep-alives went
+    ///
+    /// A session with `since_last_success` well past the interval is about to be
+    /// dropped by the device
+    fn keep_alive_info(&self) -> KeepAliveInfo;
+
+    /// Subscribe to connection lifecycle events, only events sent after
+    /// the call are received
+    fn events(&self) -> sync::broadcast::Receiver<ConnectionEvent>;
+
     /// Get the device IP address
     fn ip(&self) -> &str;
 
@@ -32,135 +86,290 @@ pub trait Connection: Send + Sync {
 #[async_trait]
 impl Connection for DVRIPCam {
     async fn connect(&mut self, timeout: Duration) -> Result<()> {
-        self.timeout = timeout;
-
-        let stream: TcpStream =
-            tokio::time::timeout(timeout, TcpStream::connect((self.ip.as_str(), self.port)))
-                .await
-                .map_err(|_| {
-                    crate::error::DVRIPError::ConnectionError("Connection timeout".to_string())
-                })?
-                .map_err(|e| {
-                    crate::error::DVRIPError::ConnectionError(format!("Connection error: {}", e))
-                })?;
+        self.command_timeout = timeout;
+        let reconnect = self.send_pool.is_some();
+
+        // The timeout covers the proxy handshake too
+        let connect = async {
+            match &self.proxy {
+                Some(proxy) => proxy.connect(&self.ip, self.port).await,
+                None => TcpStream::connect((self.ip.as_str(), self.port))
+                    .await
+                    .map_err(|e| {
+                        crate::error::DVRIPError::ConnectionError(format!(
+                            "Connection error: {}",
+                            e
+                        ))
+                    }),
+            }
+        };
+        let stream: TcpStream = tokio::time::timeout(timeout, connect).await.map_err(|_| {
+            crate::error::DVRIPError::ConnectionError("Connection timeout".to_string())
+        })??;
+        // Every packet is written whole, waiting to coalesce them only delays the
+        // chunks of a windowed upload until the previous one is acknowledged
+        let _ = stream.set_nodelay(true);
 
         let (mut read, mut write) = stream.into_split();
 
-        let message_handlers: Arc<
-            DashMap<u32, tokio::sync::oneshot::Sender<(PacketHeader, Vec<u8>)>>,
-        > = Arc::new(DashMap::new());
+        #[cfg(feature = "trace")]
+        let tracer = match &self.trace_path {
+            Some(path) => Some(Arc::new(crate::trace::Tracer::create(path).await?)),
+            None => None,
+        };
+        #[cfg(feature = "trace")]
+        let send_tracer = tracer.clone();
+
+        let message_handlers = Arc::clone(&self.response_handlers);
+        message_handlers.clear();
+        *self.packet_count.lock().unwrap_or_else(|e| e.into_inner()) = 1;
+        let packet_count = Arc::clone(&self.packet_count);
 
         let ptr_1 = Arc::clone(&message_handlers);
         let alarm_callback = Arc::clone(&self.alarm_callback);
-        let frame_channel = Arc::clone(&self.frame_sender);
+        let alarm_event_callback = Arc::clone(&self.alarm_event_callback);
+        let alarm_filter = Arc::clone(&self.alarm_filter);
+        let monitors = Arc::clone(&self.monitors);
+        let (frame_capacity, frame_overflow) = (self.frame_capacity, self.frame_overflow);
         let monitoring = Arc::clone(&self.alarm_monitoring);
-        let video_monitoring = Arc::clone(&self.monitoring);
         let stream_handlers = Arc::clone(&self.stream_handlers);
+        let events = Arc::clone(&self.events);
+        let recv_events = Arc::clone(&events);
+        let recv_connected = Arc::clone(&self.connected);
+        let recv_closing = Arc::clone(&self.closing);
+        let max_packet_size = self.max_packet_size;
+        let protocol_version = Arc::clone(&self.protocol_version);
+        let idle_read_timeout = self.idle_read_timeout;
 
+        // This task is the only reader of the socket, alarms, media and replies
+        // are all dispatched from here
+        let alarm_info_code = self.code("AlarmInfo").unwrap_or(1504);
         *self.recv_handle.lock().await = Some(tokio::spawn(async move {
-            let alarm_info_code = QCODES.get("AlarmInfo").copied().unwrap_or(1504);
+            let mut buffer = BytesMut::with_capacity(RECV_BUFFER_CAPACITY);
+            let disconnect = |reason: String| {
+                recv_connected.store(false, Ordering::Release);
+                // Nothing will answer the commands in flight, fail them now
+                ptr_1.clear();
+                if !recv_closing.load(Ordering::Acquire) {
+                    let _ = recv_events.send(ConnectionEvent::Disconnected(reason));
+                }
+            };
+
             loop {
                 let mut header = [0u8; 20];
-                read.read_exact(&mut header)
-                    .await
-                    .expect("Error reading packet header");
-                let decoded_header = PacketHeader::decode(&header).unwrap();
+                let received = match idle_read_timeout {
+                    Some(idle) => tokio::time::timeout(idle, read.read_exact(&mut header)).await,
+                    None => Ok(read.read_exact(&mut header).await),
+                };
+                match received {
+                    Ok(Ok(_)) => {}
+                    Ok(Err(e)) => {
+                        disconnect(format!("Error reading packet header: {}", e));
+                        break;
+                    }
+                    Err(_) => {
+                        disconnect(format!(
+                            "Nothing received for {:?}",
+                            idle_read_timeout.unwrap_or_default()
+                        ));
+                        break;
+                    }
+                }
+                let decoded_header = match PacketHeader::decode(&header) {
+                    Ok(header) => header,
+                    Err(e) => {
+                        disconnect(format!("Invalid packet header: {}", e));
+                        break;
+                    }
+                };
 
-                let mut data = vec![0u8; decoded_header.data_len as usize];
-                read.read_exact(&mut data)
-                    .await
-                    .expect("Error reading packet data");
+                // The rest of the stream can't be trusted after a bogus length
+                if let Err(e) = decoded_header.check_data_len(max_packet_size) {
+                    disconnect(e.to_string());
+                    break;
+                }
+
+                if decoded_header.msg_id == LOGIN_REPLY_MSG_ID {
+                    protocol_version.store(decoded_header.version, Ordering::Release);
+                }
+
+                // The buffer is reused once the frames handed out of it are dropped,
+                // it only grows when a packet is bigger than anything seen before
+                buffer.clear();
+                buffer.resize(decoded_header.data_len as usize, 0);
+                if let Err(e) = read.read_exact(&mut buffer).await {
+                    disconnect(format!("Error reading packet data: {}", e));
+                    break;
+                }
+                let data = buffer.split().freeze();
+                #[cfg(feature = "trace")]
+                if let Some(tracer) = &tracer {
+                    tracer.record(
+                        crate::trace::Direction::Received,
+                        &header,
+                        vec![data.clone()],
+                    );
+                }
 
-                if decoded_header.msg_id == 1412 && video_monitoring.load(Ordering::Acquire) {
-                    DVRIPCam::__handle_video(frame_channel.clone(), data).await;
+                // Media packets carry their channel in byte 12, which PacketHeader skips
+                if decoded_header.msg_id == 1412 && !monitors.is_empty() {
+                    DVRIPCam::dispatch_video(
+                        &monitors,
+                        frame_capacity,
+                        frame_overflow,
+                        header[12],
+                        data,
+                    )
+                    .await;
                     continue;
                 }
 
                 if decoded_header.msg_id == alarm_info_code && monitoring.load(Ordering::Acquire) {
-                    DVRIPCam::__handle_alarm(Arc::clone(&alarm_callback), decoded_header, data)
-                        .await;
+                    DVRIPCam::__handle_alarm(
+                        &alarm_callback,
+                        &alarm_event_callback,
+                        &alarm_filter,
+                        &recv_events,
+                        decoded_header,
+                        &data,
+                    )
+                    .await;
                     continue;
                 }
 
-                if let Some((_, handler)) = ptr_1.remove(&decoded_header.packet_count) {
-                    let _ = handler.send((decoded_header, data));
+                // ACKs of upload chunks usually come on the next message id, some
+                // firmware answers on the id of the chunk
+                let packet_count = decoded_header.packet_count;
+                let msg_id = decoded_header.msg_id;
+                let handler = [
+                    ReplyKey::Chunk(msg_id.wrapping_sub(1), packet_count),
+                    ReplyKey::Chunk(msg_id, packet_count),
+                    ReplyKey::Counter(packet_count),
+                ]
+                .iter()
+                .find_map(|key| ptr_1.remove(key));
+                if let Some((_, handler)) = handler {
+                    let _ = handler.send((decoded_header, data.to_vec()));
                     continue;
                 }
 
-                if let Some(handler) = stream_handlers.get(&decoded_header.msg_id) {
-                    let _ = handler.send((decoded_header, data)).await;
+                // Queues are cloned out so a full one doesn't hold the map while it waits
+                let queue = match stream_handlers.get(&decoded_header.msg_id).as_deref() {
+                    Some(StreamHandler::Queue(queue)) => Some(queue.clone()),
+                    Some(StreamHandler::Latest(latest)) => {
+                        latest.send_replace(Some((decoded_header, data.to_vec())));
+                        continue;
+                    }
+                    None => None,
+                };
+                if let Some(queue) = queue {
+                    let _ = queue.send((decoded_header, data.to_vec())).await;
                 }
             }
         }));
 
-        let (send, mut recv) = sync::mpsc::channel(100);
+        let (send, mut recv) = sync::mpsc::channel(self.stream_capacity);
         self.send_pool = Arc::new(Some(send));
+        let send_events = Arc::clone(&events);
+        let send_connected = Arc::clone(&self.connected);
+        let send_closing = Arc::clone(&self.closing);
         *self.send_handle.lock().await = Some(tokio::spawn(async move {
-            let mut packet_count = 1;
-            while let Some(request) = recv.recv().await {
-                let mut header = request.header;
-                let use_internal_counter = request.use_internal_counter;
-
-                if use_internal_counter {
-                    header.packet_count = packet_count;
-                }
-
-                // If a response sender is provided, wait for the response
-                if let Some(sender) = request.response_sender {
-                    message_handlers.insert(
-                        // 0x0585 is the code for starting the stream
-                        // i don't really know why the packet count for this specifically has to be one more but ok
-                        if header.msg_id == 0x0585
-                            || header.msg_id == 0x590
-                            || header.msg_id == 0x059a
-                        {
-                            header.packet_count + 1
-                        } else {
-                            header.packet_count
-                        },
-                        sender,
-                    );
+            while let Some(mut request) = recv.recv().await {
+                // Requests queued through `Transport` are already numbered and registered
+                if request.use_internal_counter {
+                    let mut count = packet_count.lock().unwrap_or_else(|e| e.into_inner());
+                    request.header.packet_count = *count;
+                    *count = count.wrapping_add(1);
+                    request.use_internal_counter = false;
                 }
+                if let Some(sender) = request.response_sender.take() {
+                    message_handlers.insert(request.reply_key(), sender);
+                }
+                let header = &request.header;
 
-                // Send the packet
-                write
-                    .write_all(&header.encode())
-                    .await
-                    .expect("Error sending packet header. Cannot continue.");
-                write
-                    .write_all(&request.data)
-                    .await
-                    .expect("Error sending packet data. Cannot continue.");
-                write.flush().await.unwrap();
-
-                if use_internal_counter {
-                    packet_count += 1;
+                // Send the packet, header, payload and tail in a single write
+                let encoded = header.encode();
+                let mut bufs = [
+                    IoSlice::new(&encoded),
+                    IoSlice::new(&request.data),
+                    IoSlice::new(request.tail),
+                ];
+                let mut written = write_all_vectored(&mut write, &mut bufs).await;
+                // Packets sent without a flush go out at the latest once the queue is empty
+                if written.is_ok() && (request.flush || recv.is_empty()) {
+                    written = write.flush().await;
+                }
+                #[cfg(feature = "trace")]
+                if let Some(tracer) = &send_tracer {
+                    let payload = vec![
+                        request.data.clone(),
+                        bytes::Bytes::from_static(request.tail),
+                    ];
+                    tracer.record(crate::trace::Direction::Sent, &encoded, payload);
+                }
+                if let Err(e) = written {
+                    send_connected.store(false, Ordering::Release);
+                    if !send_closing.load(Ordering::Acquire) {
+                        let _ = send_events.send(ConnectionEvent::Disconnected(format!(
+                            "Error sending packet: {}",
+                            e
+                        )));
+                    }
+                    break;
                 }
             }
         }));
 
+        self.closing.store(false, Ordering::Release);
         self.connected.store(true, Ordering::Release);
 
+        if reconnect {
+            let _ = events.send(ConnectionEvent::Reconnected);
+        }
+
         Ok(())
     }
 
     async fn close(&mut self) -> Result<()> {
+        self.close_with_timeout(self.command_timeout).await
+    }
+
+    async fn close_with_timeout(&mut self, close_timeout: Duration) -> Result<()> {
+        let deadline = tokio::time::Instant::now() + close_timeout;
+
+        if self.connected.load(Ordering::Acquire) {
+            // Refuse new commands, the ones in flight can still get their reply
+            self.closing.store(true, Ordering::Release);
+
+            while !self.response_handlers.is_empty() && tokio::time::Instant::now() < deadline {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+
+            if self.authenticated.load(Ordering::Acquire) {
+                self.send_logout(deadline).await;
+            }
+
+            let _ = self
+                .events
+                .send(ConnectionEvent::Disconnected("Closed".to_string()));
+        }
+
         self.connected.store(false, Ordering::Release);
         self.authenticated.store(false, Ordering::Release);
-        self.monitoring.store(false, Ordering::Release);
+        self.monitors.clear();
         self.alarm_monitoring.store(false, Ordering::Release);
 
         // Cancel background tasks
         if let Some(handle) = self.keep_alive_handle.lock().await.take() {
             handle.abort();
         }
-        // Removed alarm_handle cancellation as it's no longer used
         if let Some(handle) = self.recv_handle.lock().await.take() {
             handle.abort();
         }
         if let Some(handle) = self.send_handle.lock().await.take() {
             handle.abort();
         }
+        self.response_handlers.clear();
 
         Ok(())
     }
@@ -169,6 +378,41 @@ impl Connection for DVRIPCam {
         self.connected.load(Ordering::Acquire)
     }
 
+    async fn ping(&self) -> Result<Duration> {
+        let keep_alive_code = self.code("KeepAlive").unwrap_or(1006);
+        let data = json!({
+            "Name": "KeepAlive",
+            "SessionID": format!("0x{:08X}", self.session.load(Ordering::Acquire)),
+        });
+
+        let start = tokio::time::Instant::now();
+        self.send_command_recv_bin(keep_alive_code, data, true)
+            .await?
+            .ok_or_else(|| {
+                crate::error::DVRIPError::ConnectionError("No reply to ping".to_string())
+            })?;
+
+        Ok(start.elapsed())
+    }
+
+    fn keep_alive_info(&self) -> KeepAliveInfo {
+        let since_last_success = self
+            .last_keep_alive
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .map(|last| last.elapsed());
+
+        KeepAliveInfo {
+            interval: Duration::from_secs(self.alive_time.load(Ordering::Acquire)),
+            since_last_success,
+            failures: self.keep_alive_failures.load(Ordering::Acquire),
+        }
+    }
+
+    fn events(&self) -> sync::broadcast::Receiver<ConnectionEvent> {
+        self.events.subscribe()
+    }
+
     fn ip(&self) -> &str {
         &self.ip
     }
@@ -177,3 +421,273 @@ impl Connection for DVRIPCam {
         self.port
     }
 }
+
+impl DVRIPCam {
+    async fn send_logout(&self, deadline: tokio::time::Instant) {
+        let Ok(transport) = self.transport() else {
+            return;
+        };
+
+        let logout_code = self.code("Logout").unwrap_or(1002);
+        let session = self.session.load(Ordering::Acquire);
+        let data = json!({
+            "Name": "",
+            "SessionID": format!("0x{:08X}", session),
+        });
+        let Ok(data) = serde_json::to_vec(&data) else {
+            return;
+        };
+        let version = self.protocol_version();
+        let Ok((header, body)) = pack_packet(session, 0, logout_code, &data, version, true).await
+        else {
+            return;
+        };
+
+        // Wait for the reply so the packet is actually written before the tasks are aborted
+        let request = CommandRequest::new(header, body).with_counter(true);
+        if let Ok(reply) = transport.send_with_reply(request).await {
+            let _ = tokio::time::timeout_at(deadline, reply).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::DVRIPError;
+    use crate::test_device;
+
+    #[tokio::test]
+    async fn timed_out_command_does_not_hold_close() {
+        let cam = DVRIPCam::new("127.0.0.1").with_require_auth(false);
+        let (mut cam, mut device) = test_device::connect(cam).await;
+        cam.command_timeout = Duration::from_millis(200);
+
+        let (result, _) = tokio::join!(cam.get_command("General", None), device.recv());
+        assert!(matches!(result, Err(DVRIPError::ConnectionError(_))));
+        assert!(cam.response_handlers.is_empty());
+
+        let start = tokio::time::Instant::now();
+        cam.close_with_timeout(Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn disconnect_fails_pending_commands() {
+        let cam = DVRIPCam::new("127.0.0.1").with_require_auth(false);
+        let (cam, mut device) = test_device::connect(cam).await;
+
+        let device_side = async move {
+            device.recv().await;
+            device.disconnect().await;
+        };
+        let (result, _) = tokio::join!(
+            tokio::time::timeout(Duration::from_secs(1), cam.get_command("General", None)),
+            device_side
+        );
+        assert!(matches!(result, Ok(Err(DVRIPError::ConnectionError(_)))));
+        assert!(cam.response_handlers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn keep_alives_stay_one_interval_apart_without_replies() {
+        let (cam, mut device) = test_device::login(DVRIPCam::new("127.0.0.1")).await;
+        cam.alive_time.store(1, Ordering::Release);
+        cam.start_keep_alive().await;
+
+        let start = tokio::time::Instant::now();
+        for _ in 0..3 {
+            device.recv().await;
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(2900), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(3500), "{:?}", elapsed);
+
+        // Unanswered ones are forgotten instead of piling up
+        assert!(cam.response_handlers.len() <= 2);
+        let info = cam.keep_alive_info();
+        assert!(info.failures >= 1);
+        assert_eq!(info.since_last_success, None);
+    }
+
+    #[tokio::test]
+    async fn unanswered_keep_alives_fail_the_connection() {
+        let (cam, mut device) = test_device::login(DVRIPCam::new("127.0.0.1")).await;
+        let mut events = cam.events();
+        cam.alive_time.store(1, Ordering::Release);
+        cam.start_keep_alive().await;
+
+        let device_side = async {
+            loop {
+                device.recv().await;
+            }
+        };
+        let event = tokio::select! {
+            event = tokio::time::timeout(Duration::from_secs(6), events.recv()) => event,
+            _ = device_side => unreachable!(),
+        };
+        assert_eq!(event.unwrap().unwrap(), ConnectionEvent::KeepAliveFailed);
+        assert!(!cam.is_connected());
+        assert_eq!(
+            cam.keep_alive_info().failures,
+            crate::constants::MAX_MISSED_KEEP_ALIVES
+        );
+    }
+
+    #[tokio::test]
+    async fn chunk_acks_are_apart_from_command_replies() {
+        let (cam, mut device) = test_device::login(DVRIPCam::new("127.0.0.1")).await;
+        let data = bytes::Bytes::from_static(b"abcdefghi");
+
+        let device_side = async {
+            let mut chunks = vec![];
+            let mut command = None;
+            while chunks.len() < 3 || command.is_none() {
+                let (header, payload) = device.recv().await;
+                if header.msg_id == 0x5F2 {
+                    chunks.push(header);
+                } else {
+                    command = Some((header, payload));
+                }
+            }
+            let (command, _) = command.unwrap();
+            // Numbered like one of the chunks, which used to take its reply
+            assert!(
+                chunks
+                    .iter()
+                    .any(|c| c.packet_count == command.packet_count)
+            );
+
+            device
+                .reply(
+                    &command,
+                    json!({"Name": "General", "Ret": 100, "General": {}}),
+                )
+                .await;
+            for chunk in &chunks {
+                device.reply(chunk, json!({"Ret": 100})).await;
+            }
+            let (end, _) = device.recv().await;
+            assert_eq!(end.data_len as usize, crate::protocol::packet_tail(0).len());
+            device.reply(&end, json!({"Ret": 100})).await;
+        };
+        let both = async {
+            tokio::join!(
+                cam.send_chunked_with_progress(0x5F2, data, 3, 3, &|_, _| {}),
+                cam.get_command("General", None),
+                device_side
+            )
+        };
+        let (upload, general, _) = tokio::time::timeout(Duration::from_secs(5), both)
+            .await
+            .unwrap();
+        assert_eq!(upload.unwrap()["Ret"], 100);
+        assert!(general.is_ok());
+        assert!(cam.response_handlers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn unread_progress_does_not_block_replies() {
+        let cam = DVRIPCam::new("127.0.0.1").with_require_auth(false);
+        let (cam, mut device) = test_device::connect(cam).await;
+        let (tx, rx) = tokio::sync::watch::channel(None);
+        cam.stream_handlers.insert(0x5F2, StreamHandler::Latest(tx));
+
+        // Far more steps than a queue would hold, and nobody reading them
+        for ret in 0..100u32 {
+            let payload = serde_json::to_vec(&json!({"Ret": ret})).unwrap();
+            device.send(0, 0x5F2, &payload).await;
+        }
+        let device_side = device.answer(json!({"Name": "General", "Ret": 100, "General": {}}));
+        let (general, _) = tokio::time::timeout(Duration::from_secs(5), async {
+            tokio::join!(cam.get_command("General", None), device_side)
+        })
+        .await
+        .unwrap();
+        assert!(general.is_ok());
+
+        let last = rx.borrow().as_ref().map(|(_, data)| data.clone()).unwrap();
+        assert_eq!(crate::protocol::parse_json(&last).unwrap()["Ret"], 99);
+    }
+
+    #[tokio::test]
+    async fn oversized_packet_drops_the_connection() {
+        let cam = DVRIPCam::new("127.0.0.1")
+            .with_require_auth(false)
+            .with_max_packet_size(1024);
+        let (cam, mut device) = test_device::connect(cam).await;
+        let mut events = cam.events();
+
+        let device_side = async {
+            let (request, _) = device.recv().await;
+            // Announces 4 GB and sends nothing of it
+            let header = crate::protocol::packet_header(
+                test_device::SESSION,
+                request.packet_count,
+                request.msg_id + 1,
+                u32::MAX as usize,
+                0,
+            );
+            device.send_raw(&header.encode()).await;
+        };
+        let (result, _) = tokio::join!(
+            tokio::time::timeout(Duration::from_secs(2), cam.get_command("General", None)),
+            device_side
+        );
+        assert!(matches!(result, Ok(Err(DVRIPError::ConnectionError(_)))));
+        assert!(!cam.is_connected());
+        match events.recv().await.unwrap() {
+            ConnectionEvent::Disconnected(reason) => {
+                assert!(reason.contains("exceeds"), "{}", reason)
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+
+    fn is_not_connected<T>(result: Result<T>) -> bool {
+        matches!(result, Err(DVRIPError::NotInitialized()))
+    }
+
+    #[tokio::test]
+    async fn commands_before_connect_are_not_connected() {
+        let cam = DVRIPCam::new("127.0.0.1").with_require_auth(false);
+
+        assert!(is_not_connected(cam.get_command("General", None).await));
+        assert!(is_not_connected(
+            cam.send_raw_packet(1042, b"{}".to_vec(), true, true).await
+        ));
+        assert!(is_not_connected(
+            cam.send_chunked_with_progress(
+                0x5F2,
+                bytes::Bytes::from_static(b"abc"),
+                3,
+                1,
+                &|_, _| {}
+            )
+            .await
+        ));
+    }
+
+    #[tokio::test]
+    async fn reply_to_another_command_is_rejected() {
+        let (cam, mut device) = test_device::login(DVRIPCam::new("127.0.0.1")).await;
+
+        let wrong = json!({"Name": "Users", "Ret": 100, "Users": []});
+        let (general, _) = tokio::join!(cam.get_command("General", None), device.answer(wrong));
+        assert!(matches!(general, Err(DVRIPError::ProtocolError(_))));
+
+        let wrong = json!({"Name": "General", "Ret": 100});
+        let (set, _) = tokio::join!(
+            cam.set_command("NetWork.NetCommon", json!({}), None),
+            device.answer(wrong)
+        );
+        assert!(matches!(set, Err(DVRIPError::ProtocolError(_))));
+
+        // Replies without a name are still taken
+        let unnamed = json!({"Ret": 100, "General": {}});
+        let (general, _) = tokio::join!(cam.get_command("General", None), device.answer(unnamed));
+        assert!(general.is_ok());
+    }
+}
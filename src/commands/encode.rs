@@ -0,0 +1,318 @@
+use crate::commands::SystemInfo;
+use crate::commands::system_info::value_to_u64;
+use crate::constants::OK_CODES;
+use crate::dvrip::DVRIPCam;
+use crate::error::{DVRIPError, Result};
+use async_trait::async_trait;
+use serde_json::{Value, json};
+use strum_macros::AsRefStr;
+
+/// Resolutions by bit index of the device resolution masks
+const RESOLUTIONS: &[(&str, u32, u32)] = &[
+    ("D1", 704, 576),
+    ("HD1", 704, 288),
+    ("BCIF", 352, 576),
+    ("CIF", 352, 288),
+    ("QCIF", 176, 144),
+    ("VGA", 640, 480),
+    ("QVGA", 320, 240),
+    ("SVCD", 480, 480),
+    ("QQVGA", 160, 128),
+    ("ND1", 240, 192),
+    ("960H", 928, 576),
+    ("720P", 1280, 720),
+    ("1_3M", 1280, 960),
+    ("UXGA", 1600, 1200),
+    ("1080P", 1920, 1080),
+    ("WUXGA", 1920, 1200),
+    ("2_5M", 1872, 1408),
+    ("3M", 2048, 1536),
+    ("5M", 3744, 1408),
+    ("1080N", 960, 1080),
+    ("4M", 2592, 1520),
+    ("6M", 3072, 2048),
+    ("8M", 3264, 2448),
+    ("12M", 4000, 3000),
+    ("4K", 3840, 2160),
+    ("720N", 640, 720),
+    ("WSVGA", 1024, 576),
+    ("NHD", 640, 360),
+    ("3M_N", 1024, 1536),
+    ("4M_N", 1296, 1520),
+    ("5M_N", 1872, 1408),
+    ("4K_N", 1920, 2160),
+];
+
+/// Codecs by bit index of the compression mask
+const CODECS: &[&str] = &[
+    "DIVX MPEG4",
+    "MS MPEG4",
+    "MPEG2",
+    "MPEG1",
+    "H.263",
+    "MJPG",
+    "FCC MPEG4",
+    "H.264",
+    "H.265",
+];
+
+/// Highest frame rate any firmware accepts (NTSC)
+const MAX_FPS: u32 = 30;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ResolutionCaps {
+    pub name: String,
+    /// Derived from the encode power of the channel, `None` when the device doesn't report it
+    pub max_fps: Option<u32>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ChannelCaps {
+    pub main_resolutions: Vec<ResolutionCaps>,
+    pub extra_resolutions: Vec<ResolutionCaps>,
+    pub codecs: Vec<String>,
+}
+
+impl ChannelCaps {
+    fn resolutions(&self, stream: EncodeStream) -> &[ResolutionCaps] {
+        match stream {
+            EncodeStream::Main => &self.main_resolutions,
+            EncodeStream::Extra => &self.extra_resolutions,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EncodeCapability {
+    pub per_channel: Vec<ChannelCaps>,
+}
+
+impl EncodeCapability {
+    pub(crate) fn from_value(value: &Value) -> Self {
+        let per_channel_mask = |key: &str| -> Vec<u64> {
+            value
+                .get(key)
+                .and_then(|v| v.as_array())
+                .map(|a| a.iter().filter_map(value_to_u64).collect())
+                .unwrap_or_default()
+        };
+
+        let main_masks = per_channel_mask("ImageSizePerChannel");
+        let extra_masks = per_channel_mask("ExImageSizePerChannel");
+        let powers = per_channel_mask("MaxEncodePowerPerChannel");
+
+        let compression_mask = value
+            .get("EncodeInfo")
+            .and_then(|e| e.as_array())
+            .map(|infos| {
+                infos
+                    .iter()
+                    .filter_map(|i| i.get("CompressionMask").and_then(value_to_u64))
+                    .fold(0, |acc, m| acc | m)
+            })
+            .unwrap_or(0);
+        let codecs: Vec<String> = CODECS
+            .iter()
+            .enumerate()
+            .filter(|(bit, _)| compression_mask & (1 << bit) != 0)
+            .map(|(_, name)| name.to_string())
+            .collect();
+
+        let resolutions = |mask: u64, power: Option<u64>| -> Vec<ResolutionCaps> {
+            RESOLUTIONS
+                .iter()
+                .enumerate()
+                .filter(|(bit, _)| mask & (1 << bit) != 0)
+                .map(|(_, (name, w, h))| ResolutionCaps {
+                    name: name.to_string(),
+                    max_fps: power
+                        .map(|p| (p / (*w as u64 * *h as u64)).min(MAX_FPS as u64) as u32),
+                })
+                .collect()
+        };
+
+        let per_channel = main_masks
+            .iter()
+            .enumerate()
+            .map(|(i, mask)| {
+                let power = powers.get(i).copied();
+                ChannelCaps {
+                    main_resolutions: resolutions(*mask, power),
+                    extra_resolutions: resolutions(extra_masks.get(i).copied().unwrap_or(0), power),
+                    codecs: codecs.clone(),
+                }
+            })
+            .collect();
+
+        Self { per_channel }
+    }
+
+    /// Check `config` against what the device supports for this channel and stream
+    pub fn validate(&self, channel: u8, stream: EncodeStream, config: &EncodeConfig) -> Result<()> {
+        let caps = self.per_channel.get(channel as usize).ok_or_else(|| {
+            DVRIPError::InvalidParameter(format!("Channel {} doesn't exist", channel))
+        })?;
+
+        let resolution = caps
+            .resolutions(stream)
+            .iter()
+            .find(|r| r.name == config.resolution)
+            .ok_or_else(|| {
+                DVRIPError::InvalidParameter(format!(
+                    "Resolution {} is not supported on the {} stream of channel {}",
+                    config.resolution,
+                    stream.as_ref(),
+                    channel
+                ))
+            })?;
+
+        if let Some(max_fps) = resolution.max_fps
+            && config.fps > max_fps
+        {
+            return Err(DVRIPError::InvalidParameter(format!(
+                "{} fps is over the maximum of {} for {}",
+                config.fps, max_fps, config.resolution
+            )));
+        }
+
+        if !caps.codecs.is_empty() && !caps.codecs.contains(&config.compression) {
+            return Err(DVRIPError::InvalidParameter(format!(
+                "Codec {} is not supported, expected one of {:?}",
+                config.compression, caps.codecs
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, AsRefStr)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum EncodeStream {
+    #[strum(serialize = "MainFormat")]
+    Main,
+    #[strum(serialize = "ExtraFormat")]
+    Extra,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EncodeConfig {
+    /// Resolution name as used by the device, e.g. "1080P"
+    pub resolution: String,
+    pub fps: u32,
+    /// Codec name as used by the device, e.g. "H.265"
+    pub compression: String,
+    /// Bitrate in Kbps, left unchanged when `None`
+    pub bitrate: Option<u32>,
+}
+
+impl EncodeConfig {
+    fn apply(&self, video: &mut Value) {
+        video["Resolution"] = json!(self.resolution);
+        video["FPS"] = json!(self.fps);
+        video["Compression"] = json!(self.compression);
+        if let Some(bitrate) = self.bitrate {
+            video["BitRate"] = json!(bitrate);
+        }
+    }
+}
+
+/// Encoding of both streams of a channel, written together
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MainAndSubConfig {
+    pub main: EncodeConfig,
+    pub extra: EncodeConfig,
+}
+
+#[async_trait]
+pub trait EncodeSettings: Send + Sync {
+    /// Get the resolutions, frame rates and codecs supported by each channel
+    async fn get_encode_caps(&self) -> Result<EncodeCapability>;
+
+    /// Change the encoding of one stream, the settings are checked against
+    /// the device capabilities before anything is sent
+    async fn set_encode_config(
+        &self,
+        channel: u8,
+        stream: EncodeStream,
+        config: EncodeConfig,
+    ) -> Result<bool>;
+
+    /// Change the main and extra stream of a channel in a single write, so the device
+    /// never runs with one changed and the other not. Both are validated first
+    async fn set_all_encode(&self, channel: u8, config: MainAndSubConfig) -> Result<bool>;
+}
+
+#[async_trait]
+impl EncodeSettings for DVRIPCam {
+    async fn get_encode_caps(&self) -> Result<EncodeCapability> {
+        let caps = self.get_encode_capabilities().await?;
+        Ok(EncodeCapability::from_value(&caps))
+    }
+
+    async fn set_encode_config(
+        &self,
+        channel: u8,
+        stream: EncodeStream,
+        config: EncodeConfig,
+    ) -> Result<bool> {
+        self.get_encode_caps()
+            .await?
+            .validate(channel, stream, &config)?;
+
+        self.write_encode(channel, &[(stream, &config)]).await
+    }
+
+    async fn set_all_encode(&self, channel: u8, config: MainAndSubConfig) -> Result<bool> {
+        let caps = self.get_encode_caps().await?;
+        caps.validate(channel, EncodeStream::Main, &config.main)?;
+        caps.validate(channel, EncodeStream::Extra, &config.extra)?;
+
+        self.write_encode(
+            channel,
+            &[
+                (EncodeStream::Main, &config.main),
+                (EncodeStream::Extra, &config.extra),
+            ],
+        )
+        .await
+    }
+}
+
+impl DVRIPCam {
+    /// Apply the configs to the current `Simplify.Encode` and write it back at once
+    async fn write_encode(
+        &self,
+        channel: u8,
+        configs: &[(EncodeStream, &EncodeConfig)],
+    ) -> Result<bool> {
+        let mut encode = self.get_encode_info(false).await?;
+        for (stream, config) in configs {
+            let Some(video) = encode
+                .get_mut(channel as usize)
+                .and_then(|c| c.get_mut(stream.as_ref()))
+                .and_then(|f| f.get_mut("Video"))
+            else {
+                return Err(DVRIPError::ProtocolError(format!(
+                    "No {} encode config for channel {}",
+                    stream.as_ref(),
+                    channel
+                )));
+            };
+            config.apply(video);
+        }
+
+        let reply = self
+            .set_command("Simplify.Encode", encode, Some(1040))
+            .await?;
+        if let Some(ret) = reply.get("Ret").and_then(|r| r.as_u64()) {
+            return Ok(OK_CODES.contains(&(ret as u32)));
+        }
+        Ok(false)
+    }
+}
@@ -1,10 +1,212 @@
+use crate::constants::{DATE_FORMAT, OK_CODES, UNSUPPORTED_CODES};
+use crate::dvrip::{DVRIPCam, StreamHandler};
+use crate::encoding::parse_device_time;
 use crate::error::Result;
-use crate::{DVRIPError, dvrip::DVRIPCam};
+use crate::protocol::PacketHeader;
+use crate::{DVRIPError, SystemInfo};
 use async_trait::async_trait;
-use chrono::{DateTime, Local};
+use chrono::{DateTime, Local, TimeDelta, TimeZone};
 use serde_json::{Value, json};
 use std::path::Path;
-use tokio::{fs::File, io::AsyncWriteExt};
+use std::str::FromStr;
+use strum_macros::{AsRefStr, EnumString};
+use tokio::fs::File;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// Filter recordings by what triggered them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, AsRefStr)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum EventFilter {
+    #[default]
+    #[strum(serialize = "*")]
+    All,
+    #[strum(serialize = "M")]
+    Motion,
+    #[strum(serialize = "A")]
+    Alarm,
+    #[strum(serialize = "H")]
+    Manual,
+    #[strum(serialize = "I")]
+    Intelligence,
+}
+
+/// What triggered a recording, from the flag the device stores with each file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, AsRefStr, EnumString)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum RecordEvent {
+    /// Recorded following the schedule
+    #[strum(serialize = "R")]
+    Schedule,
+    #[strum(serialize = "M")]
+    Motion,
+    #[strum(serialize = "A")]
+    Alarm,
+    #[strum(serialize = "H")]
+    Manual,
+    /// Human or vehicle detection and other analytics
+    #[strum(serialize = "I")]
+    Intelligence,
+}
+
+impl RecordEvent {
+    /// Decode the event of an entry of `list_local_files`
+    ///
+    /// Read from its `Event` field, or from the `[X]` tag of the file name for firmware
+    /// that only puts it there, e.g. `00.00.00-00.05.00[M][@1a2b][0].h264`
+    pub fn of_recording(recording: &Value) -> Option<Self> {
+        if let Some(event) = recording.get("Event").and_then(|e| e.as_str())
+            && let Ok(event) = RecordEvent::from_str(event.trim())
+        {
+            return Some(event);
+        }
+        let filename = recording.get("FileName")?.as_str()?;
+        filename
+            .split('[')
+            .skip(1)
+            .filter_map(|tag| tag.split_once(']'))
+            .find_map(|(tag, _)| RecordEvent::from_str(tag).ok())
+    }
+
+    /// Filter matching the recordings of this event, `Schedule` ones can only be
+    /// listed with `EventFilter::All`
+    pub fn filter(self) -> EventFilter {
+        match self {
+            RecordEvent::Schedule => EventFilter::All,
+            RecordEvent::Motion => EventFilter::Motion,
+            RecordEvent::Alarm => EventFilter::Alarm,
+            RecordEvent::Manual => EventFilter::Manual,
+            RecordEvent::Intelligence => EventFilter::Intelligence,
+        }
+    }
+}
+
+impl std::fmt::Display for RecordEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            RecordEvent::Schedule => "Schedule",
+            RecordEvent::Motion => "Motion",
+            RecordEvent::Alarm => "Alarm",
+            RecordEvent::Manual => "Manual",
+            RecordEvent::Intelligence => "Intelligence",
+        })
+    }
+}
+
+/// Kind of file stored on the device
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, AsRefStr)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum FileType {
+    #[default]
+    #[strum(serialize = "h264")]
+    Video,
+    /// Snapshots taken on events or on schedule
+    #[strum(serialize = "jpg")]
+    Picture,
+}
+
+impl FileType {
+    /// Stream type of the query, pictures aren't stored per stream
+    fn stream_type(self) -> u32 {
+        match self {
+            FileType::Video => 0,
+            FileType::Picture => 1,
+        }
+    }
+
+    fn of(filename: &str) -> Self {
+        if filename.to_ascii_lowercase().ends_with(".jpg") {
+            FileType::Picture
+        } else {
+            FileType::Video
+        }
+    }
+}
+
+/// Where `list_local_files_with_options` searches
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileQueryOptions {
+    /// Disks to search, one bit per disk in the order the device numbers them
+    /// (bit 0 is the first disk). The default `0x0000FFFF` searches all of them
+    pub driver_type_mask: u32,
+    /// Stream the recordings were made from, 0 for the main stream and 1 for the
+    /// extra (sub) stream. `None` uses the default of the file type, the main
+    /// stream for video
+    pub stream_type: Option<u32>,
+}
+
+impl Default for FileQueryOptions {
+    fn default() -> Self {
+        Self {
+            driver_type_mask: 0x0000FFFF,
+            stream_type: None,
+        }
+    }
+}
+
+/// Removes the stream handlers of a download however it ends, along with the
+/// playback of `stream_file` when `session` is set
+struct PlaybackGuard<'a> {
+    cam: &'a DVRIPCam,
+    stream_ids: &'a [u16],
+    session: bool,
+}
+
+impl Drop for PlaybackGuard<'_> {
+    fn drop(&mut self) {
+        for id in self.stream_ids {
+            self.cam.stream_handlers.remove(id);
+        }
+        if self.session {
+            *self.cam.playback.lock().unwrap_or_else(|e| e.into_inner()) = None;
+        }
+    }
+}
+
+/// Control of the playback started by `stream_file`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PlaybackAction {
+    Pause,
+    Resume,
+    /// Playback speed multiplier. Firmware supports powers of two up to 16x faster
+    /// (2.0, 4.0, ...) and 16x slower (0.5, 0.25, ...), other values are rounded to
+    /// the nearest one and kept within 16x. 1.0 is normal speed
+    Speed(f32),
+}
+
+/// Fastest and slowest playback level, 16x
+const MAX_SPEED_LEVEL: i32 = 4;
+
+impl PlaybackAction {
+    /// OPPlayBack action and its `Value`
+    fn command(self) -> Result<(&'static str, i32)> {
+        match self {
+            PlaybackAction::Pause => Ok(("Pause", 0)),
+            PlaybackAction::Resume => Ok(("Continue", 0)),
+            PlaybackAction::Speed(speed) => {
+                if !speed.is_finite() || speed <= 0.0 {
+                    return Err(DVRIPError::InvalidParameter(format!(
+                        "Invalid playback speed {}",
+                        speed
+                    )));
+                }
+                let level = (speed.log2().round() as i32).clamp(-MAX_SPEED_LEVEL, MAX_SPEED_LEVEL);
+                Ok(match level {
+                    0 => ("Continue", 0),
+                    l if l > 0 => ("Fast", l),
+                    l => ("Slow", -l),
+                })
+            }
+        }
+    }
+}
+
+/// File being played by `stream_file`, needed to address control commands
+#[derive(Debug, Clone)]
+pub(crate) struct PlaybackSession {
+    filename: String,
+    start_time: String,
+    end_time: String,
+}
 
 #[async_trait]
 pub trait FileManagement: Send + Sync {
@@ -13,11 +215,26 @@ pub trait FileManagement: Send + Sync {
         &self,
         start_time: DateTime<Local>,
         end_time: DateTime<Local>,
-        file_type: &str,
+        file_type: FileType,
         channel: u8,
+        event_filter: EventFilter,
     ) -> Result<Vec<Value>>;
 
-    /// Download a file from the device
+    /// List local files on the device, on the disks and stream selected by `options`
+    async fn list_local_files_with_options(
+        &self,
+        start_time: DateTime<Local>,
+        end_time: DateTime<Local>,
+        file_type: FileType,
+        channel: u8,
+        event_filter: EventFilter,
+        options: FileQueryOptions,
+    ) -> Result<Vec<Value>>;
+
+    /// Download a file from the device, pictures are saved as plain JPEG
+    ///
+    /// Fails with `DVRIPError::ProtocolError` and removes `target_path` when no data
+    /// arrives, or when the stream stops for longer than the command timeout
     async fn download_file(
         &self,
         start_time: DateTime<Local>,
@@ -26,7 +243,24 @@ pub trait FileManagement: Send + Sync {
         target_path: &str,
     ) -> Result<()>;
 
+    /// Download a file from the device into any writer (a pipe, an upload, a hashing
+    /// wrapper, ...), flushed once the file is complete. Returns the bytes written
+    ///
+    /// Fails with `DVRIPError::ProtocolError` when no data arrives, or when the stream
+    /// stops for longer than the command timeout. What was written before stays in
+    /// `writer`
+    async fn download_to_writer(
+        &self,
+        start_time: DateTime<Local>,
+        end_time: DateTime<Local>,
+        filename: &str,
+        writer: &mut (dyn AsyncWrite + Unpin + Send),
+    ) -> Result<u64>;
+
     /// Streams a file from the device
+    ///
+    /// Only one file is streamed at a time, `DVRIPError::InvalidParameter` is returned
+    /// while another one is. The download is stopped on the device however it ends
     async fn stream_file(
         &self,
         start_time: DateTime<Local>,
@@ -34,6 +268,34 @@ pub trait FileManagement: Send + Sync {
         filename: &str,
         receiver: tokio::sync::mpsc::Sender<Vec<u8>>,
     ) -> Result<()>;
+
+    /// Pause, resume or change the speed of the running `stream_file`
+    ///
+    /// Returns `DVRIPError::InvalidParameter` when no file is being streamed or the
+    /// speed isn't a positive number
+    async fn playback_control(&self, action: PlaybackAction) -> Result<bool>;
+
+    /// Delete a recording returned by `list_local_files`
+    ///
+    /// Returns `DVRIPError::Unsupported` when the firmware can't delete single files
+    async fn delete_recording(&self, recording: &Value) -> Result<bool>;
+
+    /// Delete every recording of a channel in a time range and return the deleted ones
+    ///
+    /// With `dry_run` nothing is deleted, the recordings that would be are returned
+    async fn delete_recordings(
+        &self,
+        start_time: DateTime<Local>,
+        end_time: DateTime<Local>,
+        file_type: FileType,
+        channel: u8,
+        event_filter: EventFilter,
+        dry_run: bool,
+    ) -> Result<Vec<Value>>;
+
+    /// Get the start of the oldest and the end of the newest video recording over all
+    /// channels, `None` when there are no recordings
+    async fn get_recording_span(&self) -> Result<Option<(DateTime<Local>, DateTime<Local>)>>;
 }
 
 #[async_trait]
@@ -42,22 +304,50 @@ impl FileManagement for DVRIPCam {
         &self,
         start_time: DateTime<Local>,
         end_time: DateTime<Local>,
-        file_type: &str,
+        file_type: FileType,
+        channel: u8,
+        event_filter: EventFilter,
+    ) -> Result<Vec<Value>> {
+        self.list_local_files_with_options(
+            start_time,
+            end_time,
+            file_type,
+            channel,
+            event_filter,
+            FileQueryOptions::default(),
+        )
+        .await
+    }
+
+    async fn list_local_files_with_options(
+        &self,
+        start_time: DateTime<Local>,
+        end_time: DateTime<Local>,
+        file_type: FileType,
         channel: u8,
+        event_filter: EventFilter,
+        options: FileQueryOptions,
     ) -> Result<Vec<Value>> {
         let start_str = start_time.format("%Y-%m-%d %H:%M:%S").to_string();
         let end_str = end_time.format("%Y-%m-%d %H:%M:%S").to_string();
+        let driver_type_mask = format!("0x{:08X}", options.driver_type_mask);
+        let stream_type = format!(
+            "0x{:08X}",
+            options
+                .stream_type
+                .unwrap_or_else(|| file_type.stream_type())
+        );
 
         let data = json!({
             "Name": "OPFileQuery",
             "OPFileQuery": {
                 "BeginTime": start_str,
                 "Channel": channel,
-                "DriverTypeMask": "0x0000FFFF",
+                "DriverTypeMask": driver_type_mask,
                 "EndTime": end_str,
-                "Event": "*",
-                "StreamType": "0x00000000",
-                "Type": file_type,
+                "Event": event_filter.as_ref(),
+                "StreamType": stream_type,
+                "Type": file_type.as_ref(),
             },
         });
 
@@ -98,11 +388,11 @@ impl FileManagement for DVRIPCam {
                 "OPFileQuery": {
                     "BeginTime": new_start,
                     "Channel": channel,
-                    "DriverTypeMask": "0x0000FFFF",
+                    "DriverTypeMask": driver_type_mask,
                     "EndTime": end_str,
-                    "Event": "*",
-                    "StreamType": "0x00000000",
-                    "Type": file_type,
+                    "Event": event_filter.as_ref(),
+                    "StreamType": stream_type,
+                    "Type": file_type.as_ref(),
                 },
             });
 
@@ -150,13 +440,35 @@ impl FileManagement for DVRIPCam {
             },
         });
 
+        {
+            // Taken before the claim so a second stream can't replace this one
+            let mut playback = self.playback.lock().unwrap_or_else(|e| e.into_inner());
+            if playback.is_some() {
+                return Err(DVRIPError::InvalidParameter(
+                    "A file is already being streamed".to_string(),
+                ));
+            }
+            *playback = Some(PlaybackSession {
+                filename: filename.to_string(),
+                start_time: start_str.clone(),
+                end_time: end_str.clone(),
+            });
+        }
+        // Standard media + explicit stream ID
+        let stream_ids = [0x1FC, 0x1FD, 0x1FA, 0x1F9, 0x5FC, 0x0592];
+        let playback = PlaybackGuard {
+            cam: self,
+            stream_ids: &stream_ids,
+            session: true,
+        };
+
         self.send_command(1424, claim_data, true).await?;
 
         // Prepare stream listener
-        let (tx, mut rx) = tokio::sync::mpsc::channel(100);
-        let stream_ids = [0x1FC, 0x1FD, 0x1FA, 0x1F9, 0x5FC, 0x0592]; // Standard media + explicit stream ID
+        let (tx, mut rx) = tokio::sync::mpsc::channel(self.stream_capacity);
         for &id in &stream_ids {
-            self.stream_handlers.insert(id, tx.clone());
+            self.stream_handlers
+                .insert(id, StreamHandler::Queue(tx.clone()));
         }
 
         // DownloadStart
@@ -176,47 +488,70 @@ impl FileManagement for DVRIPCam {
             },
         });
 
-        self.send_command(1420, download_start_data, false).await?;
+        let streamed = async {
+            self.send_command(1420, download_start_data, false).await?;
 
-        while let Some((header, data)) = rx.recv().await {
-            if header.data_len == 0 {
-                break;
+            while let Some((header, data)) = rx.recv().await {
+                if header.data_len == 0 {
+                    break;
+                }
+                receiver
+                    .send(data)
+                    .await
+                    .map_err(|_| DVRIPError::Unknown("Failed to send".to_string()))?;
             }
-            receiver
-                .send(data)
-                .await
-                .map_err(|_| DVRIPError::Unknown("Failed to send".to_string()))?;
+            Ok(())
         }
+        .await;
 
-        // Cleanup handlers
-        for &id in &stream_ids {
-            self.stream_handlers.remove(&id);
-        }
+        drop(playback);
 
-        // DownloadStop
-        let download_stop_data = json!({
+        // Otherwise the device keeps sending the rest of the file
+        let stopped = self
+            .send_download_stop(filename, &start_str, &end_str)
+            .await;
+        streamed.and(stopped)
+    }
+
+    async fn playback_control(&self, action: PlaybackAction) -> Result<bool> {
+        let session = self
+            .playback
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        let Some(session) = session else {
+            return Err(DVRIPError::InvalidParameter(
+                "No playback in progress".to_string(),
+            ));
+        };
+
+        let (action, value) = action.command()?;
+        let data = json!({
             "Name": "OPPlayBack",
             "OPPlayBack": {
-                "Action": "DownloadStop",
+                "Action": action,
                 "Parameter": {
-                    "FileName": filename,
                     "PlayMode": "ByName",
+                    "FileName": session.filename,
                     "StreamType": 0,
+                    "Value": value,
                     "TransMode": "TCP",
-                    "Channel": 0,
-                    "Value": 0,
                 },
-                "StartTime": start_str,
-                "EndTime": end_str,
+                "StartTime": session.start_time,
+                "EndTime": session.end_time,
             },
         });
 
-        self.send_command(1420, download_stop_data, false).await?;
-
-        Ok(())
+        let reply = self
+            .send_command(1420, data, true)
+            .await?
+            .ok_or_else(|| DVRIPError::ProtocolError("Empty response".to_string()))?;
+        if let Some(ret) = reply.get("Ret").and_then(|r| r.as_u64()) {
+            return Ok(OK_CODES.contains(&(ret as u32)));
+        }
+        Ok(false)
     }
 
-    // TODO: migrate this to use stream_file
     async fn download_file(
         &self,
         start_time: DateTime<Local>,
@@ -228,6 +563,31 @@ impl FileManagement for DVRIPCam {
             tokio::fs::create_dir_all(parent).await?;
         }
 
+        let mut file = File::create(target_path).await?;
+        let received = match self
+            .download_to_writer(start_time, end_time, filename, &mut file)
+            .await
+        {
+            Ok(_) => file.sync_all().await.map_err(DVRIPError::from),
+            Err(e) => Err(e),
+        };
+        drop(file);
+
+        // Don't leave an empty or partial file behind as if it worked
+        if received.is_err() {
+            let _ = tokio::fs::remove_file(target_path).await;
+        }
+        received
+    }
+
+    // TODO: migrate this to use stream_file
+    async fn download_to_writer(
+        &self,
+        start_time: DateTime<Local>,
+        end_time: DateTime<Local>,
+        filename: &str,
+        writer: &mut (dyn AsyncWrite + Unpin + Send),
+    ) -> Result<u64> {
         let start_str = start_time.format("%Y-%m-%d %H:%M:%S").to_string();
         let end_str = end_time.format("%Y-%m-%d %H:%M:%S").to_string();
 
@@ -251,10 +611,16 @@ impl FileManagement for DVRIPCam {
         self.send_command(1424, claim_data, true).await?;
 
         // Prepare stream listener
-        let (tx, mut rx) = tokio::sync::mpsc::channel(100);
+        let (tx, mut rx) = tokio::sync::mpsc::channel(self.stream_capacity);
         let stream_ids = [0x1FC, 0x1FD, 0x1FA, 0x1F9, 0x5FC, 0x0592]; // Standard media + explicit stream ID
+        let handlers = PlaybackGuard {
+            cam: self,
+            stream_ids: &stream_ids,
+            session: false,
+        };
         for &id in &stream_ids {
-            self.stream_handlers.insert(id, tx.clone());
+            self.stream_handlers
+                .insert(id, StreamHandler::Queue(tx.clone()));
         }
 
         // DownloadStart
@@ -276,23 +642,104 @@ impl FileManagement for DVRIPCam {
 
         self.send_command(1420, download_start_data, false).await?;
 
-        // Receive data and write to file
-        let mut file = File::create(target_path).await?;
+        // Receive data and write it out
+        let received = self.write_download(&mut rx, writer, filename).await;
+        drop(handlers);
 
-        while let Some((header, data)) = rx.recv().await {
-            if header.data_len == 0 {
-                break;
+        self.send_download_stop(filename, &start_str, &end_str)
+            .await?;
+
+        // Nothing came from the stream ids (device refused, wrong file)
+        match received {
+            Ok(0) => Err(DVRIPError::ProtocolError(format!(
+                "No data received for {}",
+                filename
+            ))),
+            received => received,
+        }
+    }
+
+    async fn delete_recording(&self, recording: &Value) -> Result<bool> {
+        let field = |key: &str| {
+            recording
+                .get(key)
+                .and_then(|v| v.as_str())
+                .ok_or_else(|| DVRIPError::InvalidParameter(format!("Recording has no {}", key)))
+        };
+
+        let data = json!({
+            "Action": "DeleteFile",
+            "FileName": field("FileName")?,
+            "BeginTime": field("BeginTime")?,
+            "EndTime": field("EndTime")?,
+        });
+
+        let code = self.code("OPStorageManager").unwrap_or(1460);
+        let reply = self
+            .set_command("OPStorageManager", data, Some(code as u32))
+            .await?;
+
+        if let Some(ret) = reply.get("Ret").and_then(|r| r.as_u64()) {
+            if UNSUPPORTED_CODES.contains(&(ret as u32)) {
+                return Err(DVRIPError::Unsupported("Deleting recordings".to_string()));
             }
-            file.write_all(&data).await?;
+            return Ok(OK_CODES.contains(&(ret as u32)));
         }
-        file.sync_all().await?;
+        Ok(false)
+    }
 
-        // Cleanup handlers
-        for &id in &stream_ids {
-            self.stream_handlers.remove(&id);
+    async fn delete_recordings(
+        &self,
+        start_time: DateTime<Local>,
+        end_time: DateTime<Local>,
+        file_type: FileType,
+        channel: u8,
+        event_filter: EventFilter,
+        dry_run: bool,
+    ) -> Result<Vec<Value>> {
+        let recordings = self
+            .list_local_files(start_time, end_time, file_type, channel, event_filter)
+            .await?;
+
+        if dry_run {
+            return Ok(recordings);
         }
 
-        // DownloadStop
+        let mut deleted = Vec::new();
+        for recording in recordings {
+            if self.delete_recording(&recording).await? {
+                deleted.push(recording);
+            }
+        }
+        Ok(deleted)
+    }
+
+    async fn get_recording_span(&self) -> Result<Option<(DateTime<Local>, DateTime<Local>)>> {
+        let channels = self.channel_count().await?;
+
+        let mut span: Option<(DateTime<Local>, DateTime<Local>)> = None;
+        for channel in 0..channels.min(u8::MAX as u32) as u8 {
+            if let Some((oldest, newest)) = self.channel_recording_span(channel).await? {
+                span = Some(match span {
+                    Some((start, end)) => (start.min(oldest), end.max(newest)),
+                    None => (oldest, newest),
+                });
+            }
+        }
+        Ok(span)
+    }
+}
+
+/// Look back this far for the newest recording before listing everything
+const NEWEST_RECORDING_WINDOWS_DAYS: &[i64] = &[1, 7, 31, 366];
+
+fn recording_time(recording: &Value, key: &str) -> Option<DateTime<Local>> {
+    parse_device_time(recording.get(key)?.as_str()?)
+}
+
+impl DVRIPCam {
+    /// Tell the device to stop sending the file of `stream_file` or `download_to_writer`
+    async fn send_download_stop(&self, filename: &str, start: &str, end: &str) -> Result<()> {
         let download_stop_data = json!({
             "Name": "OPPlayBack",
             "OPPlayBack": {
@@ -305,13 +752,300 @@ impl FileManagement for DVRIPCam {
                     "Channel": 0,
                     "Value": 0,
                 },
-                "StartTime": start_str,
-                "EndTime": end_str,
+                "StartTime": start,
+                "EndTime": end,
             },
         });
 
         self.send_command(1420, download_stop_data, false).await?;
-
         Ok(())
     }
+
+    /// Write the download stream to `writer` until its empty end packet, returning the
+    /// number of bytes written. Fails when nothing arrives for the command timeout
+    async fn write_download(
+        &self,
+        rx: &mut tokio::sync::mpsc::Receiver<(PacketHeader, Vec<u8>)>,
+        writer: &mut (dyn AsyncWrite + Unpin + Send),
+        filename: &str,
+    ) -> Result<u64> {
+        // Pictures may come behind a media header, drop everything before the JPEG SOI
+        let mut skip_to_soi = FileType::of(filename) == FileType::Picture;
+        let mut written = 0u64;
+
+        loop {
+            let Ok(packet) = tokio::time::timeout(self.command_timeout, rx.recv()).await else {
+                return Err(DVRIPError::ProtocolError(match written {
+                    0 => format!("No data received for {}", filename),
+                    _ => format!("Download of {} stalled after {} bytes", filename, written),
+                }));
+            };
+            let Some((header, data)) = packet else {
+                break;
+            };
+            if header.data_len == 0 {
+                break;
+            }
+            let mut data = data.as_slice();
+            if skip_to_soi {
+                match data.windows(2).position(|w| w == [0xFF, 0xD8]) {
+                    Some(soi) => {
+                        data = &data[soi..];
+                        skip_to_soi = false;
+                    }
+                    None => continue,
+                }
+            }
+            writer.write_all(data).await?;
+            written += data.len() as u64;
+        }
+        writer.flush().await?;
+
+        Ok(written)
+    }
+
+    async fn channel_recording_span(
+        &self,
+        channel: u8,
+    ) -> Result<Option<(DateTime<Local>, DateTime<Local>)>> {
+        let now = Local::now();
+        // The device clock may be ahead of ours
+        let end = now + TimeDelta::days(1);
+        let epoch = Local
+            .with_ymd_and_hms(2000, 1, 1, 0, 0, 0)
+            .earliest()
+            .unwrap_or_default();
+
+        // Recordings come back oldest first, listing them all to find the newest can
+        // take hundreds of pages so start with a short window and widen it
+        let windows = NEWEST_RECORDING_WINDOWS_DAYS
+            .iter()
+            .map(|days| now - TimeDelta::days(*days))
+            .chain(std::iter::once(epoch));
+
+        let mut newest = None;
+        for start in windows {
+            let recordings = self
+                .list_local_files(start, end, FileType::Video, channel, EventFilter::All)
+                .await?;
+            newest = recordings
+                .iter()
+                .filter_map(|r| recording_time(r, "EndTime"))
+                .max();
+            if newest.is_some() {
+                break;
+            }
+        }
+        let Some(newest) = newest else {
+            return Ok(None);
+        };
+
+        let oldest = self
+            .first_recording(epoch, end, channel)
+            .await?
+            .unwrap_or(newest);
+        Ok(Some((oldest.min(newest), newest)))
+    }
+
+    /// Start time of the first recording, only the first page of the query is read
+    async fn first_recording(
+        &self,
+        start_time: DateTime<Local>,
+        end_time: DateTime<Local>,
+        channel: u8,
+    ) -> Result<Option<DateTime<Local>>> {
+        let data = json!({
+            "Name": "OPFileQuery",
+            "OPFileQuery": {
+                "BeginTime": start_time.format(DATE_FORMAT).to_string(),
+                "Channel": channel,
+                "DriverTypeMask": "0x0000FFFF",
+                "EndTime": end_time.format(DATE_FORMAT).to_string(),
+                "Event": EventFilter::All.as_ref(),
+                "StreamType": format!("0x{:08X}", FileType::Video.stream_type()),
+                "Type": FileType::Video.as_ref(),
+            },
+        });
+
+        let reply = self
+            .send_command(1440, data, true)
+            .await?
+            .ok_or_else(|| DVRIPError::ProtocolError("Empty response".to_string()))?;
+
+        Ok(reply
+            .get("OPFileQuery")
+            .and_then(|f| f.as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|r| recording_time(r, "BeginTime"))
+            .min())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_device;
+    use std::time::Duration;
+
+    fn recording() -> Value {
+        json!({
+            "FileName": "/idea0/2024-01-01/001/00.00.00-00.10.00[R][@1][0].h264",
+            "BeginTime": "2024-01-01 00:00:00",
+            "EndTime": "2024-01-01 00:10:00",
+        })
+    }
+
+    #[tokio::test]
+    async fn delete_recording_is_a_session_command() {
+        let (cam, mut device) = test_device::login(DVRIPCam::new("127.0.0.1")).await;
+        let recording = recording();
+
+        let (deleted, (header, request)) = tokio::join!(
+            cam.delete_recording(&recording),
+            device.answer(json!({"Name": "OPStorageManager", "Ret": 100}))
+        );
+        assert!(deleted.unwrap());
+        assert_eq!(header.msg_id, 1460);
+        assert_eq!(
+            request["SessionID"],
+            format!("0x{:08X}", test_device::SESSION)
+        );
+        assert_eq!(request["OPStorageManager"]["Action"], "DeleteFile");
+
+        let (denied, _) = tokio::join!(
+            cam.delete_recording(&recording),
+            device.answer(json!({"Name": "OPStorageManager", "Ret": 107}))
+        );
+        assert!(matches!(
+            denied,
+            Err(DVRIPError::PermissionDenied { code: 107, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn failed_stream_forgets_the_playback() {
+        let (cam, mut device) = test_device::login(DVRIPCam::new("127.0.0.1")).await;
+        let (start, end) = (Local::now() - chrono::TimeDelta::hours(1), Local::now());
+        // Nobody takes the data, the stream fails on the first packet
+        let (sink, _) = tokio::sync::mpsc::channel(1);
+
+        let device_side = async {
+            device
+                .answer(json!({"Name": "OPPlayBack", "Ret": 100}))
+                .await;
+            let (download, _) = device.recv().await;
+            assert_eq!(download.msg_id, 1420);
+            device.send(download.packet_count, 0x0592, b"media").await;
+            let (stop, request) = device.recv_json().await;
+            assert_eq!(stop.msg_id, 1420);
+            assert_eq!(request["OPPlayBack"]["Action"], "DownloadStop");
+        };
+        let (streamed, _) = tokio::join!(
+            cam.stream_file(start, end, "/idea0/00.h264", sink),
+            device_side
+        );
+        assert!(streamed.is_err());
+
+        assert!(cam.stream_handlers.is_empty());
+        assert!(matches!(
+            cam.playback_control(PlaybackAction::Pause).await,
+            Err(DVRIPError::InvalidParameter(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn every_page_is_queried_with_the_event_filter() {
+        let (cam, mut device) = test_device::login(DVRIPCam::new("127.0.0.1")).await;
+        let (start, end) = (Local::now() - chrono::TimeDelta::days(1), Local::now());
+
+        let device_side = async {
+            // A full page makes the query continue from its last file
+            let page = vec![recording(); 64];
+            let full = json!({"Name": "OPFileQuery", "Ret": 100, "OPFileQuery": page});
+            let (_, first) = device.answer(full).await;
+            let last = json!({"Name": "OPFileQuery", "Ret": 100, "OPFileQuery": [recording()]});
+            let (_, second) = device.answer(last).await;
+            (first, second)
+        };
+        let (files, (first, second)) = tokio::join!(
+            cam.list_local_files(start, end, FileType::Video, 0, EventFilter::Motion),
+            device_side
+        );
+        assert_eq!(files.unwrap().len(), 65);
+
+        assert_eq!(first["OPFileQuery"]["Event"], "M");
+        assert_eq!(second["OPFileQuery"]["Event"], "M");
+        assert_eq!(second["OPFileQuery"]["BeginTime"], "2024-01-01 00:00:00");
+    }
+
+    #[tokio::test]
+    async fn second_stream_is_refused() {
+        let (cam, mut device) = test_device::login(DVRIPCam::new("127.0.0.1")).await;
+        let (start, end) = (Local::now() - chrono::TimeDelta::hours(1), Local::now());
+        let session = PlaybackSession {
+            filename: "/idea0/00.h264".to_string(),
+            start_time: String::new(),
+            end_time: String::new(),
+        };
+        *cam.playback.lock().unwrap() = Some(session);
+
+        let (sink, _keep) = tokio::sync::mpsc::channel(1);
+        let streamed = cam.stream_file(start, end, "/idea0/01.h264", sink).await;
+        assert!(matches!(streamed, Err(DVRIPError::InvalidParameter(_))));
+        assert!(device.try_recv(Duration::from_millis(100)).await.is_none());
+        // The running one is still addressed
+        let filename = cam
+            .playback
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|p| p.filename.clone());
+        assert_eq!(filename.as_deref(), Some("/idea0/00.h264"));
+    }
+
+    #[test]
+    fn playback_speed_is_kept_within_16x() {
+        assert_eq!(
+            PlaybackAction::Speed(1.0).command().unwrap(),
+            ("Continue", 0)
+        );
+        assert_eq!(PlaybackAction::Speed(3.0).command().unwrap(), ("Fast", 2));
+        assert_eq!(PlaybackAction::Speed(0.25).command().unwrap(), ("Slow", 2));
+        assert_eq!(
+            PlaybackAction::Speed(1000.0).command().unwrap(),
+            ("Fast", 4)
+        );
+        assert_eq!(PlaybackAction::Speed(1e-9).command().unwrap(), ("Slow", 4));
+
+        for speed in [0.0, -2.0, f32::INFINITY, f32::NAN] {
+            assert!(matches!(
+                PlaybackAction::Speed(speed).command(),
+                Err(DVRIPError::InvalidParameter(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_download_start_removes_the_handlers() {
+        let (cam, mut device) = test_device::login(DVRIPCam::new("127.0.0.1")).await;
+        let (start, end) = (Local::now() - chrono::TimeDelta::hours(1), Local::now());
+        let mut file = Vec::new();
+
+        // DownloadStart isn't answered, only a local send error can fail it
+        let device_side = async {
+            device
+                .answer(json!({"Name": "OPPlayBack", "Ret": 100}))
+                .await;
+            cam.closing
+                .store(true, std::sync::atomic::Ordering::Release);
+        };
+        let (downloaded, _) = tokio::join!(
+            cam.download_to_writer(start, end, "/idea0/00.h264", &mut file),
+            device_side
+        );
+
+        assert!(matches!(downloaded, Err(DVRIPError::ConnectionError(_))));
+        assert!(cam.stream_handlers.is_empty());
+    }
 }
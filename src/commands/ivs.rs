@@ -0,0 +1,253 @@
+use crate::commands::ptz::GRID_RANGE;
+use crate::commands::system_info::value_to_u64;
+use crate::constants::OK_CODES;
+use crate::dvrip::DVRIPCam;
+use crate::error::{DVRIPError, Result};
+use async_trait::async_trait;
+use serde_json::{Value, json};
+
+/// Most points a region rule can have
+const MAX_REGION_POINTS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum IvsRuleType {
+    /// Tripwire between two points
+    Line,
+    /// Polygon area (intrusion)
+    Region,
+}
+
+/// Crossings that trigger a rule, for regions forward means entering
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum IvsDirection {
+    Forward,
+    Backward,
+    Both,
+}
+
+impl IvsDirection {
+    fn from_device(value: u64) -> Self {
+        match value {
+            0 => IvsDirection::Forward,
+            1 => IvsDirection::Backward,
+            _ => IvsDirection::Both,
+        }
+    }
+
+    fn to_device(self) -> u64 {
+        match self {
+            IvsDirection::Forward => 0,
+            IvsDirection::Backward => 1,
+            IvsDirection::Both => 2,
+        }
+    }
+}
+
+/// Line-crossing or intrusion rule of the human detection
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct IvsRule {
+    pub enabled: bool,
+    pub kind: IvsRuleType,
+    /// (x, y) as fractions of the picture width and height (0.0 to 1.0).
+    /// Two points for a line, 3 to 8 for a region
+    pub points: Vec<(f32, f32)>,
+    pub direction: IvsDirection,
+}
+
+impl IvsRule {
+    // {"Enable": true, "RuleType": 0,
+    //  "RuleLine": {"AlarmDirect": 2, "Pts": {"StartX": 0, "StartY": 0, "StopX": 0, "StopY": 0}},
+    //  "RuleRegion": {"AlarmDirect": 2, "PtsNum": 4, "Pts": [{"X": 0, "Y": 0}, ...]}}
+    fn from_device(value: &Value) -> Option<Self> {
+        let coord = |v: &Value, key: &str| {
+            v.get(key)
+                .and_then(value_to_u64)
+                .map(|c| (c as f32 / GRID_RANGE).min(1.0))
+        };
+        let direction = |section: &Value| {
+            IvsDirection::from_device(
+                section
+                    .get("AlarmDirect")
+                    .and_then(value_to_u64)
+                    .unwrap_or(2),
+            )
+        };
+        let enabled = value
+            .get("Enable")
+            .and_then(|e| e.as_bool())
+            .unwrap_or(false);
+
+        match value.get("RuleType").and_then(value_to_u64)? {
+            0 => {
+                let line = value.get("RuleLine")?;
+                let pts = line.get("Pts")?;
+                Some(Self {
+                    enabled,
+                    kind: IvsRuleType::Line,
+                    points: vec![
+                        (coord(pts, "StartX")?, coord(pts, "StartY")?),
+                        (coord(pts, "StopX")?, coord(pts, "StopY")?),
+                    ],
+                    direction: direction(line),
+                })
+            }
+            _ => {
+                let region = value.get("RuleRegion")?;
+                let count = region.get("PtsNum").and_then(value_to_u64).unwrap_or(0) as usize;
+                let points = region
+                    .get("Pts")?
+                    .as_array()?
+                    .iter()
+                    .take(count)
+                    .map(|p| Some((coord(p, "X")?, coord(p, "Y")?)))
+                    .collect::<Option<Vec<_>>>()?;
+                Some(Self {
+                    enabled,
+                    kind: IvsRuleType::Region,
+                    points,
+                    direction: direction(region),
+                })
+            }
+        }
+    }
+
+    /// Write the rule over `value`, keeping the fields this struct doesn't know about
+    fn apply(&self, value: &mut Value) -> Result<()> {
+        let expected = match self.kind {
+            IvsRuleType::Line => 2..=2,
+            IvsRuleType::Region => 3..=MAX_REGION_POINTS,
+        };
+        if !expected.contains(&self.points.len()) {
+            return Err(DVRIPError::InvalidParameter(format!(
+                "A {:?} rule needs {:?} points, got {}",
+                self.kind,
+                expected,
+                self.points.len()
+            )));
+        }
+        let points = self
+            .points
+            .iter()
+            .map(|&(x, y)| {
+                if !(0.0..=1.0).contains(&x) || !(0.0..=1.0).contains(&y) {
+                    return Err(DVRIPError::InvalidParameter(format!(
+                        "Point ({}, {}) is outside the picture",
+                        x, y
+                    )));
+                }
+                Ok((
+                    (x * GRID_RANGE).round() as u32,
+                    (y * GRID_RANGE).round() as u32,
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        value["Enable"] = json!(self.enabled);
+        match self.kind {
+            IvsRuleType::Line => {
+                value["RuleType"] = json!(0);
+                value["RuleLine"]["AlarmDirect"] = json!(self.direction.to_device());
+                value["RuleLine"]["Pts"] = json!({
+                    "StartX": points[0].0,
+                    "StartY": points[0].1,
+                    "StopX": points[1].0,
+                    "StopY": points[1].1,
+                });
+            }
+            IvsRuleType::Region => {
+                value["RuleType"] = json!(1);
+                value["RuleRegion"]["AlarmDirect"] = json!(self.direction.to_device());
+                value["RuleRegion"]["PtsNum"] = json!(points.len());
+                value["RuleRegion"]["Pts"] = json!(
+                    points
+                        .iter()
+                        .map(|(x, y)| json!({"X": x, "Y": y}))
+                        .collect::<Vec<_>>()
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+pub trait Ivs: Send + Sync {
+    /// Get the line-crossing and intrusion rules of a channel
+    ///
+    /// Returns `DVRIPError::Unsupported` on devices without human detection
+    async fn get_ivs_rules(&self, channel: u8) -> Result<Vec<IvsRule>>;
+
+    /// Replace the rules of a channel, the rule slots left over are disabled
+    ///
+    /// There can't be more rules than the slots the device has
+    async fn set_ivs_rules(&self, channel: u8, rules: Vec<IvsRule>) -> Result<bool>;
+}
+
+#[async_trait]
+impl Ivs for DVRIPCam {
+    async fn get_ivs_rules(&self, channel: u8) -> Result<Vec<IvsRule>> {
+        let detection = self.get_human_detection().await?;
+
+        Ok(detection
+            .get(channel as usize)
+            .and_then(|d| d.get("PedRule"))
+            .and_then(|r| r.as_array())
+            .map(|rules| rules.iter().filter_map(IvsRule::from_device).collect())
+            .unwrap_or_default())
+    }
+
+    async fn set_ivs_rules(&self, channel: u8, rules: Vec<IvsRule>) -> Result<bool> {
+        let mut detection = self.get_human_detection().await?;
+
+        let Some(slots) = detection
+            .get_mut(channel as usize)
+            .and_then(|d| d.get_mut("PedRule"))
+            .and_then(|r| r.as_array_mut())
+        else {
+            return Err(DVRIPError::InvalidParameter(format!(
+                "Channel {} has no human detection rules",
+                channel
+            )));
+        };
+        if rules.len() > slots.len() {
+            return Err(DVRIPError::InvalidParameter(format!(
+                "Got {} rules but channel {} supports {}",
+                rules.len(),
+                channel,
+                slots.len()
+            )));
+        }
+
+        for (i, slot) in slots.iter_mut().enumerate() {
+            match rules.get(i) {
+                Some(rule) => rule.apply(slot)?,
+                None => slot["Enable"] = json!(false),
+            }
+        }
+
+        let reply = self
+            .set_command("Detect.HumanDetection", detection, Some(1040))
+            .await?;
+        if let Some(ret) = reply.get("Ret").and_then(|r| r.as_u64()) {
+            return Ok(OK_CODES.contains(&(ret as u32)));
+        }
+        Ok(false)
+    }
+}
+
+impl DVRIPCam {
+    async fn get_human_detection(&self) -> Result<Value> {
+        let detection = self
+            .get_command("Detect.HumanDetection", Some(1042))
+            .await?;
+
+        // Devices without analytics answer with an error instead of the section
+        if !detection.is_array() {
+            return Err(DVRIPError::Unsupported("Intelligent analytics".to_string()));
+        }
+        Ok(detection)
+    }
+}
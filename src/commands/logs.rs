@@ -0,0 +1,180 @@
+use crate::constants::DATE_FORMAT;
+use crate::dvrip::DVRIPCam;
+use crate::encoding::parse_device_time;
+use crate::error::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Local};
+use serde_json::{Value, json};
+use strum_macros::AsRefStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, AsRefStr)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum LogType {
+    #[default]
+    #[strum(serialize = "LogAll")]
+    All,
+    #[strum(serialize = "LogSystem")]
+    System,
+    #[strum(serialize = "LogConfig")]
+    Config,
+    #[strum(serialize = "LogAlarm")]
+    Alarm,
+    #[strum(serialize = "LogAccount")]
+    Account,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LogEntry {
+    pub time: Option<DateTime<Local>>,
+    /// Event logged, e.g. "LogIn", "SaveConfig", "AlarmStart"
+    pub log_type: String,
+    pub user: String,
+    pub detail: String,
+}
+
+impl LogEntry {
+    fn from_value(value: &Value) -> Self {
+        let text = |key: &str| {
+            value
+                .get(key)
+                .and_then(|v| v.as_str())
+                .unwrap_or_default()
+                .to_string()
+        };
+
+        Self {
+            time: value
+                .get("Time")
+                .and_then(|t| t.as_str())
+                .and_then(parse_device_time),
+            log_type: text("Type"),
+            user: text("User"),
+            detail: text("Data"),
+        }
+    }
+}
+
+#[async_trait]
+pub trait Logs: Send + Sync {
+    /// Get the log entries of the device between `start_time` and `end_time`
+    async fn query_logs(
+        &self,
+        start_time: DateTime<Local>,
+        end_time: DateTime<Local>,
+        log_type: LogType,
+    ) -> Result<Vec<LogEntry>>;
+}
+
+#[async_trait]
+impl Logs for DVRIPCam {
+    async fn query_logs(
+        &self,
+        start_time: DateTime<Local>,
+        end_time: DateTime<Local>,
+        log_type: LogType,
+    ) -> Result<Vec<LogEntry>> {
+        let start_str = start_time.format(DATE_FORMAT).to_string();
+        let end_str = end_time.format(DATE_FORMAT).to_string();
+        let code = self.code("OPLogQuery").unwrap_or(1442);
+
+        let mut result = Vec::new();
+        let mut position = 0u64;
+
+        // The device returns a page of entries at a time, keep going from the
+        // position of the last one until a page comes back empty
+        loop {
+            let params = json!({
+                "BeginTime": start_str,
+                "EndTime": end_str,
+                "LogPosition": position,
+                "Type": log_type.as_ref(),
+            });
+
+            // The entries, or the whole reply when the device refused the query
+            let reply = self
+                .query_command("OPLogQuery", Some(params), Some(code as u32))
+                .await?;
+
+            let Some(entries) = reply.as_array() else {
+                break;
+            };
+            if entries.is_empty() {
+                break;
+            }
+            result.extend(entries.iter().map(LogEntry::from_value));
+
+            let Some(last) = entries
+                .last()
+                .and_then(|e| e.get("Position"))
+                .and_then(|p| p.as_u64())
+            else {
+                break;
+            };
+            // Guard against firmware that ignores LogPosition
+            if last < position {
+                break;
+            }
+            position = last + 1;
+        }
+
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::DVRIPError;
+    use crate::test_device;
+
+    fn range() -> (DateTime<Local>, DateTime<Local>) {
+        let start = parse_device_time("2024-01-01 00:00:00").unwrap();
+        let end = parse_device_time("2024-01-02 00:00:00").unwrap();
+        (start, end)
+    }
+
+    #[tokio::test]
+    async fn logs_are_queried_page_by_page() {
+        let (cam, mut device) = test_device::login(DVRIPCam::new("127.0.0.1")).await;
+        let (start, end) = range();
+
+        let device_side = async {
+            let entry = json!({"Time": "2024-01-01 10:00:00", "Type": "LogIn", "User": "admin", "Data": "", "Position": 4});
+            let (_, first) = device
+                .answer(json!({"Name": "OPLogQuery", "Ret": 100, "OPLogQuery": [entry]}))
+                .await;
+            let (_, second) = device
+                .answer(json!({"Name": "OPLogQuery", "Ret": 100, "OPLogQuery": []}))
+                .await;
+            (first, second)
+        };
+        let (logs, (first, second)) =
+            tokio::join!(cam.query_logs(start, end, LogType::All), device_side);
+
+        let logs = logs.unwrap();
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0].log_type, "LogIn");
+        assert_eq!(
+            first["SessionID"],
+            format!("0x{:08X}", test_device::SESSION)
+        );
+        assert_eq!(first["OPLogQuery"]["LogPosition"], 0);
+        assert_eq!(second["OPLogQuery"]["LogPosition"], 5);
+    }
+
+    #[tokio::test]
+    async fn denied_log_query_is_an_error() {
+        let (cam, mut device) = test_device::login(DVRIPCam::new("127.0.0.1")).await;
+        let (start, end) = range();
+
+        let (logs, _) = tokio::join!(
+            cam.query_logs(start, end, LogType::All),
+            device.answer(json!({"Name": "OPLogQuery", "Ret": 103}))
+        );
+        assert!(matches!(
+            logs,
+            Err(DVRIPError::PermissionDenied { code: 103, .. })
+        ));
+    }
+}
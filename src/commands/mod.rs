@@ -1,21 +1,55 @@
 pub mod alarm;
 pub mod authentication;
 pub mod backchannel;
+pub mod camera_settings;
+pub mod config_transfer;
 pub mod connection;
+pub mod encode;
 pub mod file_management;
+pub mod ivs;
+pub mod logs;
 pub mod monitoring;
+pub mod network;
 pub mod ptz;
+pub mod record;
+pub mod resilient;
 pub mod system_info;
 pub mod upgrade;
 pub mod user_management;
 
-pub use alarm::{Alarm, AlarmCallback};
-pub use authentication::Authentication;
+pub use alarm::{
+    Alarm, AlarmCallback, AlarmEvent, AlarmEventCallback, AlarmFilter, AlarmInput,
+    AlarmInputConfig, AlarmType, SensorType,
+};
+pub use authentication::{Authentication, LoginInfo};
 pub use backchannel::{AudioCodec, Backchannel};
-pub use connection::Connection;
-pub use file_management::FileManagement;
-pub use monitoring::{FrameCallback, FrameMetadata, Monitoring};
-pub use ptz::{PTZ, PTZCommand};
-pub use system_info::SystemInfo;
-pub use upgrade::{Upgrade, UpgradeProgressCallback};
+pub use camera_settings::{CameraSettings, DayNightMode, ImageParams, PrivacyMask};
+pub use config_transfer::ConfigTransfer;
+pub use connection::{Connection, ConnectionEvent, KeepAliveInfo};
+pub use encode::{
+    ChannelCaps, EncodeCapability, EncodeConfig, EncodeSettings, EncodeStream, MainAndSubConfig,
+    ResolutionCaps,
+};
+pub use file_management::{
+    EventFilter, FileManagement, FileQueryOptions, FileType, PlaybackAction, RecordEvent,
+};
+pub use ivs::{Ivs, IvsDirection, IvsRule, IvsRuleType};
+pub use logs::{LogEntry, LogType, Logs};
+pub use monitoring::{
+    FRAME_BLOCK_TIMEOUT, FrameCallback, FrameMetadata, FrameOverflow, MonitorHandle,
+    MonitorOptions, Monitoring, to_annexb,
+};
+pub use network::{CloudStatus, Network, WifiConfig, WifiNetwork};
+pub use ptz::{Key, PTZ, PTZCommand, Preset, Rect};
+pub use record::{
+    RecordBuffer, RecordBufferLimits, RecordConfig, RecordControl, RecordMode, TimeSegment,
+};
+pub use resilient::{MonitorItem, ResilientMonitor};
+pub use system_info::{
+    AutoRebootConfig, BandwidthStats, ChannelRecordStatus, ChannelStatus, DeviceIdentity,
+    MachineStatus, RebootDay, SystemInfo, VideoStandard,
+};
+pub use upgrade::{Upgrade, UpgradeOptions, UpgradeProgress, UpgradeProgressCallback};
+#[cfg(feature = "experimental")]
+pub use user_management::OnlineUser;
 pub use user_management::UserManagement;
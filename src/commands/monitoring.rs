@@ -1,20 +1,500 @@
-use crate::constants::{OK_CODES, QCODES};
-use crate::dvrip::DVRIPCam;
-use crate::error::Result;
+use crate::constants::OK_CODES;
+use crate::dvrip::{CommandRequest, DVRIPCam, StreamHandler, Transport};
+use crate::encoding::packed_time_to_datetime;
+use crate::error::{DVRIPError, Result};
+use crate::protocol::{PacketHeader, pack_packet, parse_json};
 use async_trait::async_trait;
 use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use bytes::Bytes;
+use dashmap::DashMap;
 use serde_json::json;
-use std::sync::atomic::Ordering;
-use tokio::sync::broadcast;
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU8, AtomicU32, Ordering};
+use tokio::sync::{Notify, broadcast, mpsc};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
 pub struct FrameMetadata {
     pub width: Option<u32>,
     pub height: Option<u32>,
     pub fps: Option<u8>,
     pub frame_type: Option<String>,
     pub media_type: Option<String>,
+    /// Device time of the frame, with second precision (the frame header has no
+    /// room for more), use `pts` to order frames within a second.
+    /// P-frames have no timestamp of their own and carry the one of the last I-frame
+    /// Follows the device clock, so it can go backward unless
+    /// `MonitorOptions::monotonic_timestamps` is set
     pub datetime: Option<chrono::DateTime<chrono::Local>>,
+    /// Presentation time relative to the start of the monitor, computed from the
+    /// fps for video and from the sample count for audio so both can be synced
+    pub pts: Option<std::time::Duration>,
+    /// Samples per second of audio frames
+    pub sample_rate: Option<u32>,
+    /// `datetime` went backward compared to the previous frames of the monitor, e.g.
+    /// after the device clock was changed. Also set on the frames that inherit it
+    pub timestamp_suspect: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct MonitorOptions {
+    /// Deliver the audio frames (`media_type` "g711a") interleaved with the video,
+    /// only to the handle that asked for them
+    pub include_audio: bool,
+    /// Keep `FrameMetadata::datetime` from going backward: once the device time
+    /// jumps back, the following timestamps are shifted by the jump so they carry on
+    /// from the last one. Suspect frames are flagged either way
+    pub monotonic_timestamps: bool,
+}
+
+impl MonitorOptions {
+    pub fn with_audio(mut self, include_audio: bool) -> Self {
+        self.include_audio = include_audio;
+        self
+    }
+
+    pub fn with_monotonic_timestamps(mut self, monotonic: bool) -> Self {
+        self.monotonic_timestamps = monotonic;
+        self
+    }
+}
+
+/// What happens to the frames of a monitor whose consumer falls behind, set with
+/// `DVRIPCam::with_frame_buffer`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum FrameOverflow {
+    /// Overwrite the oldest buffered frames, the consumer's next `recv()` returns
+    /// `RecvError::Lagged` with the number it missed. Keeps the latency low
+    #[default]
+    DropOldest,
+    /// Wait until the consumer made room. No frame is lost, but the recv loop waits
+    /// too so every command and stream of the connection stalls meanwhile.
+    /// A consumer that doesn't read for `FRAME_BLOCK_TIMEOUT` loses the oldest frame
+    /// as with `DropOldest`, so a forgotten handle can't stall the connection for good
+    Block,
+}
+
+/// Longest the recv loop waits for a consumer with `FrameOverflow::Block`
+pub const FRAME_BLOCK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Timing state of a monitor session, lives in the recv loop
+#[derive(Debug, Default)]
+pub(crate) struct MonitorClock {
+    last_datetime: Option<chrono::DateTime<chrono::Local>>,
+    last_suspect: bool,
+    // Added to the device time once it went backward, only when keeping it monotonic
+    pub(crate) monotonic: bool,
+    offset: chrono::TimeDelta,
+    fps: Option<u8>,
+    video_frames: u64,
+    audio_samples: u64,
+}
+
+impl MonitorClock {
+    // G.711 is one byte per sample at 8kHz
+    const AUDIO_SAMPLE_RATE: u64 = 8000;
+
+    pub(crate) fn stamp(&mut self, metadata: &mut FrameMetadata, payload_len: usize) {
+        match metadata.datetime {
+            Some(mut datetime) => {
+                datetime += self.offset;
+                let last = self.last_datetime.filter(|last| datetime < *last);
+                self.last_suspect = last.is_some();
+                if let Some(last) = last
+                    && self.monotonic
+                {
+                    self.offset += last - datetime;
+                    datetime = last;
+                }
+                metadata.datetime = Some(datetime);
+                self.last_datetime = Some(datetime);
+            }
+            None => metadata.datetime = self.last_datetime,
+        }
+        metadata.timestamp_suspect = self.last_suspect;
+
+        if metadata.frame_type.is_some() {
+            if metadata.fps.is_some() {
+                self.fps = metadata.fps;
+            }
+            if let Some(fps) = self.fps.filter(|f| *f > 0) {
+                metadata.pts = Some(std::time::Duration::from_secs_f64(
+                    self.video_frames as f64 / fps as f64,
+                ));
+            }
+            self.video_frames += 1;
+        } else if is_audio(metadata) {
+            metadata.pts = Some(std::time::Duration::from_secs_f64(
+                self.audio_samples as f64 / Self::AUDIO_SAMPLE_RATE as f64,
+            ));
+            self.audio_samples += payload_len as u64;
+        }
+    }
+}
+
+/// Audio frames are the G.711 A-law ones, the only audio devices send
+pub(crate) fn is_audio(metadata: &FrameMetadata) -> bool {
+    metadata.media_type.as_deref() == Some("g711a")
+}
+
+/// Stream claimed for audio-only monitoring, the audio is the same on every stream
+const AUDIO_MONITOR_STREAM: &str = "Extra1";
+
+/// Key of the audio-only monitors in `Monitors`, next to the stream types
+const AUDIO_MONITOR_KEY: &str = "Audio";
+
+/// Streams being monitored, keyed by channel and stream type ("Main", "Extra1")
+pub(crate) type Monitors = DashMap<(u8, String), MonitorEntry>;
+
+pub(crate) struct MonitorEntry {
+    pub(crate) sender: broadcast::Sender<(FrameMetadata, Bytes)>,
+    // Signalled by the handles whenever they took a frame, see `send_frame`
+    pub(crate) space: Arc<Notify>,
+    pub(crate) clock: MonitorClock,
+    pub(crate) include_video: bool,
+    // Handles given out for this entry, it's removed when the last one goes
+    handles: usize,
+    // Handles of them that want the audio, it's sent while there is one
+    audio_handles: usize,
+    // Streams claimed on the device for this entry, stopped along with it
+    claims: Vec<String>,
+}
+
+impl MonitorEntry {
+    pub(crate) fn include_audio(&self) -> bool {
+        self.audio_handles > 0
+    }
+}
+
+/// Frames of one monitored stream, dropping it stops the delivery of that stream
+/// while the other monitors keep running
+///
+/// Once the last handle of a stream is gone the device is told to stop sending it,
+/// from a spawned task when dropped or awaiting the reply with `stop`.
+/// Derefs to the underlying `broadcast::Receiver`, frames are read with `recv()`
+/// which skips the audio frames unless the handle asked for them
+pub struct MonitorHandle<T = Bytes> {
+    receiver: broadcast::Receiver<(FrameMetadata, T)>,
+    space: Arc<Notify>,
+    stop: Option<MonitorStop>,
+    // Other handles of the stream may get the audio from the same channel
+    audio: bool,
+}
+
+impl<T: Clone> MonitorHandle<T> {
+    /// Wait for the next frame of the stream
+    pub async fn recv(
+        &mut self,
+    ) -> std::result::Result<(FrameMetadata, T), broadcast::error::RecvError> {
+        loop {
+            let frame = self.receiver.recv().await;
+            self.space.notify_one();
+            let frame = frame?;
+            if self.audio || !is_audio(&frame.0) {
+                return Ok(frame);
+            }
+        }
+    }
+
+    /// Take the next frame if one is buffered
+    pub fn try_recv(
+        &mut self,
+    ) -> std::result::Result<(FrameMetadata, T), broadcast::error::TryRecvError> {
+        loop {
+            let frame = self.receiver.try_recv();
+            self.space.notify_one();
+            let frame = frame?;
+            if self.audio || !is_audio(&frame.0) {
+                return Ok(frame);
+            }
+        }
+    }
+}
+
+impl<T> MonitorHandle<T> {
+    /// Stop receiving the stream, waiting for the device to confirm it stopped
+    /// sending when this was the last handle of the stream
+    pub async fn stop(mut self) -> Result<()> {
+        let Some(mut stop) = self.stop.take() else {
+            return Ok(());
+        };
+        let claims = stop.release();
+        let (Some(release), Some(channel)) = (stop.release.take(), stop.channel) else {
+            return Ok(());
+        };
+        for stream in claims {
+            release.send_stop(&stream, channel, true).await?;
+        }
+        Ok(())
+    }
+}
+
+impl<T> Deref for MonitorHandle<T> {
+    type Target = broadcast::Receiver<(FrameMetadata, T)>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.receiver
+    }
+}
+
+impl<T> DerefMut for MonitorHandle<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.receiver
+    }
+}
+
+struct MonitorStop {
+    // Taken once the handle is released
+    key: Option<(u8, String)>,
+    audio: bool,
+    channel: Option<u8>,
+    monitors: Arc<Monitors>,
+    release: Option<MonitorRelease>,
+}
+
+impl MonitorStop {
+    /// Give up this handle, returning the streams to stop on the device
+    fn release(&mut self) -> Vec<String> {
+        let Some(key) = self.key.take() else {
+            return vec![];
+        };
+
+        // The same stream can be handed out more than once, keep it for the others
+        let Some((_, entry)) = self.monitors.remove_if_mut(&key, |_, entry| {
+            entry.handles = entry.handles.saturating_sub(1);
+            if self.audio {
+                entry.audio_handles = entry.audio_handles.saturating_sub(1);
+            }
+            entry.handles == 0
+        }) else {
+            return vec![];
+        };
+
+        // Another monitor of the channel may still get its frames from these claims
+        if let Some(mut other) = self
+            .monitors
+            .iter_mut()
+            .find(|other| other.key().0 == key.0)
+        {
+            for stream in entry.claims {
+                if !other.claims.contains(&stream) {
+                    other.claims.push(stream);
+                }
+            }
+            return vec![];
+        }
+        entry.claims
+    }
+}
+
+impl Drop for MonitorStop {
+    fn drop(&mut self) {
+        let claims = self.release();
+        let (Some(release), Some(channel)) = (self.release.take(), self.channel) else {
+            return;
+        };
+        if claims.is_empty() {
+            return;
+        }
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            handle.spawn(async move {
+                for stream in claims {
+                    let _ = release.send_stop(&stream, channel, false).await;
+                }
+            });
+        }
+    }
+}
+
+/// What's needed to send the monitor stop once the `DVRIPCam` borrow is gone
+pub(crate) struct MonitorRelease {
+    transport: Transport,
+    session: Arc<AtomicU32>,
+    protocol_version: Arc<AtomicU8>,
+    code: u16,
+    timeout: std::time::Duration,
+}
+
+impl MonitorRelease {
+    async fn send_stop(&self, stream: &str, channel: u8, wait: bool) -> Result<()> {
+        let session = self.session.load(Ordering::Acquire);
+        let data = json!({
+            "Name": "OPMonitor",
+            "SessionID": format!("0x{:08X}", session),
+            "OPMonitor": {
+                "Action": "Stop",
+                "Parameter": monitor_params(stream, channel),
+            },
+        });
+        let data =
+            serde_json::to_vec(&data).map_err(|e| DVRIPError::SerializationError(e.to_string()))?;
+        let version = self.protocol_version.load(Ordering::Acquire);
+        let (header, body) = pack_packet(session, 0, self.code, &data, version, true).await?;
+
+        let request = CommandRequest::new(header, body).with_counter(true);
+        let failed = |_| DVRIPError::ConnectionError("Failed to send the monitor stop".to_string());
+        if !wait {
+            return self.transport.send(request).await.map_err(failed);
+        }
+        let recv = self
+            .transport
+            .send_with_reply(request)
+            .await
+            .map_err(failed)?;
+
+        let (_, reply) = tokio::time::timeout(self.timeout, recv)
+            .await
+            .map_err(|_| DVRIPError::ConnectionError("Timeout stopping the monitor".to_string()))?
+            .map_err(|_| DVRIPError::ConnectionError("No reply to the monitor stop".to_string()))?;
+        let reply = parse_json(&reply)?;
+        if let Some(ret) = reply.get("Ret").and_then(|r| r.as_u64())
+            && !OK_CODES.contains(&(ret as u32))
+        {
+            return Err(DVRIPError::ProtocolError(format!(
+                "Failed to stop monitoring ({})",
+                ret
+            )));
+        }
+        Ok(())
+    }
+}
+
+fn monitor_params(stream: &str, channel: u8) -> serde_json::Value {
+    json!({
+        "Channel": channel,
+        "CombinMode": "NONE",
+        "StreamType": stream,
+        "TransMode": "TCP",
+    })
+}
+
+const ANNEXB_START_CODE: [u8; 4] = [0, 0, 0, 1];
+
+/// Make sure an h264/h265 frame is in Annex-B format (start code before every NAL unit)
+///
+/// Frames already in Annex-B are returned as they are, AVCC style frames (4 byte
+/// big endian length before every NAL unit) get their lengths replaced and a raw
+/// NAL unit gets a start code in front. Other media types are returned untouched.
+/// P-frames don't carry a media type, use the one of the last I-frame.
+pub fn to_annexb(frame: &[u8], media_type: &str) -> Vec<u8> {
+    // h265 NAL headers are 2 bytes long
+    let min_nal_len = match media_type {
+        "h264" => 1,
+        "h265" => 2,
+        _ => return frame.to_vec(),
+    };
+
+    if frame.starts_with(&[0, 0, 1]) || frame.starts_with(&ANNEXB_START_CODE) {
+        return frame.to_vec();
+    }
+
+    if let Some(nals) = split_length_prefixed(frame, min_nal_len) {
+        let mut out = Vec::with_capacity(frame.len());
+        for nal in nals {
+            out.extend_from_slice(&ANNEXB_START_CODE);
+            out.extend_from_slice(nal);
+        }
+        return out;
+    }
+
+    let mut out = Vec::with_capacity(frame.len() + ANNEXB_START_CODE.len());
+    out.extend_from_slice(&ANNEXB_START_CODE);
+    out.extend_from_slice(frame);
+    out
+}
+
+/// Split an AVCC style frame, `None` if the lengths don't add up to the frame size
+fn split_length_prefixed(frame: &[u8], min_nal_len: usize) -> Option<Vec<&[u8]>> {
+    let mut nals = Vec::new();
+    let mut rest = frame;
+
+    while !rest.is_empty() {
+        if rest.len() < 4 {
+            return None;
+        }
+        let len = BigEndian::read_u32(&rest[..4]) as usize;
+        let nal = rest.get(4..4 + len)?;
+        // The forbidden zero bit must be clear on a real NAL header
+        if len < min_nal_len || nal[0] & 0x80 != 0 {
+            return None;
+        }
+        nals.push(nal);
+        rest = &rest[4 + len..];
+    }
+
+    (!nals.is_empty()).then_some(nals)
+}
+
+/// Send a frame to a monitor following the overflow policy, `capacity` is the one
+/// the channel was created with and `space` the one its handles signal
+pub(crate) async fn send_frame<T>(
+    sender: &broadcast::Sender<(FrameMetadata, T)>,
+    frame: (FrameMetadata, T),
+    capacity: usize,
+    overflow: FrameOverflow,
+    space: &Notify,
+) -> bool {
+    if overflow == FrameOverflow::Block {
+        // A broadcast channel never waits on its own, frames stay queued until
+        // every receiver has seen them
+        let deadline = tokio::time::Instant::now() + FRAME_BLOCK_TIMEOUT;
+        while sender.receiver_count() > 0 && sender.len() >= capacity {
+            if tokio::time::timeout_at(deadline, space.notified())
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+    }
+    sender.send(frame).is_ok()
+}
+
+/// Copy every frame into its own `Vec<u8>`, the handle keeps the stream claimed
+fn to_vec_frames(
+    frames: MonitorHandle,
+    capacity: usize,
+    overflow: FrameOverflow,
+) -> MonitorHandle<Vec<u8>> {
+    let MonitorHandle {
+        mut receiver,
+        space: entry_space,
+        stop,
+        audio,
+    } = frames;
+    let (tx, rx) = broadcast::channel(capacity);
+    let space = Arc::new(Notify::new());
+    let copy_space = Arc::clone(&space);
+
+    // Ends once the entry is removed and its sender dropped
+    tokio::spawn(async move {
+        loop {
+            let frame = receiver.recv().await;
+            entry_space.notify_one();
+            match frame {
+                Ok((metadata, frame)) => {
+                    let frame = (metadata, frame.to_vec());
+                    if !send_frame(&tx, frame, capacity, overflow, &copy_space).await {
+                        break;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+
+    MonitorHandle {
+        receiver: rx,
+        space,
+        stop,
+        audio,
+    }
 }
 
 pub type FrameCallback = Box<dyn Fn(Vec<u8>, FrameMetadata) + Send + Sync>;
@@ -22,35 +502,220 @@ pub type FrameCallback = Box<dyn Fn(Vec<u8>, FrameMetadata) + Send + Sync>;
 #[async_trait]
 pub trait Monitoring: Send + Sync {
     /// Start video monitoring
-    async fn start_monitor(
+    ///
+    /// Every frame is copied into its own `Vec<u8>`, prefer `start_monitor_bytes`
+    /// for high frame rates
+    async fn start_monitor(&self, stream: &str, channel: u8) -> Result<MonitorHandle<Vec<u8>>>;
+
+    /// Start video monitoring, frames share the buffer they were received in
+    ///
+    /// Several channels can be monitored at once on the same connection. Media packets
+    /// only identify their channel, to watch the main and extra stream of the same
+    /// channel at the same time use a second `DVRIPCam`. Claiming a second stream of
+    /// a monitored channel fails with `InvalidParameter`
+    async fn start_monitor_bytes(&self, stream: &str, channel: u8) -> Result<MonitorHandle>;
+
+    /// Start monitoring with extra options, e.g. to receive the audio along with the video
+    async fn start_monitor_with_options(
         &self,
         stream: &str,
         channel: u8,
-    ) -> Result<broadcast::Receiver<(FrameMetadata, Vec<u8>)>>;
+        options: MonitorOptions,
+    ) -> Result<MonitorHandle>;
+
+    /// Start monitoring only the audio of a channel (G.711 A-law frames)
+    ///
+    /// Devices have no audio-only stream. The audio comes from the stream already
+    /// monitored on the channel, otherwise the extra stream is claimed as the cheapest
+    /// one and its video is dropped. Start video monitors of the same channel first,
+    /// once the extra stream is claimed here only that stream can be monitored on it
+    async fn start_audio_monitor(&self, channel: u8) -> Result<MonitorHandle<Vec<u8>>>;
 
-    /// Stop video monitoring
+    /// Stop every monitored stream, waiting for the device to stop sending them
     async fn stop_monitor(&self) -> Result<()>;
 
+    /// Find the codec of a stream ("h264", "h265", ...) by monitoring it until
+    /// the first I-frame, waiting at most the command timeout
+    async fn probe_codec(&self, channel: u8, stream: &str) -> Result<String>;
+
     /// Get a snapshot (screenshot)
+    ///
+    /// Snapshots of one camera are taken one after the other, a concurrent call
+    /// waits for the previous picture
     async fn snapshot(&self, channel: u8) -> Result<Vec<u8>>;
 
+    /// Get a snapshot decoded into an image, for resizing or converting it
+    ///
+    /// Fails with a `ProtocolError` when the device sends something that isn't a
+    /// complete JPEG
+    #[cfg(feature = "image")]
+    async fn snapshot_image(&self, channel: u8) -> Result<image::DynamicImage>;
+
     /// Check if monitoring
     fn is_monitoring(&self) -> bool;
 }
 
 #[async_trait]
 impl Monitoring for DVRIPCam {
-    async fn start_monitor(
+    async fn start_monitor(&self, stream: &str, channel: u8) -> Result<MonitorHandle<Vec<u8>>> {
+        let frames = self.start_monitor_bytes(stream, channel).await?;
+        Ok(to_vec_frames(
+            frames,
+            self.frame_capacity,
+            self.frame_overflow,
+        ))
+    }
+
+    async fn start_audio_monitor(&self, channel: u8) -> Result<MonitorHandle<Vec<u8>>> {
+        let claim = if self.monitors.iter().any(|entry| entry.key().0 == channel) {
+            None
+        } else {
+            self.claim_monitor(AUDIO_MONITOR_STREAM, channel).await?;
+            Some(AUDIO_MONITOR_STREAM)
+        };
+        let options = MonitorOptions::default().with_audio(true);
+        let frames = self.subscribe_monitor(
+            (channel, AUDIO_MONITOR_KEY.to_string()),
+            options,
+            false,
+            claim,
+        );
+        Ok(to_vec_frames(
+            frames,
+            self.frame_capacity,
+            self.frame_overflow,
+        ))
+    }
+
+    async fn probe_codec(&self, channel: u8, stream: &str) -> Result<String> {
+        let mut frames = self.start_monitor_bytes(stream, channel).await?;
+
+        let codec = tokio::time::timeout(self.command_timeout, async {
+            loop {
+                match frames.recv().await {
+                    Ok((metadata, _)) if metadata.frame_type.as_deref() == Some("I") => {
+                        if let Some(media_type) = metadata.media_type {
+                            return Ok(media_type);
+                        }
+                    }
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => {
+                        return Err(crate::error::DVRIPError::ConnectionError(
+                            "Frame channel closed".to_string(),
+                        ));
+                    }
+                }
+            }
+        })
+        .await;
+
+        // Only this stream, other monitors keep running
+        drop(frames);
+
+        codec.map_err(|_| {
+            crate::error::DVRIPError::ConnectionError("Timeout waiting for an I-frame".to_string())
+        })?
+    }
+
+    async fn start_monitor_bytes(&self, stream: &str, channel: u8) -> Result<MonitorHandle> {
+        self.start_monitor_with_options(stream, channel, MonitorOptions::default())
+            .await
+    }
+
+    async fn start_monitor_with_options(
         &self,
         stream: &str,
         channel: u8,
-    ) -> Result<broadcast::Receiver<(FrameMetadata, Vec<u8>)>> {
-        let params = json!({
-            "Channel": channel,
-            "CombinMode": "NONE",
-            "StreamType": stream,
-            "TransMode": "TCP",
-        });
+        options: MonitorOptions,
+    ) -> Result<MonitorHandle> {
+        // Its frames would be mixed with the ones of the stream already sent
+        let claimed = self
+            .monitors
+            .iter()
+            .filter(|entry| entry.key().0 == channel)
+            .find_map(|entry| entry.claims.iter().find(|c| *c != stream).cloned());
+        if let Some(claimed) = claimed {
+            return Err(DVRIPError::InvalidParameter(format!(
+                "Channel {} already sends its {} stream on this connection, use another DVRIPCam for {}",
+                channel, claimed, stream
+            )));
+        }
+
+        self.claim_monitor(stream, channel).await?;
+        Ok(self.subscribe_monitor((channel, stream.to_string()), options, true, Some(stream)))
+    }
+
+    async fn stop_monitor(&self) -> Result<()> {
+        let claims: Vec<(u8, String)> = self
+            .monitors
+            .iter()
+            .flat_map(|entry| {
+                let channel = entry.key().0;
+                entry
+                    .claims
+                    .iter()
+                    .map(|stream| (channel, stream.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        self.monitors.clear();
+
+        let Some(release) = self.monitor_release() else {
+            return Ok(());
+        };
+        for (channel, stream) in claims {
+            release.send_stop(&stream, channel, true).await?;
+        }
+        Ok(())
+    }
+
+    async fn snapshot(&self, channel: u8) -> Result<Vec<u8>> {
+        let code = self.code("OPSNAP").unwrap_or(1560);
+
+        // Big pictures continue in more packets on the reply id, which reach the
+        // stream handlers once the first one took the response slot. Nothing tells
+        // the pictures apart there, so one snapshot at a time
+        let _snapshot = self.snapshot_lock.lock().await;
+        let (tx, mut rx) = mpsc::channel(self.stream_capacity);
+        self.stream_handlers
+            .insert(code + 1, StreamHandler::Queue(tx));
+        let image = self.read_snapshot(code, channel, &mut rx).await;
+        self.stream_handlers.remove(&(code + 1));
+
+        image
+    }
+
+    #[cfg(feature = "image")]
+    async fn snapshot_image(&self, channel: u8) -> Result<image::DynamicImage> {
+        let jpeg = self.snapshot(channel).await?;
+        // Checked first, the decoder would also take a truncated picture
+        if !jpeg.starts_with(&[0xFF, 0xD8]) || !has_jpeg_end(&jpeg) {
+            return Err(DVRIPError::ProtocolError(
+                "Snapshot isn't a complete JPEG".to_string(),
+            ));
+        }
+        image::load_from_memory_with_format(&jpeg, image::ImageFormat::Jpeg)
+            .map_err(|e| DVRIPError::ProtocolError(format!("Invalid snapshot: {}", e)))
+    }
+
+    fn is_monitoring(&self) -> bool {
+        !self.monitors.is_empty()
+    }
+}
+
+/// A JPEG ends with the EOI marker, ignoring the packet tail
+fn has_jpeg_end(image: &[u8]) -> bool {
+    let end = image
+        .iter()
+        .rposition(|b| !matches!(b, 0x00 | b'\n'))
+        .map_or(0, |i| i + 1);
+    image[..end].ends_with(&[0xFF, 0xD9])
+}
+
+impl DVRIPCam {
+    /// Ask the device to send a stream over this connection
+    async fn claim_monitor(&self, stream: &str, channel: u8) -> Result<()> {
+        let params = monitor_params(stream, channel);
 
         let data = json!({
             "Action": "Claim",
@@ -77,17 +742,75 @@ impl Monitoring for DVRIPCam {
         });
 
         self.send_command(1410, start_data, false).await?;
-        self.monitoring.store(true, Ordering::Release);
 
-        Ok(self.frame_sender.subscribe())
+        Ok(())
     }
 
-    async fn stop_monitor(&self) -> Result<()> {
-        self.monitoring.store(false, Ordering::Release);
-        Ok(())
+    /// Register a consumer of the frames dispatched to `key`, `claim` is the stream
+    /// claimed on the device for it
+    fn subscribe_monitor(
+        &self,
+        key: (u8, String),
+        options: MonitorOptions,
+        include_video: bool,
+        claim: Option<&str>,
+    ) -> MonitorHandle {
+        let mut entry = self
+            .monitors
+            .entry(key.clone())
+            .or_insert_with(|| MonitorEntry {
+                sender: broadcast::channel(self.frame_capacity).0,
+                space: Arc::new(Notify::new()),
+                clock: MonitorClock::default(),
+                include_video,
+                handles: 0,
+                audio_handles: 0,
+                claims: vec![],
+            });
+        entry.clock.monotonic |= options.monotonic_timestamps;
+        entry.handles += 1;
+        if options.include_audio {
+            entry.audio_handles += 1;
+        }
+        if let Some(stream) = claim
+            && !entry.claims.iter().any(|c| c == stream)
+        {
+            entry.claims.push(stream.to_string());
+        }
+        let receiver = entry.sender.subscribe();
+        let space = Arc::clone(&entry.space);
+        drop(entry);
+
+        MonitorHandle {
+            receiver,
+            space,
+            audio: options.include_audio,
+            stop: Some(MonitorStop {
+                channel: Some(key.0),
+                key: Some(key),
+                audio: options.include_audio,
+                monitors: Arc::clone(&self.monitors),
+                release: self.monitor_release(),
+            }),
+        }
     }
 
-    async fn snapshot(&self, channel: u8) -> Result<Vec<u8>> {
+    fn monitor_release(&self) -> Option<MonitorRelease> {
+        Some(MonitorRelease {
+            transport: self.transport().ok()?,
+            session: Arc::clone(&self.session),
+            protocol_version: Arc::clone(&self.protocol_version),
+            code: self.code("OPMonitor").unwrap_or(1413),
+            timeout: self.command_timeout,
+        })
+    }
+
+    async fn read_snapshot(
+        &self,
+        code: u16,
+        channel: u8,
+        continuation: &mut mpsc::Receiver<(PacketHeader, Vec<u8>)>,
+    ) -> Result<Vec<u8>> {
         let session = self.session_id();
         let data = json!({
             "Name": "OPSNAP",
@@ -97,29 +820,51 @@ impl Monitoring for DVRIPCam {
             },
         });
 
-        let data = self
-            .send_command_recv_bin(QCODES.get("OPSNAP").copied().unwrap_or(1560), data, true)
-            .await?;
+        let Some(first) = self.send_command_recv_bin(code, data, true).await? else {
+            return Err(DVRIPError::ConnectionError(
+                "Stream not available".to_string(),
+            ));
+        };
 
-        if let Some(s) = data {
-            let (frame, _) = DVRIPCam::read_bin_payload_static(s).await?;
-            return Ok(frame);
-        }
+        // A media header (0x1FE) gives the size of the whole picture
+        let expected = (first.len() >= 16 && BigEndian::read_u32(&first[0..4]) == 0x1FE)
+            .then(|| LittleEndian::read_u32(&first[12..16]) as usize);
+        let (mut image, _) = DVRIPCam::read_bin_payload_static(first).await?;
 
-        Err(crate::error::DVRIPError::ConnectionError(
-            "Stream not available".to_string(),
-        ))
-    }
+        loop {
+            let complete = match expected {
+                Some(expected) => image.len() >= expected,
+                None => has_jpeg_end(&image),
+            };
+            if complete {
+                break;
+            }
 
-    fn is_monitoring(&self) -> bool {
-        self.monitoring.load(Ordering::Acquire)
+            let (header, data) = tokio::time::timeout(self.command_timeout, continuation.recv())
+                .await
+                .map_err(|_| DVRIPError::ProtocolError("Incomplete snapshot".to_string()))?
+                .ok_or_else(|| DVRIPError::ConnectionError("Not connected".to_string()))?;
+            if header.data_len == 0 {
+                break;
+            }
+            image.extend_from_slice(&data);
+        }
+
+        if let Some(expected) = expected {
+            image.truncate(expected);
+        }
+        Ok(image)
     }
-}
 
-impl DVRIPCam {
     pub(crate) async fn read_bin_payload_static(
         packet: Vec<u8>,
     ) -> Result<(Vec<u8>, FrameMetadata)> {
+        let (frame, metadata) = Self::read_bin_payload(Bytes::from(packet))?;
+        Ok((frame.to_vec(), metadata))
+    }
+
+    /// Parse the media header, the returned frame is a slice of `packet` (no copy)
+    pub(crate) fn read_bin_payload(packet: Bytes) -> Result<(Bytes, FrameMetadata)> {
         let mut metadata = FrameMetadata {
             width: None,
             height: None,
@@ -127,11 +872,19 @@ impl DVRIPCam {
             frame_type: None,
             media_type: None,
             datetime: None,
+            pts: None,
+            sample_rate: None,
+            timestamp_suspect: false,
         };
-        let mut buf: Vec<u8> = vec![];
         let mut length = 0u32;
         let frame_len;
 
+        if packet.len() < 4 {
+            return Err(DVRIPError::ProtocolError(format!(
+                "Media payload of {} bytes has no header",
+                packet.len()
+            )));
+        }
         let data_type = BigEndian::read_u32(&packet[0..4]);
         if data_type == 0x1FC || data_type == 0x1FE {
             frame_len = 16;
@@ -145,7 +898,8 @@ impl DVRIPCam {
 
                 metadata.width = Some(w * 8);
                 metadata.height = Some(h * 8);
-                metadata.datetime = Some(Self::internal_to_datetime_static(dt));
+                metadata.datetime =
+                    Some(packed_time_to_datetime(dt).unwrap_or_else(chrono::Local::now));
 
                 if data_type == 0x1FC {
                     metadata.frame_type = Some("I".to_string());
@@ -163,9 +917,12 @@ impl DVRIPCam {
             frame_len = 8;
             if packet.len() >= frame_len {
                 let media = packet[4];
-                let _samp_rate = LittleEndian::read_u16(&packet[5..7]);
                 length = LittleEndian::read_u16(&packet[6..8]) as u32;
                 metadata.media_type = Self::internal_to_type_static(data_type, media);
+                // G.711 is always sampled at 8kHz, whatever the rate byte says
+                if is_audio(&metadata) {
+                    metadata.sample_rate = Some(MonitorClock::AUDIO_SAMPLE_RATE as u32);
+                }
             }
         } else if data_type == 0x1F9 {
             frame_len = 8;
@@ -183,12 +940,9 @@ impl DVRIPCam {
                 data_type
             )));
         }
-        if frame_len < packet.len() {
-            buf.extend_from_slice(&packet[frame_len..]);
-        }
-
-        buf.truncate(length as usize);
-        Ok((buf, metadata))
+        let start = frame_len.min(packet.len());
+        let end = (start + length as usize).min(packet.len());
+        Ok((packet.slice(start..end), metadata))
     }
 
     fn internal_to_type_static(data_type: u32, value: u8) -> Option<String> {
@@ -223,20 +977,251 @@ impl DVRIPCam {
             _ => None,
         }
     }
+}
 
-    fn internal_to_datetime_static(value: u32) -> chrono::DateTime<chrono::Local> {
-        let second = value & 0x3F;
-        let minute = (value & 0xFC0) >> 6;
-        let hour = (value & 0x1F000) >> 12;
-        let day = (value & 0x3E0000) >> 17;
-        let month = (value & 0x3C00000) >> 22;
-        let year = ((value & 0xFC000000) >> 26) + 2000;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_device::{self, FakeDevice};
+    use serde_json::json;
+    use std::time::Duration;
 
-        chrono::NaiveDate::from_ymd_opt(year as i32, month, day)
-            .and_then(|d| d.and_hms_opt(hour, minute, second))
-            .map(|dt| {
-                chrono::DateTime::from_naive_utc_and_offset(dt, *chrono::Local::now().offset())
-            })
-            .unwrap_or_else(chrono::Local::now)
+    /// Payload of a P-frame
+    fn p_frame(data: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0, 0, 1, 0xFD];
+        frame.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        frame.extend_from_slice(data);
+        frame
+    }
+
+    /// Payload of a G.711 A-law frame
+    fn audio_frame(data: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0, 0, 1, 0xFA, 0x0E, 2];
+        frame.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        frame.extend_from_slice(data);
+        frame
+    }
+
+    /// Media type and payload of the next frame
+    async fn next(frames: &mut MonitorHandle) -> (Option<String>, Bytes) {
+        let (metadata, frame) = tokio::time::timeout(Duration::from_secs(1), frames.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        (metadata.media_type, frame)
+    }
+
+    async fn start(
+        cam: &DVRIPCam,
+        device: &mut FakeDevice,
+        stream: &str,
+        channel: u8,
+    ) -> MonitorHandle {
+        start_with(cam, device, stream, channel, MonitorOptions::default()).await
+    }
+
+    async fn start_with(
+        cam: &DVRIPCam,
+        device: &mut FakeDevice,
+        stream: &str,
+        channel: u8,
+        options: MonitorOptions,
+    ) -> MonitorHandle {
+        let monitor = cam.start_monitor_with_options(stream, channel, options);
+        let (frames, _) = tokio::join!(monitor, async {
+            device
+                .answer(json!({"Name": "OPMonitor", "Ret": 100}))
+                .await;
+            device.recv().await;
+        });
+        frames.unwrap()
+    }
+
+    #[tokio::test]
+    async fn frames_of_unmonitored_channels_are_dropped() {
+        let (cam, mut device) = test_device::login(DVRIPCam::new("127.0.0.1")).await;
+        let mut frames = start(&cam, &mut device, "Main", 0).await;
+
+        device.send_on(1, 0, 1412, &p_frame(b"other")).await;
+        device.send_on(0, 0, 1412, &p_frame(b"mine")).await;
+
+        let (_, frame) = tokio::time::timeout(Duration::from_secs(1), frames.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&frame[..], b"mine");
+    }
+
+    #[tokio::test]
+    async fn short_media_packets_are_dropped() {
+        let (cam, mut device) = test_device::login(DVRIPCam::new("127.0.0.1")).await;
+        let mut frames = start(&cam, &mut device, "Main", 0).await;
+
+        device.send_on(0, 0, 1412, b"").await;
+        device.send_on(0, 0, 1412, &[0, 0, 1]).await;
+
+        // The reader is still there for the replies and the next frames
+        let reply = json!({"Name": "General", "Ret": 100, "General": {}});
+        let (general, _) = tokio::join!(cam.get_command("General", None), device.answer(reply));
+        general.unwrap();
+        device.send_on(0, 0, 1412, &p_frame(b"next")).await;
+        let (_, frame) = tokio::time::timeout(Duration::from_secs(1), frames.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&frame[..], b"next");
+    }
+
+    #[tokio::test]
+    async fn second_stream_of_a_channel_is_refused() {
+        let (cam, mut device) = test_device::login(DVRIPCam::new("127.0.0.1")).await;
+        let _main = start(&cam, &mut device, "Main", 0).await;
+
+        let extra = cam.start_monitor_bytes("Extra1", 0).await;
+        assert!(matches!(extra, Err(DVRIPError::InvalidParameter(_))));
+        // Nothing was sent for it
+        assert!(device.try_recv(Duration::from_millis(100)).await.is_none());
+
+        let _same = start(&cam, &mut device, "Main", 0).await;
+        let _other_channel = start(&cam, &mut device, "Extra1", 1).await;
+    }
+
+    #[tokio::test]
+    async fn audio_goes_only_to_the_handles_asking_for_it() {
+        let (cam, mut device) = test_device::login(DVRIPCam::new("127.0.0.1")).await;
+        let with_audio = MonitorOptions::default().with_audio(true);
+        let mut audio = start_with(&cam, &mut device, "Main", 0, with_audio).await;
+        let mut video = start(&cam, &mut device, "Main", 0).await;
+
+        device.send_on(0, 0, 1412, &audio_frame(b"sound")).await;
+        device.send_on(0, 0, 1412, &p_frame(b"picture")).await;
+
+        assert_eq!(
+            next(&mut audio).await,
+            (Some("g711a".to_string()), Bytes::from_static(b"sound"))
+        );
+        assert_eq!(next(&mut audio).await.1, Bytes::from_static(b"picture"));
+        assert_eq!(next(&mut video).await.1, Bytes::from_static(b"picture"));
+
+        // Once the audio handle is gone the audio isn't sent anymore
+        drop(audio);
+        device.send_on(0, 0, 1412, &audio_frame(b"sound")).await;
+        device.send_on(0, 0, 1412, &p_frame(b"again")).await;
+        assert_eq!(
+            video.receiver.recv().await.unwrap().1,
+            Bytes::from_static(b"again")
+        );
+    }
+
+    #[tokio::test]
+    async fn blocked_frames_wait_for_the_consumer() {
+        let cam = DVRIPCam::new("127.0.0.1").with_frame_buffer(2, FrameOverflow::Block);
+        let (cam, mut device) = test_device::login(cam).await;
+        let mut frames = start(&cam, &mut device, "Main", 0).await;
+
+        for n in 0..4u8 {
+            device.send_on(0, 0, 1412, &p_frame(&[n])).await;
+        }
+        // The recv loop holds the last two until there is room
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(frames.len(), 2);
+
+        for n in 0..4u8 {
+            assert_eq!(next(&mut frames).await.1, Bytes::from(vec![n]));
+        }
+    }
+
+    fn avcc(nals: &[&[u8]]) -> Vec<u8> {
+        nals.iter()
+            .flat_map(|nal| [&(nal.len() as u32).to_be_bytes()[..], nal].concat())
+            .collect()
+    }
+
+    fn annexb(nals: &[&[u8]]) -> Vec<u8> {
+        nals.iter()
+            .flat_map(|nal| [&ANNEXB_START_CODE[..], nal].concat())
+            .collect()
+    }
+
+    #[test]
+    fn h264_frames_get_start_codes() {
+        let sps: &[u8] = &[0x67, 0x42, 0x00, 0x1F, 0xE9];
+        let pps: &[u8] = &[0x68, 0xCE, 0x3C, 0x80];
+        let idr: &[u8] = &[0x65, 0x88, 0x84, 0x00, 0x33];
+
+        let i_frame = annexb(&[sps, pps, idr]);
+        assert_eq!(to_annexb(&avcc(&[sps, pps, idr]), "h264"), i_frame);
+        assert_eq!(to_annexb(&i_frame, "h264"), i_frame);
+        let short_start = [&[0, 0, 1][..], idr].concat();
+        assert_eq!(to_annexb(&short_start, "h264"), short_start);
+
+        // A lone NAL unit without any prefix
+        let slice: &[u8] = &[0x41, 0x9A, 0x02, 0x03];
+        assert_eq!(to_annexb(slice, "h264"), annexb(&[slice]));
+    }
+
+    #[test]
+    fn h265_frames_get_start_codes() {
+        let vps: &[u8] = &[0x40, 0x01, 0x0C, 0x01];
+        let sps: &[u8] = &[0x42, 0x01, 0x01, 0x01];
+        let pps: &[u8] = &[0x44, 0x01, 0xC1, 0x72];
+        let idr: &[u8] = &[0x26, 0x01, 0xAF, 0x09];
+
+        let i_frame = annexb(&[vps, sps, pps, idr]);
+        assert_eq!(to_annexb(&avcc(&[vps, sps, pps, idr]), "h265"), i_frame);
+        assert_eq!(to_annexb(&i_frame, "h265"), i_frame);
+
+        // A P-frame slice on its own
+        let trail: &[u8] = &[0x02, 0x01, 0xD0, 0x10];
+        assert_eq!(to_annexb(trail, "h265"), annexb(&[trail]));
+    }
+
+    #[test]
+    fn other_media_is_left_alone() {
+        let jpeg = [0xFF, 0xD8, 0xFF, 0xE0];
+        assert_eq!(to_annexb(&jpeg, "jpeg"), jpeg);
+        assert_eq!(to_annexb(&jpeg, ""), jpeg);
+    }
+
+    #[tokio::test]
+    async fn snapshot_spans_two_packets() {
+        let (cam, mut device) = test_device::login(DVRIPCam::new("127.0.0.1")).await;
+        let head = [&[0xFF, 0xD8, 0xFF, 0xE0][..], &[0x11; 1000]].concat();
+        let tail = [&[0x22; 500][..], &[0xFF, 0xD9]].concat();
+
+        let device_side = async {
+            let (request, snap) = device.recv_json().await;
+            assert_eq!(request.msg_id, 1560);
+            assert_eq!(snap["OPSNAP"]["Channel"], 1);
+            device.send(request.packet_count, 1561, &head).await;
+            device.send(request.packet_count, 1561, &tail).await;
+        };
+        let (jpeg, _) = tokio::join!(cam.snapshot(1), device_side);
+
+        assert_eq!(jpeg.unwrap(), [head, tail].concat());
+        assert!(cam.stream_handlers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn snapshots_are_taken_one_at_a_time() {
+        let (cam, mut device) = test_device::login(DVRIPCam::new("127.0.0.1")).await;
+        let pictures = [0x11, 0x22]
+            .map(|b| [&[0xFF, 0xD8, 0xFF, 0xE0][..], &[b; 600], &[0xFF, 0xD9]].concat());
+
+        let device_side = async {
+            for _ in 0..2 {
+                let (request, snap) = device.recv_json().await;
+                // The other snapshot waits for this one to be complete
+                assert!(device.try_recv(Duration::from_millis(100)).await.is_none());
+                let jpeg = &pictures[snap["OPSNAP"]["Channel"].as_u64().unwrap() as usize];
+                device.send(request.packet_count, 1561, &jpeg[..300]).await;
+                device.send(request.packet_count, 1561, &jpeg[300..]).await;
+            }
+        };
+        let (first, second, _) = tokio::join!(cam.snapshot(0), cam.snapshot(1), device_side);
+
+        assert_eq!(first.unwrap(), pictures[0]);
+        assert_eq!(second.unwrap(), pictures[1]);
+        assert!(cam.stream_handlers.is_empty());
     }
 }
@@ -0,0 +1,245 @@
+use crate::commands::SystemInfo;
+use crate::commands::system_info::{value_to_bool, value_to_u64};
+use crate::constants::OK_CODES;
+use crate::dvrip::DVRIPCam;
+use crate::error::{DVRIPError, Result};
+use async_trait::async_trait;
+use serde_json::{Value, json};
+
+/// Wireless settings of the device
+///
+/// The password is write-only: it is never read back from the device, so
+/// `get_wifi_config` always returns `None` for it
+#[derive(Clone, PartialEq, Eq)]
+pub struct WifiConfig {
+    pub enable: bool,
+    pub ssid: String,
+    /// New password, `None` keeps the one stored on the device
+    pub password: Option<String>,
+}
+
+impl std::fmt::Debug for WifiConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WifiConfig")
+            .field("enable", &self.enable)
+            .field("ssid", &self.ssid)
+            .field("password", &self.password.as_ref().map(|_| "***"))
+            .finish()
+    }
+}
+
+impl WifiConfig {
+    fn from_value(value: &Value) -> Self {
+        Self {
+            enable: value
+                .get("Enable")
+                .and_then(|e| e.as_bool())
+                .unwrap_or(false),
+            ssid: value
+                .get("SSID")
+                .and_then(|s| s.as_str())
+                .unwrap_or_default()
+                .to_string(),
+            password: None,
+        }
+    }
+
+    /// Write the typed fields over `value`, keeping the IP settings untouched
+    fn apply(&self, value: &mut Value) {
+        value["Enable"] = json!(self.enable);
+        value["SSID"] = json!(self.ssid);
+        // The section is written back as read otherwise, stored password included
+        if let Some(password) = &self.password {
+            value["Keys"] = json!(password);
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct WifiNetwork {
+    pub ssid: String,
+    /// Signal strength as reported by the device (usually 0 to 100)
+    pub signal: i32,
+    /// Authentication and cipher, e.g. "WPA2PSK" / "AES"
+    pub encryption: String,
+    pub channel: Option<u32>,
+}
+
+impl WifiNetwork {
+    fn from_value(value: &Value) -> Option<Self> {
+        let text = |key: &str| value.get(key).and_then(|v| v.as_str()).unwrap_or_default();
+
+        let encryption = match (text("Auth"), text("EncrypType")) {
+            ("", cipher) => cipher.to_string(),
+            (auth, "") => auth.to_string(),
+            (auth, cipher) => format!("{} {}", auth, cipher),
+        };
+
+        Some(Self {
+            ssid: value.get("SSID")?.as_str()?.to_string(),
+            signal: value
+                .get("RSSI")
+                .and_then(|r| r.as_i64())
+                .unwrap_or_default() as i32,
+            encryption,
+            channel: value
+                .get("Channel")
+                .and_then(value_to_u64)
+                .map(|c| c as u32),
+        })
+    }
+}
+
+/// Registration of the device with the P2P cloud
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CloudStatus {
+    pub enabled: bool,
+    /// Whether the device is currently registered with the cloud server
+    pub online: bool,
+    /// Serial number used as the cloud id
+    pub serial: Option<String>,
+    pub server: Option<String>,
+}
+
+#[async_trait]
+pub trait Network: Send + Sync {
+    /// Get the WiFi settings, the password is never returned
+    async fn get_wifi_config(&self) -> Result<WifiConfig>;
+
+    /// Set the WiFi settings
+    async fn set_wifi_config(&self, config: WifiConfig) -> Result<bool>;
+
+    /// List the access points the device can see
+    async fn scan_wifi(&self) -> Result<Vec<WifiNetwork>>;
+
+    /// Get the P2P cloud registration state
+    async fn get_cloud_status(&self) -> Result<CloudStatus>;
+
+    /// Enable or disable the P2P cloud
+    async fn set_cloud_enabled(&self, enabled: bool) -> Result<bool>;
+}
+
+#[async_trait]
+impl Network for DVRIPCam {
+    async fn get_wifi_config(&self) -> Result<WifiConfig> {
+        let wifi = self.get_wifi_section().await?;
+        Ok(WifiConfig::from_value(&wifi))
+    }
+
+    async fn set_wifi_config(&self, config: WifiConfig) -> Result<bool> {
+        let mut wifi = self.get_wifi_section().await?;
+        config.apply(&mut wifi);
+
+        let reply = self.set_command("NetWork.Wifi", wifi, Some(1040)).await?;
+        if let Some(ret) = reply.get("Ret").and_then(|r| r.as_u64()) {
+            return Ok(OK_CODES.contains(&(ret as u32)));
+        }
+        Ok(false)
+    }
+
+    async fn scan_wifi(&self) -> Result<Vec<WifiNetwork>> {
+        let scan = self.get_command("WifiAP", Some(1020)).await?;
+
+        // Some firmware wraps the list in an object
+        let networks = scan
+            .as_array()
+            .or_else(|| scan.as_object()?.values().find_map(|v| v.as_array()))
+            .map(|a| a.iter().filter_map(WifiNetwork::from_value).collect())
+            .unwrap_or_default();
+
+        Ok(networks)
+    }
+
+    async fn get_cloud_status(&self) -> Result<CloudStatus> {
+        let nat = self.get_nat_section().await?;
+        let info = self.get_command("Status.NatInfo", Some(1042)).await?;
+
+        let text = |value: &Value, key: &str| {
+            value
+                .get(key)
+                .and_then(|v| v.as_str())
+                .filter(|s| !s.is_empty())
+                .map(|s| s.to_string())
+        };
+
+        // Older firmware only has the serial in the system info
+        let serial = match text(&info, "SerialNo") {
+            Some(serial) => Some(serial),
+            None => text(&self.get_system_info().await?, "SerialNo"),
+        };
+
+        Ok(CloudStatus {
+            enabled: nat
+                .get("NatEnable")
+                .and_then(value_to_bool)
+                .unwrap_or(false),
+            // "Conneted" is not a typo on our side
+            online: info
+                .get("NatStatus")
+                .and_then(|s| s.as_str())
+                .is_some_and(|s| s.starts_with("Conne")),
+            serial,
+            server: text(&nat, "Addr"),
+        })
+    }
+
+    async fn set_cloud_enabled(&self, enabled: bool) -> Result<bool> {
+        let mut nat = self.get_nat_section().await?;
+        nat["NatEnable"] = json!(enabled);
+
+        let reply = self.set_command("NetWork.Nat", nat, Some(1040)).await?;
+        if let Some(ret) = reply.get("Ret").and_then(|r| r.as_u64()) {
+            return Ok(OK_CODES.contains(&(ret as u32)));
+        }
+        Ok(false)
+    }
+}
+
+impl DVRIPCam {
+    async fn get_wifi_section(&self) -> Result<Value> {
+        let wifi = self.get_command("NetWork.Wifi", Some(1042)).await?;
+
+        // Wired-only devices answer with an error instead of the section
+        if wifi.get("SSID").is_none() {
+            return Err(DVRIPError::Unsupported("WiFi configuration".to_string()));
+        }
+        Ok(wifi)
+    }
+
+    async fn get_nat_section(&self) -> Result<Value> {
+        let nat = self.get_command("NetWork.Nat", Some(1042)).await?;
+
+        if nat.get("NatEnable").is_none() {
+            return Err(DVRIPError::Unsupported("P2P cloud".to_string()));
+        }
+        Ok(nat)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn password_is_only_written_when_set() {
+        let stored =
+            json!({"Enable": true, "SSID": "home", "Keys": "secret", "GateWay": "0x0101A8C0"});
+        let mut config = WifiConfig::from_value(&stored);
+        assert_eq!(config.password, None);
+
+        config.ssid = "office".to_string();
+        let mut kept = stored.clone();
+        config.apply(&mut kept);
+        assert_eq!(kept["SSID"], "office");
+        assert_eq!(kept["Keys"], "secret");
+        assert_eq!(kept["GateWay"], stored["GateWay"]);
+
+        config.password = Some("changed".to_string());
+        let mut changed = stored.clone();
+        config.apply(&mut changed);
+        assert_eq!(changed["Keys"], "changed");
+        assert!(!format!("{:?}", config).contains("changed"));
+    }
+}
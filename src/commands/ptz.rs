@@ -1,9 +1,10 @@
+use crate::commands::system_info::value_to_u64;
 use crate::constants::{KEY_CODES, OK_CODES};
 use crate::dvrip::DVRIPCam;
-use crate::error::Result;
+use crate::error::{DVRIPError, Result};
 use async_trait::async_trait;
-use serde_json::json;
-use strum_macros::AsRefStr;
+use serde_json::{Value, json};
+use strum_macros::{AsRefStr, EnumString};
 use tokio::time::{Duration, sleep};
 
 #[derive(Debug, Clone, Copy, AsRefStr)]
@@ -29,6 +30,166 @@ pub enum PTZCommand {
     StopTour,
 }
 
+/// Key of the device front panel or a network keyboard, sent with `OPNetKeyboard`
+///
+/// `as_ref()` gives the token the device expects
+#[derive(Debug, Clone, Copy, PartialEq, Eq, AsRefStr, EnumString)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Key {
+    #[strum(serialize = "0")]
+    Num0,
+    #[strum(serialize = "1")]
+    Num1,
+    #[strum(serialize = "2")]
+    Num2,
+    #[strum(serialize = "3")]
+    Num3,
+    #[strum(serialize = "4")]
+    Num4,
+    #[strum(serialize = "5")]
+    Num5,
+    #[strum(serialize = "6")]
+    Num6,
+    #[strum(serialize = "7")]
+    Num7,
+    #[strum(serialize = "8")]
+    Num8,
+    #[strum(serialize = "9")]
+    Num9,
+    Enter,
+    Esc,
+    Menu,
+    Info,
+    Func,
+    Shift,
+    Up,
+    Down,
+    Left,
+    Right,
+    Play,
+    Pause,
+    Stop,
+    Rec,
+    Fast,
+    Slow,
+    Prev,
+    Next,
+    Search,
+    Backup,
+    Split,
+    #[strum(serialize = "PTZ")]
+    Ptz,
+    Preset,
+    Tour,
+    ZoomTele,
+    ZoomWide,
+    FocusNear,
+    FocusFar,
+    IrisOpen,
+    IrisClose,
+    Alarm,
+    Mute,
+    Shutdown,
+}
+
+impl Key {
+    /// Key of a `key_script_chars` character (see `KEY_CODES`), letters are case insensitive
+    pub fn from_char(c: char) -> Option<Self> {
+        let c = c.to_uppercase().to_string();
+        KEY_CODES.get(c.as_str())?.parse().ok()
+    }
+}
+
+/// Size of the device coordinate grid (`POINT`, privacy masks), the whole picture
+/// is 0 to 8192 on both axes
+pub(crate) const GRID_RANGE: f32 = 8192.0;
+
+/// Command of the 3D positioning, the `POINT` box is centered and zoomed to
+const POSITION_3D_COMMAND: &str = "ExactGotoPoint";
+
+/// Region of the picture, each side as a fraction of the width or height (0.0 to 1.0)
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Rect {
+    pub left: f32,
+    pub top: f32,
+    pub right: f32,
+    pub bottom: f32,
+}
+
+impl Rect {
+    /// Left, top, right and bottom on the device grid
+    pub(crate) fn to_grid(self) -> Result<[u32; 4]> {
+        let sides = [self.left, self.top, self.right, self.bottom];
+        if sides.iter().any(|s| !(0.0..=1.0).contains(s))
+            || self.left >= self.right
+            || self.top >= self.bottom
+        {
+            return Err(DVRIPError::InvalidParameter(format!(
+                "Invalid rectangle {:?}, expected 0.0 <= left < right <= 1.0 and 0.0 <= top < bottom <= 1.0",
+                self
+            )));
+        }
+        Ok(sides.map(|s| (s * GRID_RANGE).round() as u32))
+    }
+
+    /// Read left, top, right and bottom on the device grid, clamping to the picture
+    pub(crate) fn from_grid(sides: [u64; 4]) -> Self {
+        let [left, top, right, bottom] = sides.map(|s| (s as f32 / GRID_RANGE).min(1.0));
+        Self {
+            left,
+            top,
+            right,
+            bottom,
+        }
+    }
+
+    /// Whether the two rectangles share some area, touching edges don't count
+    pub(crate) fn overlaps(&self, other: &Rect) -> bool {
+        self.left < other.right
+            && other.left < self.right
+            && self.top < other.bottom
+            && other.top < self.bottom
+    }
+
+    /// The `POINT` object in device coordinates, a box drawn from the bottom right
+    /// to the top left asks the device to zoom out
+    fn to_point(self, zoom_in: bool) -> Result<Value> {
+        let [left, top, right, bottom] = self.to_grid()?;
+        Ok(if zoom_in {
+            json!({"bottom": bottom, "left": left, "right": right, "top": top})
+        } else {
+            json!({"bottom": top, "left": right, "right": left, "top": bottom})
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Preset {
+    pub id: u32,
+    pub name: String,
+    pub enabled: bool,
+}
+
+impl Preset {
+    pub(crate) fn from_value(value: &Value) -> Option<Self> {
+        Some(Self {
+            id: value.get("Id").and_then(|i| i.as_u64())? as u32,
+            name: value
+                .get("PresetName")
+                .and_then(|n| n.as_str())
+                .unwrap_or_default()
+                .to_string(),
+            // Only configured presets are listed, some firmware also sends an explicit flag
+            enabled: value
+                .get("Enable")
+                .and_then(|e| e.as_bool())
+                .unwrap_or(true),
+        })
+    }
+}
+
 #[async_trait]
 pub trait PTZ: Send + Sync {
     /// Control PTZ with continuous command
@@ -40,6 +201,26 @@ pub trait PTZ: Send + Sync {
     async fn ptz_start(&self, cmd: PTZCommand, step: u8) -> Result<bool>;
     async fn ptz_stop(&self, cmd: PTZCommand, step: u8) -> Result<bool>;
 
+    /// Center the camera on a region of the picture and zoom in to fill it with that
+    /// region, or zoom out when `zoom_in` is false (3D positioning)
+    async fn ptz_3d(&self, channel: u8, rect: Rect, zoom_in: bool) -> Result<bool>;
+
+    /// List the presets configured on a channel
+    async fn list_presets(&self, channel: u8) -> Result<Vec<Preset>>;
+
+    /// Set (or rename) a preset on the current position
+    async fn set_preset_name(&self, channel: u8, id: u32, name: &str) -> Result<bool>;
+
+    /// Remove a preset
+    async fn clear_preset(&self, channel: u8, id: u32) -> Result<bool>;
+
+    /// Get the current (pan, tilt, zoom) of a channel
+    ///
+    /// Pan and tilt are in degrees (pan 0 to 360, tilt 0 to 90) and zoom is the
+    /// magnification factor (1.0 is wide). Devices that don't report their position
+    /// return `DVRIPError::Unsupported`
+    async fn get_ptz_position(&self, channel: u8) -> Result<(f32, f32, f32)>;
+
     /// Press a key (keyDown)
     async fn key_down(&self, key: &str) -> Result<bool>;
 
@@ -47,10 +228,14 @@ pub trait PTZ: Send + Sync {
     async fn key_up(&self, key: &str) -> Result<bool>;
 
     /// Press and release a key
-    async fn key_press(&self, key: &str) -> Result<bool>;
+    async fn key_press(&self, key: Key) -> Result<bool>;
 
-    /// Execute a key script
-    async fn key_script(&self, keys: &str) -> Result<bool>;
+    /// Press the keys one after the other
+    async fn key_script(&self, keys: &[Key]) -> Result<bool>;
+
+    /// Press the keys of a string one after the other, see `KEY_CODES` for the
+    /// characters. A space waits a second, unknown characters are skipped
+    async fn key_script_chars(&self, keys: &str) -> Result<bool>;
 }
 
 #[async_trait]
@@ -174,6 +359,90 @@ impl PTZ for DVRIPCam {
         Ok(false)
     }
 
+    async fn ptz_3d(&self, channel: u8, rect: Rect, zoom_in: bool) -> Result<bool> {
+        let data = json!({
+            "Command": POSITION_3D_COMMAND,
+            "Parameter": {
+                "AUX": {"Number": 0, "Status": "On"},
+                "Channel": channel,
+                "MenuOpts": "Enter",
+                "POINT": rect.to_point(zoom_in)?,
+                "Pattern": "SetBegin",
+                "Preset": -1,
+                "Step": 0,
+                "Tour": 0,
+            },
+        });
+
+        let reply = self.set_command("OPPTZControl", data, None).await?;
+        if let Some(ret) = reply.get("Ret").and_then(|r| r.as_u64()) {
+            return Ok(OK_CODES.contains(&(ret as u32)));
+        }
+        Ok(false)
+    }
+
+    async fn list_presets(&self, channel: u8) -> Result<Vec<Preset>> {
+        let data = self.get_command("Uart.PTZPreset", Some(1042)).await?;
+
+        // One list of presets per channel
+        let presets = data
+            .as_array()
+            .and_then(|channels| channels.get(channel as usize))
+            .and_then(|p| p.as_array());
+
+        Ok(presets
+            .map(|p| p.iter().filter_map(Preset::from_value).collect())
+            .unwrap_or_default())
+    }
+
+    async fn set_preset_name(&self, channel: u8, id: u32, name: &str) -> Result<bool> {
+        let data = json!({
+            "Command": PTZCommand::SetPreset.as_ref(),
+            "Parameter": {
+                "Channel": channel,
+                "Preset": id,
+                "PresetName": name,
+            },
+        });
+
+        let reply = self.set_command("OPPTZControl", data, None).await?;
+        if let Some(ret) = reply.get("Ret").and_then(|r| r.as_u64()) {
+            return Ok(OK_CODES.contains(&(ret as u32)));
+        }
+        Ok(false)
+    }
+
+    async fn clear_preset(&self, channel: u8, id: u32) -> Result<bool> {
+        self.ptz(PTZCommand::ClearPreset, 0, id as i32, channel)
+            .await
+    }
+
+    async fn get_ptz_position(&self, channel: u8) -> Result<(f32, f32, f32)> {
+        let status = self.get_command("fPTZ.Status", Some(1042)).await?;
+
+        // Either one object per channel or a single object for single channel cameras
+        let status = status
+            .as_array()
+            .map_or(Some(&status), |channels| channels.get(channel as usize));
+
+        // Pan and tilt are reported in tenths of a degree
+        let field = |keys: &[&str]| {
+            let status = status?;
+            keys.iter()
+                .find_map(|k| status.get(*k).and_then(value_to_u64))
+                .map(|v| v as f32)
+        };
+        let (Some(pan), Some(tilt)) = (field(&["Pan", "PanPos"]), field(&["Tilt", "TiltPos"]))
+        else {
+            return Err(DVRIPError::Unsupported(
+                "Reading the PTZ position".to_string(),
+            ));
+        };
+        let zoom = field(&["Zoom", "ZoomPos"]).unwrap_or(1.0).max(1.0);
+
+        Ok(((pan / 10.0) % 360.0, (tilt / 10.0).clamp(0.0, 90.0), zoom))
+    }
+
     async fn key_down(&self, key: &str) -> Result<bool> {
         let data = json!({
             "Status": "KeyDown",
@@ -200,18 +469,24 @@ impl PTZ for DVRIPCam {
         Ok(false)
     }
 
-    async fn key_press(&self, key: &str) -> Result<bool> {
-        self.key_down(key).await?;
+    async fn key_press(&self, key: Key) -> Result<bool> {
+        self.key_down(key.as_ref()).await?;
         sleep(Duration::from_millis(300)).await;
-        self.key_up(key).await
+        self.key_up(key.as_ref()).await
+    }
+
+    async fn key_script(&self, keys: &[Key]) -> Result<bool> {
+        for key in keys {
+            self.key_press(*key).await?;
+        }
+        Ok(true)
     }
 
-    async fn key_script(&self, keys: &str) -> Result<bool> {
+    async fn key_script_chars(&self, keys: &str) -> Result<bool> {
         for k in keys.chars() {
             if k != ' ' {
-                let key_upper = k.to_uppercase().to_string();
-                if let Some(key_code) = KEY_CODES.get(key_upper.as_str()) {
-                    self.key_press(key_code).await?;
+                if let Some(key) = Key::from_char(k) {
+                    self.key_press(key).await?;
                 }
             } else {
                 sleep(Duration::from_secs(1)).await;
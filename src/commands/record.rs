@@ -0,0 +1,442 @@
+use crate::constants::OK_CODES;
+use crate::dvrip::DVRIPCam;
+use crate::error::{DVRIPError, Result};
+use async_trait::async_trait;
+use serde_json::{Value, json};
+use std::ops::RangeInclusive;
+use strum_macros::{AsRefStr, EnumString};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, AsRefStr, EnumString)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum RecordMode {
+    /// Record following the schedule
+    #[strum(serialize = "ConfigRecord")]
+    Schedule,
+    /// Always record
+    #[strum(serialize = "ManualRecord")]
+    Manual,
+    /// Never record
+    #[strum(serialize = "ClosedRecord")]
+    Closed,
+}
+
+/// One entry of the recording schedule, times are seconds since midnight (end can be 86400)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TimeSegment {
+    pub enabled: bool,
+    pub start: u32,
+    pub end: u32,
+}
+
+impl TimeSegment {
+    const DAY_SECONDS: u32 = 24 * 60 * 60;
+
+    // "1 00:00:00-24:00:00"
+    fn parse(value: &str) -> Option<Self> {
+        let (enabled, range) = value.split_once(' ')?;
+        let (start, end) = range.split_once('-')?;
+
+        Some(Self {
+            enabled: enabled == "1",
+            start: Self::parse_time(start)?,
+            end: Self::parse_time(end)?,
+        })
+    }
+
+    fn parse_time(value: &str) -> Option<u32> {
+        let mut parts = value.split(':').map(|p| p.parse::<u32>().ok());
+        let (h, m, s) = (parts.next()??, parts.next()??, parts.next()??);
+        let secs = h * 3600 + m * 60 + s;
+        (secs <= Self::DAY_SECONDS).then_some(secs)
+    }
+
+    fn format_time(secs: u32) -> String {
+        format!(
+            "{:02}:{:02}:{:02}",
+            secs / 3600,
+            (secs / 60) % 60,
+            secs % 60
+        )
+    }
+
+    fn to_device(self) -> Result<String> {
+        if self.start > self.end || self.end > Self::DAY_SECONDS {
+            return Err(DVRIPError::InvalidParameter(format!(
+                "Invalid time segment {}-{}",
+                self.start, self.end
+            )));
+        }
+
+        Ok(format!(
+            "{} {}-{}",
+            if self.enabled { 1 } else { 0 },
+            Self::format_time(self.start),
+            Self::format_time(self.end)
+        ))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RecordConfig {
+    pub mode: RecordMode,
+    /// Seconds recorded before an event
+    pub pre_record: u32,
+    /// Length of each recorded file in minutes
+    pub packet_length: u32,
+    pub redundancy: bool,
+    /// Segments for each day of the week, starting on sunday
+    pub schedule: Vec<Vec<TimeSegment>>,
+}
+
+impl RecordConfig {
+    fn from_value(value: &Value) -> Option<Self> {
+        let schedule = value
+            .get("TimeSection")
+            .and_then(|t| t.as_array())
+            .map(|days| {
+                days.iter()
+                    .map(|day| {
+                        day.as_array()
+                            .map(|s| {
+                                s.iter()
+                                    .filter_map(|v| v.as_str().and_then(TimeSegment::parse))
+                                    .collect()
+                            })
+                            .unwrap_or_default()
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Some(Self {
+            mode: value.get("RecordMode")?.as_str()?.parse().ok()?,
+            pre_record: value.get("PreRecord").and_then(|p| p.as_u64()).unwrap_or(0) as u32,
+            packet_length: value
+                .get("PacketLength")
+                .and_then(|p| p.as_u64())
+                .unwrap_or(0) as u32,
+            redundancy: value
+                .get("Redundancy")
+                .and_then(|r| r.as_bool())
+                .unwrap_or(false),
+            schedule,
+        })
+    }
+
+    /// Write the typed fields over `value`, keeping the ones this struct doesn't know about
+    fn apply(&self, value: &mut Value) -> Result<()> {
+        let schedule = self
+            .schedule
+            .iter()
+            .map(|day| {
+                day.iter()
+                    .map(|s| s.to_device())
+                    .collect::<Result<Vec<_>>>()
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        value["RecordMode"] = json!(self.mode.as_ref());
+        value["PreRecord"] = json!(self.pre_record);
+        value["PacketLength"] = json!(self.packet_length);
+        value["Redundancy"] = json!(self.redundancy);
+        // Don't wipe a schedule we weren't able to parse
+        if !schedule.is_empty() {
+            value["TimeSection"] = json!(schedule);
+        }
+        Ok(())
+    }
+}
+
+/// Seconds recorded around a motion event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RecordBuffer {
+    /// Recorded before the event, `PreRecord` of the `Record` config
+    pub pre_secs: u32,
+    /// Recorded after the event ended, `RecordLatch` of the motion detection
+    pub post_secs: u32,
+}
+
+/// Values `set_record_buffer` accepts, in seconds. The defaults are the ranges of the
+/// usual firmware, set others with `DVRIPCam::with_record_buffer_limits`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordBufferLimits {
+    pub max_pre_secs: u32,
+    pub post_secs: RangeInclusive<u32>,
+}
+
+impl Default for RecordBufferLimits {
+    fn default() -> Self {
+        Self {
+            max_pre_secs: 30,
+            post_secs: 10..=300,
+        }
+    }
+}
+
+#[async_trait]
+pub trait RecordControl: Send + Sync {
+    /// Force recording on (manual mode) for a channel, or end it
+    ///
+    /// Ending it restores the mode the channel had when manual recording was turned
+    /// on through this `DVRIPCam`, the schedule otherwise
+    async fn set_manual_record(&self, channel: u8, enable: bool) -> Result<bool>;
+
+    /// Get the recording config of a channel
+    async fn get_record_config(&self, channel: u8) -> Result<RecordConfig>;
+
+    /// Set the recording config of a channel
+    async fn set_record_config(&self, channel: u8, config: RecordConfig) -> Result<bool>;
+
+    /// Get how long is recorded before and after a motion event on a channel
+    async fn get_record_buffer(&self, channel: u8) -> Result<RecordBuffer>;
+
+    /// Set how long is recorded before (0 to 30 s) and after (10 to 300 s) a motion event,
+    /// the limits can be changed with `DVRIPCam::with_record_buffer_limits`
+    ///
+    /// The pre-record is part of the record config and the post-record of the motion
+    /// detection config, both are written keeping their other fields. The device has
+    /// no command writing both, so when the second write fails the first is undone
+    async fn set_record_buffer(&self, channel: u8, pre_secs: u32, post_secs: u32) -> Result<bool>;
+}
+
+#[async_trait]
+impl RecordControl for DVRIPCam {
+    async fn set_manual_record(&self, channel: u8, enable: bool) -> Result<bool> {
+        let mut config = self.get_record_config(channel).await?;
+        config.mode = if enable {
+            if config.mode != RecordMode::Manual {
+                self.manual_record_restore.insert(channel, config.mode);
+            }
+            RecordMode::Manual
+        } else {
+            self.manual_record_restore
+                .remove(&channel)
+                .map_or(RecordMode::Schedule, |(_, mode)| mode)
+        };
+        self.set_record_config(channel, config).await
+    }
+
+    async fn get_record_config(&self, channel: u8) -> Result<RecordConfig> {
+        let records = self.get_command("Record", Some(1042)).await?;
+
+        records
+            .get(channel as usize)
+            .and_then(RecordConfig::from_value)
+            .ok_or_else(|| {
+                DVRIPError::ProtocolError(format!("No record config for channel {}", channel))
+            })
+    }
+
+    async fn set_record_config(&self, channel: u8, config: RecordConfig) -> Result<bool> {
+        let mut records = self.get_command("Record", Some(1042)).await?;
+
+        let Some(record) = records.get_mut(channel as usize) else {
+            return Err(DVRIPError::InvalidParameter(format!(
+                "Channel {} has no record config",
+                channel
+            )));
+        };
+        config.apply(record)?;
+
+        let reply = self.set_command("Record", records, Some(1040)).await?;
+        if let Some(ret) = reply.get("Ret").and_then(|r| r.as_u64()) {
+            return Ok(OK_CODES.contains(&(ret as u32)));
+        }
+        Ok(false)
+    }
+
+    async fn get_record_buffer(&self, channel: u8) -> Result<RecordBuffer> {
+        let pre_secs = self.get_record_config(channel).await?.pre_record;
+
+        let detect = self.get_command("Detect.MotionDetect", Some(1042)).await?;
+        let post_secs = detect
+            .get(channel as usize)
+            .and_then(|d| d.get("EventHandler"))
+            .and_then(|h| h.get("RecordLatch"))
+            .and_then(|l| l.as_u64())
+            .ok_or_else(|| {
+                DVRIPError::ProtocolError(format!(
+                    "No motion detection config for channel {}",
+                    channel
+                ))
+            })? as u32;
+
+        Ok(RecordBuffer {
+            pre_secs,
+            post_secs,
+        })
+    }
+
+    async fn set_record_buffer(&self, channel: u8, pre_secs: u32, post_secs: u32) -> Result<bool> {
+        let limits = &self.record_buffer_limits;
+        if pre_secs > limits.max_pre_secs {
+            return Err(DVRIPError::InvalidParameter(format!(
+                "Pre-record of {} s is over the maximum of {} s",
+                pre_secs, limits.max_pre_secs
+            )));
+        }
+        if !limits.post_secs.contains(&post_secs) {
+            return Err(DVRIPError::InvalidParameter(format!(
+                "Post-record of {} s is outside {:?} s",
+                post_secs, limits.post_secs
+            )));
+        }
+
+        let mut detect = self.get_command("Detect.MotionDetect", Some(1042)).await?;
+        let Some(handler) = detect
+            .get_mut(channel as usize)
+            .and_then(|d| d.get_mut("EventHandler"))
+        else {
+            return Err(DVRIPError::InvalidParameter(format!(
+                "Channel {} has no motion detection config",
+                channel
+            )));
+        };
+        handler["RecordLatch"] = json!(post_secs);
+
+        let previous = self.get_record_config(channel).await?;
+        let mut config = previous.clone();
+        config.pre_record = pre_secs;
+        if !self.set_record_config(channel, config).await? {
+            return Ok(false);
+        }
+
+        let written = self
+            .set_command("Detect.MotionDetect", detect, Some(1040))
+            .await
+            .map(|reply| {
+                reply
+                    .get("Ret")
+                    .and_then(|r| r.as_u64())
+                    .is_some_and(|ret| OK_CODES.contains(&(ret as u32)))
+            });
+        if !matches!(written, Ok(true)) {
+            // Put the pre-record back, the error of the failed write is the one returned
+            let _ = self.set_record_config(channel, previous).await;
+        }
+        written
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_device::{self, FakeDevice};
+
+    fn records(mode: &str) -> Value {
+        json!({
+            "Name": "Record",
+            "Ret": 100,
+            "Record": [{"RecordMode": mode, "PreRecord": 4, "PacketLength": 60, "Redundancy": false}],
+        })
+    }
+
+    /// Answer the reads of `set_manual_record` with `mode`, returning the mode written
+    async fn written_mode(device: &mut FakeDevice, mode: &str) -> String {
+        device.answer(records(mode)).await;
+        device.answer(records(mode)).await;
+        let (_, set) = device.answer(json!({"Name": "Record", "Ret": 100})).await;
+        set["Record"][0]["RecordMode"].as_str().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn manual_record_restores_previous_mode() {
+        let (cam, mut device) = test_device::login(DVRIPCam::new("127.0.0.1")).await;
+
+        let (on, mode) = tokio::join!(
+            cam.set_manual_record(0, true),
+            written_mode(&mut device, "ClosedRecord")
+        );
+        assert!(on.unwrap());
+        assert_eq!(mode, "ManualRecord");
+
+        let (off, mode) = tokio::join!(
+            cam.set_manual_record(0, false),
+            written_mode(&mut device, "ManualRecord")
+        );
+        assert!(off.unwrap());
+        assert_eq!(mode, "ClosedRecord");
+    }
+
+    #[tokio::test]
+    async fn manual_record_ends_on_the_schedule() {
+        let (cam, mut device) = test_device::login(DVRIPCam::new("127.0.0.1")).await;
+
+        let (off, mode) = tokio::join!(
+            cam.set_manual_record(0, false),
+            written_mode(&mut device, "ManualRecord")
+        );
+        assert!(off.unwrap());
+        assert_eq!(mode, "ConfigRecord");
+    }
+
+    fn motion_detect() -> Value {
+        json!({
+            "Name": "Detect.MotionDetect",
+            "Ret": 100,
+            "Detect.MotionDetect": [{"Enable": true, "EventHandler": {"RecordLatch": 30}}],
+        })
+    }
+
+    #[tokio::test]
+    async fn failed_buffer_write_restores_the_pre_record() {
+        let (cam, mut device) = test_device::login(DVRIPCam::new("127.0.0.1")).await;
+
+        let device_side = async {
+            device.answer(motion_detect()).await;
+            device.answer(records("ConfigRecord")).await;
+            device.answer(records("ConfigRecord")).await;
+            let (_, set) = device.answer(json!({"Name": "Record", "Ret": 100})).await;
+            assert_eq!(set["Record"][0]["PreRecord"], 10);
+
+            let denied = json!({"Name": "Detect.MotionDetect", "Ret": 107});
+            let (_, set) = device.answer(denied).await;
+            assert_eq!(
+                set["Detect.MotionDetect"][0]["EventHandler"]["RecordLatch"],
+                60
+            );
+
+            let mut written = records("ConfigRecord");
+            written["Record"][0]["PreRecord"] = json!(10);
+            device.answer(written).await;
+            let (_, set) = device.answer(json!({"Name": "Record", "Ret": 100})).await;
+            set["Record"][0]["PreRecord"].clone()
+        };
+
+        let (result, restored) = tokio::join!(cam.set_record_buffer(0, 10, 60), device_side);
+        assert!(matches!(
+            result,
+            Err(DVRIPError::PermissionDenied { code: 107, .. })
+        ));
+        assert_eq!(restored, 4);
+    }
+
+    #[tokio::test]
+    async fn buffer_limits_can_be_changed() {
+        let limits = RecordBufferLimits {
+            max_pre_secs: 60,
+            post_secs: 0..=600,
+        };
+        let (cam, mut device) =
+            test_device::login(DVRIPCam::new("127.0.0.1").with_record_buffer_limits(limits)).await;
+
+        let result = cam.set_record_buffer(0, 61, 60).await;
+        assert!(matches!(result, Err(DVRIPError::InvalidParameter(_))));
+
+        let device_side = async {
+            device.answer(motion_detect()).await;
+            device.answer(records("ConfigRecord")).await;
+            device.answer(records("ConfigRecord")).await;
+            device.answer(json!({"Name": "Record", "Ret": 100})).await;
+            let ok = json!({"Name": "Detect.MotionDetect", "Ret": 100});
+            let (_, set) = device.answer(ok).await;
+            set["Detect.MotionDetect"][0]["EventHandler"]["RecordLatch"].clone()
+        };
+        let (result, latch) = tokio::join!(cam.set_record_buffer(0, 45, 500), device_side);
+        assert!(result.unwrap());
+        assert_eq!(latch, 500);
+    }
+}
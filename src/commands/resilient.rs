@@ -0,0 +1,192 @@
+use crate::commands::{
+    Authentication, Connection, ConnectionEvent, FrameMetadata, MonitorHandle, Monitoring,
+};
+use crate::dvrip::DVRIPCam;
+use crate::error::Result;
+use bytes::Bytes;
+use std::sync::Arc;
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
+use tokio::time::Duration;
+
+// Wait between reconnection attempts, doubled after each failure
+const RECONNECT_MIN_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+
+// A stream sends several frames a second, nothing for this long means a dead connection
+const FRAME_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Item of a `ResilientMonitor`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MonitorItem {
+    Frame(FrameMetadata, Bytes),
+    /// Frames were lost, either dropped for a slow consumer or while reconnecting.
+    /// The following P-frames refer to frames that never arrived, wait for the next I-frame
+    StreamGap,
+}
+
+/// Frames of a monitored stream that outlive connection drops, from
+/// `DVRIPCam::resilient_monitor`
+///
+/// Owns the camera: once the connection is lost, the keep-alive fails or no frame
+/// arrives for 10 seconds (or the idle read timeout when it's shorter), it reconnects,
+/// logs in again and claims the stream anew, waiting up to 30 seconds between
+/// attempts. Dropping it closes the connection
+pub struct ResilientMonitor {
+    receiver: mpsc::Receiver<MonitorItem>,
+    events: Arc<broadcast::Sender<ConnectionEvent>>,
+    task: JoinHandle<()>,
+}
+
+impl ResilientMonitor {
+    /// Next frame or gap, `None` once the monitor stopped
+    pub async fn recv(&mut self) -> Option<MonitorItem> {
+        self.receiver.recv().await
+    }
+
+    /// Lifecycle events of the underlying connection, e.g. to log the reconnections
+    pub fn events(&self) -> broadcast::Receiver<ConnectionEvent> {
+        self.events.subscribe()
+    }
+
+    /// Stop monitoring and wait for the connection to be closed
+    pub async fn stop(self) {
+        let Self { receiver, task, .. } = self;
+        drop(receiver);
+        let _ = task.await;
+    }
+}
+
+/// What the background task needs to bring the stream back
+struct Resume {
+    cam: DVRIPCam,
+    username: String,
+    password: String,
+    stream: String,
+    channel: u8,
+}
+
+impl DVRIPCam {
+    /// Monitor a stream and keep it running across reconnections, see `ResilientMonitor`
+    ///
+    /// Logs in with the credentials when the camera isn't authenticated yet, errors
+    /// of this first attempt are returned, later ones are retried
+    pub async fn resilient_monitor(
+        mut self,
+        username: &str,
+        password: &str,
+        stream: &str,
+        channel: u8,
+    ) -> Result<ResilientMonitor> {
+        if !self.is_authenticated() && !self.login(username, password).await? {
+            return Err(crate::error::DVRIPError::AuthenticationError(
+                "Login failed".to_string(),
+            ));
+        }
+        let frames = self.start_monitor_bytes(stream, channel).await?;
+
+        let (tx, receiver) = mpsc::channel(self.frame_capacity);
+        let events = Arc::clone(&self.events);
+        let resume = Resume {
+            cam: self,
+            username: username.to_string(),
+            password: password.to_string(),
+            stream: stream.to_string(),
+            channel,
+        };
+
+        Ok(ResilientMonitor {
+            receiver,
+            events,
+            task: tokio::spawn(resume.run(frames, tx)),
+        })
+    }
+}
+
+impl Resume {
+    async fn run(mut self, frames: MonitorHandle, tx: mpsc::Sender<MonitorItem>) {
+        let mut frames = Some(frames);
+        loop {
+            let Some(handle) = frames.take() else {
+                match self.reconnect(&tx).await {
+                    Some(handle) => {
+                        frames = Some(handle);
+                        if tx.send(MonitorItem::StreamGap).await.is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+                    None => break,
+                }
+            };
+
+            match self.forward(handle, &tx).await {
+                // The consumer is gone
+                None => break,
+                // Lost the connection, the handle is dropped with the dead session
+                Some(()) => {
+                    let _ = self.cam.close().await;
+                }
+            }
+        }
+        let _ = self.cam.close().await;
+    }
+
+    /// Pass the frames on until the connection looks dead, `None` when the consumer left
+    async fn forward(
+        &self,
+        mut frames: MonitorHandle,
+        tx: &mpsc::Sender<MonitorItem>,
+    ) -> Option<()> {
+        let mut events = self.cam.events();
+        // A half-open connection sends nothing and reports nothing either
+        let frame_timeout = self
+            .cam
+            .idle_read_timeout
+            .map_or(FRAME_TIMEOUT, |idle| idle.min(FRAME_TIMEOUT));
+        loop {
+            let frame = tokio::select! {
+                _ = tx.closed() => return None,
+                event = events.recv() => match event {
+                    Ok(ConnectionEvent::Disconnected(_) | ConnectionEvent::KeepAliveFailed) => {
+                        return Some(());
+                    }
+                    _ => continue,
+                },
+                frame = tokio::time::timeout(frame_timeout, frames.recv()) => match frame {
+                    Ok(frame) => frame,
+                    Err(_) => return Some(()),
+                },
+            };
+
+            let item = match frame {
+                Ok((metadata, frame)) => MonitorItem::Frame(metadata, frame),
+                Err(broadcast::error::RecvError::Lagged(_)) => MonitorItem::StreamGap,
+                Err(broadcast::error::RecvError::Closed) => return Some(()),
+            };
+            tx.send(item).await.ok()?;
+        }
+    }
+
+    /// Log in again and claim the stream, retrying until it works or the consumer left
+    async fn reconnect(&mut self, tx: &mpsc::Sender<MonitorItem>) -> Option<MonitorHandle> {
+        let mut delay = RECONNECT_MIN_DELAY;
+        loop {
+            tokio::select! {
+                _ = tx.closed() => return None,
+                _ = tokio::time::sleep(delay) => {}
+            }
+
+            if let Ok(true) = self.cam.login(&self.username, &self.password).await
+                && let Ok(frames) = self
+                    .cam
+                    .start_monitor_bytes(&self.stream, self.channel)
+                    .await
+            {
+                return Some(frames);
+            }
+            let _ = self.cam.close().await;
+            delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+        }
+    }
+}
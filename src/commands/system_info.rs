@@ -1,18 +1,283 @@
-use crate::constants::DATE_FORMAT;
+use crate::constants::{DATE_FORMAT, MAX_CHANNEL_TITLE_LEN, MAX_DEVICE_NAME_LEN, OK_CODES};
 use crate::dvrip::DVRIPCam;
-use crate::error::Result;
+use crate::encoding::{hex_to_u64, parse_device_time};
+use crate::error::{DVRIPError, Result};
+use crate::responses::{self, GeneralResponse, SystemInfoResponse};
 use async_trait::async_trait;
-use chrono::{DateTime, Local, NaiveDateTime};
-use serde_json::Value;
+use chrono::{DateTime, Local};
+use serde_json::{Value, json};
+use std::str::FromStr;
+use strum_macros::{AsRefStr, EnumString};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DeviceIdentity {
+    pub serial_number: Option<String>,
+    pub hardware: Option<String>,
+    pub software_version: Option<String>,
+    pub build_time: Option<String>,
+    pub channel_count: Option<u32>,
+    /// From the login reply, e.g. "IPC", "DVR" or "NVR"
+    pub device_type: Option<String>,
+}
+
+impl From<SystemInfoResponse> for DeviceIdentity {
+    fn from(info: SystemInfoResponse) -> Self {
+        Self {
+            serial_number: info.serial_number,
+            hardware: info.hardware,
+            software_version: info.software_version,
+            build_time: info.build_time,
+            channel_count: info.video_in_channels,
+            device_type: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ChannelRecordStatus {
+    pub channel: u8,
+    /// `None` when the device doesn't say, see `SystemInfo::get_recording_status`
+    pub recording: Option<bool>,
+    pub stream_online: bool,
+    /// `None` when the device doesn't say, see `SystemInfo::get_bandwidth_usage`
+    pub bitrate_kbps: Option<u32>,
+}
+
+/// Entry of `NetWork.ChnStatus`, missing fields read as offline/zero
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ChannelStatus {
+    pub channel: u8,
+    pub name: Option<String>,
+    pub online: bool,
+    pub record: bool,
+    pub bitrate_kbps: u32,
+    /// As reported, e.g. "1080P" or "1920x1080"
+    pub resolution: Option<String>,
+    /// Clients currently pulling a stream of the channel
+    pub connection_count: u32,
+}
+
+impl ChannelStatus {
+    pub(crate) fn from_value(channel: u8, value: &Value) -> Self {
+        let text = |keys: &[&str]| {
+            keys.iter()
+                .find_map(|k| value.get(*k).and_then(|v| v.as_str()))
+                .filter(|s| !s.is_empty())
+                .map(|s| s.to_string())
+        };
+        let number = |keys: &[&str]| {
+            keys.iter()
+                .find_map(|k| value.get(*k).and_then(value_to_u64))
+                .unwrap_or(0) as u32
+        };
+        let record = ChannelRecordStatus::from_value(channel, value);
+
+        Self {
+            channel,
+            name: text(&["ChnName", "ChannelName", "Name"]),
+            online: record.stream_online,
+            record: record.recording.unwrap_or(false),
+            bitrate_kbps: record.bitrate_kbps.unwrap_or(0),
+            resolution: text(&["CurRes", "Resolution"]),
+            connection_count: number(&["ConnectCount", "ConnCount", "LinkNum"]),
+        }
+    }
+}
+
+/// Health of the device, each value is `None` when the firmware doesn't report it
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MachineStatus {
+    pub uptime: Option<std::time::Duration>,
+    /// Percent
+    pub cpu_usage: Option<u8>,
+    pub temperature_c: Option<f32>,
+}
+
+impl MachineStatus {
+    fn from_value(value: &Value) -> Self {
+        let field = |keys: &[&str]| {
+            keys.iter()
+                .find_map(|k| value.get(*k).and_then(value_to_u64))
+        };
+
+        Self {
+            uptime: field(&["RunTime", "UpTime", "DeviceRunTime"])
+                .map(|minutes| std::time::Duration::from_secs(minutes * 60)),
+            cpu_usage: field(&["CPUUsage", "CpuUsage", "CPU"]).map(|c| c.min(100) as u8),
+            temperature_c: ["Temperature", "Temp", "CPUTemp"]
+                .iter()
+                .find_map(|k| value.get(*k).and_then(|t| t.as_f64()))
+                .map(|t| t as f32),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.uptime.is_none() && self.cpu_usage.is_none() && self.temperature_c.is_none()
+    }
+}
+
+/// Outbound stream load of the device
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BandwidthStats {
+    /// Sum of the channel bitrates the device reported
+    pub total_kbps: u64,
+    /// Bitrate of each channel, in channel order, `None` for the channels without one
+    pub per_channel_kbps: Vec<Option<u32>>,
+}
+
+/// Read a number that can be sent as a number, a decimal string or a `0x` hex string
+pub(crate) fn value_to_u64(value: &Value) -> Option<u64> {
+    match value {
+        Value::Number(n) => n.as_u64(),
+        Value::String(s) if s.starts_with("0x") || s.starts_with("0X") => hex_to_u64(s),
+        Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+/// Read a flag that can be a bool, a number/hex string or a status word
+pub(crate) fn value_to_bool(value: &Value) -> Option<bool> {
+    match value {
+        Value::Bool(b) => Some(*b),
+        Value::String(s) if s.eq_ignore_ascii_case("connected") => Some(true),
+        Value::String(s) if s.eq_ignore_ascii_case("unconnect") => Some(false),
+        Value::String(s) if s.eq_ignore_ascii_case("noconfig") => Some(false),
+        _ => value_to_u64(value).map(|n| n != 0),
+    }
+}
+
+impl ChannelRecordStatus {
+    pub(crate) fn from_value(channel: u8, value: &Value) -> Self {
+        let flag = |keys: &[&str]| {
+            keys.iter()
+                .find_map(|k| value.get(*k).and_then(value_to_bool))
+        };
+
+        Self {
+            channel,
+            recording: flag(&["RecordState", "Record"]),
+            stream_online: flag(&["Status", "Online"]).unwrap_or(false),
+            bitrate_kbps: ["BitRate", "Bitrate"]
+                .iter()
+                .find_map(|k| value.get(*k).and_then(value_to_u64))
+                .map(|kbps| kbps as u32),
+        }
+    }
+}
+
+/// Analog video standard, `General.Location.VideoFormat`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, AsRefStr, EnumString)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum VideoStandard {
+    /// 25 fps
+    #[strum(serialize = "PAL")]
+    Pal,
+    /// 30 fps
+    #[strum(serialize = "NTSC")]
+    Ntsc,
+}
+
+/// When the device reboots itself, `General.AutoMaintain.AutoRebootDay`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, AsRefStr, EnumString)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum RebootDay {
+    /// Automatic reboot disabled
+    #[default]
+    Never,
+    Everyday,
+    Sunday,
+    Monday,
+    Tuesday,
+    Wednesday,
+    Thursday,
+    Friday,
+    Saturday,
+}
+
+/// Scheduled reboot of the device
+///
+/// The firmware only takes a day and a full hour, there is no minute setting
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AutoRebootConfig {
+    pub day: RebootDay,
+    /// Hour of the day (0 to 23) the reboot happens
+    pub hour: u8,
+}
+
+impl AutoRebootConfig {
+    /// `None` when the day isn't one the device is expected to send
+    fn from_value(value: &Value) -> Option<Self> {
+        let day = value.get("AutoRebootDay").and_then(|d| d.as_str())?;
+        Some(Self {
+            day: RebootDay::from_str(day).ok()?,
+            hour: value
+                .get("AutoRebootHour")
+                .and_then(value_to_u64)
+                .unwrap_or(0) as u8,
+        })
+    }
+
+    /// Write the schedule over `value`, keeping the other maintenance settings
+    fn apply(&self, value: &mut Value) -> Result<()> {
+        if self.hour > 23 {
+            return Err(DVRIPError::InvalidParameter(format!(
+                "Reboot hour {} must be between 0 and 23",
+                self.hour
+            )));
+        }
+        value["AutoRebootDay"] = json!(self.day.as_ref());
+        value["AutoRebootHour"] = json!(self.hour);
+        Ok(())
+    }
+}
 
 #[async_trait]
 pub trait SystemInfo: Send + Sync {
     /// Get general system information
     async fn get_system_info(&self) -> Result<Value>;
 
+    /// Get the video standard (`General.Location.VideoFormat`)
+    async fn get_video_standard(&self) -> Result<VideoStandard>;
+
+    /// Set the video standard, the other `General.Location` settings are kept
+    ///
+    /// The frame rates and resolutions change with it, get them again with
+    /// `get_encode_caps` afterwards. Some models reboot or reset their encode
+    /// settings when it changes
+    async fn set_video_standard(&self, standard: VideoStandard) -> Result<bool>;
+
+    /// Get the scheduled reboot (`General.AutoMaintain`)
+    async fn get_auto_reboot(&self) -> Result<AutoRebootConfig>;
+
+    /// Set the scheduled reboot, `RebootDay::Never` disables it
+    ///
+    /// The other `General.AutoMaintain` settings (e.g. the old files removal) are kept
+    async fn set_auto_reboot(&self, config: AutoRebootConfig) -> Result<bool>;
+
+    /// Get the device serial number, firmware and channel count
+    async fn get_device_identity(&self) -> Result<DeviceIdentity>;
+
     /// Get general information
     async fn get_general_info(&self) -> Result<Value>;
 
+    /// Get the `General.General` settings (device name, disk full behaviour, ...)
+    async fn get_general_settings(&self) -> Result<GeneralResponse>;
+
+    /// Get the device name (`General.General.MachineName`)
+    async fn get_device_name(&self) -> Result<String>;
+
+    /// Set the device name, the other `General.General` settings are kept
+    ///
+    /// The name can be at most `MAX_DEVICE_NAME_LEN` characters among ASCII letters,
+    /// digits, spaces, `-`, `_` and `.`
+    async fn set_device_name(&self, name: &str) -> Result<bool>;
+
     /// Get network information
     async fn get_network_info(&self) -> Result<Value>;
 
@@ -28,20 +293,63 @@ pub trait SystemInfo: Send + Sync {
     /// Get encoding information
     async fn get_encode_info(&self, default_config: bool) -> Result<Value>;
 
-    /// Get current device time
+    /// Get current device time, with milliseconds on firmware that reports them
     async fn get_time(&self) -> Result<DateTime<Local>>;
 
     /// Set device time
     async fn set_time(&self, time: Option<DateTime<Local>>) -> Result<bool>;
 
+    /// Get how far the device clock is ahead of the local one (negative when behind)
+    ///
+    /// Half the round trip of the query is accounted for, the device only reports
+    /// whole seconds so the result is accurate to about a second
+    async fn measure_time_drift(&self) -> Result<chrono::Duration>;
+
+    /// Set the device time to the local time when the drift is larger than `threshold`
+    ///
+    /// Returns `false` when the clock was close enough and nothing was sent
+    async fn sync_time_if_drift_exceeds(&self, threshold: chrono::Duration) -> Result<bool>;
+
+    /// Get the number of video channels, the `with_channel_count` override when set
+    async fn channel_count(&self) -> Result<u32>;
+
     /// Get channel titles
     async fn get_channel_titles(&self) -> Result<Vec<String>>;
 
     /// Set channel titles
+    ///
+    /// Each title can be at most `MAX_CHANNEL_TITLE_LEN` bytes once encoded as UTF-8
+    /// and there can't be more titles than channels on the device.
+    /// Non-latin text is sent as UTF-8, some firmware expects GBK and will show it garbled
     async fn set_channel_titles(&self, titles: Vec<String>) -> Result<bool>;
 
     /// Get channel statuses
     async fn get_channel_statuses(&self) -> Result<Value>;
+
+    /// Get the parsed `get_channel_statuses`, one entry per channel in channel order
+    async fn get_channel_status_list(&self) -> Result<Vec<ChannelStatus>>;
+
+    /// Get whether each channel is online and recording
+    ///
+    /// Everything comes from `NetWork.ChnStatus`. The NVR replies seen so far only
+    /// have `ChnName`, `CurRes`, `MaxRes` and `Status`, so `recording` is `None`
+    /// unless the firmware adds a `RecordState` (or `Record`) flag. Those key names
+    /// are a guess, no reply carrying them has been captured yet
+    async fn get_recording_status(&self) -> Result<Vec<ChannelRecordStatus>>;
+
+    /// Get the uptime, CPU usage and temperature, as far as the device reports them
+    ///
+    /// Firmware that only knows the action form of `OPMachine` (reboot, ...) still
+    /// gets the uptime from `SystemInfo`
+    async fn get_machine_status(&self) -> Result<MachineStatus>;
+
+    /// Get the bitrate the device is sending, per channel and in total
+    ///
+    /// Read from the `BitRate` (or `Bitrate`) key of `NetWork.ChnStatus`, those key
+    /// names are a guess like the record flag of `get_recording_status`. Returns
+    /// `DVRIPError::Unsupported` when no channel reports a bitrate, e.g. on the NVRs
+    /// that only send `ChnName`, `CurRes`, `MaxRes` and `Status`
+    async fn get_bandwidth_usage(&self) -> Result<BandwidthStats>;
 }
 
 #[async_trait]
@@ -50,10 +358,118 @@ impl SystemInfo for DVRIPCam {
         self.get_command("SystemInfo", None).await
     }
 
+    async fn get_video_standard(&self) -> Result<VideoStandard> {
+        let location = self.get_command("General.Location", Some(1042)).await?;
+        location
+            .get("VideoFormat")
+            .and_then(|f| f.as_str())
+            .and_then(|f| VideoStandard::from_str(f).ok())
+            .ok_or_else(|| DVRIPError::ProtocolError("No VideoFormat in Location".to_string()))
+    }
+
+    async fn set_video_standard(&self, standard: VideoStandard) -> Result<bool> {
+        let mut location = self.get_command("General.Location", Some(1042)).await?;
+        if !location.is_object() {
+            return Err(DVRIPError::ProtocolError(
+                "Unexpected General.Location reply".to_string(),
+            ));
+        }
+        location["VideoFormat"] = json!(standard.as_ref());
+
+        let reply = self
+            .set_command("General.Location", location, Some(1040))
+            .await?;
+        if let Some(ret) = reply.get("Ret").and_then(|r| r.as_u64()) {
+            return Ok(OK_CODES.contains(&(ret as u32)));
+        }
+        Ok(false)
+    }
+
+    async fn get_auto_reboot(&self) -> Result<AutoRebootConfig> {
+        let maintain = self.get_command("General.AutoMaintain", Some(1042)).await?;
+        AutoRebootConfig::from_value(&maintain).ok_or_else(|| {
+            DVRIPError::ProtocolError("Unexpected General.AutoMaintain reply".to_string())
+        })
+    }
+
+    async fn set_auto_reboot(&self, config: AutoRebootConfig) -> Result<bool> {
+        let mut maintain = self.get_command("General.AutoMaintain", Some(1042)).await?;
+        if !maintain.is_object() {
+            return Err(DVRIPError::ProtocolError(
+                "Unexpected General.AutoMaintain reply".to_string(),
+            ));
+        }
+        config.apply(&mut maintain)?;
+
+        let reply = self
+            .set_command("General.AutoMaintain", maintain, Some(1040))
+            .await?;
+        if let Some(ret) = reply.get("Ret").and_then(|r| r.as_u64()) {
+            return Ok(OK_CODES.contains(&(ret as u32)));
+        }
+        Ok(false)
+    }
+
+    async fn get_device_identity(&self) -> Result<DeviceIdentity> {
+        let info = self.get_system_info().await?;
+        let mut identity: DeviceIdentity = responses::parse::<SystemInfoResponse>(&info)?.into();
+        if let Some(login) = &self.login_info {
+            identity.device_type = login.device_type.clone();
+            identity.channel_count = identity.channel_count.or(login.channel_count);
+        }
+        Ok(identity)
+    }
+
     async fn get_general_info(&self) -> Result<Value> {
         self.get_command("General", None).await
     }
 
+    async fn get_general_settings(&self) -> Result<GeneralResponse> {
+        let general = self.get_command("General.General", Some(1042)).await?;
+        responses::parse(&general)
+    }
+
+    async fn get_device_name(&self) -> Result<String> {
+        self.get_general_settings()
+            .await?
+            .machine_name
+            .ok_or_else(|| DVRIPError::ProtocolError("No MachineName in General".to_string()))
+    }
+
+    async fn set_device_name(&self, name: &str) -> Result<bool> {
+        if name.trim().is_empty() || name.len() > MAX_DEVICE_NAME_LEN {
+            return Err(DVRIPError::InvalidParameter(format!(
+                "Device name '{}' must be 1 to {} characters long",
+                name, MAX_DEVICE_NAME_LEN
+            )));
+        }
+        if let Some(c) = name
+            .chars()
+            .find(|c| !(c.is_ascii_alphanumeric() || matches!(c, ' ' | '-' | '_' | '.')))
+        {
+            return Err(DVRIPError::InvalidParameter(format!(
+                "Device name '{}' contains '{}', only letters, digits, spaces, '-', '_' and '.' are allowed",
+                name, c
+            )));
+        }
+
+        let mut general = self.get_command("General.General", Some(1042)).await?;
+        if !general.is_object() {
+            return Err(DVRIPError::ProtocolError(
+                "Unexpected General.General reply".to_string(),
+            ));
+        }
+        general["MachineName"] = json!(name);
+
+        let reply = self
+            .set_command("General.General", general, Some(1040))
+            .await?;
+        if let Some(ret) = reply.get("Ret").and_then(|r| r.as_u64()) {
+            return Ok(OK_CODES.contains(&(ret as u32)));
+        }
+        Ok(false)
+    }
+
     async fn get_network_info(&self) -> Result<Value> {
         self.get_command("NetWork.NetCommon", None).await
     }
@@ -94,14 +510,9 @@ impl SystemInfo for DVRIPCam {
             })?
             .to_string();
 
-        let naive = NaiveDateTime::parse_from_str(&time_str, DATE_FORMAT).map_err(|e| {
-            crate::error::DVRIPError::ProtocolError(format!("Error parsing date: {}", e))
-        })?;
-
-        Ok(DateTime::from_naive_utc_and_offset(
-            naive,
-            *Local::now().offset(),
-        ))
+        // The device reports its local wall clock
+        parse_device_time(&time_str)
+            .ok_or_else(|| DVRIPError::ProtocolError(format!("Invalid local time: {}", time_str)))
     }
 
     async fn set_time(&self, time: Option<DateTime<Local>>) -> Result<bool> {
@@ -117,18 +528,75 @@ impl SystemInfo for DVRIPCam {
         Ok(false)
     }
 
+    async fn measure_time_drift(&self) -> Result<chrono::Duration> {
+        let sent = Local::now();
+        let device_time = self.get_time().await?;
+        let received = Local::now();
+
+        // Assume the device read its clock halfway through the round trip
+        let local_time = sent + (received - sent) / 2;
+        Ok(device_time - local_time)
+    }
+
+    async fn sync_time_if_drift_exceeds(&self, threshold: chrono::Duration) -> Result<bool> {
+        let drift = self.measure_time_drift().await?;
+        if drift.abs() <= threshold {
+            return Ok(false);
+        }
+        self.set_time(None).await
+    }
+
+    async fn channel_count(&self) -> Result<u32> {
+        if self.channel_count > 0 {
+            return Ok(self.channel_count as u32);
+        }
+        // Login already told us on most firmware
+        if let Some(channels) = self.login_info.as_ref().and_then(|l| l.channel_count)
+            && channels > 0
+        {
+            return Ok(channels);
+        }
+        let identity = self.get_device_identity().await?;
+        Ok(identity.channel_count.unwrap_or(1).max(1))
+    }
+
     async fn get_channel_titles(&self) -> Result<Vec<String>> {
         let data = self.get_command("ChannelTitle", Some(1048)).await?;
-        if let Some(titles) = data.as_array() {
-            return Ok(titles
-                .iter()
-                .filter_map(|v| v.as_str().map(|s| s.to_string()))
-                .collect());
-        }
-        Ok(vec![])
+        let Some(titles) = data.as_array() else {
+            return Ok(vec![]);
+        };
+
+        // Some clones list titles for channels they don't have
+        let limit = match self.channel_count {
+            0 => titles.len(),
+            count => count as usize,
+        };
+        Ok(titles
+            .iter()
+            .filter_map(|v| v.as_str().map(|s| s.to_string()))
+            .take(limit)
+            .collect())
     }
 
     async fn set_channel_titles(&self, titles: Vec<String>) -> Result<bool> {
+        if let Some(title) = titles.iter().find(|t| t.len() > MAX_CHANNEL_TITLE_LEN) {
+            return Err(DVRIPError::InvalidParameter(format!(
+                "Channel title '{}' is {} bytes long, the maximum is {}",
+                title,
+                title.len(),
+                MAX_CHANNEL_TITLE_LEN
+            )));
+        }
+
+        let channel_count = self.get_channel_titles().await?.len();
+        if channel_count > 0 && titles.len() > channel_count {
+            return Err(DVRIPError::InvalidParameter(format!(
+                "Got {} titles but the device only has {} channels",
+                titles.len(),
+                channel_count
+            )));
+        }
+
         let session = self.session_id();
         let data = serde_json::json!({
             "ChannelTitle": titles,
@@ -146,4 +614,168 @@ impl SystemInfo for DVRIPCam {
     async fn get_channel_statuses(&self) -> Result<Value> {
         self.get_command("NetWork.ChnStatus", None).await
     }
+
+    async fn get_channel_status_list(&self) -> Result<Vec<ChannelStatus>> {
+        let statuses = self.get_channel_statuses().await?;
+
+        Ok(statuses
+            .as_array()
+            .map(|channels| {
+                channels
+                    .iter()
+                    .enumerate()
+                    .map(|(i, c)| ChannelStatus::from_value(i as u8, c))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    async fn get_recording_status(&self) -> Result<Vec<ChannelRecordStatus>> {
+        let statuses = self.get_channel_statuses().await?;
+
+        Ok(statuses
+            .as_array()
+            .map(|channels| {
+                channels
+                    .iter()
+                    .enumerate()
+                    .map(|(i, c)| ChannelRecordStatus::from_value(i as u8, c))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    async fn get_machine_status(&self) -> Result<MachineStatus> {
+        let machine = self.get_command("OPMachine", None).await?;
+        let status = MachineStatus::from_value(&machine);
+        if !status.is_empty() {
+            return Ok(status);
+        }
+
+        // Run time in minutes, as a hex string
+        let info = self.get_system_info().await?;
+        Ok(MachineStatus {
+            uptime: info
+                .get("DeviceRunTime")
+                .and_then(value_to_u64)
+                .map(|minutes| std::time::Duration::from_secs(minutes * 60)),
+            ..status
+        })
+    }
+
+    async fn get_bandwidth_usage(&self) -> Result<BandwidthStats> {
+        let per_channel_kbps: Vec<Option<u32>> = self
+            .get_recording_status()
+            .await?
+            .iter()
+            .map(|status| status.bitrate_kbps)
+            .collect();
+        if per_channel_kbps.iter().all(Option::is_none) {
+            return Err(DVRIPError::Unsupported(
+                "The device doesn't report the channel bitrates".to_string(),
+            ));
+        }
+
+        Ok(BandwidthStats {
+            total_kbps: per_channel_kbps
+                .iter()
+                .flatten()
+                .map(|&kbps| kbps as u64)
+                .sum(),
+            per_channel_kbps,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_device;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn nvr_channel_status_has_no_record_flag() {
+        let (cam, mut device) = test_device::login(DVRIPCam::new("127.0.0.1")).await;
+
+        let reply = json!({
+            "Name": "NetWork.ChnStatus",
+            "Ret": 100,
+            "SessionID": "0x00000011",
+            "NetWork.ChnStatus": [
+                {"ChnName": "CAM01", "CurRes": "1080P", "MaxRes": "1080P", "Status": "Connected"},
+                {"ChnName": "", "CurRes": "", "MaxRes": "", "Status": "NoConfig"},
+            ],
+        });
+        let (statuses, _) = tokio::join!(cam.get_recording_status(), device.answer(reply));
+
+        let statuses = statuses.unwrap();
+        assert_eq!(
+            statuses,
+            vec![
+                ChannelRecordStatus {
+                    channel: 0,
+                    recording: None,
+                    stream_online: true,
+                    bitrate_kbps: None,
+                },
+                ChannelRecordStatus {
+                    channel: 1,
+                    recording: None,
+                    stream_online: false,
+                    bitrate_kbps: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn hex_flags_are_read_as_booleans() {
+        let value = json!({"RecordState": "0x00000001", "Status": "0x0", "BitRate": "0x200"});
+        let status = ChannelRecordStatus::from_value(2, &value);
+
+        assert_eq!(status.recording, Some(true));
+        assert!(!status.stream_online);
+        assert_eq!(status.bitrate_kbps, Some(512));
+    }
+
+    #[tokio::test]
+    async fn time_before_login_is_refused() {
+        let (cam, mut device) = test_device::connect(DVRIPCam::new("127.0.0.1")).await;
+
+        let time = cam.get_time().await;
+        assert!(matches!(time, Err(DVRIPError::AuthenticationError(_))));
+        // Refused before anything is sent
+        assert!(device.try_recv(Duration::from_millis(100)).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn bandwidth_needs_a_reported_bitrate() {
+        let (cam, mut device) = test_device::login(DVRIPCam::new("127.0.0.1")).await;
+
+        let reply = json!({
+            "Name": "NetWork.ChnStatus",
+            "Ret": 100,
+            "NetWork.ChnStatus": [
+                {"ChnName": "CAM01", "Status": "Connected", "BitRate": 1024},
+                {"ChnName": "CAM02", "Status": "Connected"},
+                {"ChnName": "CAM03", "Status": "Connected", "Bitrate": "0x200"},
+            ],
+        });
+        let (usage, _) = tokio::join!(cam.get_bandwidth_usage(), device.answer(reply));
+        assert_eq!(
+            usage.unwrap(),
+            BandwidthStats {
+                total_kbps: 1536,
+                per_channel_kbps: vec![Some(1024), None, Some(512)],
+            }
+        );
+
+        let reply = json!({
+            "Name": "NetWork.ChnStatus",
+            "Ret": 100,
+            "NetWork.ChnStatus": [{"ChnName": "CAM01", "CurRes": "1080P", "Status": "Connected"}],
+        });
+        let (usage, _) = tokio::join!(cam.get_bandwidth_usage(), device.answer(reply));
+        assert!(matches!(usage, Err(DVRIPError::Unsupported(_))));
+    }
 }
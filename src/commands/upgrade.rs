@@ -1,13 +1,131 @@
-use crate::constants::OK_CODES;
-use crate::dvrip::DVRIPCam;
-use crate::error::Result;
+use crate::constants::{CODES, OK_CODES, UPGRADE_FAILURE_CODES, UPGRADE_STARTED, UPGRADE_SUCCESS};
+use crate::dvrip::{DVRIPCam, StreamHandler};
+use crate::error::{DVRIPError, Result};
 use async_trait::async_trait;
+use byteorder::{ByteOrder, LittleEndian};
+use bytes::Bytes;
+use flate2::read::DeflateDecoder;
 use serde_json::{Value, json};
+use std::io::Read;
 use std::sync::Arc;
-use tokio::fs::File;
-use tokio::io::AsyncReadExt;
 
-pub type UpgradeProgressCallback = Box<dyn Fn(String) + Send + Sync>;
+pub type UpgradeProgressCallback = Box<dyn Fn(UpgradeProgress) + Send + Sync>;
+
+/// Steps of an upgrade, `to_string` gives the English message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum UpgradeProgress {
+    /// Bytes of the firmware acknowledged by the device
+    Uploading {
+        sent: u64,
+        total: u64,
+    },
+    /// The device accepted the upload and started flashing
+    Started,
+    /// Percent of the flashing done
+    DeviceProgress(u8),
+    Succeeded,
+    /// The device refused or aborted the upgrade, see `CODES` for the meaning
+    Failed {
+        code: u32,
+    },
+}
+
+impl std::fmt::Display for UpgradeProgress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UpgradeProgress::Uploading { sent, total } => write!(
+                f,
+                "Uploading: {:.1}%",
+                (*sent as f64 / (*total).max(1) as f64) * 100.0
+            ),
+            UpgradeProgress::DeviceProgress(percent) => write!(f, "Upgrading: {}%", percent),
+            UpgradeProgress::Started => f.write_str(code_message(UPGRADE_STARTED).as_str()),
+            UpgradeProgress::Succeeded => f.write_str(code_message(UPGRADE_SUCCESS).as_str()),
+            UpgradeProgress::Failed { code } => f.write_str(code_message(*code).as_str()),
+        }
+    }
+}
+
+impl UpgradeProgress {
+    fn from_code(code: u32) -> Option<Self> {
+        match code {
+            0..=100 => Some(UpgradeProgress::DeviceProgress(code as u8)),
+            UPGRADE_STARTED => Some(UpgradeProgress::Started),
+            UPGRADE_SUCCESS => Some(UpgradeProgress::Succeeded),
+            code if UPGRADE_FAILURE_CODES.contains(&code) => Some(UpgradeProgress::Failed { code }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UpgradeOptions {
+    /// Chunks sent before waiting for their ACKs, 1 (the default) waits for each one.
+    /// A larger window speeds up uploads over high-latency links
+    pub window: usize,
+    /// Flash the file even when it's made for other hardware than the device
+    pub force: bool,
+}
+
+impl Default for UpgradeOptions {
+    fn default() -> Self {
+        Self {
+            window: 1,
+            force: false,
+        }
+    }
+}
+
+// Signature of a zip local file header, XiongMai firmware is a zip archive
+const ZIP_LOCAL_HEADER: u32 = 0x04034B50;
+
+// Largest `InstallDesc` inflated, a real one is a few hundred bytes
+const MAX_INSTALL_DESC: u64 = 64 * 1024;
+
+/// Read the `Hardware` the firmware is made for from its `InstallDesc`
+///
+/// The description can be stored or deflated. `None` when the file isn't a zip, the
+/// description can't be found before an entry whose size is only given after its data
+/// (a data descriptor), or it's compressed with another method
+fn firmware_hardware(data: &[u8]) -> Option<String> {
+    let mut rest = data;
+    while rest.len() >= 30 && LittleEndian::read_u32(&rest[0..4]) == ZIP_LOCAL_HEADER {
+        let flags = LittleEndian::read_u16(&rest[6..8]);
+        let method = LittleEndian::read_u16(&rest[8..10]);
+        let size = LittleEndian::read_u32(&rest[18..22]) as usize;
+        let name_len = LittleEndian::read_u16(&rest[26..28]) as usize;
+        let extra_len = LittleEndian::read_u16(&rest[28..30]) as usize;
+        // With a data descriptor the sizes come after the data, the entry can't be skipped
+        if flags & 0x08 != 0 {
+            return None;
+        }
+
+        let name = rest.get(30..30 + name_len)?;
+        let start = 30 + name_len + extra_len;
+        let content = rest.get(start..start + size)?;
+        if name == b"InstallDesc" {
+            let desc: Value = match method {
+                0 => serde_json::from_slice(content).ok()?,
+                8 => {
+                    let mut inflated = Vec::new();
+                    DeflateDecoder::new(content)
+                        .take(MAX_INSTALL_DESC)
+                        .read_to_end(&mut inflated)
+                        .ok()?;
+                    serde_json::from_slice(&inflated).ok()?
+                }
+                _ => return None,
+            };
+            return desc
+                .get("Hardware")
+                .and_then(|h| h.as_str())
+                .map(|h| h.to_string());
+        }
+        rest = &rest[start + size..];
+    }
+    None
+}
 
 #[async_trait]
 pub trait Upgrade: Send + Sync {
@@ -21,6 +139,27 @@ pub trait Upgrade: Send + Sync {
         packet_size: usize,
         progress_callback: Option<UpgradeProgressCallback>,
     ) -> Result<Value>;
+
+    /// Perform system upgrade with the upload tuned by `options`
+    ///
+    /// Unless `options.force` is set, a firmware whose `InstallDesc` names other
+    /// hardware than `get_upgrade_info` is refused with `DVRIPError::InvalidParameter`
+    /// before anything is sent. Files that aren't a zip with a stored or deflated
+    /// `InstallDesc`, and devices that don't report their `Hardware`, aren't checked
+    async fn upgrade_with_options(
+        &self,
+        filename: &str,
+        packet_size: usize,
+        options: UpgradeOptions,
+        progress_callback: Option<UpgradeProgressCallback>,
+    ) -> Result<Value>;
+
+    /// Push a file to the device (e.g. "System" for firmware, or a logo / custom config
+    /// for firmware that supports it) in `packet_size` chunks
+    ///
+    /// Returns the reply to the start request if the device refuses the transfer,
+    /// otherwise the ACK of the last packet
+    async fn send_file(&self, file_type: &str, data: &[u8], packet_size: usize) -> Result<Value>;
 }
 
 #[async_trait]
@@ -35,165 +174,96 @@ impl Upgrade for DVRIPCam {
         packet_size: usize,
         progress_callback: Option<UpgradeProgressCallback>,
     ) -> Result<Value> {
-        // Iniciar upgrade
-        let start_data = json!({
-            "Action": "Start",
-            "Type": "System",
-        });
-
-        let reply = self
-            .set_command("OPSystemUpgrade", start_data, Some(0x5F0))
-            .await?;
-
-        if let Some(ret) = reply.get("Ret").and_then(|r| r.as_u64())
-            && !OK_CODES.contains(&(ret as u32))
-        {
-            return Ok(reply);
-        }
+        self.upgrade_with_options(
+            filename,
+            packet_size,
+            UpgradeOptions::default(),
+            progress_callback,
+        )
+        .await
+    }
 
+    async fn upgrade_with_options(
+        &self,
+        filename: &str,
+        packet_size: usize,
+        options: UpgradeOptions,
+        progress_callback: Option<UpgradeProgressCallback>,
+    ) -> Result<Value> {
         let callback = progress_callback.map(Arc::new);
+        // Chunks are sliced out of the file without copying them
+        let data = Bytes::from(tokio::fs::read(filename).await?);
+        if !options.force {
+            self.check_firmware_hardware(&data).await?;
+        }
+        let upgrade_msg_id = self.code("OPSendFile").unwrap_or(0x5F2);
 
-        // Send file
-        let mut file = File::open(filename).await?;
-        let mut blocknum = 0u32;
-        let file_metadata = file.metadata().await?;
-        let file_size = file_metadata.len() as usize;
-        let mut sent_bytes = 0usize;
-
-        let pool = self.send_pool.as_ref().clone().ok_or_else(|| {
-            crate::error::DVRIPError::ConnectionError("Did you connect to the camera?".to_string())
-        })?;
-
-        let session = self.session_id();
-        let upgrade_msg_id = 0x5F2;
-
-        loop {
-            let mut buffer = vec![0u8; packet_size];
-            let bytes_read = file.read(&mut buffer).await?;
+        // Listen for the upgrade progress before the last packet goes out. Only the
+        // latest step is kept, a slow callback skips steps instead of blocking the recv loop
+        let (tx, mut rx) = tokio::sync::watch::channel(None);
+        self.stream_handlers
+            .insert(upgrade_msg_id, StreamHandler::Latest(tx));
 
-            if bytes_read == 0 {
-                break;
+        let progress = |sent: usize, total: usize| {
+            if let Some(cb) = &callback {
+                cb(UpgradeProgress::Uploading {
+                    sent: sent as u64,
+                    total: total as u64,
+                });
             }
-
-            buffer.truncate(bytes_read);
-            buffer.extend_from_slice(b"\x0a\x00"); // Append tail for version 0
-
-            let header = crate::protocol::PacketHeader {
-                data_len: buffer.len() as u32,
-                msg_id: upgrade_msg_id,
-                packet_count: blocknum,
-                session,
-                head: 0xFF,
-                version: 0,
-            };
-
-            let (send, recv) =
-                tokio::sync::oneshot::channel::<(crate::protocol::PacketHeader, Vec<u8>)>();
-
-            let request = crate::dvrip::CommandRequest::new(header, buffer)
-                .with_response(send)
-                .with_counter(false)
-                .with_expected_response(upgrade_msg_id);
-
-            pool.send(request).await.map_err(|_| {
-                crate::error::DVRIPError::ConnectionError(
-                    "Failed to send upgrade packet".to_string(),
-                )
-            })?;
-
-            // Wait for partial ACK
-            let (reply_header, reply_data_raw) = recv.await.map_err(|_| {
-                crate::error::DVRIPError::ConnectionError(
-                    "Failed to receive upgrade response".to_string(),
-                )
-            })?;
-
-            if reply_header.msg_id == upgrade_msg_id {
-                let reply_data =
-                    serde_json::from_slice::<Value>(&reply_data_raw[..reply_data_raw.len() - 2])
-                        .map_err(|_| {
-                            crate::error::DVRIPError::SerializationError(
-                                "Failed to parse upgrade response".to_string(),
-                            )
-                        })?;
-
-                if let Some(ret) = reply_data.get("Ret").and_then(|r| r.as_u64())
-                    && ret != 100
-                {
-                    if let Some(cb) = &callback {
-                        cb("Upgrade failed".to_string());
-                    }
-                    return Ok(reply_data);
-                }
+        };
+        let reply = match self
+            .send_file_with_progress("System", data, packet_size, options.window, &progress)
+            .await
+        {
+            Ok(reply) => reply,
+            Err(e) => {
+                self.stream_handlers.remove(&upgrade_msg_id);
+                return Err(e);
             }
+        };
 
-            blocknum += 1;
-            sent_bytes += bytes_read;
-
-            // Progress
+        if let Some(ret) = reply.get("Ret").and_then(|r| r.as_u64())
+            && !OK_CODES.contains(&(ret as u32))
+        {
+            self.stream_handlers.remove(&upgrade_msg_id);
             if let Some(cb) = &callback {
-                let progress = (sent_bytes as f64 / file_size as f64) * 100.0;
-                cb(format!("Uploading: {:.1}%", progress));
+                cb(UpgradeProgress::Failed { code: ret as u32 });
             }
+            return Ok(reply);
         }
 
-        let mut final_packet = vec![0u8; 0];
-        final_packet.extend_from_slice(b"\x0a\x00");
-        let header = crate::protocol::PacketHeader {
-            data_len: final_packet.len() as u32,
-            msg_id: upgrade_msg_id,
-            packet_count: blocknum,
-            session,
-            head: 0xFF,
-            version: 0,
-        };
-        let (send, recv) =
-            tokio::sync::oneshot::channel::<(crate::protocol::PacketHeader, Vec<u8>)>();
-
-        let request = crate::dvrip::CommandRequest::new(header, final_packet)
-            .with_response(send)
-            .with_counter(false)
-            .with_expected_response(upgrade_msg_id);
-
-        pool.send(request).await.map_err(|_| {
-            crate::error::DVRIPError::ConnectionError(
-                "Failed to send final upgrade packet".to_string(),
-            )
-        })?;
-
-        let _ = recv.await; // Consume the immediate ACK for the empty packet
-
-        // Wait for upgrade start confirmation (persistent listener)
-        let (tx, mut rx) = tokio::sync::mpsc::channel(10);
-        self.stream_handlers.insert(upgrade_msg_id, tx);
-
         let result = async {
             loop {
                 // Wait for packets with 0x5F2
-                if let Some((_, reply_data_raw)) = rx.recv().await {
-                    let reply_data = match serde_json::from_slice::<Value>(
-                        &reply_data_raw[..reply_data_raw.len() - 2],
-                    ) {
+                if rx.changed().await.is_ok() {
+                    let latest = rx
+                        .borrow_and_update()
+                        .as_ref()
+                        .map(|(_, data)| data.clone());
+                    let Some(reply_data_raw) = latest else {
+                        continue;
+                    };
+                    let reply_data = match crate::protocol::parse_json(&reply_data_raw) {
                         Ok(v) => v,
                         Err(_) => continue,
                     };
 
-                    if let Some(ret) = reply_data.get("Ret").and_then(|r| r.as_u64()) {
-                        if ret == 515 {
-                            if let Some(cb) = &callback {
-                                cb("Upgrade successful".to_string());
-                            }
-                            return Ok(reply_data);
-                        } else if [512, 513, 514].contains(&(ret as u32)) {
-                            if let Some(cb) = &callback {
-                                cb("Upgrade failed".to_string());
-                            }
-                            return Ok(reply_data);
-                        } else if ret <= 100
-                            && let Some(cb) = &callback
-                        {
-                            cb(format!("Upgrading: {}%", ret));
-                        }
+                    let Some(step) = reply_data
+                        .get("Ret")
+                        .and_then(|r| r.as_u64())
+                        .and_then(|ret| UpgradeProgress::from_code(ret as u32))
+                    else {
+                        continue;
+                    };
+                    if let Some(cb) = &callback {
+                        cb(step);
+                    }
+                    if matches!(
+                        step,
+                        UpgradeProgress::Succeeded | UpgradeProgress::Failed { .. }
+                    ) {
+                        return Ok(reply_data);
                     }
                 } else {
                     return Err(crate::error::DVRIPError::ConnectionError(
@@ -207,4 +277,131 @@ impl Upgrade for DVRIPCam {
         self.stream_handlers.remove(&upgrade_msg_id);
         result
     }
+
+    async fn send_file(&self, file_type: &str, data: &[u8], packet_size: usize) -> Result<Value> {
+        let data = Bytes::copy_from_slice(data);
+        self.send_file_with_progress(file_type, data, packet_size, 1, &|_, _| {})
+            .await
+    }
+}
+
+impl DVRIPCam {
+    /// Refuse a firmware made for other hardware than the device
+    async fn check_firmware_hardware(&self, data: &[u8]) -> Result<()> {
+        let Some(firmware) = firmware_hardware(data) else {
+            return Ok(());
+        };
+        let info = self.get_upgrade_info().await?;
+        let Some(device) = info.get("Hardware").and_then(|h| h.as_str()) else {
+            return Ok(());
+        };
+
+        if !firmware.eq_ignore_ascii_case(device) {
+            return Err(DVRIPError::InvalidParameter(format!(
+                "Firmware is made for {} but the device is {}, set UpgradeOptions::force to flash it anyway",
+                firmware, device
+            )));
+        }
+        Ok(())
+    }
+
+    async fn send_file_with_progress(
+        &self,
+        file_type: &str,
+        data: Bytes,
+        packet_size: usize,
+        window: usize,
+        progress: &(dyn Fn(usize, usize) + Send + Sync),
+    ) -> Result<Value> {
+        let start_data = json!({
+            "Action": "Start",
+            "Type": file_type,
+        });
+
+        let reply = self
+            .set_command("OPSystemUpgrade", start_data, Some(0x5F0))
+            .await?;
+
+        if let Some(ret) = reply.get("Ret").and_then(|r| r.as_u64())
+            && !OK_CODES.contains(&(ret as u32))
+        {
+            return Ok(reply);
+        }
+
+        let msg_id = self.code("OPSendFile").unwrap_or(0x5F2);
+        self.send_chunked_with_progress(msg_id, data, packet_size, window, progress)
+            .await
+    }
+}
+
+/// Human readable message for a code sent during the upgrade
+fn code_message(ret: u32) -> String {
+    CODES
+        .get(&ret)
+        .map(|m| m.to_string())
+        .unwrap_or_else(|| format!("Upgrade failed (code {})", ret))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::Compression;
+    use flate2::write::DeflateEncoder;
+    use std::io::Write;
+
+    /// A zip local file entry, the CRC isn't checked so it's left at 0
+    fn zip_entry(name: &str, method: u16, flags: u16, content: &[u8]) -> Vec<u8> {
+        let data = match method {
+            8 => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(content).unwrap();
+                encoder.finish().unwrap()
+            }
+            _ => content.to_vec(),
+        };
+
+        let mut entry = vec![0u8; 30];
+        LittleEndian::write_u32(&mut entry[0..4], ZIP_LOCAL_HEADER);
+        LittleEndian::write_u16(&mut entry[4..6], 20);
+        LittleEndian::write_u16(&mut entry[6..8], flags);
+        LittleEndian::write_u16(&mut entry[8..10], method);
+        LittleEndian::write_u32(&mut entry[18..22], data.len() as u32);
+        LittleEndian::write_u32(&mut entry[22..26], content.len() as u32);
+        LittleEndian::write_u16(&mut entry[26..28], name.len() as u16);
+        entry.extend_from_slice(name.as_bytes());
+        entry.extend_from_slice(&data);
+        entry
+    }
+
+    const DESC: &[u8] = br#"{"UpgradeCommand":[],"Hardware":"50H20L_S39","Vendor":"General"}"#;
+
+    #[test]
+    fn stored_install_desc_is_read() {
+        let mut firmware = zip_entry("u-boot.bin.img", 0, 0, &[0xA5; 100]);
+        firmware.extend(zip_entry("InstallDesc", 0, 0, DESC));
+
+        assert_eq!(firmware_hardware(&firmware).as_deref(), Some("50H20L_S39"));
+    }
+
+    #[test]
+    fn deflated_install_desc_is_read() {
+        let mut firmware = zip_entry("romfs-x.cramfs.img", 8, 0, &[0xA5; 1000]);
+        firmware.extend(zip_entry("InstallDesc", 8, 0, DESC));
+
+        assert_eq!(firmware_hardware(&firmware).as_deref(), Some("50H20L_S39"));
+    }
+
+    #[test]
+    fn unreadable_firmware_is_not_checked() {
+        assert_eq!(firmware_hardware(&[0xA5; 100]), None);
+
+        // Sizes after the data, the entries behind it can't be found
+        let mut firmware = zip_entry("user-x.cramfs.img", 8, 0x08, &[0xA5; 100]);
+        firmware.extend(zip_entry("InstallDesc", 0, 0, DESC));
+        assert_eq!(firmware_hardware(&firmware), None);
+
+        // Bzip2 or anything else that isn't stored or deflated
+        let firmware = zip_entry("InstallDesc", 12, 0, DESC);
+        assert_eq!(firmware_hardware(&firmware), None);
+    }
 }
@@ -1,9 +1,56 @@
 use crate::constants::OK_CODES;
+#[cfg(feature = "experimental")]
+use crate::constants::UNSUPPORTED_CODES;
 use crate::dvrip::DVRIPCam;
+#[cfg(feature = "experimental")]
+use crate::encoding::{hex_to_u64, ip_from_le_hex, parse_device_time};
+#[cfg(feature = "experimental")]
+use crate::error::DVRIPError;
 use crate::error::Result;
 use crate::protocol::sofia_hash;
+use crate::responses::{self, Group, User};
 use async_trait::async_trait;
+#[cfg(feature = "experimental")]
+use chrono::{DateTime, Local};
 use serde_json::{Value, json};
+#[cfg(feature = "experimental")]
+use std::net::Ipv4Addr;
+
+/// Client logged in to the device, from `get_online_users`
+#[cfg(feature = "experimental")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct OnlineUser {
+    pub username: String,
+    pub ip: Option<Ipv4Addr>,
+    pub login_time: Option<DateTime<Local>>,
+    /// How the client logged in, e.g. "DVRIP-Web", "GUI", "Console"
+    pub login_type: Option<String>,
+    pub session_id: Option<u32>,
+}
+
+#[cfg(feature = "experimental")]
+impl OnlineUser {
+    fn from_value(value: &Value) -> Self {
+        let text = |keys: &[&str]| {
+            keys.iter()
+                .find_map(|k| value.get(*k).and_then(|v| v.as_str()))
+                .filter(|s| !s.is_empty())
+        };
+
+        Self {
+            username: text(&["UserName", "Name"]).unwrap_or_default().to_string(),
+            // Either dotted or the little-endian hex of NetWork.NetCommon
+            ip: text(&["IP", "IPAddr", "HostIP"])
+                .and_then(|ip| ip.parse().ok().or_else(|| ip_from_le_hex(ip))),
+            login_time: text(&["LoginTime", "Time"]).and_then(parse_device_time),
+            login_type: text(&["LoginType", "Type"]).map(|t| t.to_string()),
+            session_id: text(&["SessionID"])
+                .and_then(hex_to_u64)
+                .and_then(|s| u32::try_from(s).ok()),
+        }
+    }
+}
 
 #[async_trait]
 pub trait UserManagement: Send + Sync {
@@ -31,6 +78,12 @@ pub trait UserManagement: Send + Sync {
     /// Get the list of users
     async fn get_users(&self) -> Result<Vec<Value>>;
 
+    /// Get the list of users, parsed
+    async fn list_users(&self) -> Result<Vec<User>>;
+
+    /// Get the list of groups, parsed
+    async fn list_groups(&self) -> Result<Vec<Group>>;
+
     /// Add a new user
     async fn add_user(
         &self,
@@ -55,6 +108,23 @@ pub trait UserManagement: Send + Sync {
 
     /// Delete a user
     async fn delete_user(&self, name: &str) -> Result<bool>;
+
+    /// Get the clients logged in to the device, this connection included
+    ///
+    /// Returns `DVRIPError::Unsupported` when the firmware has no session list.
+    /// Experimental: the message code (1490) and the key names are guesses, no
+    /// firmware reference or captured reply backs them yet
+    #[cfg(feature = "experimental")]
+    async fn get_online_users(&self) -> Result<Vec<OnlineUser>>;
+
+    /// Force another client off the device, `session_id` as in `OnlineUser::session_id`
+    ///
+    /// Returns `DVRIPError::PermissionDenied` when the logged in user may not do it
+    /// and `DVRIPError::Unsupported` when the firmware can't.
+    /// Experimental: the `{"Action": "Kick"}` payload on `OPOnlineUser` isn't a
+    /// documented command, firmware that doesn't know it may ignore it or answer `Ret` 100
+    #[cfg(feature = "experimental")]
+    async fn disconnect_session(&self, session_id: u32) -> Result<bool>;
 }
 
 #[async_trait]
@@ -157,6 +227,22 @@ impl UserManagement for DVRIPCam {
         Ok(vec![])
     }
 
+    async fn list_users(&self) -> Result<Vec<User>> {
+        self.get_users()
+            .await?
+            .iter()
+            .map(responses::parse)
+            .collect()
+    }
+
+    async fn list_groups(&self) -> Result<Vec<Group>> {
+        self.get_groups()
+            .await?
+            .iter()
+            .map(responses::parse)
+            .collect()
+    }
+
     async fn add_user(
         &self,
         name: &str,
@@ -271,4 +357,107 @@ impl UserManagement for DVRIPCam {
         }
         Ok(false)
     }
+
+    #[cfg(feature = "experimental")]
+    async fn get_online_users(&self) -> Result<Vec<OnlineUser>> {
+        let users = self.get_command("OPOnlineUser", None).await?;
+
+        // Firmware without the list answers with an error code instead
+        let Some(users) = users.as_array() else {
+            return Err(DVRIPError::Unsupported("Online user list".to_string()));
+        };
+        Ok(users.iter().map(OnlineUser::from_value).collect())
+    }
+
+    #[cfg(feature = "experimental")]
+    async fn disconnect_session(&self, session_id: u32) -> Result<bool> {
+        if session_id == self.session_id() {
+            return Err(DVRIPError::InvalidParameter(
+                "Use logout to end this session".to_string(),
+            ));
+        }
+
+        let data = json!({
+            "Action": "Kick",
+            "SessionID": format!("0x{:08X}", session_id),
+        });
+        let reply = self.set_command("OPOnlineUser", data, None).await?;
+        if let Some(ret) = reply.get("Ret").and_then(|r| r.as_u64()) {
+            if UNSUPPORTED_CODES.contains(&(ret as u32)) {
+                return Err(DVRIPError::Unsupported(
+                    "Disconnecting sessions".to_string(),
+                ));
+            }
+            return Ok(OK_CODES.contains(&(ret as u32)));
+        }
+        Ok(false)
+    }
+}
+
+#[cfg(all(test, feature = "experimental"))]
+mod tests {
+    use super::*;
+    use crate::test_device;
+
+    // Made up in the shape the parser expects, there's no captured reply yet
+    #[tokio::test]
+    async fn online_users_are_parsed() {
+        let (cam, mut device) = test_device::login(DVRIPCam::new("127.0.0.1")).await;
+
+        let reply = json!({
+            "Name": "OPOnlineUser",
+            "Ret": 100,
+            "OPOnlineUser": [
+                {
+                    "UserName": "admin",
+                    "IPAddr": "0x0A01A8C0",
+                    "LoginTime": "2024-03-01 08:15:00",
+                    "LoginType": "DVRIP-Web",
+                    "SessionID": "0x00000011",
+                },
+                {"Name": "guest", "HostIP": "192.168.1.20", "Type": "GUI"},
+            ],
+        });
+        let (users, (header, _)) = tokio::join!(cam.get_online_users(), device.answer(reply));
+        assert_eq!(header.msg_id, 1490);
+
+        let users = users.unwrap();
+        assert_eq!(users[0].username, "admin");
+        assert_eq!(users[0].ip, Some(Ipv4Addr::new(192, 168, 1, 10)));
+        assert_eq!(
+            users[0].login_time,
+            parse_device_time("2024-03-01 08:15:00")
+        );
+        assert_eq!(users[0].login_type.as_deref(), Some("DVRIP-Web"));
+        assert_eq!(users[0].session_id, Some(0x11));
+        assert_eq!(users[1].username, "guest");
+        assert_eq!(users[1].ip, Some(Ipv4Addr::new(192, 168, 1, 20)));
+        assert_eq!(users[1].login_time, None);
+        assert_eq!(users[1].session_id, None);
+
+        let unsupported = json!({"Name": "OPOnlineUser", "Ret": 102});
+        let (users, _) = tokio::join!(cam.get_online_users(), device.answer(unsupported));
+        assert!(matches!(users, Err(DVRIPError::Unsupported(_))));
+    }
+
+    #[tokio::test]
+    async fn sessions_are_kicked_by_id() {
+        let (cam, mut device) = test_device::login(DVRIPCam::new("127.0.0.1")).await;
+
+        let own = cam.disconnect_session(test_device::SESSION).await;
+        assert!(matches!(own, Err(DVRIPError::InvalidParameter(_))));
+
+        let ok = json!({"Name": "OPOnlineUser", "Ret": 100});
+        let (kicked, (_, request)) = tokio::join!(cam.disconnect_session(0x22), device.answer(ok));
+        assert!(kicked.unwrap());
+        assert_eq!(request["OPOnlineUser"]["Action"], "Kick");
+        assert_eq!(request["OPOnlineUser"]["SessionID"], "0x00000022");
+
+        let denied = json!({"Name": "OPOnlineUser", "Ret": 107});
+        let (kicked, _) = tokio::join!(cam.disconnect_session(0x22), device.answer(denied));
+        assert!(matches!(
+            kicked,
+            Err(DVRIPError::PermissionDenied { code: 107, .. })
+        ));
+    }
 }
@@ -2,6 +2,15 @@ use phf::phf_map;
 
 pub const DATE_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
 
+/// `DATE_FORMAT` with the fraction of a second some firmware appends (".123"), optional
+pub const DATE_FORMAT_FRACTIONAL: &str = "%Y-%m-%d %H:%M:%S%.f";
+
+/// Channel titles are stored in a 64 byte buffer on the device (including the null terminator)
+pub const MAX_CHANNEL_TITLE_LEN: usize = 63;
+
+/// `MachineName` fits in a 32 byte buffer on the device (including the null terminator)
+pub const MAX_DEVICE_NAME_LEN: usize = 31;
+
 pub static CODES: phf::Map<u32, &'static str> = phf_map! {
     100u32 => "OK",
     101u32 => "Unknown error",
@@ -32,18 +41,25 @@ pub static QCODES: phf::Map<&'static str, u16> = phf_map! {
     "ModifyUser" => 1484,
     "DelUser" => 1486,
     "ModifyPassword" => 1488,
+    // Unconfirmed, only used by the experimental get_online_users
+    "OPOnlineUser" => 1490,
     "AlarmInfo" => 1504,
     "AlarmSet" => 1500,
     "ChannelTitle" => 1046,
     "EncodeCapability" => 1360,
     "General" => 1042,
     "KeepAlive" => 1006,
+    "Logout" => 1002,
+    "OPLogQuery" => 1442,
     "OPMachine" => 1450,
     "OPMailTest" => 1636,
     "OPMonitor" => 1413,
+    "OPConfigImport" => 1540,
+    "OPConfigExport" => 1542,
     "OPNetKeyboard" => 1550,
     "OPPTZControl" => 1400,
     "OPSNAP" => 1560,
+    "OPStorageManager" => 1460,
     "OPSendFile" => 0x5F2,
     "OPSystemUpgrade" => 0x5F5,
     "OPTalk" => 1434,
@@ -57,7 +73,22 @@ pub static QCODES: phf::Map<&'static str, u16> = phf_map! {
     "SystemInfo" => 1020,
 };
 
+/// Characters of `key_script_chars` and the `Key` token they press
 pub static KEY_CODES: phf::Map<&'static str, &'static str> = phf_map! {
+    "0" => "0",
+    "1" => "1",
+    "2" => "2",
+    "3" => "3",
+    "4" => "4",
+    "5" => "5",
+    "6" => "6",
+    "7" => "7",
+    "8" => "8",
+    "9" => "9",
+    "\n" => "Enter",
+    "P" => "Play",
+    "C" => "Rec",
+    "Z" => "PTZ",
     "M" => "Menu",
     "I" => "Info",
     "E" => "Esc",
@@ -69,7 +100,46 @@ pub static KEY_CODES: phf::Map<&'static str, &'static str> = phf_map! {
     "D" => "Down",
 };
 
-pub const OK_CODES: &[u32] = &[100, 515];
+pub const OK_CODES: &[u32] = &[100, UPGRADE_SUCCESS];
+
+// Codes sent on the upgrade stream, anything from 0 to 100 is the progress in percent
+pub const UPGRADE_STARTED: u32 = 511;
+pub const UPGRADE_NOT_STARTED: u32 = 512;
+pub const UPGRADE_DATA_ERROR: u32 = 513;
+pub const UPGRADE_ERROR: u32 = 514;
+pub const UPGRADE_SUCCESS: u32 = 515;
+
+/// Upgrade codes that end the upgrade without success
+pub const UPGRADE_FAILURE_CODES: &[u32] = &[UPGRADE_NOT_STARTED, UPGRADE_DATA_ERROR, UPGRADE_ERROR];
+
+/// Login codes that usually mean a previous session is still being torn down,
+/// "User already logged in". 101 is a generic error and isn't retried
+pub const RETRYABLE_LOGIN_CODES: &[u32] = &[104];
+
+/// Login codes that can't be fixed by retrying (bad user, bad password, blacklisted)
+pub const LOGIN_ERROR_CODES: &[u32] = &[106, 203, 205, 207];
+
+/// Codes sent when the logged in user isn't allowed to run the command
+pub const PERMISSION_DENIED_CODES: &[u32] = &[103, 107];
+
+/// Codes sent by firmware that doesn't implement the request
+pub const UNSUPPORTED_CODES: &[u32] = &[102];
+
+/// Default limit for the payload size announced in a packet header, anything bigger
+/// is treated as a corrupt stream instead of being allocated
+pub const MAX_PACKET_SIZE: usize = 8 * 1024 * 1024;
+
+/// Keep-alive interval used when the login reply has none, in seconds
+pub const DEFAULT_ALIVE_INTERVAL: u64 = 20;
+
+/// Shortest keep-alive interval accepted from the device, in seconds
+pub const MIN_ALIVE_INTERVAL: u64 = 5;
+
+/// Keep-alives left unanswered in a row before the connection is given up as dead
+pub const MAX_MISSED_KEEP_ALIVES: u32 = 3;
+
+/// Reply to the login (1000), its header carries the protocol version of the device
+pub const LOGIN_REPLY_MSG_ID: u16 = 1001;
 
 pub const TCP_PORT: u16 = 34567;
 pub const UDP_PORT: u16 = 34568;
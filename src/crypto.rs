@@ -0,0 +1,184 @@
+//! AES payloads of the devices setting `DataUseAES` in their login reply.
+//!
+//! After the login every JSON payload, tail included, is padded with zeros to a
+//! multiple of 16 bytes and encrypted with AES-128 in ECB or CBC mode. Replies are
+//! decrypted the same way unless they already are plain JSON.
+//!
+//! How the firmware derives its key isn't known (see TODO.md), it has to be given
+//! with `DVRIPCam::with_aes_key`
+
+use crate::error::{DVRIPError, Result};
+use aes::Aes128;
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::{BlockDecrypt, BlockEncrypt, KeyInit};
+
+const BLOCK_SIZE: usize = 16;
+
+/// Block cipher mode of the payloads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AesMode {
+    Ecb,
+    /// CBC starting over from this IV for every payload
+    Cbc([u8; BLOCK_SIZE]),
+}
+
+/// Key and mode of the payloads of one device
+#[derive(Clone)]
+pub struct PayloadCipher {
+    cipher: Aes128,
+    mode: AesMode,
+}
+
+impl std::fmt::Debug for PayloadCipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Leaves the key out
+        f.debug_struct("PayloadCipher")
+            .field("mode", &self.mode)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PayloadCipher {
+    pub fn new(key: [u8; BLOCK_SIZE], mode: AesMode) -> Self {
+        Self {
+            cipher: Aes128::new(&GenericArray::from(key)),
+            mode,
+        }
+    }
+
+    /// `data` padded with zeros to whole blocks and encrypted
+    pub fn encrypt(&self, data: &[u8]) -> Vec<u8> {
+        let mut out = data.to_vec();
+        out.resize(data.len().div_ceil(BLOCK_SIZE).max(1) * BLOCK_SIZE, 0);
+
+        let mut previous = match self.mode {
+            AesMode::Ecb => None,
+            AesMode::Cbc(iv) => Some(iv),
+        };
+        for chunk in out.chunks_exact_mut(BLOCK_SIZE) {
+            if let Some(previous) = &previous {
+                chunk.iter_mut().zip(previous).for_each(|(b, p)| *b ^= p);
+            }
+            let block = GenericArray::from_mut_slice(chunk);
+            self.cipher.encrypt_block(block);
+            if let Some(previous) = &mut previous {
+                previous.copy_from_slice(chunk);
+            }
+        }
+        out
+    }
+
+    /// `data` decrypted, the zero padding is left for `parse_json` to strip
+    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+        if !data.len().is_multiple_of(BLOCK_SIZE) {
+            return Err(DVRIPError::ProtocolError(format!(
+                "Encrypted payload of {} bytes isn't made of whole AES blocks",
+                data.len()
+            )));
+        }
+
+        let mut out = data.to_vec();
+        let mut previous = match self.mode {
+            AesMode::Ecb => None,
+            AesMode::Cbc(iv) => Some(iv),
+        };
+        for chunk in out.chunks_exact_mut(BLOCK_SIZE) {
+            let encrypted: [u8; BLOCK_SIZE] = chunk.try_into().unwrap_or_default();
+            let block = GenericArray::from_mut_slice(chunk);
+            self.cipher.decrypt_block(block);
+            if let Some(previous) = &mut previous {
+                chunk.iter_mut().zip(&*previous).for_each(|(b, p)| *b ^= p);
+                *previous = encrypted;
+            }
+        }
+        Ok(out)
+    }
+
+    /// A reply decrypted, unless the device sent it as plain JSON (some firmware
+    /// answers errors unencrypted)
+    pub fn decrypt_reply(&self, data: Vec<u8>) -> Result<Vec<u8>> {
+        // Both ends checked, a ciphertext starting with `{` isn't that rare
+        let text = data.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(&data);
+        let first = text.iter().find(|b| !b.is_ascii_whitespace());
+        let last = text
+            .iter()
+            .rfind(|b| !matches!(b, 0x00 | b'\n' | b'\r' | b' ' | b'\t'));
+        let plain = first == Some(&b'{') && last == Some(&b'}');
+        if plain {
+            return Ok(data);
+        }
+        self.decrypt(&data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::parse_json;
+    use serde_json::json;
+
+    const KEY: [u8; 16] = [
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B, 0x0C, 0x0D, 0x0E,
+        0x0F,
+    ];
+
+    #[test]
+    fn ecb_matches_the_fips_197_vector() {
+        let plain = [
+            0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xAA, 0xBB, 0xCC, 0xDD,
+            0xEE, 0xFF,
+        ];
+        let encrypted = [
+            0x69, 0xC4, 0xE0, 0xD8, 0x6A, 0x7B, 0x04, 0x30, 0xD8, 0xCD, 0xB7, 0x80, 0x70, 0xB4,
+            0xC5, 0x5A,
+        ];
+
+        let cipher = PayloadCipher::new(KEY, AesMode::Ecb);
+        assert_eq!(cipher.encrypt(&plain), encrypted);
+        assert_eq!(cipher.decrypt(&encrypted).unwrap(), plain);
+    }
+
+    #[test]
+    fn payloads_are_zero_padded_and_read_back() {
+        let payload = br#"{"Name":"General","SessionID":"0x00000011"}"#;
+        let payload = [payload.as_slice(), b"\x0a\x00"].concat();
+
+        for mode in [AesMode::Ecb, AesMode::Cbc([0x42; 16])] {
+            let cipher = PayloadCipher::new(KEY, mode);
+            let encrypted = cipher.encrypt(&payload);
+            assert_eq!(encrypted.len(), 48);
+            assert_ne!(&encrypted[..payload.len()], payload.as_slice());
+
+            let decrypted = cipher.decrypt_reply(encrypted).unwrap();
+            assert_eq!(&decrypted[..payload.len()], payload.as_slice());
+            assert!(decrypted[payload.len()..].iter().all(|b| *b == 0));
+            assert_eq!(
+                parse_json(&decrypted).unwrap(),
+                json!({"Name": "General", "SessionID": "0x00000011"})
+            );
+        }
+    }
+
+    #[test]
+    fn cbc_chains_the_blocks() {
+        let cipher = PayloadCipher::new(KEY, AesMode::Cbc([0; 16]));
+        let encrypted = cipher.encrypt(&[0x55; 32]);
+        // Same plaintext blocks, different ciphertext once chained
+        assert_ne!(encrypted[..16], encrypted[16..]);
+        assert_eq!(
+            encrypted[..16],
+            PayloadCipher::new(KEY, AesMode::Ecb).encrypt(&[0x55; 16])[..]
+        );
+    }
+
+    #[test]
+    fn plain_replies_and_partial_blocks() {
+        let cipher = PayloadCipher::new(KEY, AesMode::Ecb);
+        let plain = br#" {"Ret":100}"#.to_vec();
+        assert_eq!(cipher.decrypt_reply(plain.clone()).unwrap(), plain);
+        assert!(matches!(
+            cipher.decrypt_reply(vec![0x80; 20]),
+            Err(DVRIPError::ProtocolError(_))
+        ));
+    }
+}
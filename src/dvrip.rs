@@ -1,32 +1,164 @@
-use crate::commands::AlarmCallback;
-use crate::constants::{OK_CODES, QCODES, TCP_PORT};
+use crate::AudioCodec;
+use crate::commands::file_management::PlaybackSession;
+use crate::commands::monitoring::{FrameMetadata, FrameOverflow, Monitors, is_audio, send_frame};
+use crate::commands::{
+    AlarmCallback, AlarmEvent, AlarmEventCallback, AlarmFilter, ConnectionEvent, LoginInfo,
+    RecordBufferLimits, RecordMode,
+};
+use crate::constants::{
+    CODES, DEFAULT_ALIVE_INTERVAL, MAX_MISSED_KEEP_ALIVES, MAX_PACKET_SIZE, OK_CODES,
+    PERMISSION_DENIED_CODES, QCODES, TCP_PORT,
+};
 use crate::error::{DVRIPError, Result};
-use crate::protocol::{PacketHeader, pack_packet, unpack_json};
-use crate::{AudioCodec, FrameMetadata};
+use crate::protocol::{
+    PacketHeader, pack_packet, packet_header, packet_tail, parse_json, unpack_json,
+};
+use crate::proxy::ProxyConfig;
+use bytes::Bytes;
 use dashmap::DashMap;
 use serde_json::{Value, json};
-use tokio::task::JoinHandle;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
 use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
-use tokio::sync::{self, Mutex, broadcast, mpsc};
-use tokio::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicU32, AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use tokio::sync::{self, Mutex, broadcast, mpsc, oneshot, watch};
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, Instant};
+
+pub(crate) type StreamHandlers = DashMap<u16, StreamHandler>;
+
+/// Where the recv loop puts the packets of a message id nobody is waiting a reply on
+pub(crate) enum StreamHandler {
+    /// Every packet, the recv loop waits while the queue is full (downloads, exports)
+    Queue(mpsc::Sender<(PacketHeader, Vec<u8>)>),
+    /// Only the last packet, older ones are overwritten so a slow reader never holds
+    /// up the recv loop (upgrade progress)
+    Latest(watch::Sender<Option<(PacketHeader, Vec<u8>)>>),
+}
+/// Chunks waiting for their ACK and their length
+type PendingChunks = VecDeque<(PendingReply, usize)>;
+
+pub(crate) type ResponseHandlers =
+    DashMap<ReplyKey, tokio::sync::oneshot::Sender<(PacketHeader, Vec<u8>)>>;
+
+/// What a reply is matched on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum ReplyKey {
+    /// The packet count of the request, assigned from the connection counter
+    Counter(u32),
+    /// Message id and block number of a chunk of an upload, numbered apart from
+    /// the counter so it can't take the reply of a command sent meanwhile
+    Chunk(u16, u32),
+}
+
+/// Reply of a queued request, its handler is removed when this is dropped so a
+/// request that times out or is cancelled doesn't stay registered
+pub(crate) struct PendingReply {
+    key: ReplyKey,
+    handlers: Arc<ResponseHandlers>,
+    recv: Option<oneshot::Receiver<(PacketHeader, Vec<u8>)>>,
+}
+
+impl Future for PendingReply {
+    type Output = std::result::Result<(PacketHeader, Vec<u8>), oneshot::error::RecvError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.recv.as_mut() {
+            Some(recv) => Pin::new(recv).poll(cx),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl Drop for PendingReply {
+    fn drop(&mut self) {
+        // A later request registered under the same key still has its receiver
+        drop(self.recv.take());
+        self.handlers
+            .remove_if(&self.key, |_, sender| sender.is_closed());
+    }
+}
+
+/// Everything needed to queue packets on the send task, cheap to clone into tasks
+/// that outlive the `DVRIPCam` borrow
+#[derive(Clone)]
+pub(crate) struct Transport {
+    pool: mpsc::Sender<CommandRequest>,
+    handlers: Arc<ResponseHandlers>,
+    packet_count: Arc<std::sync::Mutex<u32>>,
+}
+
+impl Transport {
+    /// Queue `request`, numbered from the connection counter unless it was built
+    /// `with_counter(false)`
+    pub(crate) async fn send(&self, request: CommandRequest) -> Result<()> {
+        self.queue(request, false).await.map(|_| ())
+    }
+
+    /// Queue `request` and register for its reply
+    pub(crate) async fn send_with_reply(&self, request: CommandRequest) -> Result<PendingReply> {
+        self.queue(request, true)
+            .await?
+            .ok_or_else(|| DVRIPError::ConnectionError("Not connected".to_string()))
+    }
+
+    async fn queue(
+        &self,
+        mut request: CommandRequest,
+        reply: bool,
+    ) -> Result<Option<PendingReply>> {
+        let permit = self
+            .pool
+            .reserve()
+            .await
+            .map_err(|_| DVRIPError::ConnectionError("Not connected".to_string()))?;
+
+        // Numbered and queued under the lock so packets go out in counter order
+        let mut packet_count = self.packet_count.lock().unwrap_or_else(|e| e.into_inner());
+        if request.use_internal_counter {
+            request.header.packet_count = *packet_count;
+            *packet_count = packet_count.wrapping_add(1);
+            request.use_internal_counter = false;
+        }
+
+        let pending = reply.then(|| {
+            let key = request.reply_key();
+            let (send, recv) = oneshot::channel();
+            self.handlers.insert(key, send);
+            PendingReply {
+                key,
+                handlers: Arc::clone(&self.handlers),
+                recv: Some(recv),
+            }
+        });
+        permit.send(request);
+        Ok(pending)
+    }
+}
 
 pub struct CommandRequest {
     pub header: PacketHeader,
-    pub data: Vec<u8>,
+    pub data: Bytes,
+    /// Written after `data` without copying them together, counted in `header.data_len`
+    pub tail: &'static [u8],
     pub response_sender: Option<tokio::sync::oneshot::Sender<(PacketHeader, Vec<u8>)>>,
     pub use_internal_counter: bool,
     pub expected_response_id: Option<u16>,
+    pub flush: bool,
 }
 
 impl CommandRequest {
-    pub fn new(header: PacketHeader, data: Vec<u8>) -> Self {
+    pub fn new(header: PacketHeader, data: impl Into<Bytes>) -> Self {
         Self {
             header,
-            data,
+            data: data.into(),
+            tail: b"",
             response_sender: None,
             use_internal_counter: true,
             expected_response_id: None,
+            flush: true,
         }
     }
 
@@ -38,36 +170,127 @@ impl CommandRequest {
         self
     }
 
+    pub fn with_tail(mut self, tail: &'static [u8]) -> Self {
+        self.tail = tail;
+        self
+    }
+
     pub fn with_counter(mut self, use_internal: bool) -> Self {
         self.use_internal_counter = use_internal;
         self
     }
 
+    /// The reply is an ACK of a chunk sent as message `id`, matched on its block
+    /// number (the packet count) instead of the connection counter
     pub fn with_expected_response(mut self, id: u16) -> Self {
         self.expected_response_id = Some(id);
         self
     }
+
+    /// Flush the connection once the packet is written (default). Without it the
+    /// packet can wait in the writer until a later one is flushed or nothing else is
+    /// queued, for bursts like the chunks of an upload
+    pub fn with_flush(mut self, flush: bool) -> Self {
+        self.flush = flush;
+        self
+    }
+
+    /// Key the reply of this request is matched on, once its packet count is set
+    pub(crate) fn reply_key(&self) -> ReplyKey {
+        let header = &self.header;
+        if let Some(msg_id) = self.expected_response_id {
+            return ReplyKey::Chunk(msg_id, header.packet_count);
+        }
+        // 0x0585 is the code for starting the stream
+        // i don't really know why the packet count for this specifically has to be one more but ok
+        if matches!(header.msg_id, 0x0585 | 0x590 | 0x059a) {
+            ReplyKey::Counter(header.packet_count.wrapping_add(1))
+        } else {
+            ReplyKey::Counter(header.packet_count)
+        }
+    }
+}
+
+/// Turn the `Ret` codes that need their own error into one, other codes are left
+/// to the caller
+pub(crate) fn check_ret(reply: &Value) -> Result<()> {
+    let Some(ret) = reply.get("Ret").and_then(|r| r.as_u64()) else {
+        return Ok(());
+    };
+    let code = ret as u32;
+
+    if PERMISSION_DENIED_CODES.contains(&code) {
+        return Err(DVRIPError::PermissionDenied {
+            code,
+            message: CODES.get(&code).copied().unwrap_or_default().to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// Make sure a reply answers `command`, a reply routed to the wrong request would
+/// otherwise be returned as its data. Replies without a name and unnamed commands
+/// (only identified by their message id) are accepted
+pub(crate) fn check_name(command: &str, reply: &Value) -> Result<()> {
+    match reply.get("Name").and_then(|n| n.as_str()) {
+        Some(name) if !name.is_empty() && !command.is_empty() && name != command => Err(
+            DVRIPError::ProtocolError(format!("Got a reply to {} instead of {}", name, command)),
+        ),
+        _ => Ok(()),
+    }
 }
 
 pub struct DVRIPCam {
     pub(crate) ip: String,
     pub(crate) port: u16,
-    pub(crate) timeout: Duration,
+    pub(crate) command_timeout: Duration,
+    // None lets the socket read wait for as long as it takes
+    pub(crate) idle_read_timeout: Option<Duration>,
+    pub(crate) proxy: Option<ProxyConfig>,
+    pub(crate) max_packet_size: usize,
+    // 0 means read it from the device
+    pub(crate) channel_count: u8,
+    pub(crate) keep_alive: bool,
+    pub(crate) require_auth: bool,
+    // Message ids used instead of the ones in QCODES
+    pub(crate) code_overrides: HashMap<String, u16>,
+    // Queue sizes of the commands to send and of the streamed replies (downloads)
+    pub(crate) stream_capacity: usize,
+    // Frames buffered for each monitor and what to do once they're full
+    pub(crate) frame_capacity: usize,
+    pub(crate) frame_overflow: FrameOverflow,
+    #[cfg(feature = "trace")]
+    pub(crate) trace_path: Option<std::path::PathBuf>,
+    // Used once the login reply sets DataUseAES
+    #[cfg(feature = "aes")]
+    pub(crate) aes: Option<crate::crypto::PayloadCipher>,
 
     pub(crate) username: Option<String>,
+    pub(crate) login_info: Option<LoginInfo>,
+
+    // Login retry on transient codes
+    pub(crate) login_retries: u32,
+    pub(crate) login_retry_delay: Duration,
 
     // Atomic state
     pub(crate) connected: Arc<AtomicBool>,
+    pub(crate) closing: Arc<AtomicBool>,
     pub(crate) authenticated: Arc<AtomicBool>,
-    pub(crate) monitoring: Arc<AtomicBool>,
     pub(crate) alarm_monitoring: Arc<AtomicBool>,
 
     // Atomic counters
     pub(crate) session: Arc<AtomicU32>,
 
+    // Version byte of the packet header, taken from the login reply
+    pub(crate) protocol_version: Arc<AtomicU8>,
+
     // Callbacks
     pub(crate) alarm_callback: Arc<Mutex<Option<AlarmCallback>>>,
-    pub(crate) frame_sender: Arc<broadcast::Sender<(FrameMetadata, Vec<u8>)>>,
+    pub(crate) alarm_event_callback: Arc<Mutex<Option<AlarmEventCallback>>>,
+    pub(crate) alarm_filter: Arc<Mutex<AlarmFilter>>,
+
+    // Monitored video streams
+    pub(crate) monitors: Arc<Monitors>,
 
     // Background tasks
     pub(crate) keep_alive_handle: Arc<Mutex<Option<JoinHandle<()>>>>,
@@ -75,14 +298,35 @@ pub struct DVRIPCam {
     pub(crate) send_handle: Arc<Mutex<Option<JoinHandle<()>>>>,
 
     // Stream handlers for persistent listeners (e.g. file download)
-    pub(crate) stream_handlers: Arc<DashMap<u16, mpsc::Sender<(PacketHeader, Vec<u8>)>>>,
+    pub(crate) stream_handlers: Arc<StreamHandlers>,
+
+    // Commands waiting for a reply
+    pub(crate) response_handlers: Arc<ResponseHandlers>,
+    // Packet count of the next request, restarts at 1 on connect
+    pub(crate) packet_count: Arc<std::sync::Mutex<u32>>,
+
+    // Connection lifecycle events
+    pub(crate) events: Arc<broadcast::Sender<ConnectionEvent>>,
 
     // Configuration
     pub(crate) alive_time: Arc<AtomicU64>,
+    // When the last keep-alive was answered, None before the first
+    pub(crate) last_keep_alive: Arc<std::sync::Mutex<Option<Instant>>>,
+    // Keep-alives unanswered since the last answered one
+    pub(crate) keep_alive_failures: Arc<AtomicU32>,
 
     pub(crate) codec: Arc<Mutex<Option<AudioCodec>>>,
+    // Held for a whole snapshot, their continuation packets share one message id
+    pub(crate) snapshot_lock: Arc<Mutex<()>>,
     pub(crate) backchannel_buffer: Arc<Mutex<Vec<u8>>>,
 
+    // File being played by stream_file
+    pub(crate) playback: Arc<std::sync::Mutex<Option<PlaybackSession>>>,
+
+    // Record mode of the channels set to manual recording, restored when it ends
+    pub(crate) manual_record_restore: Arc<DashMap<u8, RecordMode>>,
+    pub(crate) record_buffer_limits: RecordBufferLimits,
+
     pub send_pool: Arc<Option<sync::mpsc::Sender<CommandRequest>>>,
 }
 
@@ -90,28 +334,57 @@ impl DVRIPCam {
     pub fn new(ip: impl Into<String>) -> Self {
         let ip = ip.into();
 
-        let (tx, _s) = broadcast::channel(25);
+        let (events, _) = broadcast::channel(16);
 
         Self {
             ip,
             username: None,
+            login_info: None,
+            login_retries: 2,
+            login_retry_delay: Duration::from_secs(1),
             port: TCP_PORT,
+            proxy: None,
+            max_packet_size: MAX_PACKET_SIZE,
+            channel_count: 0,
+            keep_alive: true,
+            require_auth: true,
+            code_overrides: HashMap::new(),
+            stream_capacity: 100,
+            frame_capacity: 25,
+            frame_overflow: FrameOverflow::default(),
+            #[cfg(feature = "trace")]
+            trace_path: None,
+            #[cfg(feature = "aes")]
+            aes: None,
             codec: Arc::new(Mutex::new(None)),
+            snapshot_lock: Arc::new(Mutex::new(())),
             recv_handle: Arc::new(Mutex::new(None)),
             send_handle: Arc::new(Mutex::new(None)),
-            frame_sender: Arc::new(tx),
-            timeout: Duration::from_secs(10),
+            monitors: Arc::new(DashMap::new()),
+            command_timeout: Duration::from_secs(10),
+            idle_read_timeout: None,
             connected: Arc::new(AtomicBool::new(false)),
+            closing: Arc::new(AtomicBool::new(false)),
             authenticated: Arc::new(AtomicBool::new(false)),
-            monitoring: Arc::new(AtomicBool::new(false)),
             alarm_monitoring: Arc::new(AtomicBool::new(false)),
             session: Arc::new(AtomicU32::new(0)),
+            protocol_version: Arc::new(AtomicU8::new(0)),
             alarm_callback: Arc::new(Mutex::new(None)),
+            alarm_event_callback: Arc::new(Mutex::new(None)),
+            alarm_filter: Arc::new(Mutex::new(AlarmFilter::default())),
             keep_alive_handle: Arc::new(Mutex::new(None)),
-            alive_time: Arc::new(AtomicU64::new(20)),
+            alive_time: Arc::new(AtomicU64::new(DEFAULT_ALIVE_INTERVAL)),
+            last_keep_alive: Arc::new(std::sync::Mutex::new(None)),
+            keep_alive_failures: Arc::new(AtomicU32::new(0)),
             backchannel_buffer: Arc::new(Mutex::new(Vec::new())),
+            playback: Arc::new(std::sync::Mutex::new(None)),
+            manual_record_restore: Arc::new(DashMap::new()),
+            record_buffer_limits: RecordBufferLimits::default(),
             send_pool: Arc::new(None),
             stream_handlers: Arc::new(DashMap::new()),
+            response_handlers: Arc::new(DashMap::new()),
+            packet_count: Arc::new(std::sync::Mutex::new(1)),
+            events: Arc::new(events),
         }
     }
 
@@ -124,36 +397,279 @@ impl DVRIPCam {
         self.session.load(Ordering::Acquire)
     }
 
+    /// How long a command waits for its reply (default 10 seconds), `connect` sets it
+    /// to its own timeout
     pub fn with_timeout(mut self, timeout: Duration) -> Self {
-        self.timeout = timeout;
+        self.command_timeout = timeout;
+        self
+    }
+
+    /// Drop the connection when nothing at all is received for `timeout`. `None` (the
+    /// default) waits forever, so quiet streams aren't cut and dead connections are
+    /// left to the keep-alive to notice
+    pub fn with_idle_read_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_read_timeout = timeout;
+        self
+    }
+
+    /// Connect to the device through a SOCKS5 proxy
+    pub fn with_proxy(mut self, proxy: ProxyConfig) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// Limit the payload size accepted from the device, a header announcing more
+    /// drops the connection instead of allocating it (default `MAX_PACKET_SIZE`)
+    pub fn with_max_packet_size(mut self, max_packet_size: usize) -> Self {
+        self.max_packet_size = max_packet_size;
+        self
+    }
+
+    /// Write every packet sent and received to `path`, see the `trace` module.
+    /// The file is created (truncated) on `connect`
+    #[cfg(feature = "trace")]
+    pub fn with_trace_file(mut self, path: impl Into<std::path::PathBuf>) -> Self {
+        self.trace_path = Some(path.into());
+        self
+    }
+
+    /// AES-128 key and mode of the payloads, used after the login once the device sets
+    /// `DataUseAES` in its reply, see the `crypto` module
+    #[cfg(feature = "aes")]
+    pub fn with_aes_key(mut self, key: [u8; 16], mode: crate::crypto::AesMode) -> Self {
+        self.aes = Some(crate::crypto::PayloadCipher::new(key, mode));
+        self
+    }
+
+    /// Protocol version used until the device announces its own in the login reply
+    /// (default 0). Version 0 payloads end with `\x0a\x00`, later versions with `\x00`
+    pub fn with_protocol_version(self, version: u8) -> Self {
+        self.protocol_version.store(version, Ordering::Release);
+        self
+    }
+
+    /// Protocol version of the packets sent to the device
+    pub fn protocol_version(&self) -> u8 {
+        self.protocol_version.load(Ordering::Acquire)
+    }
+
+    /// Use this channel count instead of the one reported by the device, for clones
+    /// that misreport it. 0 (the default) means auto-detect
+    pub fn with_channel_count(mut self, channel_count: u8) -> Self {
+        self.channel_count = channel_count;
+        self
+    }
+
+    /// Send keep-alives after login (default). Without them the device drops the
+    /// session once it's idle for longer than its `AliveInterval`, so only disable
+    /// them for short one-shot operations
+    pub fn with_keep_alive(mut self, enabled: bool) -> Self {
+        self.keep_alive = enabled;
+        self
+    }
+
+    /// Refuse config commands before `login` (default), they fail with an
+    /// `AuthenticationError` instead of whatever the device answers. Disable it for
+    /// firmware that accepts some commands without a session
+    pub fn with_require_auth(mut self, required: bool) -> Self {
+        self.require_auth = required;
+        self
+    }
+
+    /// Use `msg_id` for the command `name` instead of the one in `QCODES`, for OEM
+    /// firmware that numbers some commands differently
+    pub fn with_code_override(mut self, name: impl Into<String>, msg_id: u16) -> Self {
+        self.code_overrides.insert(name.into(), msg_id);
+        self
+    }
+
+    /// Number of commands waiting to be sent and of packets buffered for a download
+    /// or config export before the reader waits for them (default 100)
+    pub fn with_stream_capacity(mut self, capacity: usize) -> Self {
+        self.stream_capacity = capacity.max(1);
+        self
+    }
+
+    /// Number of frames buffered for each monitor (default 25) and what happens once
+    /// a slow consumer lets them fill up, see `FrameOverflow` (default `DropOldest`)
+    pub fn with_frame_buffer(mut self, capacity: usize, overflow: FrameOverflow) -> Self {
+        self.frame_capacity = capacity.max(1);
+        self.frame_overflow = overflow;
+        self
+    }
+
+    /// Values accepted by `set_record_buffer`, for firmware with other ranges than
+    /// the default 0 to 30 s before and 10 to 300 s after an event
+    pub fn with_record_buffer_limits(mut self, limits: RecordBufferLimits) -> Self {
+        self.record_buffer_limits = limits;
         self
     }
 
+    /// Retry login up to `retries` times, waiting `delay` between attempts,
+    /// when the device answers with a transient code (see `RETRYABLE_LOGIN_CODES`)
+    pub fn with_login_retry(mut self, retries: u32, delay: Duration) -> Self {
+        self.login_retries = retries;
+        self.login_retry_delay = delay;
+        self
+    }
+
+    /// Deliver a media packet to a single frame channel, stamping P-frames with the
+    /// time of the last I-frame
+    ///
+    /// Kept for code driving its own read loop, the connection dispatches the frames
+    /// of its monitors by channel
+    #[doc(hidden)]
     pub async fn __handle_video(
-        frame_sender: Arc<broadcast::Sender<(FrameMetadata, Vec<u8>)>>,
+        frame_sender: Arc<broadcast::Sender<(FrameMetadata, Bytes)>>,
         data: Vec<u8>,
+        last_datetime: &mut Option<chrono::DateTime<chrono::Local>>,
     ) {
-        let Ok((frame, metadata)) = DVRIPCam::read_bin_payload_static(data).await else {
+        let Ok((frame, mut metadata)) = DVRIPCam::read_bin_payload(Bytes::from(data)) else {
             return;
         };
 
-        frame_sender
-            .send((metadata, frame))
-            .expect("Failed to send frame");
+        match metadata.datetime {
+            Some(datetime) => *last_datetime = Some(datetime),
+            None => metadata.datetime = *last_datetime,
+        }
+
+        // No receiver left is not an error of the stream
+        let _ = frame_sender.send((metadata, frame));
     }
 
-    pub async fn __handle_alarm(
-        alarm_callback: Arc<tokio::sync::Mutex<Option<AlarmCallback>>>,
+    /// Deliver a media packet to the monitors of its `channel`, called from the recv loop
+    pub(crate) async fn dispatch_video(
+        monitors: &Monitors,
+        capacity: usize,
+        overflow: FrameOverflow,
+        channel: u8,
+        data: Bytes,
+    ) {
+        let Ok((frame, metadata)) = DVRIPCam::read_bin_payload(data) else {
+            return;
+        };
+
+        // Sent once the map is released, a blocked send mustn't hold its lock
+        let mut deliveries = Vec::new();
+        for mut entry in monitors.iter_mut() {
+            // Frames of a channel nobody monitors are dropped
+            if entry.key().0 != channel {
+                continue;
+            }
+            let is_audio = is_audio(&metadata);
+            if (is_audio && !entry.include_audio()) || (!is_audio && !entry.include_video) {
+                continue;
+            }
+
+            let mut metadata = metadata.clone();
+            entry.clock.stamp(&mut metadata, frame.len());
+            deliveries.push((entry.sender.clone(), Arc::clone(&entry.space), metadata));
+        }
+
+        for (sender, space, metadata) in deliveries {
+            // Nobody listening until the handle is dropped and the entry removed
+            let frame = (metadata, frame.clone());
+            send_frame(&sender, frame, capacity, overflow, &space).await;
+        }
+    }
+
+    /// Deliver an alarm packet to the callback, called from the recv loop in `connect`
+    /// which is the single place alarm packets are read
+    pub(crate) async fn __handle_alarm(
+        alarm_callback: &Mutex<Option<AlarmCallback>>,
+        alarm_event_callback: &Mutex<Option<AlarmEventCallback>>,
+        alarm_filter: &Mutex<AlarmFilter>,
+        events: &broadcast::Sender<ConnectionEvent>,
         decoded_header: PacketHeader,
-        data: Vec<u8>,
+        data: &[u8],
     ) {
-        if let Ok(data) = unpack_json(&data).await
-            && let Some(ref callback) = *alarm_callback.lock().await
-            && let Some(name) = data.get("Name").and_then(|n| n.as_str())
-            && let Some(alarm_data) = data.get(name)
-        {
-            callback(alarm_data.clone(), decoded_header.packet_count);
+        let data = match unpack_json(data).await {
+            Ok(data) => data,
+            Err(e) => {
+                match *alarm_event_callback.lock().await {
+                    Some(ref callback) if !data.is_empty() => callback(
+                        AlarmEvent::Binary(data.to_vec()),
+                        decoded_header.packet_count,
+                    ),
+                    _ => {
+                        let _ = events.send(ConnectionEvent::AlarmReadError(e.to_string()));
+                    }
+                }
+                return;
+            }
+        };
+
+        let Some(alarm_data) = data
+            .get("Name")
+            .and_then(|n| n.as_str())
+            .and_then(|name| data.get(name))
+        else {
+            return;
         };
+        if !alarm_filter.lock().await.matches(alarm_data) {
+            return;
+        }
+
+        if let Some(ref callback) = *alarm_callback.lock().await {
+            callback(alarm_data.clone(), decoded_header.packet_count);
+        }
+        if let Some(ref callback) = *alarm_event_callback.lock().await {
+            callback(
+                AlarmEvent::Json(alarm_data.clone()),
+                decoded_header.packet_count,
+            );
+        }
+    }
+
+    pub(crate) fn check_authenticated(&self) -> Result<()> {
+        if self.require_auth && !self.authenticated.load(Ordering::Acquire) {
+            return Err(DVRIPError::AuthenticationError("Not logged in".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Whether the payloads of a device setting `DataUseAES` can be encrypted
+    pub(crate) fn has_aes_key(&self) -> bool {
+        #[cfg(feature = "aes")]
+        return self.aes.is_some();
+        #[cfg(not(feature = "aes"))]
+        false
+    }
+
+    /// Cipher of the payloads, once logged in to a device that asked for it
+    #[cfg(feature = "aes")]
+    pub(crate) fn payload_cipher(&self) -> Option<&crate::crypto::PayloadCipher> {
+        let encrypted = self.authenticated.load(Ordering::Acquire)
+            && self.login_info.as_ref().is_some_and(|l| l.data_use_aes);
+        self.aes.as_ref().filter(|_| encrypted)
+    }
+
+    /// Message id of a named command, the `with_code_override` one when set
+    pub(crate) fn code(&self, name: &str) -> Option<u16> {
+        self.code_overrides
+            .get(name)
+            .or_else(|| QCODES.get(name))
+            .copied()
+    }
+
+    /// The sender of the send task, every packet goes through it
+    ///
+    /// Fails with `DVRIPError::NotInitialized` until `connect` has been called, the
+    /// commands sent once the connection is lost fail with a `ConnectionError`
+    pub(crate) fn pool(&self) -> Result<mpsc::Sender<CommandRequest>> {
+        self.send_pool
+            .as_ref()
+            .clone()
+            .ok_or(DVRIPError::NotInitialized())
+    }
+
+    /// The send task and the reply handlers, see `Transport`
+    pub(crate) fn transport(&self) -> Result<Transport> {
+        Ok(Transport {
+            pool: self.pool()?,
+            handlers: Arc::clone(&self.response_handlers),
+            packet_count: Arc::clone(&self.packet_count),
+        })
     }
 
     pub async fn send_raw_packet(
@@ -163,42 +679,52 @@ impl DVRIPCam {
         wait_response: bool,
         add_tail: bool,
     ) -> Result<Option<Vec<u8>>> {
+        let transport = self.transport()?;
+
         if !self.connected.load(Ordering::Acquire) {
             return Err(DVRIPError::ConnectionError("Not connected".to_string()));
         }
 
-        let ptr = &*self.send_pool;
-        let pool = ptr.clone().ok_or_else(|| {
-            DVRIPError::ConnectionError("Did you connect to the camera?".to_string())
-        })?;
+        if self.closing.load(Ordering::Acquire) {
+            return Err(DVRIPError::ConnectionError(
+                "Connection is closing".to_string(),
+            ));
+        }
 
         let session = self.session.load(Ordering::Acquire);
+        let version = self.protocol_version();
 
-        let packed = pack_packet(session, 0, msg_id, &data, 0, add_tail).await?;
+        // The tail goes out with the payload instead of being appended to it
+        let tail: &'static [u8] = if add_tail { packet_tail(version) } else { b"" };
+        let header = packet_header(session, 0, msg_id, data.len() + tail.len(), version);
 
-        let mut request = CommandRequest::new(packed.0, packed.1).with_counter(true);
+        let request = CommandRequest::new(header, data)
+            .with_tail(tail)
+            .with_counter(true);
 
         if wait_response {
-            let (send, recv) = tokio::sync::oneshot::channel::<(PacketHeader, Vec<u8>)>();
-            request = request.with_response(send);
-            let _ = pool.send(request).await;
-
-            let response = tokio::time::timeout(self.timeout, recv)
+            // Dropping the reply on timeout removes its handler
+            let reply = transport.send_with_reply(request).await?;
+            let response = tokio::time::timeout(self.command_timeout, reply)
                 .await
                 .map_err(|_| {
                     DVRIPError::ConnectionError("Timeout waiting for response".to_string())
                 })? // Timeout error
                 .map_err(|_| {
-                    DVRIPError::ConnectionError("Channel closed unexpectedly".to_string())
+                    DVRIPError::ConnectionError("Connection lost waiting for response".to_string())
                 })?; // RecvError
 
             return Ok(Some(response.1));
         }
 
-        let _ = pool.send(request).await;
+        transport.send(request).await?;
         Ok(None)
     }
 
+    /// Send a JSON command and return the raw reply
+    ///
+    /// Goes through the send task like every other command, no lock is held while
+    /// waiting so several commands can be in flight, each one awaiting its own reply
     pub(crate) async fn send_command_recv_bin(
         &self,
         msg_id: u16,
@@ -209,6 +735,16 @@ impl DVRIPCam {
             .map_err(|e| DVRIPError::SerializationError(e.to_string()))?
             .into_bytes();
 
+        #[cfg(feature = "aes")]
+        if let Some(cipher) = self.payload_cipher() {
+            // The tail is encrypted along with the JSON
+            let mut plain = data_bytes;
+            plain.extend_from_slice(packet_tail(self.protocol_version()));
+            return self
+                .send_raw_packet(msg_id, cipher.encrypt(&plain), wait_response, false)
+                .await;
+        }
+
         self.send_raw_packet(msg_id, data_bytes, wait_response, true)
             .await
     }
@@ -222,28 +758,49 @@ impl DVRIPCam {
         let Some(data) = self
             .send_command_recv_bin(msg_id, data, wait_response)
             .await?
-            .map(|x| serde_json::from_slice(&x[..x.len() - 2]))
         else {
             return Ok(None);
         };
-        data.map_err(|_| DVRIPError::SerializationError("Failed to parse JSON Header".to_owned()))
+        #[cfg(feature = "aes")]
+        let data = match self.payload_cipher() {
+            Some(cipher) => cipher.decrypt_reply(data)?,
+            None => data,
+        };
+        parse_json(&data).map(Some)
     }
 
     pub(crate) async fn get_command(&self, command: &str, code: Option<u32>) -> Result<Value> {
-        let msg_id =
-            code.unwrap_or_else(|| QCODES.get(command).copied().unwrap_or(0).into()) as u16;
+        self.query_command(command, None, code).await
+    }
+
+    /// Same as `get_command` for the queries taking arguments, `params` is sent
+    /// under the command name
+    pub(crate) async fn query_command(
+        &self,
+        command: &str,
+        params: Option<Value>,
+        code: Option<u32>,
+    ) -> Result<Value> {
+        self.check_authenticated()?;
+        let msg_id = code.unwrap_or_else(|| self.code(command).unwrap_or(0).into()) as u16;
 
         let session = self.session.load(Ordering::Acquire);
-        let data = json!({
+        let mut data = json!({
             "Name": command,
             "SessionID": format!("0x{:08X}", session)
         });
+        if let Some(params) = params {
+            data[command] = params;
+        }
 
         let reply = self
             .send_command(msg_id, data, true)
             .await?
             .ok_or_else(|| DVRIPError::ProtocolError("Empty response".to_string()))?;
 
+        check_name(command, &reply)?;
+        check_ret(&reply)?;
+
         if let Some(ret) = reply.get("Ret")
             && let Some(ret_code) = ret.as_u64()
             && OK_CODES.contains(&(ret_code as u32))
@@ -261,8 +818,8 @@ impl DVRIPCam {
         data: Value,
         code: Option<u32>,
     ) -> Result<Value> {
-        let msg_id =
-            code.unwrap_or_else(|| QCODES.get(command).copied().unwrap_or(0) as u32) as u16;
+        self.check_authenticated()?;
+        let msg_id = code.unwrap_or_else(|| self.code(command).unwrap_or(0) as u32) as u16;
 
         let session = self.session.load(Ordering::Acquire);
         let mut cmd_data = json!({
@@ -276,27 +833,38 @@ impl DVRIPCam {
             .await?
             .ok_or_else(|| DVRIPError::ProtocolError("Empty response".to_string()))?;
 
+        check_name(command, &reply)?;
+        check_ret(&reply)?;
         Ok(reply)
     }
 
     pub(crate) async fn start_keep_alive(&self) {
         let session = self.session.clone();
-        let alive_time = self.alive_time.clone();
-        let stream = self.send_pool.clone();
+        let protocol_version = self.protocol_version.clone();
+        let transport = self.transport().ok();
         let connected = self.connected.clone();
-        let _ = self.timeout;
-        let keep_alive_code = QCODES.get("KeepAlive").copied().unwrap_or(1006);
+        let events = self.events.clone();
+        let last_keep_alive = self.last_keep_alive.clone();
+        let failures = self.keep_alive_failures.clone();
+        let keep_alive_code = self.code("KeepAlive").unwrap_or(1006);
+        let interval = Duration::from_secs(self.alive_time.load(Ordering::Acquire));
+        #[cfg(feature = "aes")]
+        let cipher = self.payload_cipher().cloned();
+        *last_keep_alive.lock().unwrap_or_else(|e| e.into_inner()) = None;
+        failures.store(0, Ordering::Release);
 
         let handle = tokio::spawn(async move {
+            // Ticks stay `interval` apart however long the replies take
+            let mut ticks =
+                tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+            ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
             loop {
+                ticks.tick().await;
                 if !connected.load(Ordering::Acquire) {
                     break;
                 }
 
-                let interval = Duration::from_secs(alive_time.load(Ordering::Acquire));
-                tokio::time::sleep(interval).await;
-
-                let Some(s) = &*stream else {
+                let Some(transport) = &transport else {
                     connected.store(false, Ordering::Release);
                     break;
                 };
@@ -311,25 +879,200 @@ impl DVRIPCam {
                     eprintln!("Failed to serialize keep-alive JSON");
                     continue;
                 };
+                let version = protocol_version.load(Ordering::Acquire);
+                let (data_bytes, add_tail) = (data_bytes.into_bytes(), true);
+                #[cfg(feature = "aes")]
+                let (data_bytes, add_tail) = match &cipher {
+                    Some(cipher) => (
+                        cipher.encrypt(&[&data_bytes[..], packet_tail(version)].concat()),
+                        false,
+                    ),
+                    None => (data_bytes, add_tail),
+                };
 
                 if let Ok((header, body)) = pack_packet(
                     session_id,
                     0, // Keep alive can use fixed counter
                     keep_alive_code,
-                    &data_bytes.into_bytes(),
-                    0,
-                    true,
+                    &data_bytes,
+                    version,
+                    add_tail,
                 )
                 .await
                 {
                     let request = CommandRequest::new(header, body).with_counter(true);
-                    let _ = s.send(request).await.map_err(|e| {
-                        eprintln!("Failed to send keep-alive packet: {}", e);
+                    let Ok(recv) = transport.send_with_reply(request).await else {
+                        connected.store(false, Ordering::Release);
+                        let _ = events.send(ConnectionEvent::KeepAliveFailed);
+                        break;
+                    };
+
+                    // Only counted as alive once the device answers, before the next tick.
+                    // Dropping the reply on timeout removes its handler
+                    let last_keep_alive = Arc::clone(&last_keep_alive);
+                    let failures = Arc::clone(&failures);
+                    let connected = Arc::clone(&connected);
+                    let events = Arc::clone(&events);
+                    #[cfg(feature = "aes")]
+                    let cipher = cipher.clone();
+                    tokio::spawn(async move {
+                        let reply = tokio::time::timeout(interval, recv)
+                            .await
+                            .ok()
+                            .and_then(|reply| reply.ok())
+                            .map(|(_, reply)| reply);
+                        #[cfg(feature = "aes")]
+                        let reply = match &cipher {
+                            Some(cipher) => {
+                                reply.and_then(|reply| cipher.decrypt_reply(reply).ok())
+                            }
+                            None => reply,
+                        };
+                        let answered = reply
+                            .and_then(|reply| parse_json(&reply).ok())
+                            .and_then(|reply| reply.get("Ret").and_then(|r| r.as_u64()))
+                            .is_some_and(|ret| OK_CODES.contains(&(ret as u32)));
+                        if answered {
+                            *last_keep_alive.lock().unwrap_or_else(|e| e.into_inner()) =
+                                Some(Instant::now());
+                            failures.store(0, Ordering::Release);
+                        } else if failures.fetch_add(1, Ordering::AcqRel) + 1
+                            == MAX_MISSED_KEEP_ALIVES
+                        {
+                            // A half-open connection fails nothing else, this is the only sign
+                            connected.store(false, Ordering::Release);
+                            let _ = events.send(ConnectionEvent::KeepAliveFailed);
+                        }
                     });
                 }
             }
         });
 
-        *self.keep_alive_handle.lock().await = Some(handle);
+        if let Some(previous) = self.keep_alive_handle.lock().await.replace(handle) {
+            previous.abort();
+        }
+    }
+
+    /// Send `data` in `packet_size` chunks on `msg_id`, waiting for the ACK of each chunk,
+    /// followed by an empty packet marking the end of the transfer
+    ///
+    /// Returns the first ACK that isn't OK, or the ACK of the final packet
+    pub(crate) async fn send_chunked(
+        &self,
+        msg_id: u16,
+        data: Bytes,
+        packet_size: usize,
+    ) -> Result<Value> {
+        self.send_chunked_with_progress(msg_id, data, packet_size, 1, &|_, _| {})
+            .await
+    }
+
+    /// Same as `send_chunked`, calling `progress(sent, total)` after every acknowledged chunk
+    ///
+    /// Up to `window` chunks are sent before waiting for their ACKs, which are still
+    /// checked in order. The empty end packet only goes out once every chunk is acknowledged
+    pub(crate) async fn send_chunked_with_progress(
+        &self,
+        msg_id: u16,
+        data: Bytes,
+        packet_size: usize,
+        window: usize,
+        progress: &(dyn Fn(usize, usize) + Send + Sync),
+    ) -> Result<Value> {
+        let transport = self.transport()?;
+        let session = self.session.load(Ordering::Acquire);
+        let version = self.protocol_version();
+        let window = window.max(1);
+
+        // Slices of `data`, the chunks aren't copied
+        let packet_size = packet_size.max(1);
+        let chunks = (0..data.len())
+            .step_by(packet_size)
+            .map(|start| data.slice(start..(start + packet_size).min(data.len())))
+            .chain(std::iter::once(Bytes::new()));
+        // Dropping what's left on an early return removes the handlers of the chunks
+        let mut pending = PendingChunks::new();
+        let mut reply = Value::Null;
+        let mut sent = 0;
+
+        for (blocknum, chunk) in chunks.enumerate() {
+            while pending.len() >= window || (chunk.is_empty() && !pending.is_empty()) {
+                let Some((recv, len)) = pending.pop_front() else {
+                    break;
+                };
+                reply = self.wait_chunk_ack(recv).await?;
+                if let Some(ret) = reply.get("Ret").and_then(|r| r.as_u64())
+                    && !OK_CODES.contains(&(ret as u32))
+                {
+                    return Ok(reply);
+                }
+
+                sent += len;
+                progress(sent, data.len());
+            }
+
+            let tail = packet_tail(version);
+            let header = packet_header(
+                session,
+                blocknum as u32,
+                msg_id,
+                chunk.len() + tail.len(),
+                version,
+            );
+            let len = chunk.len();
+
+            // The last (empty) chunk ends the upload, the others are flushed when the
+            // send queue runs empty
+            let request = CommandRequest::new(header, chunk)
+                .with_tail(tail)
+                .with_counter(false)
+                .with_expected_response(msg_id)
+                .with_flush(len == 0);
+
+            // Waits while the send queue is full
+            let recv = transport.send_with_reply(request).await.map_err(|_| {
+                DVRIPError::ConnectionError("Failed to send file packet".to_string())
+            })?;
+
+            if len == 0 {
+                return self.wait_chunk_ack(recv).await;
+            }
+            pending.push_back((recv, len));
+        }
+
+        Ok(reply)
+    }
+
+    async fn wait_chunk_ack(&self, recv: PendingReply) -> Result<Value> {
+        let (_, reply_data) = tokio::time::timeout(self.command_timeout, recv)
+            .await
+            .map_err(|_| DVRIPError::ConnectionError("Timeout waiting for ACK".to_string()))?
+            .map_err(|_| DVRIPError::ConnectionError("Failed to receive file ACK".to_string()))?;
+        unpack_json(&reply_data).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_device;
+
+    #[tokio::test]
+    async fn code_override_replaces_the_qcode() {
+        let cam = DVRIPCam::new("127.0.0.1").with_code_override("SystemInfo", 1060);
+        let (cam, mut device) = test_device::login(cam).await;
+
+        let reply = json!({"Name": "SystemInfo", "Ret": 100, "SessionID": "0x00000011"});
+        let (queried, (header, _)) =
+            tokio::join!(cam.get_command("SystemInfo", None), device.answer(reply));
+        queried.unwrap();
+        assert_eq!(header.msg_id, 1060);
+
+        // The other commands keep their QCODES id
+        let reply = json!({"Name": "General", "Ret": 100, "SessionID": "0x00000011"});
+        let (queried, (header, _)) =
+            tokio::join!(cam.get_command("General", None), device.answer(reply));
+        queried.unwrap();
+        assert_eq!(header.msg_id, 1042);
     }
 }
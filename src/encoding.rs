@@ -0,0 +1,86 @@
+//! Conversions for the number encodings used by the device.
+//!
+//! Sizes, masks and flags are sent as `0x` hex strings, IP addresses as the hex of
+//! their little-endian integer and frame times as bit-packed integers.
+
+use crate::constants::DATE_FORMAT_FRACTIONAL;
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeZone};
+use std::net::Ipv4Addr;
+
+/// Parse a hex string, with or without the `0x` prefix
+pub fn hex_to_u64(text: &str) -> Option<u64> {
+    let text = text.trim();
+    let hex = text
+        .strip_prefix("0x")
+        .or_else(|| text.strip_prefix("0X"))
+        .unwrap_or(text);
+    u64::from_str_radix(hex, 16).ok()
+}
+
+/// Read an IP address as sent in `NetWork.NetCommon`, e.g. "0x0A01A8C0" is 192.168.1.10
+pub fn ip_from_le_hex(text: &str) -> Option<Ipv4Addr> {
+    let value = u32::try_from(hex_to_u64(text)?).ok()?;
+    Some(Ipv4Addr::from(value.to_le_bytes()))
+}
+
+/// Encode an IP address the way `ip_from_le_hex` reads it
+pub fn ip_to_le_hex(ip: Ipv4Addr) -> String {
+    format!("0x{:08X}", u32::from_le_bytes(ip.octets()))
+}
+
+/// Read a time string of the device local clock ("2024-01-31 12:00:00")
+///
+/// Milliseconds are kept when the firmware sends them ("2024-01-31 12:00:00.250"),
+/// most only have whole seconds
+pub fn parse_device_time(text: &str) -> Option<DateTime<Local>> {
+    let naive = NaiveDateTime::parse_from_str(text.trim(), DATE_FORMAT_FRACTIONAL).ok()?;
+    Local.from_local_datetime(&naive).earliest()
+}
+
+/// Unpack the time of a media frame header
+///
+/// From the low bits: second (6), minute (6), hour (5), day (5), month (4)
+/// and years since 2000 (6). `None` when the fields don't make a valid date.
+/// There are no bits left for a fraction, frame times are whole seconds
+pub fn packed_time_to_datetime(value: u32) -> Option<DateTime<Local>> {
+    let second = value & 0x3F;
+    let minute = (value & 0xFC0) >> 6;
+    let hour = (value & 0x1F000) >> 12;
+    let day = (value & 0x3E0000) >> 17;
+    let month = (value & 0x3C00000) >> 22;
+    let year = ((value & 0xFC000000) >> 26) + 2000;
+
+    // Like every other time from the device these are of its local clock
+    let naive =
+        NaiveDate::from_ymd_opt(year as i32, month, day)?.and_hms_opt(hour, minute, second)?;
+    Local.from_local_datetime(&naive).earliest()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pack(year: u32, month: u32, day: u32, hour: u32, minute: u32, second: u32) -> u32 {
+        ((year - 2000) << 26) | (month << 22) | (day << 17) | (hour << 12) | (minute << 6) | second
+    }
+
+    #[test]
+    fn packed_time_is_local_wall_clock() {
+        let time = packed_time_to_datetime(pack(2024, 1, 31, 12, 34, 56)).unwrap();
+        assert_eq!(time.naive_local().to_string(), "2024-01-31 12:34:56");
+        assert_eq!(time, parse_device_time("2024-01-31 12:34:56").unwrap());
+    }
+
+    #[test]
+    fn packed_time_rejects_invalid_dates() {
+        assert_eq!(packed_time_to_datetime(0), None);
+        assert_eq!(packed_time_to_datetime(pack(2024, 2, 30, 0, 0, 0)), None);
+    }
+
+    #[test]
+    fn ip_round_trip() {
+        let ip = Ipv4Addr::new(192, 168, 1, 10);
+        assert_eq!(ip_to_le_hex(ip), "0x0A01A8C0");
+        assert_eq!(ip_from_le_hex("0x0A01A8C0"), Some(ip));
+    }
+}
@@ -8,6 +8,9 @@ pub enum DVRIPError {
     #[error("Authentication error: {0}")]
     AuthenticationError(String),
 
+    #[error("Login failed ({code}): {message}")]
+    LoginFailed { code: u32, message: String },
+
     #[error("Protocol error: {0}")]
     ProtocolError(String),
 
@@ -17,6 +20,16 @@ pub enum DVRIPError {
     #[error("Serialization error: {0}")]
     SerializationError(String),
 
+    #[error("Invalid parameter: {0}")]
+    InvalidParameter(String),
+
+    #[error("Permission denied ({code}): {message}")]
+    PermissionDenied { code: u32, message: String },
+
+    #[error("Not supported by the device: {0}")]
+    Unsupported(String),
+
+    /// `connect` was never called, or `send_audio` came before `start_talk`
     #[error("Not initialized")]
     NotInitialized(),
 
@@ -1,9 +1,30 @@
+#[cfg(feature = "blocking")]
+pub mod blocking;
+pub mod codec;
 pub mod commands;
 pub mod constants;
+#[cfg(feature = "aes")]
+pub mod crypto;
 pub mod dvrip;
+pub mod encoding;
 pub mod error;
+#[cfg(feature = "mp4")]
+pub mod mp4;
 pub mod protocol;
+pub mod proxy;
+pub mod responses;
+#[cfg(test)]
+mod test_device;
+#[cfg(feature = "trace")]
+pub mod trace;
 
+#[cfg(feature = "blocking")]
+pub use blocking::BlockingDVRIPCam;
 pub use commands::*;
+#[cfg(feature = "aes")]
+pub use crypto::AesMode;
 pub use dvrip::DVRIPCam;
-pub use error::{DVRIPError, Result};
\ No newline at end of file
+pub use error::{DVRIPError, Result};
+#[cfg(feature = "mp4")]
+pub use mp4::Mp4Recorder;
+pub use proxy::ProxyConfig;
@@ -0,0 +1,586 @@
+use crate::commands::{FrameMetadata, to_annexb};
+use crate::error::{DVRIPError, Result};
+use std::io::SeekFrom;
+use std::path::Path;
+use tokio::fs::File;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt, BufWriter};
+
+// Sample times are kept in 90kHz ticks like the RTP clock
+const TIMESCALE: u32 = 90_000;
+const MOVIE_TIMESCALE: u32 = 1000;
+const DEFAULT_FPS: u32 = 25;
+// Where the mdat starts (after the ftyp) and the size of its header with the 64 bit size
+const MDAT_OFFSET: u64 = 32;
+const MDAT_HEADER_LEN: u64 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Codec {
+    H264,
+    H265,
+}
+
+impl Codec {
+    fn from_media_type(media_type: &str) -> Option<Self> {
+        match media_type {
+            "h264" => Some(Codec::H264),
+            "h265" => Some(Codec::H265),
+            _ => None,
+        }
+    }
+
+    fn nal_type(self, nal: &[u8]) -> u8 {
+        match self {
+            Codec::H264 => nal[0] & 0x1F,
+            Codec::H265 => (nal[0] >> 1) & 0x3F,
+        }
+    }
+}
+
+struct Sample {
+    size: u32,
+    ticks: Option<u64>,
+    sync: bool,
+}
+
+/// Writes monitored frames to an MP4 file as they come
+///
+/// Only the video is muxed, recording starts at the first I-frame. The samples go
+/// straight to the file, only their sizes and times are kept until `finish` writes
+/// the index at the end. A recording dropped without `finish` has no index and
+/// doesn't play. When the codec isn't h264/h265 the frames are written as the raw
+/// elementary stream.
+pub struct Mp4Recorder {
+    file: BufWriter<File>,
+    codec: Option<Codec>,
+    media_type: Option<String>,
+    width: u32,
+    height: u32,
+    fps: Option<u8>,
+    vps: Option<Vec<u8>>,
+    sps: Option<Vec<u8>>,
+    pps: Option<Vec<u8>>,
+    samples: Vec<Sample>,
+    /// Bytes written after the mdat header, or of the raw stream
+    written: u64,
+}
+
+impl Mp4Recorder {
+    /// Create (truncate) the file at `path`, nothing is written before the first I-frame
+    pub async fn create(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self {
+            file: BufWriter::new(File::create(path).await?),
+            codec: None,
+            media_type: None,
+            width: 0,
+            height: 0,
+            fps: None,
+            vps: None,
+            sps: None,
+            pps: None,
+            samples: Vec::new(),
+            written: 0,
+        })
+    }
+
+    /// Add a frame as received from `start_monitor_bytes`, audio and info frames are ignored
+    pub async fn push(&mut self, metadata: &FrameMetadata, frame: &[u8]) -> Result<()> {
+        let is_keyframe = metadata.frame_type.as_deref() == Some("I");
+        if metadata.frame_type.is_none() || (self.media_type.is_none() && !is_keyframe) {
+            return Ok(());
+        }
+
+        if is_keyframe {
+            if let Some(media_type) = &metadata.media_type {
+                // The header goes out with the first I-frame, once the codec is known
+                if self.media_type.is_none() {
+                    self.codec = Codec::from_media_type(media_type);
+                    if self.codec.is_some() {
+                        self.write_header().await?;
+                    }
+                }
+                self.media_type = Some(media_type.clone());
+            }
+            self.width = metadata.width.unwrap_or(self.width);
+            self.height = metadata.height.unwrap_or(self.height);
+        }
+        if metadata.fps.is_some_and(|f| f > 0) {
+            self.fps = metadata.fps;
+        }
+        let ticks = metadata
+            .pts
+            .map(|pts| (pts.as_secs_f64() * TIMESCALE as f64) as u64);
+
+        let Some(codec) = self.codec else {
+            self.file.write_all(frame).await?;
+            self.written += frame.len() as u64;
+            return Ok(());
+        };
+
+        let media_type = self.media_type.as_deref().unwrap_or_default();
+        let annexb = to_annexb(frame, media_type);
+        let mut size = 0u32;
+
+        for nal in split_annexb(&annexb) {
+            match (codec, codec.nal_type(nal)) {
+                (Codec::H265, 32) => self.vps = Some(nal.to_vec()),
+                (Codec::H264, 7) | (Codec::H265, 33) => self.sps = Some(nal.to_vec()),
+                (Codec::H264, 8) | (Codec::H265, 34) => self.pps = Some(nal.to_vec()),
+                // Access unit delimiters have no place in MP4
+                (Codec::H264, 9) | (Codec::H265, 35) => {}
+                _ => {
+                    self.file
+                        .write_all(&(nal.len() as u32).to_be_bytes())
+                        .await?;
+                    self.file.write_all(nal).await?;
+                    size += 4 + nal.len() as u32;
+                }
+            }
+        }
+
+        if size > 0 {
+            self.written += size as u64;
+            self.samples.push(Sample {
+                size,
+                ticks,
+                sync: is_keyframe,
+            });
+        }
+        Ok(())
+    }
+
+    /// Write the index and close the file
+    ///
+    /// Returns `true` when an MP4 was written and `false` when the codec was unknown
+    /// and the raw elementary stream was written instead
+    pub async fn finish(mut self) -> Result<bool> {
+        let Some(codec) = self.codec else {
+            self.file.flush().await?;
+            self.file.get_ref().sync_all().await?;
+            return Ok(false);
+        };
+        let (Some(sps), Some(pps)) = (&self.sps, &self.pps) else {
+            return Err(DVRIPError::ProtocolError(
+                "No parameter sets received".to_string(),
+            ));
+        };
+
+        let (sample_entry, config) = match codec {
+            Codec::H264 => (*b"avc1", avcc(sps, pps)?),
+            Codec::H265 => {
+                let Some(vps) = &self.vps else {
+                    return Err(DVRIPError::ProtocolError("No VPS received".to_string()));
+                };
+                (*b"hvc1", hvcc(vps, sps, pps))
+            }
+        };
+
+        let moov = self.moov(sample_entry, &config, MDAT_OFFSET + MDAT_HEADER_LEN);
+        self.file.write_all(&moov).await?;
+        self.file.flush().await?;
+
+        // The mdat size is only known now
+        let file = self.file.get_mut();
+        file.seek(SeekFrom::Start(MDAT_OFFSET + 8)).await?;
+        file.write_all(&(self.written + MDAT_HEADER_LEN).to_be_bytes())
+            .await?;
+        file.sync_all().await?;
+        Ok(true)
+    }
+
+    /// `ftyp` and the header of an `mdat` with a 64 bit size, patched by `finish`
+    async fn write_header(&mut self) -> Result<()> {
+        let Some(codec) = self.codec else {
+            return Ok(());
+        };
+        let brand: &[u8] = match codec {
+            Codec::H264 => b"avc1",
+            Codec::H265 => b"hvc1",
+        };
+        let ftyp = ftyp(brand);
+        debug_assert_eq!(ftyp.len() as u64, MDAT_OFFSET);
+        self.file.write_all(&ftyp).await?;
+        self.file.write_all(&1u32.to_be_bytes()).await?;
+        self.file.write_all(b"mdat").await?;
+        self.file.write_all(&MDAT_HEADER_LEN.to_be_bytes()).await?;
+        Ok(())
+    }
+
+    fn durations(&self) -> Vec<u32> {
+        let default = TIMESCALE / self.fps.map_or(DEFAULT_FPS, |f| f as u32);
+        let mut durations: Vec<u32> = self
+            .samples
+            .windows(2)
+            .map(|w| match (w[0].ticks, w[1].ticks) {
+                (Some(a), Some(b)) if b > a => (b - a) as u32,
+                _ => default,
+            })
+            .collect();
+        if !self.samples.is_empty() {
+            durations.push(durations.last().copied().unwrap_or(default));
+        }
+        durations
+    }
+
+    fn moov(&self, sample_entry: [u8; 4], config: &[u8], chunk_offset: u64) -> Vec<u8> {
+        let durations = self.durations();
+        let duration: u64 = durations.iter().map(|d| *d as u64).sum();
+        let movie_duration = (duration * MOVIE_TIMESCALE as u64 / TIMESCALE as u64) as u32;
+
+        let mut mvhd = Vec::new();
+        mvhd.extend_from_slice(&[0; 8]); // creation / modification time
+        mvhd.extend_from_slice(&MOVIE_TIMESCALE.to_be_bytes());
+        mvhd.extend_from_slice(&movie_duration.to_be_bytes());
+        mvhd.extend_from_slice(&0x0001_0000u32.to_be_bytes()); // rate 1.0
+        mvhd.extend_from_slice(&0x0100u16.to_be_bytes()); // volume 1.0
+        mvhd.extend_from_slice(&[0; 10]);
+        mvhd.extend_from_slice(&MATRIX);
+        mvhd.extend_from_slice(&[0; 24]);
+        mvhd.extend_from_slice(&2u32.to_be_bytes()); // next track id
+
+        let mut tkhd = Vec::new();
+        tkhd.extend_from_slice(&[0; 8]);
+        tkhd.extend_from_slice(&1u32.to_be_bytes()); // track id
+        tkhd.extend_from_slice(&[0; 4]);
+        tkhd.extend_from_slice(&movie_duration.to_be_bytes());
+        tkhd.extend_from_slice(&[0; 8]);
+        tkhd.extend_from_slice(&[0; 8]); // layer, alternate group, volume, reserved
+        tkhd.extend_from_slice(&MATRIX);
+        tkhd.extend_from_slice(&(self.width << 16).to_be_bytes());
+        tkhd.extend_from_slice(&(self.height << 16).to_be_bytes());
+
+        let mut mdhd = Vec::new();
+        mdhd.extend_from_slice(&[0; 8]);
+        mdhd.extend_from_slice(&TIMESCALE.to_be_bytes());
+        mdhd.extend_from_slice(&(duration as u32).to_be_bytes());
+        mdhd.extend_from_slice(&0x55C4u16.to_be_bytes()); // "und"
+        mdhd.extend_from_slice(&[0; 2]);
+
+        let mut hdlr = Vec::new();
+        hdlr.extend_from_slice(&[0; 4]);
+        hdlr.extend_from_slice(b"vide");
+        hdlr.extend_from_slice(&[0; 12]);
+        hdlr.extend_from_slice(b"VideoHandler\0");
+
+        let vmhd = full_box(b"vmhd", 0, 1, &[0; 8]);
+        let dref = full_box(
+            b"dref",
+            0,
+            0,
+            &[1u32.to_be_bytes().as_slice(), &full_box(b"url ", 0, 1, &[])].concat(),
+        );
+        let dinf = bx(b"dinf", &dref);
+
+        let stbl = bx(
+            b"stbl",
+            &[
+                self.stsd(sample_entry, config),
+                self.stts(&durations),
+                self.stss(),
+                self.stsz(),
+                full_box(
+                    b"stsc",
+                    0,
+                    0,
+                    &[1u32, 1, self.samples.len() as u32, 1]
+                        .iter()
+                        .flat_map(|v| v.to_be_bytes())
+                        .collect::<Vec<_>>(),
+                ),
+                // Every sample is in one chunk, right after the mdat header
+                full_box(
+                    b"co64",
+                    0,
+                    0,
+                    &[1u32.to_be_bytes().as_slice(), &chunk_offset.to_be_bytes()].concat(),
+                ),
+            ]
+            .concat(),
+        );
+
+        let minf = bx(b"minf", &[vmhd, dinf, stbl].concat());
+        let mdia = bx(
+            b"mdia",
+            &[
+                full_box(b"mdhd", 0, 0, &mdhd),
+                full_box(b"hdlr", 0, 0, &hdlr),
+                minf,
+            ]
+            .concat(),
+        );
+        let trak = bx(b"trak", &[full_box(b"tkhd", 0, 3, &tkhd), mdia].concat());
+
+        bx(b"moov", &[full_box(b"mvhd", 0, 0, &mvhd), trak].concat())
+    }
+
+    fn stsd(&self, sample_entry: [u8; 4], config: &[u8]) -> Vec<u8> {
+        let mut entry = Vec::new();
+        entry.extend_from_slice(&[0; 6]);
+        entry.extend_from_slice(&1u16.to_be_bytes()); // data reference index
+        entry.extend_from_slice(&[0; 16]);
+        entry.extend_from_slice(&(self.width as u16).to_be_bytes());
+        entry.extend_from_slice(&(self.height as u16).to_be_bytes());
+        entry.extend_from_slice(&0x0048_0000u32.to_be_bytes()); // 72 dpi
+        entry.extend_from_slice(&0x0048_0000u32.to_be_bytes());
+        entry.extend_from_slice(&[0; 4]);
+        entry.extend_from_slice(&1u16.to_be_bytes()); // frame count
+        entry.extend_from_slice(&[0; 32]); // compressor name
+        entry.extend_from_slice(&0x0018u16.to_be_bytes()); // depth
+        entry.extend_from_slice(&0xFFFFu16.to_be_bytes());
+        let config_box = match &sample_entry {
+            b"avc1" => bx(b"avcC", config),
+            _ => bx(b"hvcC", config),
+        };
+        entry.extend_from_slice(&config_box);
+
+        full_box(
+            b"stsd",
+            0,
+            0,
+            &[1u32.to_be_bytes().as_slice(), &bx(&sample_entry, &entry)].concat(),
+        )
+    }
+
+    fn stts(&self, durations: &[u32]) -> Vec<u8> {
+        let mut runs: Vec<(u32, u32)> = Vec::new();
+        for duration in durations {
+            match runs.last_mut() {
+                Some((count, last)) if last == duration => *count += 1,
+                _ => runs.push((1, *duration)),
+            }
+        }
+
+        let mut payload = (runs.len() as u32).to_be_bytes().to_vec();
+        for (count, duration) in runs {
+            payload.extend_from_slice(&count.to_be_bytes());
+            payload.extend_from_slice(&duration.to_be_bytes());
+        }
+        full_box(b"stts", 0, 0, &payload)
+    }
+
+    fn stss(&self) -> Vec<u8> {
+        let sync: Vec<u32> = self
+            .samples
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| s.sync)
+            .map(|(i, _)| i as u32 + 1)
+            .collect();
+
+        let mut payload = (sync.len() as u32).to_be_bytes().to_vec();
+        for number in sync {
+            payload.extend_from_slice(&number.to_be_bytes());
+        }
+        full_box(b"stss", 0, 0, &payload)
+    }
+
+    fn stsz(&self) -> Vec<u8> {
+        let mut payload = 0u32.to_be_bytes().to_vec();
+        payload.extend_from_slice(&(self.samples.len() as u32).to_be_bytes());
+        for sample in &self.samples {
+            payload.extend_from_slice(&sample.size.to_be_bytes());
+        }
+        full_box(b"stsz", 0, 0, &payload)
+    }
+}
+
+const MATRIX: [u8; 36] = [
+    0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, //
+    0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, //
+    0, 0, 0, 0, 0, 0, 0, 0, 0x40, 0, 0, 0,
+];
+
+fn bx(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(payload.len() + 8);
+    out.extend_from_slice(&((payload.len() + 8) as u32).to_be_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(payload);
+    out
+}
+
+fn full_box(kind: &[u8; 4], version: u8, flags: u32, payload: &[u8]) -> Vec<u8> {
+    let header = ((version as u32) << 24) | (flags & 0x00FF_FFFF);
+    bx(kind, &[header.to_be_bytes().as_slice(), payload].concat())
+}
+
+/// NAL units of an Annex-B buffer, without their start codes
+fn split_annexb(data: &[u8]) -> Vec<&[u8]> {
+    let mut starts = Vec::new();
+    let mut i = 0;
+    while i + 3 <= data.len() {
+        if data[i] == 0 && data[i + 1] == 0 && data[i + 2] == 1 {
+            starts.push(i + 3);
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+
+    starts
+        .iter()
+        .enumerate()
+        .map(|(n, &start)| {
+            let mut end = starts.get(n + 1).map_or(data.len(), |next| next - 3);
+            // Trailing zero of a 4 byte start code
+            while end > start && data[end - 1] == 0 {
+                end -= 1;
+            }
+            &data[start..end]
+        })
+        .filter(|nal| !nal.is_empty())
+        .collect()
+}
+
+fn ftyp(brand: &[u8]) -> Vec<u8> {
+    bx(
+        b"ftyp",
+        &[
+            b"isom".as_slice(),
+            &0x200u32.to_be_bytes(),
+            b"isom",
+            b"iso2",
+            brand,
+            b"mp41",
+        ]
+        .concat(),
+    )
+}
+
+fn avcc(sps: &[u8], pps: &[u8]) -> Result<Vec<u8>> {
+    // Profile, constraints and level follow the NAL header
+    if sps.len() < 4 {
+        return Err(DVRIPError::ProtocolError(format!(
+            "SPS of {} bytes is too short",
+            sps.len()
+        )));
+    }
+    let mut out = vec![1, sps[1], sps[2], sps[3], 0xFF, 0xE1];
+    out.extend_from_slice(&(sps.len() as u16).to_be_bytes());
+    out.extend_from_slice(sps);
+    out.push(1);
+    out.extend_from_slice(&(pps.len() as u16).to_be_bytes());
+    out.extend_from_slice(pps);
+    Ok(out)
+}
+
+fn hvcc(vps: &[u8], sps: &[u8], pps: &[u8]) -> Vec<u8> {
+    // The general profile_tier_level sits right after the first byte of the SPS payload
+    let rbsp: Vec<u8> = remove_emulation_prevention(&sps[2.min(sps.len())..]);
+    let mut profile = [0u8; 12];
+    if rbsp.len() >= 13 {
+        profile.copy_from_slice(&rbsp[1..13]);
+    }
+
+    let mut out = vec![1];
+    out.extend_from_slice(&profile); // profile space/tier/idc, compatibility, constraints, level
+    out.extend_from_slice(&[0xF0, 0x00]); // min spatial segmentation
+    out.push(0xFC); // parallelism type
+    out.push(0xFD); // chroma 4:2:0
+    out.push(0xF8); // luma bit depth 8
+    out.push(0xF8); // chroma bit depth 8
+    out.extend_from_slice(&[0, 0]); // average frame rate
+    out.push(0x0F); // 1 temporal layer, nested, 4 byte lengths
+    out.push(3);
+    for (nal_type, nal) in [(32u8, vps), (33, sps), (34, pps)] {
+        out.push(0x80 | nal_type);
+        out.extend_from_slice(&1u16.to_be_bytes());
+        out.extend_from_slice(&(nal.len() as u16).to_be_bytes());
+        out.extend_from_slice(nal);
+    }
+    out
+}
+
+fn remove_emulation_prevention(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut zeros = 0;
+    for &b in data {
+        if zeros >= 2 && b == 3 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if b == 0 { zeros + 1 } else { 0 };
+        out.push(b);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const SPS: &[u8] = &[0x67, 0x42, 0xC0, 0x1E, 0xD9, 0x00, 0xA0, 0x47, 0xFE, 0xC8];
+    const PPS: &[u8] = &[0x68, 0xCE, 0x3C, 0x80];
+
+    fn metadata(frame_type: &str, n: u64) -> FrameMetadata {
+        FrameMetadata {
+            width: Some(320),
+            height: Some(240),
+            fps: Some(25),
+            frame_type: Some(frame_type.to_string()),
+            media_type: (frame_type == "I").then(|| "h264".to_string()),
+            datetime: None,
+            pts: Some(Duration::from_millis(40 * n)),
+            sample_rate: None,
+            timestamp_suspect: false,
+        }
+    }
+
+    fn annexb(nals: &[&[u8]]) -> Vec<u8> {
+        nals.iter()
+            .flat_map(|nal| [&[0, 0, 0, 1], *nal].concat())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn recording_reads_back() {
+        let path = std::env::temp_dir().join(format!("dvrip-mp4-{}.mp4", std::process::id()));
+        let idr: &[u8] = &[0x65, 0x88, 0x84, 0x00, 0x33];
+        let p_frame: &[u8] = &[0x41, 0x9A, 0x02, 0x04];
+
+        let mut recorder = Mp4Recorder::create(&path).await.unwrap();
+        // Ignored until the first I-frame
+        recorder
+            .push(&metadata("P", 0), &annexb(&[p_frame]))
+            .await
+            .unwrap();
+        recorder
+            .push(&metadata("I", 0), &annexb(&[SPS, PPS, idr]))
+            .await
+            .unwrap();
+        recorder
+            .push(&metadata("P", 1), &annexb(&[p_frame]))
+            .await
+            .unwrap();
+        recorder
+            .push(&metadata("P", 2), &annexb(&[p_frame]))
+            .await
+            .unwrap();
+        assert!(recorder.finish().await.unwrap());
+
+        let file = std::fs::File::open(&path).unwrap();
+        let size = file.metadata().unwrap().len();
+        let mut reader =
+            ::mp4::Mp4Reader::read_header(std::io::BufReader::new(file), size).unwrap();
+        let track = &reader.tracks()[&1];
+        assert_eq!(track.width(), 320);
+        assert_eq!(track.height(), 240);
+        assert_eq!(track.sequence_parameter_set().unwrap(), SPS);
+        assert_eq!(track.picture_parameter_set().unwrap(), PPS);
+        assert_eq!(reader.sample_count(1).unwrap(), 3);
+
+        let first = reader.read_sample(1, 1).unwrap().unwrap();
+        assert!(first.is_sync);
+        assert_eq!(&first.bytes[..], [&[0, 0, 0, 5], idr].concat());
+        let last = reader.read_sample(1, 3).unwrap().unwrap();
+        assert!(!last.is_sync);
+        assert_eq!(last.start_time, 2 * TIMESCALE as u64 / 25);
+        assert_eq!(&last.bytes[..], [&[0, 0, 0, 4], p_frame].concat());
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn short_sps_is_an_error() {
+        assert!(avcc(&[0x67, 0x42], PPS).is_err());
+    }
+}
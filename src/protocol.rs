@@ -1,6 +1,8 @@
+use crate::constants::MAX_PACKET_SIZE;
 use crate::error::{DVRIPError, Result};
 use byteorder::{ByteOrder, LittleEndian};
 use serde_json::Value;
+use std::io::IoSlice;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 pub struct PacketHeader {
@@ -10,6 +12,9 @@ pub struct PacketHeader {
     pub packet_count: u32,
     pub msg_id: u16,
     pub data_len: u32,
+    /// The bytes the header was decoded from, including the offsets the fields above
+    /// skip (2-3, 12-13). Zeros for headers built to be sent
+    pub raw_header: [u8; PacketHeader::SIZE],
 }
 
 impl PacketHeader {
@@ -37,8 +42,45 @@ impl PacketHeader {
             packet_count: LittleEndian::read_u32(&data[8..12]),
             msg_id: LittleEndian::read_u16(&data[14..16]),
             data_len: LittleEndian::read_u32(&data[16..20]),
+            raw_header: data[..Self::SIZE].try_into().unwrap_or_default(),
         })
     }
+
+    /// Reject a `data_len` above `max_packet_size` before anything gets allocated for it
+    pub fn check_data_len(&self, max_packet_size: usize) -> Result<()> {
+        if self.data_len as usize > max_packet_size {
+            return Err(DVRIPError::ProtocolError(format!(
+                "Packet of {} bytes exceeds the {} bytes limit",
+                self.data_len, max_packet_size
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Bytes appended to every payload, version 0 ends with a newline before the null byte.
+/// Both are stripped by `parse_json` so replies of either version parse the same
+pub fn packet_tail(version: u8) -> &'static [u8] {
+    if version == 0 { b"\x0a\x00" } else { b"\x00" }
+}
+
+/// Header of a packet to send, `data_len` counts the payload and its tail
+pub fn packet_header(
+    session: u32,
+    packet_count: u32,
+    msg_id: u16,
+    data_len: usize,
+    version: u8,
+) -> PacketHeader {
+    PacketHeader {
+        head: 255,
+        version,
+        session,
+        packet_count,
+        msg_id,
+        data_len: data_len as u32,
+        raw_header: [0; PacketHeader::SIZE],
+    }
 }
 
 pub async fn pack_packet(
@@ -49,30 +91,38 @@ pub async fn pack_packet(
     version: u8,
     add_tail: bool,
 ) -> Result<(PacketHeader, Vec<u8>)> {
-    let tail: &[u8] = if !add_tail {
-        b""
-    } else if version == 0 {
-        b"\x0a\x00"
-    } else {
-        b"\x00"
-    };
-    let data_len = (data.len() + tail.len()) as u32;
-
-    let header = PacketHeader {
-        head: 255,
-        version,
+    let tail: &[u8] = if add_tail { packet_tail(version) } else { b"" };
+    let header = packet_header(
         session,
         packet_count,
         msg_id,
-        data_len,
-    };
+        data.len() + tail.len(),
+        version,
+    );
 
-    let mut result = vec![];
+    let mut result = Vec::with_capacity(data.len() + tail.len());
     result.extend_from_slice(data);
     result.extend_from_slice(tail);
     Ok((header, result))
 }
 
+/// Write every buffer in one go where the writer supports it, without copying them
+/// together first. Nothing is flushed, that's up to the caller
+pub async fn write_all_vectored<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    mut bufs: &mut [IoSlice<'_>],
+) -> std::io::Result<()> {
+    IoSlice::advance_slices(&mut bufs, 0);
+    while !bufs.is_empty() {
+        let written = writer.write_vectored(bufs).await?;
+        if written == 0 {
+            return Err(std::io::ErrorKind::WriteZero.into());
+        }
+        IoSlice::advance_slices(&mut bufs, written);
+    }
+    Ok(())
+}
+
 pub async fn write_packet<W: AsyncWrite + Unpin>(
     writer: &mut W,
     session: u32,
@@ -81,23 +131,22 @@ pub async fn write_packet<W: AsyncWrite + Unpin>(
     data: &[u8],
     version: u8,
 ) -> Result<()> {
-    let tail: &[u8] = if version == 0 { b"\x0a\x00" } else { b"\x00" };
-    let data_len = (data.len() + tail.len()) as u32;
-
-    let header = PacketHeader {
-        head: 255,
-        version,
+    let tail = packet_tail(version);
+    let header = packet_header(
         session,
         packet_count,
         msg_id,
-        data_len,
-    };
-
-    let mut packet = header.encode();
-    packet.extend_from_slice(data);
-    packet.extend_from_slice(tail);
+        data.len() + tail.len(),
+        version,
+    );
 
-    writer.write_all(&packet).await?;
+    let encoded = header.encode();
+    let mut bufs = [
+        IoSlice::new(&encoded),
+        IoSlice::new(data),
+        IoSlice::new(tail),
+    ];
+    write_all_vectored(writer, &mut bufs).await?;
     writer.flush().await?;
     Ok(())
 }
@@ -131,11 +180,19 @@ pub async fn receive_packet_header<R: AsyncRead + Unpin>(reader: &mut R) -> Resu
     PacketHeader::decode(&buf)
 }
 
+/// Read `length` bytes, lengths above `MAX_PACKET_SIZE` are rejected without reading
 pub async fn receive_data<R: AsyncRead + Unpin>(
     reader: &mut R,
     length: usize,
     timeout: tokio::time::Duration,
 ) -> Result<Vec<u8>> {
+    if length > MAX_PACKET_SIZE {
+        return Err(DVRIPError::ProtocolError(format!(
+            "Packet of {} bytes exceeds the {} bytes limit",
+            length, MAX_PACKET_SIZE
+        )));
+    }
+
     let mut buf = vec![0u8; length];
     let mut received = 0;
 
@@ -176,18 +233,7 @@ pub async fn receive_data<R: AsyncRead + Unpin>(
 }
 
 pub async fn unpack_json(data: &[u8]) -> Result<Value> {
-    let json_data =
-        if data.len() >= 2 && data[data.len() - 2] == 0x0a && data[data.len() - 1] == 0x00 {
-            &data[..data.len() - 2]
-        } else if !data.is_empty() && data[data.len() - 1] == 0x00 {
-            &data[..data.len() - 1]
-        } else {
-            data
-        };
-
-    let json_str = String::from_utf8_lossy(json_data);
-    serde_json::from_str(&json_str)
-        .map_err(|e| DVRIPError::SerializationError(format!("Error parsing JSON: {}", e)))
+    parse_json(data)
 }
 
 pub async fn receive_json<R: AsyncRead + Unpin>(
@@ -196,21 +242,69 @@ pub async fn receive_json<R: AsyncRead + Unpin>(
     timeout: tokio::time::Duration,
 ) -> Result<Value> {
     let data = receive_data(reader, length, timeout).await?;
-    // Remove tail (\x0a\x00 or \x00)
-    let json_data =
-        if data.len() >= 2 && data[data.len() - 2] == 0x0a && data[data.len() - 1] == 0x00 {
-            &data[..data.len() - 2]
-        } else if !data.is_empty() && data[data.len() - 1] == 0x00 {
-            &data[..data.len() - 1]
-        } else {
-            &data
-        };
+    parse_json(&data)
+}
+
+/// Parse a JSON payload, ignoring a UTF-8 BOM, the tail (`\x0a\x00`), null padding
+/// and any junk after the outermost object
+///
+/// Text that isn't UTF-8 (channel titles in GBK on some firmware) is replaced with
+/// U+FFFD instead of failing the whole reply
+pub fn parse_json(data: &[u8]) -> Result<Value> {
+    let data = data.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(data);
+    let end = data
+        .iter()
+        .rposition(|b| !matches!(b, 0x00 | b'\n' | b'\r' | b' ' | b'\t'))
+        .map_or(0, |i| i + 1);
+    let json_data = &data[..end];
 
-    let json_str = String::from_utf8_lossy(json_data);
-    serde_json::from_str(&json_str)
+    let parse = |json_data: &[u8]| {
+        serde_json::from_slice(json_data).or_else(|e| match outermost_object(json_data) {
+            Some(object) => serde_json::from_slice(object),
+            None => Err(e),
+        })
+    };
+    parse(json_data)
+        .or_else(|e| match std::str::from_utf8(json_data) {
+            Ok(_) => Err(e),
+            Err(_) => parse(String::from_utf8_lossy(json_data).as_bytes()),
+        })
         .map_err(|e| DVRIPError::SerializationError(format!("Error parsing JSON: {}", e)))
 }
 
+/// Find the first balanced `{...}` in `data`, skipping braces inside strings
+fn outermost_object(data: &[u8]) -> Option<&[u8]> {
+    let start = data.iter().position(|b| *b == b'{')?;
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for (i, b) in data.iter().enumerate().skip(start) {
+        if in_string {
+            match b {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+
+        match b {
+            b'"' => in_string = true,
+            b'{' => depth += 1,
+            b'}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(&data[start..=i]);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
 pub fn sofia_hash(password: &str) -> String {
     let digest = md5::compute(password.as_bytes());
 
@@ -227,3 +321,84 @@ pub fn sofia_hash(password: &str) -> String {
     }
     result
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parse_json_strips_bom_and_padding() {
+        let expected = json!({"Name": "General", "Ret": 100});
+        let plain = br#"{"Name":"General","Ret":100}"#;
+
+        assert_eq!(parse_json(plain).unwrap(), expected);
+        assert_eq!(
+            parse_json(&[b"\xEF\xBB\xBF".as_slice(), plain].concat()).unwrap(),
+            expected
+        );
+        assert_eq!(
+            parse_json(&[plain.as_slice(), b"\x0a\x00"].concat()).unwrap(),
+            expected
+        );
+        assert_eq!(
+            parse_json(&[plain.as_slice(), &[0; 32]].concat()).unwrap(),
+            expected
+        );
+        assert_eq!(
+            parse_json(&[plain.as_slice(), b"\x00garbage"].concat()).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn parse_json_accepts_non_utf8_text() {
+        // "通道" in GBK
+        let gbk = [
+            br#"{"Name":""#.as_slice(),
+            &[0xCD, 0xA8, 0xB5, 0xC0],
+            br#"","Ret":100}"#,
+        ]
+        .concat();
+        let reply = parse_json(&gbk).unwrap();
+        assert_eq!(reply["Ret"], 100);
+        assert!(reply["Name"].as_str().unwrap().contains('\u{FFFD}'));
+    }
+
+    #[test]
+    fn parse_json_rejects_garbage() {
+        assert!(parse_json(b"not json").is_err());
+        assert!(parse_json(b"").is_err());
+    }
+
+    #[test]
+    fn both_tails_parse_the_same() {
+        assert_eq!(packet_tail(0), b"\x0a\x00");
+        assert_eq!(packet_tail(1), b"\x00");
+
+        let plain = br#"{"Name":"General","Ret":100}"#;
+        for version in [0, 1] {
+            let payload = [plain.as_slice(), packet_tail(version)].concat();
+            assert_eq!(parse_json(&payload).unwrap(), parse_json(plain).unwrap());
+        }
+    }
+
+    #[test]
+    fn oversized_data_len_is_rejected() {
+        let header = packet_header(0x11, 1, 1001, 4096, 0);
+        assert!(header.check_data_len(4096).is_ok());
+        assert!(matches!(
+            header.check_data_len(4095),
+            Err(DVRIPError::ProtocolError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn oversized_data_is_not_read() {
+        // Nothing to read, asking the reader for the data would fail differently
+        let mut reader: &[u8] = &[];
+        let timeout = tokio::time::Duration::from_secs(1);
+        let result = receive_data(&mut reader, u32::MAX as usize, timeout).await;
+        assert!(matches!(result, Err(DVRIPError::ProtocolError(_))));
+    }
+}
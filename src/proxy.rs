@@ -0,0 +1,147 @@
+use crate::error::{DVRIPError, Result};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const SOCKS_VERSION: u8 = 0x05;
+const AUTH_NONE: u8 = 0x00;
+const AUTH_PASSWORD: u8 = 0x02;
+const AUTH_UNACCEPTABLE: u8 = 0xFF;
+const CMD_CONNECT: u8 = 0x01;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+/// SOCKS5 proxy used to reach the device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyConfig {
+    pub host: String,
+    pub port: u16,
+    /// Username and password, when the proxy requires them
+    pub credentials: Option<(String, String)>,
+}
+
+impl ProxyConfig {
+    pub fn new(host: impl Into<String>, port: u16) -> Self {
+        Self {
+            host: host.into(),
+            port,
+            credentials: None,
+        }
+    }
+
+    pub fn with_credentials(
+        mut self,
+        username: impl Into<String>,
+        password: impl Into<String>,
+    ) -> Self {
+        self.credentials = Some((username.into(), password.into()));
+        self
+    }
+
+    /// Open a tunnel to `host:port` through the proxy
+    pub(crate) async fn connect(&self, host: &str, port: u16) -> Result<TcpStream> {
+        let mut stream = TcpStream::connect((self.host.as_str(), self.port))
+            .await
+            .map_err(|e| DVRIPError::ConnectionError(format!("Proxy connection error: {}", e)))?;
+
+        self.handshake(&mut stream, host, port)
+            .await
+            .map_err(|e| match e {
+                DVRIPError::IoError(e) => {
+                    DVRIPError::ConnectionError(format!("Proxy handshake error: {}", e))
+                }
+                e => e,
+            })?;
+
+        Ok(stream)
+    }
+
+    async fn handshake(&self, stream: &mut TcpStream, host: &str, port: u16) -> Result<()> {
+        let method = if self.credentials.is_some() {
+            AUTH_PASSWORD
+        } else {
+            AUTH_NONE
+        };
+        stream.write_all(&[SOCKS_VERSION, 1, method]).await?;
+
+        let mut reply = [0u8; 2];
+        stream.read_exact(&mut reply).await?;
+        if reply[0] != SOCKS_VERSION || reply[1] == AUTH_UNACCEPTABLE || reply[1] != method {
+            return Err(DVRIPError::ConnectionError(
+                "Proxy refused the authentication method".to_string(),
+            ));
+        }
+
+        if let Some((username, password)) = &self.credentials {
+            if username.len() > 255 || password.len() > 255 {
+                return Err(DVRIPError::InvalidParameter(
+                    "Proxy credentials are limited to 255 bytes".to_string(),
+                ));
+            }
+
+            let mut auth = vec![0x01, username.len() as u8];
+            auth.extend_from_slice(username.as_bytes());
+            auth.push(password.len() as u8);
+            auth.extend_from_slice(password.as_bytes());
+            stream.write_all(&auth).await?;
+
+            stream.read_exact(&mut reply).await?;
+            if reply[1] != 0x00 {
+                return Err(DVRIPError::AuthenticationError(
+                    "Proxy rejected the credentials".to_string(),
+                ));
+            }
+        }
+
+        let mut request = vec![SOCKS_VERSION, CMD_CONNECT, 0x00];
+        match host.parse::<std::net::IpAddr>() {
+            Ok(std::net::IpAddr::V4(ip)) => {
+                request.push(ATYP_IPV4);
+                request.extend_from_slice(&ip.octets());
+            }
+            Ok(std::net::IpAddr::V6(ip)) => {
+                request.push(ATYP_IPV6);
+                request.extend_from_slice(&ip.octets());
+            }
+            Err(_) => {
+                if host.len() > 255 {
+                    return Err(DVRIPError::InvalidParameter(format!(
+                        "Host name {} is too long",
+                        host
+                    )));
+                }
+                request.push(ATYP_DOMAIN);
+                request.push(host.len() as u8);
+                request.extend_from_slice(host.as_bytes());
+            }
+        }
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request).await?;
+
+        let mut header = [0u8; 4];
+        stream.read_exact(&mut header).await?;
+        if header[1] != 0x00 {
+            return Err(DVRIPError::ConnectionError(format!(
+                "Proxy failed to connect to {}:{} (code {})",
+                host, port, header[1]
+            )));
+        }
+
+        // Skip the bound address, it isn't needed
+        let address_len = match header[3] {
+            ATYP_IPV4 => 4,
+            ATYP_IPV6 => 16,
+            ATYP_DOMAIN => stream.read_u8().await? as usize,
+            atyp => {
+                return Err(DVRIPError::ProtocolError(format!(
+                    "Unknown proxy address type {}",
+                    atyp
+                )));
+            }
+        };
+        let mut bound = vec![0u8; address_len + 2];
+        stream.read_exact(&mut bound).await?;
+
+        Ok(())
+    }
+}
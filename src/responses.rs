@@ -0,0 +1,316 @@
+//! Typed shapes of the device replies used the most.
+//!
+//! Every field is optional, firmware versions disagree on what they send. The raw
+//! `Value` methods stay available for anything not covered here.
+
+use crate::commands::system_info::{value_to_bool, value_to_u64};
+use crate::encoding::hex_to_u64;
+use crate::error::{DVRIPError, Result};
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+/// Reply to the login command (1000)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LoginResponse {
+    pub ret: Option<u32>,
+    /// Hex string, e.g. "0x0000000A"
+    pub session_id: Option<String>,
+    /// Interval between keep-alives as sent, usually seconds but some firmware
+    /// uses milliseconds or a string
+    pub alive_interval: Option<u64>,
+    /// Video channels, the NVR/DVR ones only (see `extra_channel`)
+    pub channel_num: Option<u64>,
+    /// Channels added on top of `channel_num`, e.g. IP cameras on a hybrid recorder
+    pub extra_channel: Option<u64>,
+    /// e.g. "IPC", "DVR" or "NVR", some firmware sends a number
+    pub device_type: Option<String>,
+    /// The device expects the payloads to be AES encrypted
+    pub data_use_aes: Option<bool>,
+}
+
+impl LoginResponse {
+    pub fn session_id(&self) -> Option<u32> {
+        let session = hex_to_u64(self.session_id.as_deref()?)?;
+        u32::try_from(session).ok()
+    }
+}
+
+impl<'de> Deserialize<'de> for LoginResponse {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Self, D::Error> {
+        let value = Value::deserialize(deserializer)?;
+        let fields = Fields::of(&value)?;
+        Ok(Self {
+            ret: fields.number(&["Ret"]),
+            session_id: fields.text(&["SessionID"]),
+            alive_interval: fields.number(&["AliveInterval"]),
+            channel_num: fields.number(&["ChannelNum"]),
+            extra_channel: fields.number(&["ExtraChannel"]),
+            // Sent with a trailing space by a lot of firmware
+            device_type: fields.text(&["DeviceType", "DeviceType "]),
+            data_use_aes: fields.flag(&["DataUseAES"]),
+        })
+    }
+}
+
+/// `SystemInfo` section
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SystemInfoResponse {
+    pub serial_number: Option<String>,
+    pub hardware: Option<String>,
+    pub software_version: Option<String>,
+    pub build_time: Option<String>,
+    pub video_in_channels: Option<u32>,
+    pub alarm_in_channels: Option<u32>,
+    pub alarm_out_channels: Option<u32>,
+    pub audio_in_channels: Option<u32>,
+    pub device_run_time: Option<String>,
+}
+
+impl<'de> Deserialize<'de> for SystemInfoResponse {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Self, D::Error> {
+        let value = Value::deserialize(deserializer)?;
+        let fields = Fields::of(&value)?;
+        Ok(Self {
+            serial_number: fields.text(&["SerialNo", "Sn", "SN"]),
+            hardware: fields.text(&["HardWare", "Hardware"]),
+            software_version: fields.text(&["SoftWareVersion", "SoftwareVersion"]),
+            build_time: fields.text(&["BuildTime"]),
+            video_in_channels: fields.number(&["VideoInChannel", "ChannelNum"]),
+            alarm_in_channels: fields.number(&["AlarmInChannel"]),
+            alarm_out_channels: fields.number(&["AlarmOutChannel"]),
+            audio_in_channels: fields.number(&["AudioInChannel"]),
+            device_run_time: fields.text(&["DeviceRunTime"]),
+        })
+    }
+}
+
+/// `General.General` section
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct GeneralResponse {
+    #[serde(default, deserialize_with = "lenient_string")]
+    pub machine_name: Option<String>,
+    #[serde(default, deserialize_with = "lenient_u32")]
+    pub local_no: Option<u32>,
+    /// Minutes before an idle local user is logged out
+    #[serde(default, deserialize_with = "lenient_u32")]
+    pub auto_logout: Option<u32>,
+    /// What to do when the disk is full, "OverWrite" or "StopRecord"
+    #[serde(default, deserialize_with = "lenient_string")]
+    pub over_write: Option<String>,
+    /// "PAL" or "NTSC"
+    #[serde(default, deserialize_with = "lenient_string")]
+    pub video_out_put: Option<String>,
+}
+
+/// Entry of the `Users` list
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct User {
+    #[serde(default, deserialize_with = "lenient_text")]
+    pub name: String,
+    #[serde(default, deserialize_with = "lenient_string")]
+    pub group: Option<String>,
+    #[serde(default, deserialize_with = "lenient_string")]
+    pub memo: Option<String>,
+    #[serde(default, deserialize_with = "lenient_strings")]
+    pub authority_list: Vec<String>,
+    #[serde(default, deserialize_with = "lenient_flag")]
+    pub reserved: bool,
+    #[serde(default, deserialize_with = "lenient_flag")]
+    pub sharable: bool,
+}
+
+/// Entry of the `Groups` list
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Group {
+    #[serde(default, deserialize_with = "lenient_text")]
+    pub name: String,
+    #[serde(default, deserialize_with = "lenient_string")]
+    pub memo: Option<String>,
+    #[serde(default, deserialize_with = "lenient_strings")]
+    pub authority_list: Vec<String>,
+}
+
+/// Fields of a reply object, read from the first of their names that has a usable
+/// value. Firmware disagrees on names and sometimes sends two of them at once
+struct Fields<'a>(&'a serde_json::Map<String, Value>);
+
+impl<'a> Fields<'a> {
+    fn of<E: serde::de::Error>(value: &'a Value) -> std::result::Result<Self, E> {
+        value
+            .as_object()
+            .map(Fields)
+            .ok_or_else(|| E::custom(format!("expected an object, got {}", value)))
+    }
+
+    fn text(&self, keys: &[&str]) -> Option<String> {
+        keys.iter().find_map(|k| value_to_text(self.0.get(*k)?))
+    }
+
+    fn number<T: TryFrom<u64>>(&self, keys: &[&str]) -> Option<T> {
+        keys.iter()
+            .find_map(|k| value_to_u64(self.0.get(*k)?))
+            .and_then(|n| T::try_from(n).ok())
+    }
+
+    fn flag(&self, keys: &[&str]) -> Option<bool> {
+        keys.iter().find_map(|k| value_to_bool(self.0.get(*k)?))
+    }
+}
+
+/// Text that some firmware sends as a number, empty text is `None`
+fn value_to_text(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) if !s.is_empty() => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+/// A number that may be sent as a decimal or `0x` hex string, unreadable values are `None`
+fn lenient_u32<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<u32>, D::Error> {
+    let value = Value::deserialize(deserializer)?;
+    Ok(value_to_u64(&value).and_then(|n| u32::try_from(n).ok()))
+}
+
+/// A flag sent as a bool, a number or a string, unreadable values are `false`
+fn lenient_flag<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<bool, D::Error> {
+    let value = Value::deserialize(deserializer)?;
+    Ok(value_to_bool(&value).unwrap_or(false))
+}
+
+/// Text that some firmware sends as a number
+fn lenient_string<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<String>, D::Error> {
+    Ok(value_to_text(&Value::deserialize(deserializer)?))
+}
+
+/// Same as `lenient_string`, empty when missing
+fn lenient_text<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<String, D::Error> {
+    Ok(lenient_string(deserializer)?.unwrap_or_default())
+}
+
+/// A list of text, skipping the entries that aren't
+fn lenient_strings<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Vec<String>, D::Error> {
+    Ok(match Value::deserialize(deserializer)? {
+        Value::Array(values) => values.iter().filter_map(value_to_text).collect(),
+        _ => vec![],
+    })
+}
+
+/// Deserialize a reply, reporting where it doesn't match the expected shape
+pub fn parse<T: DeserializeOwned>(value: &Value) -> Result<T> {
+    T::deserialize(value).map_err(|e| {
+        DVRIPError::SerializationError(format!(
+            "Unexpected {} reply: {}",
+            std::any::type_name::<T>()
+                .rsplit("::")
+                .next()
+                .unwrap_or_default(),
+            e
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn login_reply_with_numbers() {
+        let reply = json!({
+            "AliveInterval": 20,
+            "ChannelNum": 4,
+            "DeviceType ": "DVR",
+            "ExtraChannel": 0,
+            "Ret": 100,
+            "SessionID": "0x0000000A",
+        });
+        let login: LoginResponse = parse(&reply).unwrap();
+        assert_eq!(login.ret, Some(100));
+        assert_eq!(login.session_id(), Some(10));
+        assert_eq!(login.alive_interval, Some(20));
+        assert_eq!(login.channel_num, Some(4));
+        assert_eq!(login.device_type.as_deref(), Some("DVR"));
+        assert_eq!(login.data_use_aes, None);
+    }
+
+    #[test]
+    fn login_reply_with_strings() {
+        let reply = json!({
+            "AliveInterval": "20000",
+            "ChannelNum": "0x10",
+            "DataUseAES": "0",
+            "DeviceType": 2,
+            "Ret": "100",
+            "SessionID": "0x0000000A",
+        });
+        let login: LoginResponse = parse(&reply).unwrap();
+        assert_eq!(login.ret, Some(100));
+        assert_eq!(login.alive_interval, Some(20000));
+        assert_eq!(login.channel_num, Some(16));
+        assert_eq!(login.device_type.as_deref(), Some("2"));
+        assert_eq!(login.data_use_aes, Some(false));
+    }
+
+    #[test]
+    fn system_info_with_duplicate_names() {
+        let reply = json!({
+            "SerialNo": "abc123",
+            "SN": "abc123",
+            "HardWare": "IPG-53H20AF",
+            "SoftWareVersion": "V4.02.R11.00000123.10010.140100",
+            "BuildTime": "2019-08-20 14:01:30",
+            "VideoInChannel": "1",
+            "ChannelNum": 1,
+            "AlarmInChannel": null,
+            "AlarmOutChannel": 1,
+            "AudioInChannel": 1,
+            "DeviceRunTime": "0x0000A5D1",
+        });
+        let info: SystemInfoResponse = parse(&reply).unwrap();
+        assert_eq!(info.serial_number.as_deref(), Some("abc123"));
+        assert_eq!(info.hardware.as_deref(), Some("IPG-53H20AF"));
+        assert_eq!(info.video_in_channels, Some(1));
+        assert_eq!(info.alarm_in_channels, None);
+        assert_eq!(info.alarm_out_channels, Some(1));
+        assert_eq!(info.device_run_time.as_deref(), Some("0x0000A5D1"));
+    }
+
+    #[test]
+    fn system_info_with_other_names() {
+        let reply = json!({"Sn": 12345, "Hardware": "NBD80", "ChannelNum": 8});
+        let info: SystemInfoResponse = parse(&reply).unwrap();
+        assert_eq!(info.serial_number.as_deref(), Some("12345"));
+        assert_eq!(info.hardware.as_deref(), Some("NBD80"));
+        assert_eq!(info.video_in_channels, Some(8));
+        assert!(parse::<SystemInfoResponse>(&json!([1, 2])).is_err());
+    }
+
+    #[test]
+    fn user_with_drifted_types() {
+        let reply = json!({"Name": "admin", "Reserved": "1", "Sharable": 0, "AuthorityList": ["ShutDown", 3]});
+        let user: User = parse(&reply).unwrap();
+        assert_eq!(user.name, "admin");
+        assert!(user.reserved);
+        assert!(!user.sharable);
+        assert_eq!(user.authority_list, ["ShutDown", "3"]);
+    }
+}
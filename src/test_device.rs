@@ -0,0 +1,124 @@
+//! A device on localhost answering whatever the test tells it to, for the tests
+//! that need the send and recv tasks
+
+use crate::commands::{Authentication, Connection};
+use crate::dvrip::DVRIPCam;
+use crate::protocol::{PacketHeader, packet_header, packet_tail, parse_json};
+use serde_json::{Value, json};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::Duration;
+
+pub(crate) const SESSION: u32 = 0x11;
+
+/// The device side of a connection
+pub(crate) struct FakeDevice {
+    stream: TcpStream,
+}
+
+impl FakeDevice {
+    /// Wait for the next packet
+    pub(crate) async fn recv(&mut self) -> (PacketHeader, Vec<u8>) {
+        let mut header = [0u8; PacketHeader::SIZE];
+        self.stream.read_exact(&mut header).await.unwrap();
+        let header = PacketHeader::decode(&header).unwrap();
+        let mut data = vec![0u8; header.data_len as usize];
+        self.stream.read_exact(&mut data).await.unwrap();
+        (header, data)
+    }
+
+    /// Wait for the next packet and parse its payload
+    pub(crate) async fn recv_json(&mut self) -> (PacketHeader, Value) {
+        let (header, data) = self.recv().await;
+        (header, parse_json(&data).unwrap())
+    }
+
+    /// The next packet, `None` when nothing comes within `timeout`
+    pub(crate) async fn try_recv(&mut self, timeout: Duration) -> Option<(PacketHeader, Vec<u8>)> {
+        tokio::time::timeout(timeout, self.recv()).await.ok()
+    }
+
+    /// Send a packet as the device, `payload` goes out as is
+    pub(crate) async fn send(&mut self, packet_count: u32, msg_id: u16, payload: &[u8]) {
+        self.send_on(0, packet_count, msg_id, payload).await;
+    }
+
+    /// Same as `send`, with the channel byte of media packets set
+    pub(crate) async fn send_on(
+        &mut self,
+        channel: u8,
+        packet_count: u32,
+        msg_id: u16,
+        payload: &[u8],
+    ) {
+        let mut header = packet_header(SESSION, packet_count, msg_id, payload.len(), 0).encode();
+        header[12] = channel;
+        self.stream.write_all(&header).await.unwrap();
+        self.stream.write_all(payload).await.unwrap();
+    }
+
+    /// Write `bytes` as they are, for malformed packets
+    pub(crate) async fn send_raw(&mut self, bytes: &[u8]) {
+        self.stream.write_all(bytes).await.unwrap();
+    }
+
+    /// Send `reply` as the JSON answer to `request`
+    pub(crate) async fn reply(&mut self, request: &PacketHeader, reply: Value) {
+        let mut payload = serde_json::to_vec(&reply).unwrap();
+        payload.extend_from_slice(packet_tail(0));
+        // Devices answer these one packet count further, see `CommandRequest::reply_key`
+        let packet_count = if matches!(request.msg_id, 0x0585 | 0x590 | 0x059a) {
+            request.packet_count + 1
+        } else {
+            request.packet_count
+        };
+        self.send(packet_count, request.msg_id + 1, &payload).await;
+    }
+
+    /// Answer the next request with `reply`, returning the request
+    pub(crate) async fn answer(&mut self, reply: Value) -> (PacketHeader, Value) {
+        let (header, request) = self.recv_json().await;
+        self.reply(&header, reply).await;
+        (header, request)
+    }
+
+    /// Close the connection from the device side
+    pub(crate) async fn disconnect(mut self) {
+        let _ = self.stream.shutdown().await;
+    }
+}
+
+/// A camera connected to a fake device, not logged in
+pub(crate) async fn connect(cam: DVRIPCam) -> (DVRIPCam, FakeDevice) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let mut cam = cam.with_port(port);
+    let (connected, accepted) = tokio::join!(
+        Connection::connect(&mut cam, Duration::from_secs(2)),
+        listener.accept()
+    );
+    connected.unwrap();
+    let (stream, _) = accepted.unwrap();
+    (cam, FakeDevice { stream })
+}
+
+/// A camera logged in to a fake device, keep-alives disabled
+pub(crate) async fn login(cam: DVRIPCam) -> (DVRIPCam, FakeDevice) {
+    login_with(cam.with_keep_alive(false), json!({})).await
+}
+
+/// Log in, answering with the OK reply merged with the fields of `extra`
+pub(crate) async fn login_with(cam: DVRIPCam, extra: Value) -> (DVRIPCam, FakeDevice) {
+    let (mut cam, mut device) = connect(cam).await;
+    let mut reply = json!({
+        "Ret": 100,
+        "SessionID": format!("0x{:08X}", SESSION),
+        "AliveInterval": 20,
+    });
+    if let (Some(reply), Value::Object(extra)) = (reply.as_object_mut(), extra) {
+        reply.extend(extra);
+    }
+    let (logged_in, _) = tokio::join!(cam.login("admin", ""), device.answer(reply));
+    assert!(logged_in.unwrap());
+    (cam, device)
+}
@@ -0,0 +1,292 @@
+//! Packet traces for debugging firmware quirks.
+//!
+//! With `DVRIPCam::with_trace_file` every packet sent and received is appended to a
+//! text file, one packet per line:
+//!
+//! ```text
+//! <milliseconds since connect> <'>' sent | '<' received> <header hex> <payload hex>
+//! ```
+//!
+//! Passwords (`PassWord`, the Wi-Fi `Keys`, ...) are redacted from the payloads.
+//! `replay` reads such a file back so the payloads can be fed to the parsers
+//! without the device.
+
+use crate::error::{DVRIPError, Result};
+use crate::protocol::{PacketHeader, parse_json};
+use bytes::Bytes;
+use serde_json::Value;
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::time::Instant;
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc;
+
+/// Fields replaced with `REDACTED` before a payload is written, the password hash
+/// is as good as the password for logging in
+const REDACTED_KEYS: &[&str] = &[
+    "PassWord",
+    "NewPassWord",
+    "Nonce",
+    "Challenge",
+    "Salt",
+    "Keys",
+];
+const REDACTED: &str = "<redacted>";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+impl Direction {
+    fn symbol(self) -> char {
+        match self {
+            Direction::Sent => '>',
+            Direction::Received => '<',
+        }
+    }
+}
+
+struct TracedPacket {
+    elapsed_ms: u128,
+    direction: Direction,
+    header: [u8; PacketHeader::SIZE],
+    // Shared with the packet that was sent or received, nothing is copied
+    payload: Vec<Bytes>,
+}
+
+/// Hands the packets of a connection to a task writing the trace file, so the
+/// send and recv tasks never wait for the disk
+pub(crate) struct Tracer {
+    packets: mpsc::UnboundedSender<TracedPacket>,
+    start: Instant,
+}
+
+impl Tracer {
+    pub(crate) async fn create(path: &Path) -> Result<Self> {
+        let file = tokio::fs::File::create(path).await?;
+        let (packets, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(write_trace(BufWriter::new(file), receiver));
+        Ok(Self {
+            packets,
+            start: Instant::now(),
+        })
+    }
+
+    pub(crate) fn record(&self, direction: Direction, header: &[u8], payload: Vec<Bytes>) {
+        let Ok(header) = header.try_into() else {
+            return;
+        };
+        // Tracing must never break the connection, a packet that can't be queued is dropped
+        let _ = self.packets.send(TracedPacket {
+            elapsed_ms: self.start.elapsed().as_millis(),
+            direction,
+            header,
+            payload,
+        });
+    }
+}
+
+/// Ends once every `Tracer` of the connection is dropped
+async fn write_trace(
+    mut file: BufWriter<tokio::fs::File>,
+    mut packets: mpsc::UnboundedReceiver<TracedPacket>,
+) {
+    while let Some(packet) = packets.recv().await {
+        // Each part on its own, the JSON of a command is never split across parts
+        let payload: String = packet
+            .payload
+            .iter()
+            .map(|part| to_hex(&redact(part)))
+            .collect();
+        let line = format!(
+            "{} {} {} {}\n",
+            packet.elapsed_ms,
+            packet.direction.symbol(),
+            to_hex(&packet.header),
+            payload
+        );
+        if file.write_all(line.as_bytes()).await.is_err() {
+            return;
+        }
+        // Written out whenever the queue runs dry, so a trace is complete up to a crash
+        if packets.is_empty() && file.flush().await.is_err() {
+            return;
+        }
+    }
+    let _ = file.flush().await;
+}
+
+/// Replace the secrets of a JSON payload, anything else is kept as it is
+fn redact(payload: &[u8]) -> Cow<'_, [u8]> {
+    fn redact_value(value: &mut Value) -> bool {
+        match value {
+            Value::Object(map) => {
+                let mut redacted = false;
+                for (key, value) in map.iter_mut() {
+                    if REDACTED_KEYS.contains(&key.as_str()) && !value.is_null() {
+                        *value = Value::from(REDACTED);
+                        redacted = true;
+                    } else {
+                        redacted |= redact_value(value);
+                    }
+                }
+                redacted
+            }
+            Value::Array(values) => values.iter_mut().fold(false, |r, v| redact_value(v) | r),
+            _ => false,
+        }
+    }
+
+    // Cheap check first, media payloads are never parsed
+    let has_secret = REDACTED_KEYS.iter().any(|key| {
+        payload.windows(key.len() + 2).any(|w| {
+            w[0] == b'"' && &w[1..=key.len()] == key.as_bytes() && w[key.len() + 1] == b'"'
+        })
+    });
+    if !has_secret {
+        return Cow::Borrowed(payload);
+    }
+    let Ok(mut value) = parse_json(payload) else {
+        return Cow::Borrowed(payload);
+    };
+    if !redact_value(&mut value) {
+        return Cow::Borrowed(payload);
+    }
+    Cow::Owned(serde_json::to_vec(&value).unwrap_or_default())
+}
+
+/// A packet read back from a trace file
+pub struct TraceRecord {
+    /// Milliseconds since the connection was opened
+    pub elapsed_ms: u64,
+    pub direction: Direction,
+    pub header: PacketHeader,
+    pub payload: Vec<u8>,
+}
+
+impl TraceRecord {
+    /// Parse the payload as a JSON command or reply
+    pub fn json(&self) -> Result<Value> {
+        parse_json(&self.payload)
+    }
+}
+
+/// Read every packet of a trace file written by `with_trace_file`
+pub fn replay(path: impl AsRef<Path>) -> Result<Vec<TraceRecord>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut records = Vec::new();
+
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let invalid = || DVRIPError::ProtocolError(format!("Invalid trace line {}", number + 1));
+
+        let mut fields = line.split(' ');
+        let elapsed_ms = fields
+            .next()
+            .and_then(|f| f.parse().ok())
+            .ok_or_else(invalid)?;
+        let direction = match fields.next() {
+            Some(">") => Direction::Sent,
+            Some("<") => Direction::Received,
+            _ => return Err(invalid()),
+        };
+        let header = fields.next().and_then(from_hex).ok_or_else(invalid)?;
+        let payload = from_hex(fields.next().unwrap_or_default()).ok_or_else(invalid)?;
+
+        records.push(TraceRecord {
+            elapsed_ms,
+            direction,
+            header: PacketHeader::decode(&header)?,
+            payload,
+        });
+    }
+
+    Ok(records)
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DVRIPCam;
+    use crate::commands::Connection;
+    use crate::test_device;
+    use serde_json::json;
+    use std::time::Duration;
+
+    #[test]
+    fn secrets_are_redacted() {
+        let login = br#"{"EncryptType":"MD5","PassWord":"tlJwpbo6","UserName":"admin"}"#;
+        let redacted: Value = serde_json::from_slice(&redact(login)).unwrap();
+        assert_eq!(redacted["PassWord"], REDACTED);
+        assert_eq!(redacted["UserName"], "admin");
+
+        let wifi = br#"{"NetWork.Wifi":{"SSID":"home","Keys":"secret"}}"#;
+        let redacted: Value = serde_json::from_slice(&redact(wifi)).unwrap();
+        assert_eq!(redacted["NetWork.Wifi"]["Keys"], REDACTED);
+
+        let media = [0, 0, 1, 0xFD, 1, 2, 3];
+        assert_eq!(redact(&media), &media[..]);
+    }
+
+    #[tokio::test]
+    async fn trace_replays() {
+        let path = std::env::temp_dir().join(format!("dvrip-trace-{}.txt", std::process::id()));
+        let cam = DVRIPCam::new("127.0.0.1").with_trace_file(&path);
+        let (mut cam, mut device) = test_device::login(cam).await;
+        let (general, _) = tokio::join!(
+            cam.get_command("General", None),
+            device.answer(json!({"Name": "General", "Ret": 100, "General": {"LocalNo": 3}}))
+        );
+        assert_eq!(general.unwrap()["LocalNo"], 3);
+        drop(device);
+        cam.close_with_timeout(Duration::ZERO).await.unwrap();
+
+        // The writer task flushes once it has caught up
+        let mut records = vec![];
+        for _ in 0..50 {
+            records = replay(&path).unwrap();
+            if records.len() >= 4 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let _ = std::fs::remove_file(&path);
+
+        let directions: Vec<_> = records
+            .iter()
+            .map(|r| (r.direction, r.header.msg_id))
+            .collect();
+        assert_eq!(
+            directions[..4],
+            [
+                (Direction::Sent, 1000),
+                (Direction::Received, 1001),
+                (Direction::Sent, 1042),
+                (Direction::Received, 1043)
+            ]
+        );
+        assert_eq!(records[0].json().unwrap()["PassWord"], REDACTED);
+        assert_eq!(records[3].json().unwrap()["General"]["LocalNo"], 3);
+    }
+}